@@ -0,0 +1,153 @@
+use ffms2::track::Track;
+
+/// How requested ranges are snapped to keyframes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+    /// Move the start back and the end forward to the enclosing keyframes.
+    Expand,
+    /// Move the start forward and the end back to the enclosed keyframes.
+    Shrink,
+    /// Refuse requests that do not start and end on keyframes.
+    Error,
+}
+
+pub fn parse_align(mode: &str) -> Result<Align, String> {
+    match mode {
+        "expand" => Ok(Align::Expand),
+        "shrink" => Ok(Align::Shrink),
+        "error" => Ok(Align::Error),
+        _ => Err(format!("Invalid keyframe alignment: {}", mode)),
+    }
+}
+
+/// Frame numbers of the keyframes among the first `total` frames of `track`.
+pub fn keyframes(track: &Track, total: usize) -> Vec<usize> {
+    (0..total)
+        .filter(|&n| track.FrameInfo(n).KeyFrame() != 0)
+        .collect()
+}
+
+/// Snaps `start..end` to `keyframes`, sorted, within `total` frames. The end
+/// of the input counts as a keyframe boundary. Returns a message describing
+/// why the range was refused on failure.
+pub fn align(
+    mode: Align,
+    keyframes: &[usize],
+    total: usize,
+    start: usize,
+    end: usize,
+) -> Result<(usize, usize), String> {
+    let at_or_before = |frame: usize| keyframes.iter().rev().copied().find(|&k| k <= frame);
+    let at_or_after = |frame: usize| {
+        keyframes
+            .iter()
+            .copied()
+            .find(|&k| k >= frame)
+            .or((frame <= total).then_some(total))
+    };
+
+    let aligned = match mode {
+        Align::Expand => at_or_before(start).zip(at_or_after(end)),
+        Align::Shrink => at_or_after(start).zip(at_or_before(end).or(Some(0))),
+        Align::Error => Some((start, end)),
+    };
+
+    let (aligned_start, aligned_end) = aligned
+        .filter(|(start, end)| start < end)
+        .ok_or_else(|| format!("no keyframe-aligned range for {} to {}", start, end))?;
+
+    let is_boundary = |frame: usize| frame == total || keyframes.binary_search(&frame).is_ok();
+    if mode == Align::Error && !(is_boundary(start) && is_boundary(end)) {
+        return Err(format!(
+            "not keyframe aligned, nearest keyframes {} and {}",
+            at_or_before(start).unwrap_or(0),
+            at_or_after(end).unwrap_or(total)
+        ));
+    }
+
+    Ok((aligned_start, aligned_end))
+}
+
+/// Splits `start..end` into parts of at most `max` frames. With
+/// `keyframes`, sorted, each part after the first starts on a keyframe,
+/// so the cuts come as late as they can before the limit. Returns a
+/// message describing why the range was refused when some part would
+/// have no keyframe to end on.
+pub fn split(
+    start: usize,
+    end: usize,
+    max: usize,
+    keyframes: Option<&[usize]>,
+) -> Result<Vec<(usize, usize)>, String> {
+    let mut parts = Vec::new();
+    let mut part_start = start;
+
+    while end - part_start > max {
+        let limit = part_start + max;
+        let cut = match keyframes {
+            None => Some(limit),
+            Some(keyframes) => keyframes
+                .iter()
+                .rev()
+                .copied()
+                .find(|&k| k > part_start && k <= limit),
+        }
+        .ok_or_else(|| {
+            format!(
+                "no keyframe within {} frames of {} to split at",
+                max, part_start
+            )
+        })?;
+
+        parts.push((part_start, cut));
+        part_start = cut;
+    }
+    parts.push((part_start, end));
+
+    Ok(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYFRAMES: &[usize] = &[0, 24, 48, 72];
+
+    #[test]
+    fn expand_and_shrink_snap_to_keyframes() {
+        assert_eq!(align(Align::Expand, KEYFRAMES, 90, 30, 50), Ok((24, 72)));
+        assert_eq!(align(Align::Expand, KEYFRAMES, 90, 80, 85), Ok((72, 90)));
+        assert_eq!(align(Align::Shrink, KEYFRAMES, 90, 30, 80), Ok((48, 72)));
+        assert!(align(Align::Shrink, KEYFRAMES, 90, 30, 40).is_err());
+    }
+
+    #[test]
+    fn error_mode_accepts_only_aligned_ranges() {
+        assert_eq!(align(Align::Error, KEYFRAMES, 90, 24, 90), Ok((24, 90)));
+        assert_eq!(
+            align(Align::Error, KEYFRAMES, 90, 30, 48),
+            Err("not keyframe aligned, nearest keyframes 24 and 48".to_string())
+        );
+    }
+
+    #[test]
+    fn splits_at_the_last_keyframe_in_reach() {
+        assert_eq!(
+            split(0, 90, 40, None),
+            Ok(vec![(0, 40), (40, 80), (80, 90)])
+        );
+        assert_eq!(split(10, 20, 40, None), Ok(vec![(10, 20)]));
+        assert_eq!(
+            split(0, 90, 40, Some(KEYFRAMES)),
+            Ok(vec![(0, 24), (24, 48), (48, 72), (72, 90)])
+        );
+        assert_eq!(
+            split(0, 90, 50, Some(KEYFRAMES)),
+            Ok(vec![(0, 48), (48, 90)])
+        );
+        assert_eq!(
+            split(0, 90, 20, Some(KEYFRAMES)),
+            Err("no keyframe within 20 frames of 0 to split at".to_string())
+        );
+    }
+}
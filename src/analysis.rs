@@ -0,0 +1,164 @@
+use crate::complexity::Complexity;
+use crate::Source;
+use ffms_segmenter::prefetch::Decoder;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// First line of an `--analyze` stats file.
+const HEADER: &str = "# ffms-segmenter analysis";
+
+/// What the first pass recorded about one frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameStats {
+    pub keyframe: bool,
+    /// Mean absolute luma difference to the frame before, on an 8-bit
+    /// scale, as the scene score. 0 for the first frame.
+    pub difference: f64,
+}
+
+/// Per-frame metrics of a whole input, written by `--analyze` and read back
+/// by `--first-pass` so later runs need not decode twice to pick
+/// boundaries. FFMS2 does not expose packet sizes, so these are not
+/// recorded.
+#[derive(Debug, Default, PartialEq)]
+pub struct Analysis {
+    pub frames: Vec<FrameStats>,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Parses the `<frame> <keyframe> <difference>` line of `frame`.
+fn parse_line(frame: usize, line: &str) -> io::Result<FrameStats> {
+    let invalid = || invalid(format!("Invalid analysis of frame {}: {}", frame, line));
+
+    match line.split_whitespace().collect::<Vec<&str>>()[..] {
+        [number, keyframe @ ("0" | "1"), difference] if number.parse() == Ok(frame) => {
+            Ok(FrameStats {
+                keyframe: keyframe == "1",
+                difference: difference.parse().map_err(|_| invalid())?,
+            })
+        }
+        _ => Err(invalid()),
+    }
+}
+
+impl Analysis {
+    /// Decodes every frame of `source`, recording its metrics.
+    pub fn run(source: &mut Source) -> io::Result<Analysis> {
+        let mut complexity = Complexity::new(source.format.colorspace);
+        let mut frames = Vec::with_capacity(source.total_frames);
+
+        for frame_number in 0..source.total_frames {
+            let frame = source.video_source.decode(frame_number)?;
+            frames.push(FrameStats {
+                keyframe: source.track.FrameInfo(frame_number).KeyFrame() != 0,
+                difference: complexity.push(&frame).unwrap_or(0.0),
+            });
+        }
+
+        Ok(Analysis { frames })
+    }
+
+    /// Lines of `<frame> <keyframe> <difference>` after a header.
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{} {}\n", HEADER, self.frames.len());
+        for (frame, stats) in self.frames.iter().enumerate() {
+            text.push_str(&format!(
+                "{} {} {:.3}\n",
+                frame, stats.keyframe as u8, stats.difference
+            ));
+        }
+        text
+    }
+
+    pub fn parse(text: &str) -> io::Result<Analysis> {
+        let mut lines = text.lines();
+        let total: usize = lines
+            .next()
+            .and_then(|line| line.strip_prefix(HEADER))
+            .and_then(|total| total.trim().parse().ok())
+            .ok_or_else(|| invalid("Not an ffms-segmenter analysis".to_string()))?;
+
+        let frames = lines
+            .enumerate()
+            .map(|(frame, line)| parse_line(frame, line))
+            .collect::<io::Result<Vec<FrameStats>>>()?;
+
+        if frames.len() != total {
+            return Err(invalid(format!(
+                "Analysis lists {} of its {} frames",
+                frames.len(),
+                total
+            )));
+        }
+
+        Ok(Analysis { frames })
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_text())
+    }
+
+    /// Loads the `--first-pass` stats of an input of `total` frames.
+    pub fn load(path: &Path, total: usize) -> io::Result<Analysis> {
+        let analysis = Analysis::parse(&fs::read_to_string(path)?)?;
+        if analysis.frames.len() != total {
+            return Err(invalid(format!(
+                "{} was analyzed with {} frames, the input has {}",
+                path.display(),
+                analysis.frames.len(),
+                total
+            )));
+        }
+
+        Ok(analysis)
+    }
+
+    /// The scene score of every frame, in order.
+    pub fn scores(&self) -> Vec<f64> {
+        self.frames.iter().map(|stats| stats.difference).collect()
+    }
+
+    /// The mean difference between the frames of `start..end`, the same
+    /// measure as `mean_abs_diff` of `--complexity` reports.
+    pub fn complexity(&self, start: usize, end: usize) -> f64 {
+        let end = end.min(self.frames.len());
+        match self.frames.get(start + 1..end) {
+            Some(pairs) if !pairs.is_empty() => {
+                pairs.iter().map(|stats| stats.difference).sum::<f64>() / pairs.len() as f64
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_analyses() {
+        let frame = |keyframe, difference| FrameStats {
+            keyframe,
+            difference,
+        };
+        let analysis = Analysis {
+            frames: vec![frame(true, 0.0), frame(false, 1.5), frame(true, 24.25)],
+        };
+
+        let text = analysis.to_text();
+        assert_eq!(
+            text,
+            "# ffms-segmenter analysis 3\n0 1 0.000\n1 0 1.500\n2 1 24.250\n"
+        );
+        assert_eq!(Analysis::parse(&text).unwrap(), analysis);
+        assert!(Analysis::parse("# ffms-segmenter analysis 2\n0 1 0.000\n").is_err());
+        assert!(Analysis::parse("0 1 0.000\n").is_err());
+
+        assert_eq!(analysis.complexity(0, 3), 12.875);
+        assert_eq!(analysis.complexity(2, 3), 0.0);
+    }
+}
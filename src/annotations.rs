@@ -0,0 +1,141 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// A labelled frame range (end-exclusive) read from an annotation sidecar.
+pub struct Annotation {
+    pub start: usize,
+    pub end: usize,
+    pub label: String,
+}
+
+/// Per-frame annotations produced by upstream analysis, e.g. shot labels
+/// or ad markers.
+///
+/// The sidecar is plain text with one annotation per line, either
+/// `<start> <end> <label>` or `<frame> <label>`. Blank lines and lines
+/// starting with `#` are ignored.
+pub struct Annotations {
+    entries: Vec<Annotation>,
+}
+
+impl Annotations {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut entries = Vec::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid annotation on line {}: {}", number + 1, line),
+                )
+            };
+
+            let mut tokens = line.split_whitespace();
+            let start = tokens
+                .next()
+                .and_then(|token| token.parse::<usize>().ok())
+                .ok_or_else(invalid)?;
+            let mut rest: Vec<&str> = tokens.collect();
+
+            let end = match rest.first().and_then(|token| token.parse::<usize>().ok()) {
+                Some(end) => {
+                    rest.remove(0);
+                    end
+                }
+                None => start + 1,
+            };
+
+            if rest.is_empty() || end <= start {
+                return Err(invalid());
+            }
+
+            entries.push(Annotation {
+                start,
+                end,
+                label: rest.join(" "),
+            });
+        }
+
+        Ok(Annotations { entries })
+    }
+
+    pub fn with_label<'a>(&'a self, label: &'a str) -> impl Iterator<Item = &'a Annotation> {
+        self.entries
+            .iter()
+            .filter(move |entry| entry.label == label)
+    }
+}
+
+/// Turns a requested range into the segments to extract, splitting at the
+/// edges of annotations with a split label and dropping frames covered by
+/// annotations with an exclude label.
+pub struct Planner {
+    annotations: Annotations,
+    split_labels: Vec<String>,
+    exclude_labels: Vec<String>,
+}
+
+impl Planner {
+    pub fn new(
+        annotations: Annotations,
+        split_labels: Vec<String>,
+        exclude_labels: Vec<String>,
+    ) -> Self {
+        Planner {
+            annotations,
+            split_labels,
+            exclude_labels,
+        }
+    }
+
+    pub fn plan(&self, start: usize, end: usize) -> Vec<(usize, usize)> {
+        let mut ranges = vec![(start, end)];
+
+        for label in &self.exclude_labels {
+            for excluded in self.annotations.with_label(label) {
+                ranges = ranges
+                    .into_iter()
+                    .flat_map(|(start, end)| {
+                        let before = (start, end.min(excluded.start));
+                        let after = (start.max(excluded.end), end);
+                        [before, after]
+                    })
+                    .filter(|(start, end)| start < end)
+                    .collect();
+            }
+        }
+
+        let mut cuts: Vec<usize> = self
+            .split_labels
+            .iter()
+            .flat_map(|label| self.annotations.with_label(label))
+            .flat_map(|annotation| [annotation.start, annotation.end])
+            .collect();
+        cuts.sort_unstable();
+        cuts.dedup();
+
+        ranges
+            .into_iter()
+            .flat_map(|(start, end)| {
+                let mut bounds = vec![start];
+                bounds.extend(cuts.iter().copied().filter(|&cut| cut > start && cut < end));
+                bounds.push(end);
+
+                bounds
+                    .windows(2)
+                    .map(|pair| (pair[0], pair[1]))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+}
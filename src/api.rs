@@ -0,0 +1,477 @@
+use crate::prefetch;
+use crate::yuv4mpeg::{self, Interlacing};
+use ffms2::frame::{Frame, Resizers};
+use ffms2::index::{Index, Indexer};
+use ffms2::track::TrackType;
+use ffms2::video::{ColorRanges, SeekMode, VideoSource};
+use ffms2::{IndexErrorHandling, FFMS2};
+use std::io::{self, Write};
+use std::path::Path;
+use std::thread;
+use y4m::{Colorspace, Ratio};
+
+/// Versions of the line-based stdin/stdout protocol spoken by the binary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[non_exhaustive]
+pub enum Protocol {
+    /// `<start> <end>` requests, answered with a `<start> <path>` line per
+    /// written segment.
+    V1,
+    /// V1 plus `<input> <start> <end>` requests for additional inputs and
+    /// `open <path>`, answered with `opened <input> <path>`.
+    V2,
+}
+
+impl Protocol {
+    pub const LATEST: Protocol = Protocol::V2;
+
+    pub fn version(self) -> u32 {
+        match self {
+            Protocol::V1 => 1,
+            Protocol::V2 => 2,
+        }
+    }
+
+    pub fn from_version(version: u32) -> Option<Self> {
+        match version {
+            1 => Some(Protocol::V1),
+            2 => Some(Protocol::V2),
+            _ => None,
+        }
+    }
+
+    /// Parses a request line for one segment, returning `None` if it is not
+    /// a valid request. Lines with several ranges, which the binary accepts
+    /// too, are `None` here; see [`Protocol::parse_requests`].
+    pub fn parse_request(self, line: &str) -> Option<SegmentRequest> {
+        let numbers = line
+            .split_whitespace()
+            .map(|token| token.parse().ok())
+            .collect::<Option<Vec<usize>>>()?;
+
+        match (self, &numbers[..]) {
+            (_, &[start, end]) => Some(SegmentRequest::new(start, end)),
+            (Protocol::V2, &[input, start, end]) => Some(SegmentRequest {
+                input,
+                ..SegmentRequest::new(start, end)
+            }),
+            _ => None,
+        }
+    }
+
+    /// Parses a request line for one or several segments, as the binary
+    /// does: `0 100 240 360` and `0 100; 1 240 360` are two requests each.
+    /// Returns `None` if any of them is not a valid request.
+    pub fn parse_requests(self, line: &str) -> Option<Vec<SegmentRequest>> {
+        match crate::ranges::split_ranges(line) {
+            Some(lines) => lines.iter().map(|line| self.parse_request(line)).collect(),
+            None => self.parse_request(line).map(|request| vec![request]),
+        }
+    }
+
+    /// Formats the line announcing that the segment starting at `start` was
+    /// written to `path`.
+    pub fn format_segment(self, start: usize, path: &str) -> String {
+        format!("{} {}", start, path)
+    }
+}
+
+/// Features announced by the `hello` line the binary sends before anything
+/// else when started with `--handshake`, e.g.
+/// `hello protocol=2 version=0.1.0 commands=range,open formats=y4m`.
+/// Keys a controller does not know are skipped when parsing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Handshake {
+    pub protocol: u32,
+    /// Version of the binary.
+    pub version: String,
+    pub commands: Vec<String>,
+    /// y4m colorspaces segments can be written in, as in the `C` header
+    /// parameter.
+    pub colorspaces: Vec<String>,
+    /// File formats segments can be written as.
+    pub formats: Vec<String>,
+    /// How request ends are counted, if announced. Older binaries only
+    /// take exclusive ends.
+    pub ranges: Option<RangeConvention>,
+}
+
+impl Handshake {
+    pub fn new(protocol: Protocol, version: &str) -> Self {
+        Handshake {
+            protocol: protocol.version(),
+            version: version.to_string(),
+            commands: Vec::new(),
+            colorspaces: Vec::new(),
+            formats: Vec::new(),
+            ranges: None,
+        }
+    }
+
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut tokens = line.split_whitespace();
+        if tokens.next()? != "hello" {
+            return None;
+        }
+
+        let list = |value: &str| value.split(',').map(str::to_string).collect();
+        let mut handshake = Handshake::new(Protocol::V1, "");
+        let mut protocol = None;
+
+        for token in tokens {
+            match token.split_once('=').unwrap_or((token, "")) {
+                ("protocol", value) => protocol = Some(value.parse().ok()?),
+                ("version", value) => handshake.version = value.to_string(),
+                ("commands", value) => handshake.commands = list(value),
+                ("colorspaces", value) => handshake.colorspaces = list(value),
+                ("formats", value) => handshake.formats = list(value),
+                ("ranges", value) => handshake.ranges = RangeConvention::from_name(value),
+                _ => {}
+            }
+        }
+
+        handshake.protocol = protocol?;
+        Some(handshake)
+    }
+
+    /// The protocol version, if this library knows it.
+    pub fn protocol(&self) -> Option<Protocol> {
+        Protocol::from_version(self.protocol)
+    }
+
+    pub fn supports(&self, command: &str) -> bool {
+        self.commands.iter().any(|supported| supported == command)
+    }
+
+    pub fn format(&self) -> String {
+        let mut line = format!(
+            "hello protocol={} version={} commands={} colorspaces={} formats={}",
+            self.protocol,
+            self.version,
+            self.commands.join(","),
+            self.colorspaces.join(","),
+            self.formats.join(",")
+        );
+        if let Some(ranges) = self.ranges {
+            line.push_str(&format!(" ranges={}", ranges.name()));
+        }
+        line
+    }
+}
+
+/// An end-exclusive range of frames to write as one segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SegmentRequest {
+    /// Input the range is taken from, 0 for the main input. Ignored by
+    /// [`Segmenter`], which only has one input.
+    pub input: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl SegmentRequest {
+    pub fn new(start: usize, end: usize) -> Self {
+        SegmentRequest {
+            input: 0,
+            start,
+            end,
+        }
+    }
+}
+
+/// How the end of a requested range is counted. Segments are named and
+/// reported end-exclusive either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RangeConvention {
+    /// `<start> <end>` covers `start..end`, so `0 24` is 24 frames.
+    #[default]
+    Exclusive,
+    /// `<start> <end>` covers `start..=end`, so `0 23` is 24 frames.
+    Inclusive,
+}
+
+impl RangeConvention {
+    pub fn name(self) -> &'static str {
+        match self {
+            RangeConvention::Exclusive => "exclusive",
+            RangeConvention::Inclusive => "inclusive",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "exclusive" => Some(RangeConvention::Exclusive),
+            "inclusive" => Some(RangeConvention::Inclusive),
+            _ => None,
+        }
+    }
+
+    /// The exclusive end of a range whose end was given as `end`.
+    pub fn exclusive_end(self, end: usize) -> usize {
+        match self {
+            RangeConvention::Exclusive => end,
+            RangeConvention::Inclusive => end.saturating_add(1),
+        }
+    }
+
+    /// Formats `start..end` as a `<start> <end>` request.
+    pub fn format_range(self, start: usize, end: usize) -> String {
+        match self {
+            RangeConvention::Exclusive => format!("{} {}", start, end),
+            RangeConvention::Inclusive => format!("{} {}", start, end.saturating_sub(1)),
+        }
+    }
+}
+
+/// Chroma subsampling of the written frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Chroma {
+    C420,
+    C422,
+    C444,
+}
+
+/// Format of the frames in written segments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct OutputFormat {
+    pub width: usize,
+    pub height: usize,
+    pub framerate_num: usize,
+    pub framerate_den: usize,
+    pub chroma: Chroma,
+    pub bit_depth: usize,
+    pub interlacing: Interlacing,
+}
+
+/// Progress reported while writing a segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Event {
+    FrameWritten { frame: usize },
+    SegmentFinished { start: usize, end: usize },
+}
+
+fn ffms_error(context: &str, err: ffms2::Error) -> io::Error {
+    io::Error::other(format!("{}: {:?}", context, err))
+}
+
+/// Pixel formats frames are written in as they are decoded. Others are
+/// converted to yuv420p.
+const FORMATS: [(&str, Chroma, usize, Colorspace); 9] = [
+    ("yuv420p", Chroma::C420, 8, Colorspace::C420),
+    ("yuv422p", Chroma::C422, 8, Colorspace::C422),
+    ("yuv444p", Chroma::C444, 8, Colorspace::C444),
+    ("yuv420p10le", Chroma::C420, 10, Colorspace::C420p10),
+    ("yuv422p10le", Chroma::C422, 10, Colorspace::C422p10),
+    ("yuv444p10le", Chroma::C444, 10, Colorspace::C444p10),
+    ("yuv420p12le", Chroma::C420, 12, Colorspace::C420p12),
+    ("yuv422p12le", Chroma::C422, 12, Colorspace::C422p12),
+    ("yuv444p12le", Chroma::C444, 12, Colorspace::C444p12),
+];
+
+fn supported(pixel_format: i32) -> Option<(Chroma, usize, Colorspace)> {
+    FORMATS
+        .iter()
+        .find(|(name, ..)| Frame::GetPixFmt(name) == pixel_format)
+        .map(|&(_, chroma, bit_depth, colorspace)| (chroma, bit_depth, colorspace))
+}
+
+fn output_format(
+    frame: &Frame,
+    width: usize,
+    height: usize,
+    framerate: Ratio,
+) -> io::Result<(OutputFormat, Colorspace)> {
+    let (chroma, bit_depth, colorspace) =
+        supported(frame.ConvertedPixelFormat).ok_or_else(|| {
+            io::Error::other(format!(
+                "Unsupported pixel format: {}",
+                frame.ConvertedPixelFormat
+            ))
+        })?;
+
+    let format = OutputFormat {
+        width,
+        height,
+        framerate_num: framerate.num,
+        framerate_den: framerate.den,
+        chroma,
+        bit_depth,
+        interlacing: Interlacing::from_frame_props(frame.InterlacedFrame, frame.TopFieldFirst),
+    };
+
+    Ok((format, colorspace))
+}
+
+/// An indexed video track that segments can be written from.
+pub struct Segmenter {
+    // Declared before `index` so it is dropped first.
+    video_source: VideoSource,
+    _index: Index,
+    format: OutputFormat,
+    colorspace: Colorspace,
+    range: yuv4mpeg::ColorRange,
+    frame_count: usize,
+}
+
+impl Segmenter {
+    /// Indexes `path` and opens `track`, or the first video track if
+    /// `None`, decoding with `threads` threads. Frames are written in the
+    /// pixel format and range they decode in, or as yuv420p when y4m has no
+    /// colorspace for it.
+    pub fn open(path: &Path, track: Option<usize>, threads: usize) -> io::Result<Self> {
+        FFMS2::Init();
+
+        let indexer = Indexer::new(path).map_err(|err| ffms_error("Failed to open input", err))?;
+        let index = indexer
+            .DoIndexing2(IndexErrorHandling::IEH_ABORT)
+            .map_err(|err| ffms_error("Failed to index input", err))?;
+
+        let track = match track {
+            Some(track) => track,
+            None => index
+                .FirstTrackOfType(TrackType::TYPE_VIDEO)
+                .map_err(|err| ffms_error("No video track found", err))?,
+        };
+
+        let mut video_source =
+            VideoSource::new(path, track, &index, threads, SeekMode::SEEK_NORMAL)
+                .map_err(|err| ffms_error("Failed to open video track", err))?;
+
+        let properties = video_source.GetVideoProperties();
+        let framerate = Ratio {
+            num: properties.FPSNumerator as usize,
+            den: properties.FPSDenominator as usize,
+        };
+
+        let first = Frame::GetFrame(&mut video_source, 0)
+            .map_err(|err| ffms_error("Failed to decode frame 0", err))?;
+        let (width, height) = (first.EncodedWidth as usize, first.EncodedHeight as usize);
+        let pixel_format = first.ConvertedPixelFormat;
+        let range = yuv4mpeg::ColorRange::from_frame_props(first.ColorRange);
+
+        // Frames keep the pixel format and range they were decoded in, as
+        // the binary writes them without --pix-fmt.
+        let input_range = match range {
+            yuv4mpeg::ColorRange::Limited => ColorRanges::CR_MPEG,
+            yuv4mpeg::ColorRange::Full => ColorRanges::CR_JPEG,
+        };
+        video_source
+            .SetInputFormatV(1, input_range, pixel_format as usize)
+            .map_err(|err| ffms_error("Failed to set input format", err))?;
+        if supported(pixel_format).is_none() {
+            video_source
+                .SetOutputFormatV2(
+                    &mut vec![Frame::GetPixFmt("yuv420p")],
+                    width,
+                    height,
+                    Resizers::RESIZER_POINT,
+                )
+                .map_err(|err| ffms_error("Failed to set output format", err))?;
+        }
+
+        let first = Frame::GetFrame(&mut video_source, 0)
+            .map_err(|err| ffms_error("Failed to decode frame 0", err))?;
+        let (format, colorspace) = output_format(&first, width, height, framerate)?;
+
+        Ok(Segmenter {
+            video_source,
+            _index: index,
+            format,
+            colorspace,
+            range,
+            frame_count: properties.NumFrames as usize,
+        })
+    }
+
+    pub fn format(&self) -> OutputFormat {
+        self.format
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frame_count
+    }
+
+    /// Writes `request` as y4m to `sink`. The end of the range is clamped to
+    /// the number of frames in the track.
+    pub fn write_segment<W: Write>(
+        &mut self,
+        request: SegmentRequest,
+        sink: W,
+        events: &mut dyn FnMut(Event),
+    ) -> io::Result<()> {
+        let start = request.start;
+        let end = request.end.min(self.frame_count);
+        if start >= end {
+            return Err(io::Error::other(format!(
+                "Empty segment {} to {}, the track has {} frames",
+                request.start, request.end, self.frame_count
+            )));
+        }
+
+        let format = self.format;
+        let mut writer = yuv4mpeg::Writer::new(
+            sink,
+            format.width,
+            format.height,
+            Ratio {
+                num: format.framerate_num,
+                den: format.framerate_den,
+            },
+            self.colorspace,
+            format.interlacing,
+            self.range,
+        )?;
+
+        thread::scope(|scope| -> io::Result<()> {
+            let (frames, decoder) = prefetch::spawn(
+                scope,
+                &mut self.video_source,
+                start,
+                end,
+                16,
+                prefetch::OnDecodeError::Abort,
+            );
+
+            for (frame, decoded) in frames {
+                writer.write_frame(&decoded)?;
+                events(Event::FrameWritten { frame });
+            }
+
+            decoder.join().unwrap()
+        })?;
+
+        events(Event::SegmentFinished { start, end });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_match_the_decoded_layouts() {
+        for &(name, chroma, bit_depth, colorspace) in &FORMATS {
+            let &(_, bytes, x, y) = prefetch::LAYOUTS
+                .iter()
+                .find(|layout| layout.0 == name)
+                .unwrap_or_else(|| panic!("{} frames cannot be copied out", name));
+            let shifts = match chroma {
+                Chroma::C420 => (1, 1),
+                Chroma::C422 => (1, 0),
+                Chroma::C444 => (0, 0),
+            };
+
+            assert_eq!((x, y), shifts, "{}", name);
+            assert_eq!(bytes, colorspace.get_bytes_per_sample(), "{}", name);
+            assert_eq!(bit_depth, colorspace.get_bit_depth(), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use crate::{decoder_threads, index_file, open_source, CliArgs};
+use ffms2::frame::Frame;
+use ffms2::video::VideoSource;
+use ffms2::IndexErrorHandling;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// The `--bench-pattern` choice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+    Sequential,
+    Random,
+    Both,
+}
+
+pub fn parse_pattern(pattern: &str) -> Result<Pattern, String> {
+    match pattern {
+        "sequential" => Ok(Pattern::Sequential),
+        "random" => Ok(Pattern::Random),
+        "both" => Ok(Pattern::Both),
+        _ => Err(format!("Invalid bench pattern: {}", pattern)),
+    }
+}
+
+/// Decode latencies of one pattern.
+struct Timings {
+    total: Duration,
+    /// Sorted.
+    frames: Vec<Duration>,
+}
+
+impl Timings {
+    fn fps(&self) -> f64 {
+        match self.total.as_secs_f64() {
+            seconds if seconds > 0.0 => self.frames.len() as f64 / seconds,
+            _ => 0.0,
+        }
+    }
+
+    /// Latency in milliseconds below which `percent` of frames decoded.
+    fn percentile(&self, percent: usize) -> f64 {
+        if self.frames.is_empty() {
+            return 0.0;
+        }
+
+        let index = ((self.frames.len() - 1) * percent).div_ceil(100);
+        self.frames[index].as_secs_f64() * 1000.0
+    }
+
+    fn report(&self, pattern: &str) -> String {
+        format!(
+            "{} frames={} seconds={:.3} fps={:.1} p50_ms={:.2} p95_ms={:.2} max_ms={:.2}",
+            pattern,
+            self.frames.len(),
+            self.total.as_secs_f64(),
+            self.fps(),
+            self.percentile(50),
+            self.percentile(95),
+            self.percentile(100)
+        )
+    }
+}
+
+fn time_frames(
+    video_source: &mut VideoSource,
+    frames: impl Iterator<Item = usize>,
+) -> io::Result<Timings> {
+    let started = Instant::now();
+    let mut latencies = Vec::new();
+
+    for n in frames {
+        let decoded = Instant::now();
+        Frame::GetFrame(video_source, n)
+            .map_err(|err| io::Error::other(format!("Failed to decode frame {}: {:?}", n, err)))?;
+        latencies.push(decoded.elapsed());
+    }
+    latencies.sort();
+
+    Ok(Timings {
+        total: started.elapsed(),
+        frames: latencies,
+    })
+}
+
+/// Frame numbers below `total` in a fixed pseudo-random order, so runs
+/// with different settings seek to the same frames.
+fn random_frames(count: usize, total: usize) -> impl Iterator<Item = usize> {
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+
+    (0..count).map(move |_| {
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        ((state >> 33) % total as u64) as usize
+    })
+}
+
+/// Decodes `frames` frames in `pattern` without writing anything and
+/// prints a line of decode speed and per-frame latency for each pattern.
+pub fn run(
+    args: &CliArgs,
+    ignore_errors: IndexErrorHandling,
+    frames: usize,
+    pattern: Pattern,
+) -> io::Result<()> {
+    let index = index_file(args, &args.input_file, ignore_errors)?;
+    let threads = decoder_threads(args.threads);
+    let mut source = open_source(args, &args.input_file, 0, &index, threads)?;
+    let frames = frames.min(source.total_frames);
+
+    println!("threads={} seek_mode={:?}", threads, args.seek_mode);
+
+    if pattern != Pattern::Random {
+        let timings = time_frames(&mut source.video_source, 0..frames)?;
+        println!("{}", timings.report("sequential"));
+    }
+
+    if pattern != Pattern::Sequential && source.total_frames > 0 {
+        let total = source.total_frames;
+        let timings = time_frames(&mut source.video_source, random_frames(frames, total))?;
+        println!("{}", timings.report("random"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_latency_percentiles() {
+        let timings = Timings {
+            total: Duration::from_secs(2),
+            frames: (1..=100).map(Duration::from_millis).collect(),
+        };
+
+        assert_eq!(
+            timings.report("random"),
+            "random frames=100 seconds=2.000 fps=50.0 p50_ms=51.00 p95_ms=96.00 max_ms=100.00"
+        );
+        assert!(random_frames(1000, 24).all(|frame| frame < 24));
+    }
+}
@@ -0,0 +1,127 @@
+use crate::compare::samples;
+use crate::{silence, Source};
+use ffms_segmenter::prefetch::Decoder;
+use ffms_segmenter::yuv4mpeg::ColorRange;
+use std::io::{self, BufWriter, Write};
+use y4m::Colorspace;
+
+/// Share of the pixels of a frame that must be black for the frame to
+/// count as black, as in FFmpeg's blackdetect.
+const BLACK_PICTURE: f64 = 0.98;
+
+/// Parses the `--black-threshold` share of the luma range above black
+/// that a pixel may be and still count as black.
+pub fn parse_threshold(threshold: &str) -> Result<f64, String> {
+    match threshold.parse::<f64>() {
+        Ok(threshold) if (0.0..=1.0).contains(&threshold) => Ok(threshold),
+        _ => Err(format!(
+            "Invalid black threshold, expected a share from 0 to 1: {}",
+            threshold
+        )),
+    }
+}
+
+/// The highest luma value of a black pixel, `threshold` of the way from
+/// black to white.
+fn black_level(colorspace: Colorspace, range: ColorRange, threshold: f64) -> f64 {
+    let depth = colorspace.get_bit_depth();
+    let (black, white) = match range {
+        ColorRange::Limited => (
+            (16u32 << (depth - 8)) as f64,
+            (235u32 << (depth - 8)) as f64,
+        ),
+        ColorRange::Full => (0.0, ((1u32 << depth) - 1) as f64),
+    };
+
+    black + threshold * (white - black)
+}
+
+/// Whether at least `BLACK_PICTURE` of `luma` is at or below `level`.
+fn is_black(luma: &[f64], level: f64) -> bool {
+    let black = luma.iter().filter(|&&sample| sample <= level).count();
+    black as f64 >= BLACK_PICTURE * luma.len() as f64
+}
+
+/// Groups frames flagged black, numbered from `start`, into `(first, last)`
+/// intervals.
+pub fn intervals(start: usize, black: impl IntoIterator<Item = bool>) -> Vec<(usize, usize)> {
+    let mut intervals = Vec::new();
+    let mut first = None;
+    let mut end = start;
+
+    for (frame, black) in (start..).zip(black) {
+        match (black, first) {
+            (true, None) => first = Some(frame),
+            (false, Some(from)) => {
+                intervals.push((from, frame - 1));
+                first = None;
+            }
+            _ => {}
+        }
+        end = frame + 1;
+    }
+    intervals.extend(first.map(|from| (from, end - 1)));
+
+    intervals
+}
+
+/// Prints `<first> <last> <cut>` for every interval of black frames in
+/// `start..end`, with the frame in its middle as a candidate cut point,
+/// so segments can be split at fades to black. With a `silence` level in
+/// dBFS, only the parts of intervals that are silent too are printed.
+pub fn print(
+    source: &mut Source,
+    start: usize,
+    end: usize,
+    threshold: f64,
+    silence: Option<f64>,
+) -> io::Result<()> {
+    let end = end.min(source.total_frames);
+    let format = &source.format;
+    let bytes = format.colorspace.get_bytes_per_sample();
+    let level = black_level(format.colorspace, format.color_range, threshold);
+
+    let mut black = Vec::with_capacity(end.saturating_sub(start));
+    for frame_number in start..end {
+        let frame = source.video_source.decode(frame_number)?;
+        black.push(is_black(&samples(&frame.planes[0], bytes), level));
+    }
+
+    let mut black = intervals(start, black);
+    if let Some(level) = silence {
+        let silent = intervals(start, silence::silent_frames(source, start, end, level)?);
+        black = silence::overlaps(&black, &silent);
+    }
+
+    let mut out = BufWriter::new(io::stdout().lock());
+    for (first, last) in black {
+        writeln!(
+            out,
+            "{} {} {}",
+            first,
+            last,
+            first + (last - first).div_ceil(2)
+        )?;
+    }
+
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_black_intervals() {
+        let level = black_level(Colorspace::C420p10, ColorRange::Limited, 0.1);
+        assert_eq!(level, 64.0 + 0.1 * 876.0);
+        assert!(is_black(&[64.0; 100], level));
+        assert!(!is_black(
+            &[[64.0; 97].as_slice(), &[940.0; 3]].concat(),
+            level
+        ));
+
+        let black = [false, true, true, true, false, false, true, false, true];
+        assert_eq!(intervals(100, black), [(101, 103), (106, 106), (108, 108)]);
+    }
+}
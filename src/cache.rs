@@ -0,0 +1,258 @@
+use ffms_segmenter::prefetch::{DecodedFrame, Decoder};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread::{self, JoinHandle};
+
+fn frame_bytes(frame: &DecodedFrame) -> usize {
+    frame.planes.iter().map(Vec::len).sum::<usize>() + frame.alpha.as_ref().map_or(0, Vec::len)
+}
+
+/// The most recently decoded frames of an input, up to `capacity` bytes,
+/// for `--frame-cache-mb`. Overlapping and repeated requests take their
+/// frames from here instead of decoding them again.
+///
+/// The last `tail` frames are kept whatever their size. A request that
+/// continues the one before it, whose padding or deinterlacing reaches a
+/// few frames back, then finds those frames here and the decoder carries
+/// on from where it stopped, instead of seeking back to the keyframe of
+/// the boundary GOP and decoding it again.
+pub struct FrameCache {
+    capacity: usize,
+    tail: usize,
+    bytes: usize,
+    frames: HashMap<usize, DecodedFrame>,
+    /// Frame numbers from the least to the most recently used.
+    order: VecDeque<usize>,
+}
+
+impl FrameCache {
+    /// A cache of `capacity` bytes and at least `tail` frames.
+    pub fn new(capacity: usize, tail: usize) -> Self {
+        FrameCache {
+            capacity,
+            tail,
+            bytes: 0,
+            frames: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn touch(&mut self, frame: usize) {
+        if let Some(position) = self.order.iter().position(|&cached| cached == frame) {
+            self.order.remove(position);
+        }
+        self.order.push_back(frame);
+    }
+
+    pub fn get(&mut self, frame: usize) -> Option<DecodedFrame> {
+        let decoded = self.frames.get(&frame)?.clone();
+        self.touch(frame);
+        Some(decoded)
+    }
+
+    /// Keeps `decoded` as `frame`, dropping the least recently used frames
+    /// beyond the tail to make room.
+    pub fn insert(&mut self, frame: usize, decoded: &DecodedFrame) {
+        let size = frame_bytes(decoded);
+        if size > self.capacity && self.tail == 0 {
+            return;
+        }
+
+        if let Some(old) = self.frames.insert(frame, decoded.clone()) {
+            self.bytes -= frame_bytes(&old);
+        }
+        self.bytes += size;
+        self.touch(frame);
+
+        while self.bytes > self.capacity && self.order.len() > self.tail {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            if let Some(evicted) = self.frames.remove(&oldest) {
+                self.bytes -= frame_bytes(&evicted);
+            }
+        }
+    }
+}
+
+/// Decodes through a `FrameCache`, only asking the decoder for the frames
+/// the cache does not hold.
+pub struct Cached<'a, D: ?Sized> {
+    decoder: &'a mut D,
+    cache: &'a mut FrameCache,
+}
+
+impl<'a, D: Decoder + ?Sized> Cached<'a, D> {
+    pub fn new(decoder: &'a mut D, cache: &'a mut FrameCache) -> Self {
+        Cached { decoder, cache }
+    }
+}
+
+impl<D: Decoder + ?Sized> Decoder for Cached<'_, D> {
+    fn decode(&mut self, frame: usize) -> io::Result<DecodedFrame> {
+        if let Some(decoded) = self.cache.get(frame) {
+            return Ok(decoded);
+        }
+
+        let decoded = self.decoder.decode(frame)?;
+        self.cache.insert(frame, &decoded);
+        Ok(decoded)
+    }
+
+    fn frames(&self) -> usize {
+        self.decoder.frames()
+    }
+}
+
+/// Frames of `start..end` decoded ahead of a request, on a thread and a
+/// decoder of their own, for the `prefetch` command. They wait here until
+/// `drain` or `finish` moves them into the frame cache.
+pub struct Warmup {
+    pub start: usize,
+    pub end: usize,
+    receiver: Option<Receiver<(usize, DecodedFrame)>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Warmup {
+    /// Starts decoding `start..end` with `decoder`, stopping once
+    /// `capacity` bytes are decoded, as a cache that size would drop the
+    /// first frames again. A frame that fails to decode ends the warm-up;
+    /// the request for it will fail the same way and say so.
+    pub fn spawn<D: Decoder + Send + 'static>(
+        mut decoder: D,
+        start: usize,
+        end: usize,
+        capacity: usize,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let mut bytes = 0;
+            for frame in start..end.min(decoder.frames()) {
+                let Ok(decoded) = decoder.decode(frame) else {
+                    break;
+                };
+                bytes += frame_bytes(&decoded);
+                if sender.send((frame, decoded)).is_err() || bytes >= capacity {
+                    break;
+                }
+            }
+        });
+
+        Warmup {
+            start,
+            end,
+            receiver: Some(receiver),
+            handle: Some(handle),
+        }
+    }
+
+    /// Moves the frames decoded so far into `cache`, returning whether the
+    /// warm-up is done.
+    pub fn drain(&self, cache: &mut FrameCache) -> bool {
+        let Some(ref receiver) = self.receiver else {
+            return true;
+        };
+        loop {
+            match receiver.try_recv() {
+                Ok((frame, decoded)) => cache.insert(frame, &decoded),
+                Err(TryRecvError::Empty) => return false,
+                Err(TryRecvError::Disconnected) => return true,
+            }
+        }
+    }
+
+    /// Waits for the warm-up to end and moves all of its frames into
+    /// `cache`.
+    pub fn finish(mut self, cache: &mut FrameCache) {
+        if let Some(receiver) = self.receiver.take() {
+            for (frame, decoded) in receiver {
+                cache.insert(frame, &decoded);
+            }
+        }
+    }
+}
+
+impl Drop for Warmup {
+    /// Stops the thread after the frame it is decoding, so its decoder is
+    /// gone before the index of the input is.
+    fn drop(&mut self) {
+        drop(self.receiver.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frames of one byte holding their number, counting the decodes.
+    struct Counting(usize);
+
+    impl Decoder for Counting {
+        fn decode(&mut self, frame: usize) -> io::Result<DecodedFrame> {
+            self.0 += 1;
+            Ok(DecodedFrame {
+                planes: [vec![frame as u8], Vec::new(), Vec::new()],
+                alpha: None,
+            })
+        }
+
+        fn frames(&self) -> usize {
+            100
+        }
+    }
+
+    #[test]
+    fn keeps_recently_used_frames() {
+        let mut source = Counting(0);
+        let mut cache = FrameCache::new(3, 0);
+
+        let mut decode = |cache: &mut FrameCache, frame: usize| {
+            let decoded = Cached::new(&mut source, cache).decode(frame).unwrap();
+            assert_eq!(decoded.planes[0], [frame as u8]);
+        };
+        for frame in [0, 1, 2, 0, 3, 0, 1] {
+            decode(&mut cache, frame);
+        }
+        // 0, 1, 2 and 3 once, then 1 again after 3 pushed it out.
+        assert_eq!(source.0, 5);
+
+        let mut nothing = FrameCache::new(0, 0);
+        nothing.insert(0, &Counting(0).decode(0).unwrap());
+        assert!(nothing.get(0).is_none());
+    }
+
+    #[test]
+    fn keeps_the_tail_of_the_previous_request() {
+        let mut source = Counting(0);
+        let mut cache = FrameCache::new(0, 2);
+
+        // 0..10 then 8..20, as with a frame of padding either side.
+        for frame in (0..10).chain(8..20) {
+            Cached::new(&mut source, &mut cache).decode(frame).unwrap();
+        }
+        assert_eq!(source.0, 20);
+        assert_eq!(cache.order, [18, 19]);
+    }
+
+    #[test]
+    fn warms_up_the_cache_in_the_background() {
+        let mut cache = FrameCache::new(100, 0);
+        Warmup::spawn(Counting(0), 10, 20, 100).finish(&mut cache);
+
+        let mut source = Counting(0);
+        for frame in 8..20 {
+            Cached::new(&mut source, &mut cache).decode(frame).unwrap();
+        }
+        assert_eq!(source.0, 2);
+
+        // Frames beyond the capacity would push out the first ones.
+        let mut small = FrameCache::new(100, 0);
+        Warmup::spawn(Counting(0), 0, 100, 4).finish(&mut small);
+        assert_eq!(small.order, [0, 1, 2, 3]);
+    }
+}
@@ -0,0 +1,117 @@
+use crate::timecodes::frame_time_ms;
+use crate::Source;
+use ffms_segmenter::log;
+use ffms_segmenter::prefetch::{DecodedFrame, Decoder};
+use std::io;
+use y4m::Ratio;
+
+/// The source frame on screen in the middle of each frame of a constant
+/// `fps`, for source frames presented at `times` in milliseconds. Frames
+/// are repeated across gaps and dropped where they come faster than `fps`.
+/// The last frame lasts as long as the one before it.
+pub fn frames(times: &[f64], (num, den): (i32, i32)) -> Vec<usize> {
+    let interval = 1000.0 * den as f64 / num as f64;
+    let (first, end) = match *times {
+        [] => return Vec::new(),
+        [first] => (first, first + interval),
+        [first, ..] => {
+            let (before, last) = (times[times.len() - 2], times[times.len() - 1]);
+            (first, last + (last - before))
+        }
+    };
+    let count = ((end - first) / interval).round().max(1.0) as usize;
+
+    let mut source = 0;
+    (0..count)
+        .map(|frame| {
+            let time = first + (frame as f64 + 0.5) * interval;
+            while source + 1 < times.len() && times[source + 1] <= time {
+                source += 1;
+            }
+            source
+        })
+        .collect()
+}
+
+/// Puts `source` on a constant `fps` timeline for `--cfr`, so its frame
+/// numbers and properties count the frames of that rate.
+pub fn retime(source: &mut Source, fps: (i32, i32)) {
+    let times: Vec<f64> = (0..source.total_frames)
+        .map(|frame| frame_time_ms(&source.track, frame))
+        .collect();
+    let frames = frames(&times, fps);
+    log!(
+        Info,
+        "Converting {} frames to {} at {}/{} fps",
+        source.total_frames,
+        frames.len(),
+        fps.0,
+        fps.1
+    );
+
+    let framerate = Ratio {
+        num: fps.0 as usize,
+        den: fps.1 as usize,
+    };
+    announce(source, frames.len(), framerate);
+    source.cfr = Some(frames);
+}
+
+/// Gives `source` a new timeline of `total` frames at `framerate`, in its
+/// format and properties line.
+pub fn announce(source: &mut Source, total: usize, framerate: Ratio) {
+    source.total_frames = total;
+    source.format.framerate = framerate;
+    let mut properties: Vec<String> = source.properties.split(' ').map(str::to_string).collect();
+    properties[2] = total.to_string();
+    properties[3] = framerate.den.to_string();
+    properties[4] = framerate.num.to_string();
+    source.properties = properties.join(" ");
+}
+
+/// Decodes the frames of a constant rate timeline from a decoder of the
+/// source frames they show.
+pub struct Retimed<'a, D: ?Sized> {
+    decoder: &'a mut D,
+    frames: &'a [usize],
+}
+
+impl<'a, D: Decoder + ?Sized> Retimed<'a, D> {
+    pub fn new(decoder: &'a mut D, frames: &'a [usize]) -> Self {
+        Retimed { decoder, frames }
+    }
+}
+
+impl<D: Decoder + ?Sized> Decoder for Retimed<'_, D> {
+    fn decode(&mut self, frame: usize) -> io::Result<DecodedFrame> {
+        let source = self.frames.get(frame).copied().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Frame {} is past the constant rate timeline", frame),
+            )
+        })?;
+        self.decoder.decode(source)
+    }
+
+    fn frames(&self) -> usize {
+        self.frames.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeats_and_drops_frames() {
+        // A 25 fps source missing the frame at 120 ms.
+        assert_eq!(
+            frames(&[0.0, 40.0, 80.0, 160.0], (25, 1)),
+            [0, 1, 2, 2, 3, 3]
+        );
+        // 50 fps to 25.
+        assert_eq!(frames(&[0.0, 20.0, 40.0, 60.0, 80.0], (25, 1)), [1, 3, 4]);
+        assert_eq!(frames(&[500.0], (25, 1)), [0]);
+        assert!(frames(&[], (25, 1)).is_empty());
+    }
+}
@@ -0,0 +1,280 @@
+use crate::timecodes::frame_time_ms;
+use ffms2::track::Track;
+use ffms_segmenter::RangeConvention;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+const SEGMENT: u32 = 0x1853_8067;
+const CLUSTER: u32 = 0x1f43_b675;
+const CHAPTERS: u32 = 0x1043_a770;
+const EDITION_ENTRY: u32 = 0x45b9;
+const CHAPTER_ATOM: u32 = 0xb6;
+const CHAPTER_TIME_START: u32 = 0x91;
+const CHAPTER_FLAG_HIDDEN: u32 = 0x98;
+const CHAPTER_DISPLAY: u32 = 0x80;
+const CHAP_STRING: u32 = 0x85;
+
+/// A chapter of the input, starting `start` nanoseconds into it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chapter {
+    pub start: u64,
+    pub title: String,
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid Matroska file, {}", reason),
+    )
+}
+
+/// Reads an EBML variable length integer, keeping its marker bit for IDs
+/// and clearing it for sizes. `None` stands for an unknown size.
+fn read_vint(reader: &mut impl Read, keep_marker: bool) -> io::Result<Option<u64>> {
+    let mut first = [0];
+    reader.read_exact(&mut first)?;
+    let len = first[0].leading_zeros() as usize + 1;
+    if len > 8 {
+        return Err(invalid("bad element header"));
+    }
+
+    let mut rest = [0; 7];
+    reader.read_exact(&mut rest[..len - 1])?;
+
+    let marker = 1u64 << (7 * len);
+    let value = rest[..len - 1]
+        .iter()
+        .fold(first[0] as u64, |value, &byte| value << 8 | byte as u64);
+
+    if keep_marker {
+        Ok(Some(value))
+    } else if value == (marker << 1) - 1 {
+        Ok(None)
+    } else {
+        Ok(Some(value & (marker - 1)))
+    }
+}
+
+/// Reads the ID and size of the next element.
+fn read_header(reader: &mut impl Read) -> io::Result<(u32, Option<u64>)> {
+    let id = read_vint(reader, true)?.unwrap_or_default() as u32;
+    Ok((id, read_vint(reader, false)?))
+}
+
+/// The children of a master element body as `(id, body)` pairs.
+fn children(mut body: &[u8]) -> io::Result<Vec<(u32, &[u8])>> {
+    let mut children = Vec::new();
+
+    while !body.is_empty() {
+        let (id, size) = read_header(&mut body)?;
+        let size = size.ok_or_else(|| invalid("unknown size inside chapters"))? as usize;
+        if size > body.len() {
+            return Err(invalid("truncated chapters"));
+        }
+        children.push((id, &body[..size]));
+        body = &body[size..];
+    }
+
+    Ok(children)
+}
+
+fn uint(body: &[u8]) -> u64 {
+    body.iter().fold(0, |value, &byte| value << 8 | byte as u64)
+}
+
+/// The visible chapters of the first edition of a `Chapters` element.
+fn parse_chapters(body: &[u8]) -> io::Result<Vec<Chapter>> {
+    let edition = match children(body)?
+        .into_iter()
+        .find(|&(id, _)| id == EDITION_ENTRY)
+    {
+        Some((_, edition)) => edition,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut chapters = Vec::new();
+    for (id, atom) in children(edition)? {
+        if id != CHAPTER_ATOM {
+            continue;
+        }
+
+        let mut start = None;
+        let mut hidden = false;
+        let mut title = String::new();
+        for (id, body) in children(atom)? {
+            match id {
+                CHAPTER_TIME_START => start = Some(uint(body)),
+                CHAPTER_FLAG_HIDDEN => hidden = uint(body) != 0,
+                CHAPTER_DISPLAY if title.is_empty() => {
+                    if let Some((_, string)) = children(body)?
+                        .into_iter()
+                        .find(|&(id, _)| id == CHAP_STRING)
+                    {
+                        title = String::from_utf8_lossy(string).into_owned();
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        match start {
+            Some(start) if !hidden => chapters.push(Chapter { start, title }),
+            Some(_) => {}
+            None => return Err(invalid("chapter without a start time")),
+        }
+    }
+
+    chapters.sort_by_key(|chapter| chapter.start);
+    Ok(chapters)
+}
+
+/// Reads the chapters of a Matroska or WebM file, skipping over the
+/// clusters rather than reading them.
+pub fn read(path: &Path) -> io::Result<Vec<Chapter>> {
+    let mut file = BufReader::new(File::open(path)?);
+
+    // The EBML header.
+    match read_header(&mut file)? {
+        (0x1a45_dfa3, Some(size)) => file.seek_relative(size as i64)?,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Chapters can only be read from Matroska files: {}",
+                    path.display()
+                ),
+            ))
+        }
+    }
+
+    match read_header(&mut file)? {
+        (SEGMENT, _) => {}
+        _ => return Err(invalid("no segment")),
+    }
+
+    loop {
+        let (id, size) = match read_header(&mut file) {
+            Ok(header) => header,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+
+        match (id, size) {
+            (CHAPTERS, Some(size)) => {
+                let mut body = vec![0; size as usize];
+                file.read_exact(&mut body)?;
+                return parse_chapters(&body);
+            }
+            (_, Some(size)) => file.seek_relative(size as i64)?,
+            // A live stream's clusters cannot be skipped. Chapters are
+            // written before them in such files.
+            (CLUSTER, None) => return Ok(Vec::new()),
+            (_, None) => return Err(invalid("element of unknown size")),
+        }
+    }
+}
+
+/// A chapter title as a file name: runs of anything but letters and digits
+/// become `-`.
+fn file_name(title: &str) -> String {
+    let mut name = String::new();
+    for c in title.chars() {
+        if c.is_alphanumeric() {
+            name.push(c);
+        } else if !name.is_empty() && !name.ends_with('-') {
+            name.push('-');
+        }
+    }
+    name.trim_end_matches('-').to_string()
+}
+
+/// The `<start> <end> name=<nn>-<title>` requests writing one segment per
+/// chapter, each ending where the next begins, with ends in `convention`.
+pub fn requests(
+    chapters: &[Chapter],
+    track: &Track,
+    total_frames: usize,
+    convention: RangeConvention,
+) -> Vec<String> {
+    let frames: Vec<f64> = (0..total_frames)
+        .map(|frame| frame_time_ms(track, frame))
+        .collect();
+    // The first frame showing at or after each chapter start.
+    let starts: Vec<usize> = chapters
+        .iter()
+        .map(|chapter| frames.partition_point(|&time| time < chapter.start as f64 / 1e6))
+        .collect();
+
+    starts
+        .iter()
+        .zip(starts.iter().skip(1).chain(Some(&total_frames)))
+        .zip(chapters)
+        .enumerate()
+        .filter(|(_, ((start, end), _))| start < end)
+        .map(|(n, ((start, end), chapter))| {
+            let name = match file_name(&chapter.title) {
+                title if title.is_empty() => format!("{:02}", n + 1),
+                title => format!("{:02}-{}", n + 1, title),
+            };
+            format!("{} name={}", convention.format_range(*start, *end), name)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_visible_chapters() {
+        let atom = |start: u8, hidden: u8, title: &[u8]| {
+            let mut display = vec![0x85, 0x80 | title.len() as u8];
+            display.extend_from_slice(title);
+            let mut atom = vec![0x91, 0x81, start, 0x98, 0x81, hidden, 0x80];
+            atom.push(0x80 | display.len() as u8);
+            atom.extend(display);
+
+            let mut element = vec![0xb6, 0x80 | atom.len() as u8];
+            element.extend(atom);
+            element
+        };
+        let atoms = [
+            atom(20, 0, b"Credits"),
+            atom(0, 0, b"Intro"),
+            atom(10, 1, b""),
+        ]
+        .concat();
+        let mut edition = vec![0x45, 0xb9, 0x80 | atoms.len() as u8];
+        edition.extend(atoms);
+
+        assert_eq!(
+            parse_chapters(&edition).unwrap(),
+            vec![
+                Chapter {
+                    start: 0,
+                    title: "Intro".to_string()
+                },
+                Chapter {
+                    start: 20,
+                    title: "Credits".to_string()
+                },
+            ]
+        );
+        assert_eq!(
+            read_vint(&mut &[0x1a, 0x45, 0xdf, 0xa3][..], true).unwrap(),
+            Some(0x1a45_dfa3)
+        );
+        assert_eq!(read_vint(&mut &[0x40, 0x7f][..], false).unwrap(), Some(127));
+        assert_eq!(read_vint(&mut &[0xff][..], false).unwrap(), None);
+    }
+
+    #[test]
+    fn names_files_after_titles() {
+        assert_eq!(
+            file_name("Chapter 1: The Beginning!"),
+            "Chapter-1-The-Beginning"
+        );
+        assert_eq!(file_name("  Épilogue"), "Épilogue");
+    }
+}
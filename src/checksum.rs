@@ -0,0 +1,133 @@
+use crate::prefetch::DecodedFrame;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC32_TABLE: [u32; 256] = crc32_table();
+
+/// Streaming CRC-32 (IEEE 802.3, as used by zlib and PNG).
+#[derive(Clone)]
+pub struct Crc32 {
+    value: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32 { value: 0xffff_ffff }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.value =
+                CRC32_TABLE[((self.value ^ byte as u32) & 0xff) as usize] ^ (self.value >> 8);
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        self.value ^ 0xffff_ffff
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// CRC-32 over all planes of `frame`, in plane order.
+pub fn frame_crc32(frame: &DecodedFrame) -> u32 {
+    let mut crc = Crc32::new();
+    for plane in &frame.planes {
+        crc.update(plane);
+    }
+    crc.finish()
+}
+
+/// A `Write` adapter that hashes everything written through it. With the
+/// default `io::Sink` it only hashes.
+pub struct HashWriter<W = io::Sink> {
+    inner: W,
+    pub crc: Crc32,
+    pub bytes: u64,
+}
+
+impl HashWriter {
+    pub fn new() -> Self {
+        HashWriter::wrap(io::sink())
+    }
+}
+
+impl Default for HashWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<W: Write> HashWriter<W> {
+    pub fn wrap(inner: W) -> Self {
+        HashWriter {
+            inner,
+            crc: Crc32::new(),
+            bytes: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.crc.update(&buf[..written]);
+        self.bytes += written as u64;
+        Ok(written)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        let written = self.inner.write_vectored(bufs)?;
+
+        let mut remaining = written;
+        for buf in bufs {
+            let len = buf.len().min(remaining);
+            self.crc.update(&buf[..len]);
+            remaining -= len;
+        }
+        self.bytes += written as u64;
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Size and CRC-32 of the file at `path`.
+pub fn file_crc32(path: &Path) -> io::Result<(u64, u32)> {
+    let mut hasher = HashWriter::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+
+    Ok((hasher.bytes, hasher.crc.finish()))
+}
@@ -0,0 +1,219 @@
+use crate::align::keyframes;
+use crate::timecodes::frame_time_ms;
+use ffms2::track::Track;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Returns every integer value of `key` in `text`, in order. av1an's
+/// scenes file keeps each scene's bounds as flat `start_frame` and
+/// `end_frame` members, so a scan is enough to read it.
+fn numbers_of(text: &str, key: &str) -> Vec<usize> {
+    let quoted = format!("\"{}\"", key);
+
+    text.match_indices(&quoted)
+        .filter_map(|(position, _)| {
+            let value = text[position + quoted.len()..]
+                .trim_start()
+                .strip_prefix(':')?
+                .trim_start();
+            let digits = value
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(value.len());
+            value[..digits].parse().ok()
+        })
+        .collect()
+}
+
+/// Parses the `scenes` of an av1an `scenes.json`.
+pub fn parse_scenes(text: &str) -> io::Result<Vec<(usize, usize)>> {
+    let starts = numbers_of(text, "start_frame");
+    let ends = numbers_of(text, "end_frame");
+
+    if starts.len() != ends.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Scenes file has unmatched start_frame and end_frame entries",
+        ));
+    }
+
+    let chunks: Vec<(usize, usize)> = starts.into_iter().zip(ends).collect();
+    match chunks.iter().find(|(start, end)| start >= end) {
+        Some((start, end)) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid scene {} to {}", start, end),
+        )),
+        None => Ok(chunks),
+    }
+}
+
+/// Splits `0..total` into chunks starting at each of `keyframes`.
+fn split_at_keyframes(keyframes: &[usize], total: usize) -> Vec<(usize, usize)> {
+    let mut starts: Vec<usize> = keyframes.iter().copied().filter(|&k| k < total).collect();
+    if starts.first() != Some(&0) {
+        starts.insert(0, 0);
+    }
+
+    starts
+        .iter()
+        .zip(starts.iter().skip(1).chain(Some(&total)))
+        .map(|(&start, &end)| (start, end))
+        .filter(|(start, end)| start < end)
+        .collect()
+}
+
+/// The `--auto-split` chunk length.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Length {
+    Frames(usize),
+    Seconds(f64),
+}
+
+/// Parses a frame count, or seconds with an `s` suffix such as `10s`.
+pub fn parse_length(length: &str) -> Result<Length, String> {
+    let parsed = match length.strip_suffix('s') {
+        Some(seconds) => seconds
+            .parse::<f64>()
+            .ok()
+            .filter(|seconds| seconds.is_finite() && *seconds > 0.0)
+            .map(Length::Seconds),
+        None => length
+            .parse::<usize>()
+            .ok()
+            .filter(|&frames| frames > 0)
+            .map(Length::Frames),
+    };
+
+    parsed.ok_or_else(|| format!("Invalid split length: {}", length))
+}
+
+/// Splits `0..total` into chunks that each end on the first keyframe at
+/// least `length` after their start, or at the end. `times` holds the time
+/// of each frame in milliseconds and is only read for lengths in seconds.
+/// With the scene `scores` of a first pass, chunks instead end on the
+/// keyframe scoring highest up to twice `length` after their start.
+fn split_by_length(
+    keyframes: &[usize],
+    times: &[f64],
+    scores: &[f64],
+    total: usize,
+    length: Length,
+) -> Vec<(usize, usize)> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+
+    while start < total {
+        let at_least = |keyframe: usize, lengths: f64| match length {
+            Length::Frames(frames) => keyframe as f64 >= start as f64 + frames as f64 * lengths,
+            Length::Seconds(seconds) => {
+                times[keyframe] - times[start] >= seconds * 1000.0 * lengths
+            }
+        };
+        let mut candidates = keyframes
+            .iter()
+            .copied()
+            .filter(|&keyframe| keyframe > start && keyframe < total)
+            .skip_while(|&keyframe| !at_least(keyframe, 1.0));
+        let first = candidates.next();
+        let end = match scores {
+            [] => first,
+            _ => first.map(|first| {
+                candidates
+                    .take_while(|&keyframe| !at_least(keyframe, 2.0))
+                    .fold(first, |best, keyframe| {
+                        match scores[keyframe] > scores[best] {
+                            true => keyframe,
+                            false => best,
+                        }
+                    })
+            }),
+        }
+        .unwrap_or(total);
+
+        chunks.push((start, end));
+        start = end;
+    }
+
+    chunks
+}
+
+/// Keyframe aligned chunks of roughly `length` covering the whole track, for
+/// `--auto-split`, ending on scene changes if `scores` are known.
+pub fn auto_split(
+    track: &Track,
+    total: usize,
+    length: Length,
+    scores: &[f64],
+) -> Vec<(usize, usize)> {
+    let times: Vec<f64> = match length {
+        Length::Frames(_) => Vec::new(),
+        Length::Seconds(_) => (0..total)
+            .map(|frame| frame_time_ms(track, frame))
+            .collect(),
+    };
+
+    split_by_length(&keyframes(track, total), &times, scores, total, length)
+}
+
+/// Loads the chunks named by `--chunks`: an av1an scenes file, or
+/// `keyframes` to split the track at every keyframe.
+pub fn load(spec: &Path, track: &Track, total: usize) -> io::Result<Vec<(usize, usize)>> {
+    if spec == Path::new("keyframes") {
+        return Ok(split_at_keyframes(&keyframes(track, total), total));
+    }
+
+    parse_scenes(&fs::read_to_string(spec)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_av1an_scenes() {
+        let text = r#"{"scenes":[{"start_frame":0,"end_frame":48,"zone_overrides":null},
+            {"start_frame": 48, "end_frame": 120, "zone_overrides": null}],"frames":120}"#;
+
+        assert_eq!(parse_scenes(text).unwrap(), vec![(0, 48), (48, 120)]);
+        assert!(parse_scenes(r#"{"scenes":[{"start_frame":5,"end_frame":5}]}"#).is_err());
+    }
+
+    #[test]
+    fn splits_at_keyframes() {
+        assert_eq!(
+            split_at_keyframes(&[0, 24, 60], 100),
+            vec![(0, 24), (24, 60), (60, 100)]
+        );
+        assert_eq!(split_at_keyframes(&[10], 20), vec![(0, 10), (10, 20)]);
+    }
+
+    #[test]
+    fn splits_by_length_on_keyframes() {
+        let keyframes = [0, 20, 50, 60, 110];
+
+        assert_eq!(
+            split_by_length(&keyframes, &[], &[], 120, Length::Frames(40)),
+            vec![(0, 50), (50, 110), (110, 120)]
+        );
+
+        // 25 fps.
+        let times: Vec<f64> = (0..120).map(|frame| frame as f64 * 40.0).collect();
+        assert_eq!(
+            split_by_length(&keyframes, &times, &[], 120, Length::Seconds(2.0)),
+            vec![(0, 50), (50, 110), (110, 120)]
+        );
+
+        // The scene change at 60 wins over the first keyframe past 40.
+        let mut scores = vec![0.0; 120];
+        scores[60] = 30.0;
+        assert_eq!(
+            split_by_length(&keyframes, &[], &scores, 120, Length::Frames(40)),
+            vec![(0, 60), (60, 110), (110, 120)]
+        );
+
+        assert_eq!(parse_length("10s"), Ok(Length::Seconds(10.0)));
+        assert_eq!(parse_length("240"), Ok(Length::Frames(240)));
+        assert!(parse_length("0").is_err());
+        assert!(parse_length("-1s").is_err());
+    }
+}
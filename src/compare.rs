@@ -0,0 +1,268 @@
+use crate::rotate::Rotator;
+use crate::segment::{encode_segment, FrameSink};
+use crate::{
+    decode_error_policy, decoder_threads, filter, index_file, prefetch_depth, CliArgs, Input,
+};
+use ffms2::frame::Frame;
+use ffms2::track::TrackType;
+use ffms2::video::VideoSource;
+use ffms2::IndexErrorHandling;
+use ffms_segmenter::prefetch::DecodedFrame;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use y4m::Colorspace;
+
+/// Side of the blocks SSIM is computed over.
+const SSIM_BLOCK: usize = 8;
+
+/// Luma samples of a plane with `bytes` per sample.
+pub fn samples(plane: &[u8], bytes: usize) -> Vec<f64> {
+    match bytes {
+        2 => plane
+            .chunks_exact(2)
+            .map(|sample| u16::from_le_bytes([sample[0], sample[1]]) as f64)
+            .collect(),
+        _ => plane.iter().map(|&sample| sample as f64).collect(),
+    }
+}
+
+fn mse(reference: &[f64], encoded: &[f64]) -> f64 {
+    let sum: f64 = reference
+        .iter()
+        .zip(encoded)
+        .map(|(a, b)| (a - b) * (a - b))
+        .sum();
+    sum / reference.len().max(1) as f64
+}
+
+fn psnr(mse: f64, max: f64) -> f64 {
+    if mse == 0.0 {
+        f64::INFINITY
+    } else {
+        10.0 * (max * max / mse).log10()
+    }
+}
+
+/// SSIM averaged over non-overlapping blocks of `SSIM_BLOCK` samples
+/// square, leaving out the partial blocks at the right and bottom edges.
+pub fn ssim(reference: &[f64], encoded: &[f64], width: usize, height: usize, max: f64) -> f64 {
+    let c1 = (0.01 * max) * (0.01 * max);
+    let c2 = (0.03 * max) * (0.03 * max);
+    let n = (SSIM_BLOCK * SSIM_BLOCK) as f64;
+
+    let mut total = 0.0;
+    let mut blocks = 0;
+    for block_y in (0..height.saturating_sub(SSIM_BLOCK - 1)).step_by(SSIM_BLOCK) {
+        for block_x in (0..width.saturating_sub(SSIM_BLOCK - 1)).step_by(SSIM_BLOCK) {
+            let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) =
+                (0.0, 0.0, 0.0, 0.0, 0.0);
+            for y in block_y..block_y + SSIM_BLOCK {
+                for x in block_x..block_x + SSIM_BLOCK {
+                    let (a, b) = (reference[y * width + x], encoded[y * width + x]);
+                    sum_a += a;
+                    sum_b += b;
+                    sum_aa += a * a;
+                    sum_bb += b * b;
+                    sum_ab += a * b;
+                }
+            }
+
+            let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+            let variance_a = sum_aa / n - mean_a * mean_a;
+            let variance_b = sum_bb / n - mean_b * mean_b;
+            let covariance = sum_ab / n - mean_a * mean_b;
+
+            total += ((2.0 * mean_a * mean_b + c1) * (2.0 * covariance + c2))
+                / ((mean_a * mean_a + mean_b * mean_b + c1) * (variance_a + variance_b + c2));
+            blocks += 1;
+        }
+    }
+
+    match blocks {
+        0 => 1.0,
+        blocks => total / blocks as f64,
+    }
+}
+
+/// Luma PSNR and SSIM of one frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameScore {
+    pub frame: usize,
+    pub mse: f64,
+    pub psnr: f64,
+    pub ssim: f64,
+}
+
+/// Scores the frames of a segment against the next frames of an encode.
+struct Comparison {
+    encoded: VideoSource,
+    encoded_frames: usize,
+    width: usize,
+    height: usize,
+    bytes: usize,
+    max: f64,
+    next: usize,
+    start: usize,
+    scores: Vec<FrameScore>,
+}
+
+impl Comparison {
+    fn score(&self, reference: &DecodedFrame, encoded: &DecodedFrame) -> io::Result<FrameScore> {
+        let expected = self.width * self.height * self.bytes;
+        if encoded.planes[0].len() != expected || reference.planes[0].len() != expected {
+            return Err(io::Error::other(format!(
+                "Frame {} of the encode is not {}x{} at the segment's bit depth",
+                self.next, self.width, self.height
+            )));
+        }
+
+        let reference = samples(&reference.planes[0], self.bytes);
+        let encoded = samples(&encoded.planes[0], self.bytes);
+        let mse = mse(&reference, &encoded);
+
+        Ok(FrameScore {
+            frame: self.start + self.next,
+            mse,
+            psnr: psnr(mse, self.max),
+            ssim: ssim(&reference, &encoded, self.width, self.height, self.max),
+        })
+    }
+}
+
+impl FrameSink for Comparison {
+    fn write_frame(&mut self, reference: &DecodedFrame) -> io::Result<()> {
+        if self.next >= self.encoded_frames {
+            return Err(io::Error::other(format!(
+                "The encode ends after {} frames",
+                self.encoded_frames
+            )));
+        }
+
+        let mut frame = Frame::GetFrame(&mut self.encoded, self.next).map_err(|err| {
+            io::Error::other(format!(
+                "Failed to decode frame {} of the encode: {:?}",
+                self.next, err
+            ))
+        })?;
+        let encoded = DecodedFrame::from_frame(&mut frame);
+
+        let score = self.score(reference, &encoded)?;
+        self.scores.push(score);
+        self.next += 1;
+
+        Ok(())
+    }
+}
+
+/// Mean luma PSNR from the mean squared error of every frame, and mean
+/// SSIM.
+pub fn aggregate(scores: &[FrameScore], colorspace: Colorspace) -> (f64, f64) {
+    let count = scores.len().max(1) as f64;
+    let max = ((1u32 << colorspace.get_bit_depth()) - 1) as f64;
+    let mse = scores.iter().map(|score| score.mse).sum::<f64>() / count;
+    let ssim = scores.iter().map(|score| score.ssim).sum::<f64>() / count;
+
+    (psnr(mse, max), ssim)
+}
+
+/// Decodes `start..end` of `input` through the same filters as its
+/// segments and the first video track of `encoded`, returning the luma
+/// scores of each frame.
+pub fn run(
+    args: &CliArgs,
+    ignore_errors: IndexErrorHandling,
+    input: &mut Input,
+    start: usize,
+    end: usize,
+    encoded: &Path,
+) -> io::Result<Vec<FrameScore>> {
+    let index = index_file(args, encoded, ignore_errors)?;
+    let track = index
+        .FirstTrackOfType(TrackType::TYPE_VIDEO)
+        .map_err(|_| io::Error::other(format!("{} has no video track", encoded.display())))?;
+    let encoded_source = VideoSource::new(
+        encoded,
+        track,
+        &index,
+        decoder_threads(args.threads),
+        args.seek_mode,
+    )
+    .map_err(|err| io::Error::other(format!("Failed to open {}: {:?}", encoded.display(), err)))?;
+    let encoded_frames = encoded_source.GetVideoProperties().NumFrames as usize;
+
+    let format = &input.format;
+    let source = &mut input.source;
+    // Declared after `index` so the encoded source is dropped first.
+    let mut comparison = Comparison {
+        encoded: encoded_source,
+        encoded_frames,
+        width: format.width,
+        height: format.height,
+        bytes: format.colorspace.get_bytes_per_sample(),
+        max: ((1u32 << format.colorspace.get_bit_depth()) - 1) as f64,
+        next: 0,
+        start,
+        scores: Vec::new(),
+    };
+
+    let mut deinterlacer = args
+        .deinterlace
+        .map(|mode| filter::Deinterlacer::new(mode, &source.format));
+    let rotator = Rotator::new(
+        source.rotation,
+        format.colorspace,
+        format.width,
+        format.height,
+    );
+    let on_error = decode_error_policy(args, source);
+    let prefetch = prefetch_depth(args, &source.format);
+
+    encode_segment(
+        &mut comparison,
+        &mut source.video_source,
+        deinterlacer.as_mut(),
+        input.scaler.as_ref(),
+        rotator.as_ref(),
+        None,
+        start,
+        end,
+        1,
+        prefetch,
+        on_error,
+        &mut |_, _, _| {},
+    )?;
+
+    Ok(comparison.scores)
+}
+
+/// Writes `<frame> <psnr> <ssim>` lines for every frame compared.
+pub fn write_report(path: &Path, scores: &[FrameScore]) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+
+    for score in scores {
+        writeln!(file, "{} {:.3} {:.5}", score.frame, score.psnr, score.ssim)?;
+    }
+
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scores_identical_and_shifted_planes() {
+        let reference: Vec<f64> = (0..256).map(|sample| (sample % 16 * 16) as f64).collect();
+        let shifted: Vec<f64> = reference.iter().map(|sample| sample + 2.0).collect();
+
+        assert_eq!(psnr(mse(&reference, &reference), 255.0), f64::INFINITY);
+        assert_eq!(ssim(&reference, &reference, 16, 16, 255.0), 1.0);
+
+        let mse = mse(&reference, &shifted);
+        assert_eq!(mse, 4.0);
+        assert!((psnr(mse, 255.0) - 42.11).abs() < 0.01);
+        let score = ssim(&reference, &shifted, 16, 16, 255.0);
+        assert!(score < 1.0 && score > 0.99);
+    }
+}
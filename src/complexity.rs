@@ -0,0 +1,116 @@
+use ffms_segmenter::prefetch::DecodedFrame;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use y4m::Colorspace;
+
+/// Mean absolute difference between the luma of consecutive frames, a
+/// cheap measure of how hard a segment is to encode.
+pub struct Complexity {
+    bytes: usize,
+    /// Divides samples down to 8-bit values.
+    scale: f64,
+    previous: Option<Vec<u8>>,
+    sum: f64,
+    pairs: usize,
+}
+
+fn sample(plane: &[u8], bytes: usize, index: usize) -> i32 {
+    match bytes {
+        2 => u16::from_le_bytes([plane[index * 2], plane[index * 2 + 1]]) as i32,
+        _ => plane[index] as i32,
+    }
+}
+
+impl Complexity {
+    pub fn new(colorspace: Colorspace) -> Self {
+        Complexity {
+            bytes: colorspace.get_bytes_per_sample(),
+            scale: (1 << (colorspace.get_bit_depth() - 8)) as f64,
+            previous: None,
+            sum: 0.0,
+            pairs: 0,
+        }
+    }
+
+    /// Adds `frame`, returning its difference to the frame before it.
+    pub fn push(&mut self, frame: &DecodedFrame) -> Option<f64> {
+        let luma = &frame.planes[0];
+        let mut difference = None;
+        if let Some(ref previous) = self.previous {
+            let samples = luma.len().min(previous.len()) / self.bytes;
+            let total: u64 = (0..samples)
+                .map(|index| {
+                    (sample(luma, self.bytes, index) - sample(previous, self.bytes, index))
+                        .unsigned_abs() as u64
+                })
+                .sum();
+            let pair = total as f64 / samples.max(1) as f64 / self.scale;
+            self.sum += pair;
+            self.pairs += 1;
+            difference = Some(pair);
+        }
+        self.previous = Some(luma.clone());
+
+        difference
+    }
+
+    /// The mean over every pair of frames, on an 8-bit scale.
+    pub fn mean_difference(&self) -> f64 {
+        match self.pairs {
+            0 => 0.0,
+            pairs => self.sum / pairs as f64,
+        }
+    }
+
+    /// Writes `<key> <value>` lines describing the segment: its `frames`,
+    /// `seconds` and `keyframes`, the `mean_abs_diff`, and the average
+    /// `input_kbps` of the whole input. FFMS2 does not expose packet sizes,
+    /// so the bitrate of the range itself is not known.
+    pub fn write_report(
+        &self,
+        path: &Path,
+        frames: usize,
+        seconds: f64,
+        keyframes: usize,
+        input_kbps: f64,
+    ) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        writeln!(file, "frames {}", frames)?;
+        writeln!(file, "seconds {:.3}", seconds)?;
+        writeln!(file, "keyframes {}", keyframes)?;
+        writeln!(file, "mean_abs_diff {:.3}", self.mean_difference())?;
+        writeln!(file, "input_kbps {:.1}", input_kbps)?;
+
+        file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(luma: Vec<u8>) -> DecodedFrame {
+        DecodedFrame {
+            planes: [luma, Vec::new(), Vec::new()],
+            alpha: None,
+        }
+    }
+
+    #[test]
+    fn averages_frame_differences() {
+        let mut complexity = Complexity::new(Colorspace::Cmono);
+        complexity.push(&frame(vec![10, 10, 10, 10]));
+        assert_eq!(complexity.mean_difference(), 0.0);
+        complexity.push(&frame(vec![14, 6, 10, 10]));
+        complexity.push(&frame(vec![14, 6, 10, 10]));
+        assert_eq!(complexity.mean_difference(), 1.0);
+
+        // 10-bit samples are scaled to 8 bits.
+        let mut complexity = Complexity::new(Colorspace::C420p10);
+        complexity.push(&frame(vec![0, 0]));
+        complexity.push(&frame(40u16.to_le_bytes().to_vec()));
+        assert_eq!(complexity.mean_difference(), 10.0);
+    }
+}
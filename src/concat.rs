@@ -0,0 +1,182 @@
+use crate::SegmentFormat;
+use ffms2::video::VideoSource;
+use ffms_segmenter::prefetch::{self, DecodedFrame, Decoder, Watchdog};
+use std::io;
+
+/// The inputs of `--concat` played one after another: where each starts
+/// on the joint timeline.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timeline {
+    starts: Vec<usize>,
+    total: usize,
+}
+
+impl Timeline {
+    /// The timeline of inputs of `frames` frames each, in order.
+    pub fn new(frames: impl IntoIterator<Item = usize>) -> Self {
+        let mut starts = Vec::new();
+        let mut total = 0;
+        for frames in frames {
+            starts.push(total);
+            total += frames;
+        }
+
+        Timeline { starts, total }
+    }
+
+    /// Number of inputs on the timeline.
+    pub fn inputs(&self) -> usize {
+        self.starts.len()
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// The input `frame` of the timeline is in, and its number there.
+    /// Frames past the end are placed in the last input.
+    pub fn locate(&self, frame: usize) -> (usize, usize) {
+        let input = self.starts.partition_point(|&start| start <= frame).max(1) - 1;
+        (input, frame - self.starts[input])
+    }
+}
+
+/// Checks that segments of the `formats` of the inputs can be joined into
+/// one stream: the same size, frame rate, pixel format and field order.
+pub fn check_formats(formats: &[&SegmentFormat]) -> io::Result<()> {
+    let describe = |format: &SegmentFormat| {
+        format!(
+            "{}x{} at {}/{} fps, {:?} {:?} {:?}",
+            format.width,
+            format.height,
+            format.framerate.num,
+            format.framerate.den,
+            format.colorspace,
+            format.interlacing,
+            format.color_range
+        )
+    };
+
+    let first = describe(formats[0]);
+    match formats
+        .iter()
+        .enumerate()
+        .find(|(_, format)| describe(format) != first)
+    {
+        Some((input, format)) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Cannot concatenate input {}, {}, after input 0, {}",
+                input,
+                describe(format),
+                first
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Decodes frames of the timeline from the decoder of the input holding
+/// them. Without a timeline, the only decoder is used as is.
+pub struct Concat<'a> {
+    sources: Vec<&'a mut VideoSource>,
+    timeline: Option<&'a Timeline>,
+    verified: bool,
+    watchdog: Watchdog,
+}
+
+impl<'a> Concat<'a> {
+    pub fn new(sources: Vec<&'a mut VideoSource>, timeline: Option<&'a Timeline>) -> Self {
+        Concat {
+            sources,
+            timeline,
+            verified: false,
+            watchdog: Watchdog::default(),
+        }
+    }
+
+    /// Checks decoded frames against the index if `verified`, as
+    /// `prefetch::decode_verified` does.
+    pub fn verified(mut self, verified: bool) -> Self {
+        self.verified = verified;
+        self
+    }
+
+    /// Gives up on frames once `watchdog` runs out, as
+    /// `prefetch::decode_watched` does.
+    pub fn watched(mut self, watchdog: Watchdog) -> Self {
+        self.watchdog = watchdog;
+        self
+    }
+}
+
+impl Decoder for Concat<'_> {
+    fn decode(&mut self, frame: usize) -> io::Result<DecodedFrame> {
+        let (input, frame) = self
+            .timeline
+            .map_or((0, frame), |timeline| timeline.locate(frame));
+        if self.watchdog.is_set() {
+            return prefetch::decode_watched(
+                self.sources[input],
+                frame,
+                self.verified,
+                &self.watchdog,
+            );
+        }
+        match self.verified {
+            true => prefetch::decode_verified(self.sources[input], frame),
+            false => self.sources[input].decode(frame),
+        }
+    }
+
+    fn frames(&self) -> usize {
+        match self.timeline {
+            Some(timeline) => timeline.total(),
+            None => self.sources[0].frames(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ffms_segmenter::yuv4mpeg::{ColorRange, Interlacing};
+    use y4m::{Colorspace, Ratio};
+
+    #[test]
+    fn locates_frames_across_reels() {
+        let timeline = Timeline::new([100, 50, 25]);
+        assert_eq!(timeline.total(), 175);
+        assert_eq!(timeline.locate(0), (0, 0));
+        assert_eq!(timeline.locate(99), (0, 99));
+        assert_eq!(timeline.locate(100), (1, 0));
+        assert_eq!(timeline.locate(174), (2, 24));
+    }
+
+    #[test]
+    fn rejects_mismatched_inputs() {
+        let format = SegmentFormat {
+            width: 1920,
+            height: 1080,
+            framerate: Ratio { num: 24, den: 1 },
+            colorspace: Colorspace::C420p10,
+            interlacing: Interlacing::Progressive,
+            color_range: ColorRange::Limited,
+            color: None,
+            sar: None,
+        };
+        let other = SegmentFormat {
+            framerate: Ratio { num: 25, den: 1 },
+            ..format.clone()
+        };
+
+        assert!(check_formats(&[&format, &format]).is_ok());
+        assert_eq!(
+            check_formats(&[&format, &format, &other])
+                .unwrap_err()
+                .to_string(),
+            "Cannot concatenate input 2, 1920x1080 at 25/1 fps, C420p10 Progressive Limited, \
+             after input 0, 1920x1080 at 24/1 fps, C420p10 Progressive Limited"
+        );
+    }
+}
@@ -0,0 +1,277 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The prefix of environment variables setting options, as in
+/// `FFMS_SEGMENTER_SEEK_MODE` for `--seek-mode`.
+const ENV_PREFIX: &str = "FFMS_SEGMENTER_";
+
+/// A value of the configuration file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    /// The value as a command line argument.
+    pub fn to_argument(&self) -> String {
+        match self {
+            Value::String(string) => string.clone(),
+            Value::Integer(integer) => integer.to_string(),
+            Value::Float(float) => float.to_string(),
+            Value::Boolean(boolean) => boolean.to_string(),
+            Value::Array(_) => unreachable!("arrays are flattened"),
+        }
+    }
+}
+
+/// Parses one value, returning it with the rest of the line.
+fn parse_value(text: &str) -> Option<(Value, &str)> {
+    let text = text.trim_start();
+
+    if let Some(rest) = text.strip_prefix('"') {
+        let mut string = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Some((Value::String(string), &rest[i + 1..])),
+                '\\' => match chars.next()?.1 {
+                    'n' => string.push('\n'),
+                    't' => string.push('\t'),
+                    c @ ('"' | '\\') => string.push(c),
+                    _ => return None,
+                },
+                c => string.push(c),
+            }
+        }
+        return None;
+    }
+
+    if let Some(rest) = text.strip_prefix('\'') {
+        let (string, rest) = rest.split_once('\'')?;
+        return Some((Value::String(string.to_string()), rest));
+    }
+
+    if let Some(mut rest) = text.strip_prefix('[') {
+        let mut values = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(rest) = rest.strip_prefix(']') {
+                return Some((Value::Array(values), rest));
+            }
+            let (value, after) = parse_value(rest)?;
+            values.push(value);
+            rest = after.trim_start();
+            rest = rest.strip_prefix(',').unwrap_or(rest);
+        }
+    }
+
+    let end = text
+        .find(|c: char| c.is_whitespace() || c == ',' || c == ']' || c == '#')
+        .unwrap_or(text.len());
+    let (token, rest) = text.split_at(end);
+    let token = token.replace('_', "");
+    let value = match token.as_str() {
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        token => match token.parse() {
+            Ok(integer) => Value::Integer(integer),
+            Err(_) => Value::Float(token.parse().ok()?),
+        },
+    };
+
+    Some((value, rest))
+}
+
+/// Parses the `key = value` lines of a TOML file. Tables, which no option
+/// needs, are rejected.
+pub fn parse(text: &str) -> io::Result<Vec<(String, Value)>> {
+    let mut entries = Vec::new();
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid configuration on line {}: {}", number + 1, line),
+            )
+        };
+
+        let (key, value) = line.split_once('=').ok_or_else(invalid)?;
+        let key = key.trim().trim_matches('"');
+        if key.is_empty() || !key.chars().all(|c| c.is_alphanumeric() || "-_".contains(c)) {
+            return Err(invalid());
+        }
+        let (value, rest) = parse_value(value).ok_or_else(invalid)?;
+        match rest.trim_start() {
+            rest if rest.is_empty() || rest.starts_with('#') => {}
+            _ => return Err(invalid()),
+        }
+
+        entries.push((key.replace('_', "-"), value));
+    }
+
+    Ok(entries)
+}
+
+pub fn load(path: &Path) -> io::Result<Vec<(String, Value)>> {
+    parse(&fs::read_to_string(path)?)
+}
+
+/// The options set by `FFMS_SEGMENTER_*` variables among `vars`. Flags are
+/// set with `true`; every other value is taken as a string.
+pub fn from_env(vars: impl Iterator<Item = (String, String)>) -> Vec<(String, Value)> {
+    let mut entries: Vec<(String, Value)> = vars
+        .filter_map(|(name, value)| {
+            let key = name
+                .strip_prefix(ENV_PREFIX)?
+                .to_lowercase()
+                .replace('_', "-");
+            let value = match value.as_str() {
+                "true" => Value::Boolean(true),
+                "false" => Value::Boolean(false),
+                _ => Value::String(value),
+            };
+            Some((key, value))
+        })
+        .collect();
+    // The environment has no order of its own.
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    entries
+}
+
+/// The command line arguments standing for `entries`, leaving out the
+/// options `given` on the command line. Returns the `--<key> <value>`
+/// options, and the output folder, which is positional.
+pub fn arguments(
+    entries: &[(String, Value)],
+    given: impl Fn(&str) -> bool,
+) -> io::Result<(Vec<OsString>, Option<OsString>)> {
+    let mut options = Vec::new();
+    let mut output_folder = None;
+
+    for (key, value) in entries.iter().filter(|(key, _)| !given(key)) {
+        let values = match value {
+            Value::Array(values) => values.clone(),
+            value => vec![value.clone()],
+        };
+
+        match key.as_str() {
+            "input-file" | "config" => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} cannot be set in the configuration file", key),
+                ))
+            }
+            "output-folder" => {
+                output_folder = values.first().map(|value| value.to_argument().into())
+            }
+            _ => {
+                for value in values {
+                    match value {
+                        Value::Boolean(true) => options.push(format!("--{}", key).into()),
+                        Value::Boolean(false) => {}
+                        value => {
+                            options.push(format!("--{}", key).into());
+                            options.push(value.to_argument().into());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Ok((options, output_folder))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_flat_values() {
+        let text = r#"
+            # Defaults for the farm
+            threads = 4
+            seek_mode = 1 # the FFMS2 default
+            output-folder = "/srv/segments"
+            on-decode-error = 'skip'
+            gpu = true
+            input = ["a.mkv", "b.mkv"]
+            quality-gate = 0.5
+        "#;
+
+        assert_eq!(
+            parse(text).unwrap(),
+            vec![
+                ("threads".to_string(), Value::Integer(4)),
+                ("seek-mode".to_string(), Value::Integer(1)),
+                (
+                    "output-folder".to_string(),
+                    Value::String("/srv/segments".to_string())
+                ),
+                (
+                    "on-decode-error".to_string(),
+                    Value::String("skip".to_string())
+                ),
+                ("gpu".to_string(), Value::Boolean(true)),
+                (
+                    "input".to_string(),
+                    Value::Array(vec![
+                        Value::String("a.mkv".to_string()),
+                        Value::String("b.mkv".to_string())
+                    ])
+                ),
+                ("quality-gate".to_string(), Value::Float(0.5)),
+            ]
+        );
+        assert!(parse("[segments]").is_err());
+        assert!(parse("threads = 4 8").is_err());
+    }
+
+    #[test]
+    fn reads_prefixed_variables() {
+        let vars = [
+            ("FFMS_SEGMENTER_THREADS", "2"),
+            ("HOME", "/root"),
+            ("FFMS_SEGMENTER_OUTPUT_FOLDER", "/srv/segments"),
+            ("FFMS_SEGMENTER_RGB", "true"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+
+        assert_eq!(
+            from_env(vars.into_iter()),
+            vec![
+                (
+                    "output-folder".to_string(),
+                    Value::String("/srv/segments".to_string())
+                ),
+                ("rgb".to_string(), Value::Boolean(true)),
+                ("threads".to_string(), Value::String("2".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn command_line_options_win() {
+        let entries =
+            parse("threads = 4\nrgb = true\nmmap = false\noutput-folder = \"out\"").unwrap();
+
+        let (options, output_folder) = arguments(&entries, |key| key == "threads").unwrap();
+        assert_eq!(options, vec![OsString::from("--rgb")]);
+        assert_eq!(output_folder, Some(OsString::from("out")));
+
+        let (options, _) = arguments(&entries, |_| false).unwrap();
+        assert_eq!(options, ["--threads", "4", "--rgb"].map(OsString::from));
+    }
+}
@@ -0,0 +1,80 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// First line of the XviD first pass stats SCXviD writes.
+const XVID_HEADER: &str = "# XviD 2pass stat file";
+
+/// Parses a `--cuts` list: one frame number per line, optionally followed
+/// by more fields as in an x264 qpfile (`<frame> <type> [qp]`), or the
+/// XviD first pass stats of SCXviD, where the frames whose line starts
+/// with `i` are the scene changes. Blank lines and `#` comments are
+/// skipped.
+pub fn parse(text: &str) -> io::Result<Vec<usize>> {
+    let lines = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'));
+
+    // Each line after the header and comments is a frame.
+    if text.trim_start().starts_with(XVID_HEADER) {
+        return Ok(lines
+            .enumerate()
+            .filter(|(_, line)| line.starts_with('i'))
+            .map(|(frame, _)| frame)
+            .collect());
+    }
+
+    lines
+        .map(|line| {
+            line.split_whitespace()
+                .next()
+                .and_then(|frame| frame.parse::<usize>().ok())
+                .ok_or_else(|| io::Error::other(format!("Invalid cut line: {}", line)))
+        })
+        .collect()
+}
+
+pub fn read(path: &Path) -> io::Result<Vec<usize>> {
+    parse(&fs::read_to_string(path)?)
+}
+
+/// The ranges between `cuts` covering `0..total`, each cut starting one.
+/// Cuts are sorted, repeats dropped and cuts past the end ignored.
+pub fn segments(cuts: &[usize], total: usize) -> Vec<(usize, usize)> {
+    let mut bounds: Vec<usize> = cuts.iter().copied().filter(|&cut| cut < total).collect();
+    bounds.extend([0, total]);
+    bounds.sort_unstable();
+    bounds.dedup();
+
+    bounds
+        .windows(2)
+        .map(|bounds| (bounds[0], bounds[1]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_cut_lists() {
+        assert_eq!(parse("# cuts\n240\n\n 96 \n").unwrap(), [240, 96]);
+        assert_eq!(parse("0 I -1\n120 I -1\n300 K\n").unwrap(), [0, 120, 300]);
+        assert!(parse("0\nscene\n").is_err());
+
+        let stats = "# XviD 2pass stat file (core version 1.1-127)\n\
+                     # Please do not modify this file\n\n\
+                     i 0 0 1 0 0 0\np 1 0 1 0 0 0\nb 1 0 1 0 0 0\ni 0 0 1 0 0 0\np 1 0 1 0 0 0\n";
+        assert_eq!(parse(stats).unwrap(), [0, 3]);
+    }
+
+    #[test]
+    fn splits_between_cuts() {
+        assert_eq!(
+            segments(&[240, 96, 96, 0, 500], 300),
+            [(0, 96), (96, 240), (240, 300)]
+        );
+        assert_eq!(segments(&[], 24), [(0, 24)]);
+    }
+}
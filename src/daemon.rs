@@ -0,0 +1,139 @@
+use crate::transport::{Client, Reply};
+use ffms_segmenter::log;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixListener;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+fn write_line(writer: &Mutex<impl Write>, line: &str) -> io::Result<()> {
+    let mut writer = writer.lock().unwrap();
+    writeln!(writer, "{}", line)?;
+    writer.flush()
+}
+
+/// Writes the responses to request `id`, each prefixed with the id, until
+/// it finishes.
+fn forward_replies(
+    writer: &Mutex<impl Write>,
+    id: u64,
+    replies: Receiver<Reply>,
+) -> io::Result<()> {
+    for reply in replies {
+        match reply {
+            Reply::Message(message) => write_line(writer, &format!("{} {}", id, message))?,
+            Reply::Progress(_) => {}
+            Reply::Finished => return write_line(writer, &format!("finished {}", id)),
+        }
+    }
+
+    write_line(writer, &format!("failed {} Session failed", id))
+}
+
+/// Reads the commands of session `session` until it disconnects. Requests
+/// run on after their session is gone, and `status` still lists them.
+fn handle_connection<W: Write + Send + 'static>(
+    client: &Client,
+    session: u64,
+    reader: impl BufRead,
+    writer: W,
+) -> io::Result<()> {
+    let writer = Arc::new(Mutex::new(writer));
+    write_line(&writer, &format!("session {}", session))?;
+    for message in client.announced() {
+        write_line(&writer, &message)?;
+    }
+
+    let mut forwarders = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let command = line.trim();
+        if command.is_empty() {
+            continue;
+        }
+
+        let (id, replies) = match client.request(command) {
+            Err(err) if err.kind() == io::ErrorKind::ResourceBusy => {
+                write_line(&writer, &format!("rejected {}: {}", command, err))?;
+                continue;
+            }
+            result => result?,
+        };
+        write_line(&writer, &format!("queued {} {}", id, command))?;
+
+        let writer = writer.clone();
+        forwarders.retain(|forwarder: &thread::JoinHandle<_>| !forwarder.is_finished());
+        forwarders.push(thread::spawn(move || forward_replies(&writer, id, replies)));
+    }
+
+    for forwarder in forwarders {
+        let _ = forwarder.join();
+    }
+
+    Ok(())
+}
+
+/// Accepts sessions on `listener`, handling each on its own thread.
+pub fn serve(listener: UnixListener, client: Client) {
+    for (session, stream) in (1..).zip(listener.incoming()) {
+        let stream = match stream.and_then(|stream| Ok((stream.try_clone()?, stream))) {
+            Ok(stream) => stream,
+            Err(err) => {
+                log!(Error, "Daemon accept failed: {}", err);
+                continue;
+            }
+        };
+
+        let client = client.clone();
+        thread::spawn(move || {
+            log!(Info, "Session {} connected", session);
+            let (reader, writer) = stream;
+            match handle_connection(&client, session, BufReader::new(reader), writer) {
+                Ok(()) => log!(Info, "Session {} closed", session),
+                Err(err) => log!(Warn, "Session {} failed: {}", session, err),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{channel_transport, Transport};
+
+    /// A writer the test can read back after the connection is done.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn tags_responses_with_their_request() {
+        let (mut transport, client) = channel_transport();
+        transport.send("1920 1080 100 1 24").unwrap();
+        let out = Shared::default();
+
+        let connection = {
+            let out = out.clone();
+            thread::spawn(move || handle_connection(&client, 7, &b"0 10\n\n"[..], out))
+        };
+
+        assert_eq!(transport.receive().unwrap().as_deref(), Some("0 10"));
+        transport.send("0 ./0-10.y4m").unwrap();
+        transport.finish().unwrap();
+        connection.join().unwrap().unwrap();
+
+        assert_eq!(
+            String::from_utf8(out.0.lock().unwrap().clone()).unwrap(),
+            "session 7\n1920 1080 100 1 24\nqueued 0 0 10\n0 0 ./0-10.y4m\nfinished 0\n"
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use crate::checksum::{frame_crc32, HashWriter};
+use crate::filter::Deinterlacer;
+use crate::rotate::Rotator;
+use crate::segment::{encode_segment, y4m_writer, Stage};
+use crate::{
+    decode_error_policy, decoder_threads, index_file, open_source, output_format, prefetch_depth,
+    zones, CliArgs,
+};
+use ffms2::IndexErrorHandling;
+use ffms_segmenter::log;
+use std::io;
+use std::path::Path;
+
+/// Hashes of every stage of one segment in one run.
+struct SegmentHashes {
+    decoded: Vec<u32>,
+    deinterlaced: Vec<u32>,
+    scaled: Vec<u32>,
+    output: u32,
+}
+
+fn run_plan(
+    args: &CliArgs,
+    index: &ffms2::index::Index,
+    threads: usize,
+    plan: &[(usize, usize)],
+) -> io::Result<Vec<SegmentHashes>> {
+    let mut source = open_source(args, &args.input_file, 0, index, threads)?;
+    let (scaler, format) = output_format(args, &source)?;
+    let on_error = decode_error_policy(args, &source);
+    let rotator = Rotator::new(
+        source.rotation,
+        format.colorspace,
+        format.width,
+        format.height,
+    );
+
+    let mut segments = Vec::with_capacity(plan.len());
+
+    for &(start, end) in plan {
+        let end = end.min(source.total_frames);
+        let mut decoded = Vec::new();
+        let mut deinterlaced = Vec::new();
+        let mut scaled = Vec::new();
+        let mut output = HashWriter::new();
+
+        let mut deinterlacer = args
+            .deinterlace
+            .map(|mode| Deinterlacer::new(mode, &source.format));
+
+        encode_segment(
+            &mut y4m_writer(&mut output, &format)?,
+            &mut source.video_source,
+            deinterlacer.as_mut(),
+            scaler.as_ref(),
+            rotator.as_ref(),
+            None,
+            start,
+            end,
+            1,
+            prefetch_depth(args, &source.format),
+            on_error.clone(),
+            &mut |stage, _, frame| match stage {
+                Stage::Decoded => decoded.push(frame_crc32(frame)),
+                Stage::Deinterlaced => deinterlaced.push(frame_crc32(frame)),
+                Stage::Scaled => scaled.push(frame_crc32(frame)),
+                Stage::Output => {}
+            },
+        )?;
+
+        segments.push(SegmentHashes {
+            decoded,
+            deinterlaced,
+            scaled,
+            output: output.crc.finish(),
+        });
+    }
+
+    Ok(segments)
+}
+
+/// Describes which frames differ between two runs of a stage.
+fn compare_stage(start: usize, first: &[u32], second: &[u32]) -> Option<String> {
+    if first.len() != second.len() {
+        return Some(format!("frame count {} != {}", first.len(), second.len()));
+    }
+
+    let mismatched: Vec<String> = first
+        .iter()
+        .zip(second)
+        .enumerate()
+        .filter(|(_, (a, b))| a != b)
+        .map(|(offset, _)| (start + offset).to_string())
+        .collect();
+
+    match mismatched.len() {
+        0 => None,
+        n if n > 10 => Some(format!(
+            "frames {} and {} more",
+            mismatched[..10].join(","),
+            n - 10
+        )),
+        _ => Some(format!("frames {}", mismatched.join(","))),
+    }
+}
+
+/// Runs the plan in `plan_path` twice, the second time with
+/// `second_threads` decoder threads if given, and prints a per-segment
+/// report of stages whose output differed. Returns whether both runs
+/// matched.
+pub fn check(
+    args: &CliArgs,
+    ignore_errors: IndexErrorHandling,
+    plan_path: &Path,
+    second_threads: Option<usize>,
+) -> io::Result<bool> {
+    let plan = zones::read(plan_path)?;
+    let index = index_file(args, &args.input_file, ignore_errors)?;
+
+    let first_threads = decoder_threads(args.threads);
+    let second_threads = decoder_threads(second_threads.or(args.threads));
+
+    log!(
+        Info,
+        "Determinism check, run 1 with {} threads",
+        first_threads
+    );
+    let first = run_plan(args, &index, first_threads, &plan)?;
+    log!(
+        Info,
+        "Determinism check, run 2 with {} threads",
+        second_threads
+    );
+    let second = run_plan(args, &index, second_threads, &plan)?;
+
+    let mut unstable_stages: Vec<&str> = Vec::new();
+
+    for (&(start, end), (a, b)) in plan.iter().zip(first.iter().zip(&second)) {
+        let mut report = Vec::new();
+
+        let stages = [
+            ("decode", compare_stage(start, &a.decoded, &b.decoded)),
+            (
+                "deinterlace",
+                compare_stage(start, &a.deinterlaced, &b.deinterlaced),
+            ),
+            ("scale", compare_stage(start, &a.scaled, &b.scaled)),
+            (
+                "output",
+                (a.output != b.output)
+                    .then(|| format!("crc32 {:08x} != {:08x}", a.output, b.output)),
+            ),
+        ];
+
+        for (stage, mismatch) in stages {
+            match mismatch {
+                Some(details) => {
+                    report.push(format!("{}=mismatch ({})", stage, details));
+                    if !unstable_stages.contains(&stage) {
+                        unstable_stages.push(stage);
+                    }
+                }
+                None => report.push(format!("{}=ok", stage)),
+            }
+        }
+
+        println!("{} {} {}", start, end, report.join(" "));
+    }
+
+    if unstable_stages.is_empty() {
+        println!("deterministic");
+    } else {
+        println!("nondeterministic {}", unstable_stages.join(","));
+    }
+
+    Ok(unstable_stages.is_empty())
+}
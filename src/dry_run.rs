@@ -0,0 +1,147 @@
+use crate::{
+    build_planner, cuts, index_file, limit_segment_bytes, open_source, output_folder,
+    output_format, part_name, segment_bytes, segment_extension, segment_range, segment_stem,
+    split_options, zones, CliArgs,
+};
+use ffms2::IndexErrorHandling;
+use ffms_segmenter::{log, Protocol};
+use std::io::{self, BufRead};
+
+/// Validates the requested ranges from `--zones`, `--cuts` or stdin and
+/// prints `<start> <end> <path> <bytes>` for every segment that would be
+/// written, without decoding any segments. Returns whether all requests were valid.
+pub fn run(args: &CliArgs, ignore_errors: IndexErrorHandling) -> io::Result<bool> {
+    let planner = build_planner(args)?;
+    let index = index_file(args, &args.input_file, ignore_errors)?;
+    let source = open_source(args, &args.input_file, 0, &index, 1)?;
+    let (_, format) = output_format(args, &source)?;
+
+    let ranges = match (&args.zones, &args.cuts) {
+        (Some(zones), _) => Some(zones::read(zones)?),
+        (None, Some(path)) => Some(cuts::segments(&cuts::read(path)?, source.total_frames)),
+        (None, None) => None,
+    };
+    let requests: Vec<String> = match ranges {
+        Some(ranges) => ranges
+            .into_iter()
+            .map(|(start, end)| args.range_convention.format_range(start, end))
+            .collect(),
+        None => io::stdin().lock().lines().collect::<io::Result<_>>()?,
+    };
+
+    let total_frames = source.total_frames;
+    let track = &source.track;
+    let previous_keyframe = |frame: usize| {
+        (0..=frame)
+            .rev()
+            .find(|&n| track.FrameInfo(n).KeyFrame() != 0)
+    };
+
+    let mut valid = true;
+    let mut total_bytes = 0;
+
+    for line in requests.iter().map(|line| line.trim()) {
+        if line.is_empty() {
+            continue;
+        }
+
+        let (options, command) = match split_options(args, line) {
+            Ok(split) => split,
+            Err(err) => {
+                println!("invalid {}: {}", line, err);
+                valid = false;
+                continue;
+            }
+        };
+
+        let mut request = match Protocol::V1.parse_request(&command) {
+            Some(request) => request,
+            None => {
+                println!("invalid {}: expected <start> <end>", line);
+                valid = false;
+                continue;
+            }
+        };
+        request.end = args.range_convention.exclusive_end(request.end);
+
+        if request.start >= request.end || request.start >= total_frames {
+            println!(
+                "invalid {}: not a range within the {} frames of the input",
+                line, total_frames
+            );
+            valid = false;
+            continue;
+        }
+
+        if request.end > total_frames {
+            log!(
+                Warn,
+                "Request {} ends after the last frame and will be cut to {}",
+                line,
+                total_frames
+            );
+        }
+        let end = request.end.min(total_frames);
+
+        let segments = match planner {
+            Some(ref planner) => planner.plan(request.start, end),
+            None => vec![(request.start, end)],
+        };
+        let segments =
+            match limit_segment_bytes(args, &format, &options, &source.keyframes, segments) {
+                Ok(segments) => segments,
+                Err(reason) => {
+                    println!("invalid {}: {}", line, reason);
+                    valid = false;
+                    continue;
+                }
+            };
+
+        let total = segments.len();
+        for (sequence, (start, end)) in segments.into_iter().enumerate() {
+            let padding = options.padding.clamp(start, end, total_frames);
+            let (first, last) = (start - padding.before, end + padding.after);
+
+            match previous_keyframe(first) {
+                Some(keyframe) if keyframe == first => {}
+                Some(keyframe) => log!(
+                    Warn,
+                    "Segment {} to {} does not start on a keyframe, decoding starts at {}",
+                    start,
+                    end,
+                    keyframe
+                ),
+                None => log!(
+                    Warn,
+                    "Segment {} to {} has no keyframe before it",
+                    start,
+                    end
+                ),
+            }
+
+            let frames = (last - first).div_ceil(options.step) * options.repeat;
+            let format = format.stepped(options.step);
+            let bytes = segment_bytes(args, &format, frames);
+            total_bytes += bytes;
+
+            let name = part_name(options.name.as_deref(), sequence, total);
+            println!(
+                "{} {} {}/{}.{} {}",
+                start,
+                end,
+                output_folder(args).display(),
+                segment_stem(
+                    source.id,
+                    &segment_range(args, &source, start, end),
+                    name.as_deref()
+                ),
+                segment_extension(args),
+                bytes
+            );
+        }
+    }
+
+    log!(Info, "Would write {} bytes in total", total_bytes);
+
+    Ok(valid)
+}
@@ -0,0 +1,175 @@
+use crate::compare::{samples, ssim};
+use crate::Source;
+use ffms_segmenter::checksum::frame_crc32;
+use ffms_segmenter::prefetch::{DecodedFrame, Decoder};
+use std::io::{self, BufWriter, Write};
+
+/// Parses the `--duplicate-ssim` score above which frames count as
+/// duplicates.
+pub fn parse_ssim(score: &str) -> Result<f64, String> {
+    match score.parse::<f64>() {
+        Ok(score) if (0.0..=1.0).contains(&score) => Ok(score),
+        _ => Err(format!(
+            "Invalid SSIM, expected a score from 0 to 1: {}",
+            score
+        )),
+    }
+}
+
+/// Frames each repeating the one before them, after its `first`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Run {
+    pub first: usize,
+    /// Number of frames in the run, `first` included.
+    pub frames: usize,
+    /// The lowest luma SSIM between neighbouring frames of the run, 1 for
+    /// exact repeats.
+    pub ssim: f64,
+}
+
+/// Collects runs from frames in order.
+#[derive(Default)]
+struct Runs {
+    runs: Vec<Run>,
+    current: Option<Run>,
+}
+
+impl Runs {
+    /// Adds `frame`, with the SSIM to the frame before it if it repeats
+    /// that frame.
+    fn push(&mut self, frame: usize, repeat: Option<f64>) {
+        match (repeat, self.current.as_mut()) {
+            (Some(score), Some(run)) => {
+                run.frames += 1;
+                run.ssim = run.ssim.min(score);
+            }
+            (Some(score), None) => {
+                self.current = Some(Run {
+                    first: frame - 1,
+                    frames: 2,
+                    ssim: score,
+                })
+            }
+            (None, _) => self.runs.extend(self.current.take()),
+        }
+    }
+
+    fn finish(mut self) -> Vec<Run> {
+        self.runs.extend(self.current.take());
+        self.runs
+    }
+}
+
+/// Finds the runs of repeated frames in `start..end`: frames identical to
+/// the one before them, or with a luma SSIM to it of at least `threshold`.
+pub fn find(
+    source: &mut Source,
+    start: usize,
+    end: usize,
+    threshold: Option<f64>,
+) -> io::Result<Vec<Run>> {
+    let end = end.min(source.total_frames);
+    let format = &source.format;
+    let bytes = format.colorspace.get_bytes_per_sample();
+    let max = ((1u32 << format.colorspace.get_bit_depth()) - 1) as f64;
+    let (width, height) = (format.width, format.height);
+
+    let mut runs = Runs::default();
+    let mut previous: Option<(DecodedFrame, u32)> = None;
+    for frame_number in start..end {
+        let frame = source.video_source.decode(frame_number)?;
+        let crc = frame_crc32(&frame);
+
+        let repeat = previous.as_ref().and_then(|(previous, previous_crc)| {
+            if crc == *previous_crc {
+                return Some(1.0);
+            }
+            let threshold = threshold?;
+            let score = ssim(
+                &samples(&previous.planes[0], bytes),
+                &samples(&frame.planes[0], bytes),
+                width,
+                height,
+                max,
+            );
+            (score >= threshold).then_some(score)
+        });
+        runs.push(frame_number, repeat);
+
+        previous = Some((frame, crc));
+    }
+
+    Ok(runs.finish())
+}
+
+/// Prints `<first> <last> <frames> <ssim>` for every run of repeated
+/// frames in `start..end`, for telling bad pulldown and dropped frames of
+/// captures apart before segmenting.
+pub fn print(
+    source: &mut Source,
+    start: usize,
+    end: usize,
+    threshold: Option<f64>,
+) -> io::Result<()> {
+    let runs = find(source, start, end, threshold)?;
+    let mut out = BufWriter::new(io::stdout().lock());
+
+    for run in &runs {
+        writeln!(
+            out,
+            "{} {} {} {:.5}",
+            run.first,
+            run.first + run.frames - 1,
+            run.frames,
+            run.ssim
+        )?;
+    }
+
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_repeated_frames() {
+        let mut runs = Runs::default();
+        let repeats = [
+            None,
+            Some(1.0),
+            Some(0.99),
+            None,
+            None,
+            Some(1.0),
+            None,
+            Some(1.0),
+        ];
+        for (frame, repeat) in (10..).zip(repeats) {
+            runs.push(frame, repeat);
+        }
+
+        assert_eq!(
+            runs.finish(),
+            [
+                Run {
+                    first: 10,
+                    frames: 3,
+                    ssim: 0.99
+                },
+                Run {
+                    first: 14,
+                    frames: 2,
+                    ssim: 1.0
+                },
+                Run {
+                    first: 16,
+                    frames: 2,
+                    ssim: 1.0
+                },
+            ]
+        );
+        assert!(parse_ssim("0.98").is_ok());
+        assert!(parse_ssim("1.5").is_err());
+    }
+}
@@ -0,0 +1,121 @@
+/// When one track of an input starts and ends, for the `duration` command.
+pub struct TrackTimes {
+    pub id: usize,
+    pub kind: &'static str,
+    pub frames: usize,
+    /// PTS are in units of `num / den` milliseconds.
+    pub time_base: (i64, i64),
+    /// PTS of the first and the last frame in presentation order. Unknown
+    /// for tracks that were not indexed.
+    pub pts: Option<(i64, i64)>,
+    /// Seconds to the start of the first frame and the end of the last.
+    pub times: Option<(f64, f64)>,
+    /// Samples and sample rate of audio tracks that could be opened.
+    pub samples: Option<(i64, i32)>,
+}
+
+impl TrackTimes {
+    /// Seconds the track plays for: its samples at its rate for audio,
+    /// otherwise from the start of its first frame to the end of its last.
+    pub fn duration(&self) -> Option<f64> {
+        match (self.samples, self.times) {
+            (Some((samples, rate)), _) if rate > 0 => Some(samples as f64 / rate as f64),
+            (_, Some((start, end))) => Some(end - start),
+            _ => None,
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let number = |value: Option<String>| value.unwrap_or_else(|| "null".to_string());
+        let seconds = |value: Option<f64>| number(value.map(|value| format!("{:.6}", value)));
+
+        let mut json = format!(
+            "{{\"track\":{},\"type\":\"{}\",\"frames\":{},\"time_base\":{{\"num\":{},\"den\":{}}},\
+             \"first_pts\":{},\"last_pts\":{},\"start\":{},\"end\":{},\"duration\":{}",
+            self.id,
+            self.kind,
+            self.frames,
+            self.time_base.0,
+            self.time_base.1,
+            number(self.pts.map(|(first, _)| first.to_string())),
+            number(self.pts.map(|(_, last)| last.to_string())),
+            seconds(self.times.map(|(start, _)| start)),
+            seconds(self.times.map(|(_, end)| end)),
+            seconds(self.duration())
+        );
+        if let Some((samples, rate)) = self.samples {
+            json.push_str(&format!(
+                ",\"samples\":{},\"sample_rate\":{}",
+                samples, rate
+            ));
+        }
+        json.push('}');
+
+        json
+    }
+}
+
+/// The `duration` response for input `input`, whose video is `video`, with
+/// the frame count and duration of the video first.
+pub fn to_json(input: usize, video: &TrackTimes, tracks: &[TrackTimes]) -> String {
+    let tracks: Vec<String> = tracks.iter().map(TrackTimes::to_json).collect();
+
+    format!(
+        "{{\"input\":{},\"frames\":{},\"duration\":{},\"tracks\":[{}]}}",
+        input,
+        video.frames,
+        video
+            .duration()
+            .map_or_else(|| "null".to_string(), |duration| format!("{:.6}", duration)),
+        tracks.join(",")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times_tracks_as_json() {
+        let tracks = [
+            TrackTimes {
+                id: 0,
+                kind: "video",
+                frames: 48,
+                time_base: (1, 1),
+                pts: Some((0, 1960)),
+                times: Some((0.0, 2.002)),
+                samples: None,
+            },
+            TrackTimes {
+                id: 1,
+                kind: "audio",
+                frames: 94,
+                time_base: (1, 1),
+                pts: Some((-21, 1984)),
+                times: Some((-0.021, 2.005)),
+                samples: Some((96_000, 48_000)),
+            },
+            TrackTimes {
+                id: 2,
+                kind: "audio",
+                frames: 0,
+                time_base: (1, 1),
+                pts: None,
+                times: None,
+                samples: None,
+            },
+        ];
+
+        assert_eq!(
+            to_json(0, &tracks[0], &tracks),
+            concat!(
+                r#"{"input":0,"frames":48,"duration":2.002000,"tracks":["#,
+                r#"{"track":0,"type":"video","frames":48,"time_base":{"num":1,"den":1},"first_pts":0,"last_pts":1960,"start":0.000000,"end":2.002000,"duration":2.002000},"#,
+                r#"{"track":1,"type":"audio","frames":94,"time_base":{"num":1,"den":1},"first_pts":-21,"last_pts":1984,"start":-0.021000,"end":2.005000,"duration":2.000000,"samples":96000,"sample_rate":48000},"#,
+                r#"{"track":2,"type":"audio","frames":0,"time_base":{"num":1,"den":1},"first_pts":null,"last_pts":null,"start":null,"end":null,"duration":null}"#,
+                "]}"
+            )
+        );
+    }
+}
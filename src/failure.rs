@@ -0,0 +1,109 @@
+use std::fmt;
+use std::io;
+
+/// Exit code of failures without a more specific one.
+pub const FAILED: i32 = 1;
+/// Exit code after Ctrl-C, the one shells report for SIGINT.
+pub const INTERRUPTED: i32 = 130;
+
+/// What a fatal error was about, so wrapper scripts can tell failures apart
+/// by the exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// Invalid options or requests.
+    BadArguments,
+    InputNotFound,
+    Indexing,
+    /// An input, track or pixel format that cannot be segmented.
+    Unsupported,
+    Decode,
+    /// An input without video, such as an audio-only file.
+    NoVideo,
+    /// A decode that took longer than `--decode-timeout` or
+    /// `--segment-timeout`.
+    Timeout,
+}
+
+impl Failure {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Failure::BadArguments => 2,
+            Failure::InputNotFound => 3,
+            Failure::Indexing => 4,
+            Failure::Unsupported => 5,
+            Failure::Decode => 6,
+            Failure::NoVideo => 7,
+            Failure::Timeout => 8,
+        }
+    }
+}
+
+/// An error with its [`Failure`], displayed as the error alone.
+#[derive(Debug)]
+struct Tagged {
+    failure: Failure,
+    error: io::Error,
+}
+
+impl fmt::Display for Tagged {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl std::error::Error for Tagged {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Marks `error` as a `failure`, keeping its kind and message.
+pub fn tag(failure: Failure, error: io::Error) -> io::Error {
+    io::Error::new(error.kind(), Tagged { failure, error })
+}
+
+/// Whether `error` was tagged as a `failure`.
+pub fn is(error: &io::Error, failure: Failure) -> bool {
+    error
+        .get_ref()
+        .and_then(|error| error.downcast_ref::<Tagged>())
+        .is_some_and(|tagged| tagged.failure == failure)
+}
+
+/// The exit code for a fatal `error`: that of the failure it was tagged
+/// with, or else of its kind.
+pub fn exit_code(error: &io::Error) -> i32 {
+    if let Some(tagged) = error
+        .get_ref()
+        .and_then(|error| error.downcast_ref::<Tagged>())
+    {
+        return tagged.failure.exit_code();
+    }
+
+    match error.kind() {
+        io::ErrorKind::Interrupted => INTERRUPTED,
+        io::ErrorKind::InvalidInput => Failure::BadArguments.exit_code(),
+        _ => FAILED,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tagged_errors_keep_their_message() {
+        let error = tag(
+            Failure::Decode,
+            io::Error::other("Failed to decode frame 3"),
+        );
+        assert_eq!(error.to_string(), "Failed to decode frame 3");
+        assert_eq!(exit_code(&error), 6);
+        assert!(is(&error, Failure::Decode));
+        assert!(!is(&error, Failure::Indexing));
+
+        let request = io::Error::new(io::ErrorKind::InvalidInput, "Invalid request option x");
+        assert_eq!(exit_code(&request), 2);
+        assert_eq!(exit_code(&io::Error::other("Disk full")), 1);
+    }
+}
@@ -0,0 +1,266 @@
+use crate::failure::{self, Failure};
+use crate::prefetch::{DecodedFrame, Decoder, Layout, LAYOUTS};
+use ffmpeg::codec::{self, threading};
+use ffmpeg::format::{context::Input, Pixel};
+use ffmpeg::software::scaling;
+use ffmpeg::{decoder, frame, Packet, Rescale};
+use ffmpeg_next as ffmpeg;
+use std::path::Path;
+
+/// A frame of the index: its presentation timestamp, in the time base of
+/// its stream, and whether it is a keyframe.
+pub type IndexedFrame = (i64, bool);
+
+/// Decodes a video stream with FFmpeg rather than FFMS2, for `--decoder
+/// ffmpeg`. Frames are found by the timestamps FFMS2 indexed, so a frame
+/// number means the same frame with either decoder.
+pub struct FfmpegSource {
+    input: Input,
+    stream: usize,
+    decoder: decoder::Video,
+    frames: Vec<IndexedFrame>,
+    /// The pixel format frames are written in, which decoded frames of
+    /// another format are converted to.
+    pixel_format: Pixel,
+    layout: Layout,
+    width: u32,
+    height: u32,
+    scaler: Option<scaling::Context>,
+    /// The frame decoding carries on with, without seeking first.
+    next: Option<usize>,
+    /// Whether the end of the stream has been handed to the decoder.
+    drained: bool,
+}
+
+// The FFmpeg contexts are only used by the one thread decoding through
+// them at a time.
+unsafe impl Send for FfmpegSource {}
+
+fn decode_error(message: String) -> std::io::Error {
+    failure::tag(Failure::Decode, std::io::Error::other(message))
+}
+
+impl FfmpegSource {
+    /// Opens stream `stream` of `path`, whose `frames` FFMS2 indexed, to
+    /// decode it into `width`x`height` frames of `pixel_format` on
+    /// `threads` threads.
+    pub fn open(
+        path: &Path,
+        stream: usize,
+        frames: Vec<IndexedFrame>,
+        pixel_format: &str,
+        (width, height): (usize, usize),
+        threads: usize,
+    ) -> std::io::Result<Self> {
+        let unsupported =
+            |message: String| failure::tag(Failure::Unsupported, std::io::Error::other(message));
+
+        let layout = LAYOUTS
+            .iter()
+            .find(|(name, ..)| *name == pixel_format)
+            .map(|&(_, bytes, x, y)| (bytes, x, y))
+            .ok_or_else(|| unsupported(format!("FFmpeg cannot write {} frames", pixel_format)))?;
+        let target = pixel_format.parse::<Pixel>().map_err(|_| {
+            unsupported(format!(
+                "FFmpeg does not know pixel format {}",
+                pixel_format
+            ))
+        })?;
+
+        ffmpeg::init().map_err(|err| unsupported(format!("Failed to start FFmpeg: {}", err)))?;
+        let input = ffmpeg::format::input(&path)
+            .map_err(|err| unsupported(format!("Failed to open {}: {}", path.display(), err)))?;
+        let parameters = input
+            .stream(stream)
+            .ok_or_else(|| unsupported(format!("{} has no stream {}", path.display(), stream)))?
+            .parameters();
+
+        let mut context = codec::context::Context::from_parameters(parameters)
+            .map_err(|err| unsupported(format!("Failed to open stream {}: {}", stream, err)))?;
+        context.set_threading(threading::Config {
+            kind: threading::Type::Frame,
+            count: threads,
+            ..Default::default()
+        });
+        let decoder = context.decoder().video().map_err(|err| {
+            unsupported(format!(
+                "Failed to open the decoder of stream {}: {}",
+                stream, err
+            ))
+        })?;
+
+        Ok(FfmpegSource {
+            input,
+            stream,
+            decoder,
+            frames,
+            pixel_format: target,
+            layout,
+            width: width as u32,
+            height: height as u32,
+            scaler: None,
+            next: None,
+            drained: false,
+        })
+    }
+
+    /// Seeks to the keyframe at or before frame `i`.
+    fn seek(&mut self, i: usize) -> std::io::Result<()> {
+        let keyframe = self.frames[..=i]
+            .iter()
+            .rposition(|&(_, keyframe)| keyframe)
+            .unwrap_or(0);
+        let time_base = self.input.stream(self.stream).unwrap().time_base();
+        let position = self.frames[keyframe]
+            .0
+            .rescale(time_base, ffmpeg::rescale::TIME_BASE);
+
+        self.input
+            .seek(position, ..position)
+            .map_err(|err| decode_error(format!("Failed to seek to frame {}: {}", i, err)))?;
+        self.decoder.flush();
+        self.drained = false;
+        Ok(())
+    }
+
+    /// Hands the decoder the next packet of the stream, or the end of it.
+    fn feed(&mut self, i: usize) -> std::io::Result<()> {
+        if self.drained {
+            return Err(decode_error(format!(
+                "Frame {} is past the frames FFmpeg decoded",
+                i
+            )));
+        }
+
+        let mut packet = Packet::empty();
+        loop {
+            match packet.read(&mut self.input) {
+                Ok(()) if packet.stream() == self.stream => break,
+                Ok(()) => continue,
+                Err(ffmpeg::Error::Eof) => {
+                    self.drained = true;
+                    return self.decoder.send_eof().map_err(|err| {
+                        decode_error(format!("Failed to decode frame {}: {}", i, err))
+                    });
+                }
+                Err(err) => {
+                    return Err(decode_error(format!("Failed to read frame {}: {}", i, err)))
+                }
+            }
+        }
+        self.decoder
+            .send_packet(&packet)
+            .map_err(|err| decode_error(format!("Failed to decode frame {}: {}", i, err)))
+    }
+
+    /// Copies `decoded` out, converted to the pixel format frames are
+    /// written in.
+    fn copy(&mut self, i: usize, decoded: &frame::Video) -> std::io::Result<DecodedFrame> {
+        let mut converted = frame::Video::empty();
+        let decoded = if decoded.format() == self.pixel_format {
+            decoded
+        } else {
+            let stale = self.scaler.as_ref().is_some_and(|scaler| {
+                let input = scaler.input();
+                (input.format, input.width, input.height)
+                    != (decoded.format(), decoded.width(), decoded.height())
+            });
+            if self.scaler.is_none() || stale {
+                let scaler = scaling::Context::get(
+                    decoded.format(),
+                    decoded.width(),
+                    decoded.height(),
+                    self.pixel_format,
+                    self.width,
+                    self.height,
+                    scaling::Flags::POINT,
+                )
+                .map_err(|err| decode_error(format!("Failed to convert frame {}: {}", i, err)))?;
+                self.scaler = Some(scaler);
+            }
+            self.scaler
+                .as_mut()
+                .unwrap()
+                .run(decoded, &mut converted)
+                .map_err(|err| decode_error(format!("Failed to convert frame {}: {}", i, err)))?;
+            &converted
+        };
+
+        let planes = std::array::from_fn(|plane| {
+            (plane < decoded.planes()).then(|| (decoded.data(plane), decoded.stride(plane)))
+        });
+        DecodedFrame::from_planes(
+            planes,
+            self.width as usize,
+            self.height as usize,
+            self.layout,
+        )
+        .ok_or_else(|| {
+            decode_error(format!(
+                "Frame {} was decoded at {}x{}, not {}x{}",
+                i,
+                decoded.width(),
+                decoded.height(),
+                self.width,
+                self.height
+            ))
+        })
+    }
+}
+
+impl Decoder for FfmpegSource {
+    fn decode(&mut self, i: usize) -> std::io::Result<DecodedFrame> {
+        let &(timestamp, _) = self.frames.get(i).ok_or_else(|| {
+            decode_error(format!(
+                "Frame {} is past the {} frames of the index",
+                i,
+                self.frames.len()
+            ))
+        })?;
+        if self.next != Some(i) {
+            self.next = None;
+            self.seek(i)?;
+        }
+
+        let mut decoded = frame::Video::empty();
+        loop {
+            match self.decoder.receive_frame(&mut decoded) {
+                Ok(()) => match decoded.timestamp().or(decoded.pts()) {
+                    Some(decoded_at) if decoded_at == timestamp => break,
+                    // Frames from the keyframe up to this one.
+                    Some(decoded_at) if decoded_at < timestamp => continue,
+                    _ => {
+                        self.next = None;
+                        return Err(decode_error(format!(
+                            "Frame {} was not decoded at its indexed timestamp {}",
+                            i, timestamp
+                        )));
+                    }
+                },
+                Err(ffmpeg::Error::Other {
+                    errno: ffmpeg::error::EAGAIN,
+                }) => self.feed(i)?,
+                Err(ffmpeg::Error::Eof) => {
+                    return Err(decode_error(format!(
+                        "Frame {} is past the frames FFmpeg decoded",
+                        i
+                    )))
+                }
+                Err(err) => {
+                    self.next = None;
+                    return Err(decode_error(format!(
+                        "Failed to decode frame {}: {}",
+                        i, err
+                    )));
+                }
+            }
+        }
+
+        self.next = Some(i + 1);
+        self.copy(i, &decoded)
+    }
+
+    fn frames(&self) -> usize {
+        self.frames.len()
+    }
+}
@@ -0,0 +1,316 @@
+use crate::SegmentFormat;
+use ffms_segmenter::prefetch::DecodedFrame;
+use ffms_segmenter::scale::{plane_sizes, PlaneSize};
+use ffms_segmenter::Interlacing;
+
+/// Deinterlacing algorithm, ported from the FFmpeg filters of the same
+/// name. Both output one frame per input frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Deinterlace {
+    Yadif,
+    Bwdif,
+}
+
+pub fn parse_deinterlace(mode: &str) -> Result<Deinterlace, String> {
+    match mode {
+        "yadif" => Ok(Deinterlace::Yadif),
+        "bwdif" => Ok(Deinterlace::Bwdif),
+        _ => Err(format!("Invalid deinterlacer: {}", mode)),
+    }
+}
+
+/// A plane of one of the three frames around the frame being filtered.
+struct Plane<'a> {
+    data: &'a [u8],
+    size: PlaneSize,
+    bytes: usize,
+}
+
+impl<'a> Plane<'a> {
+    fn of(data: &'a [u8], size: PlaneSize, bytes: usize) -> Self {
+        Plane { data, size, bytes }
+    }
+
+    /// Sample at `x` of line `y`. Lines outside the plane are mirrored onto
+    /// lines of the same field and columns are clamped.
+    fn at(&self, y: isize, x: isize) -> i32 {
+        let height = self.size.height as isize;
+        let mut y = y;
+        while y < 0 {
+            y += 2;
+        }
+        while y >= height {
+            y -= 2;
+        }
+        let y = y.clamp(0, height - 1) as usize;
+        let x = x.clamp(0, self.size.width as isize - 1) as usize;
+        let index = y * self.size.width + x;
+
+        if self.bytes == 2 {
+            u16::from_le_bytes([self.data[index * 2], self.data[index * 2 + 1]]) as i32
+        } else {
+            self.data[index] as i32
+        }
+    }
+}
+
+fn max3(a: i32, b: i32, c: i32) -> i32 {
+    a.max(b).max(c)
+}
+
+fn min3(a: i32, b: i32, c: i32) -> i32 {
+    a.min(b).min(c)
+}
+
+/// FFmpeg `yadif` in mode 0 with the spatial interlacing check.
+fn yadif(prev: &Plane, cur: &Plane, next: &Plane, y: isize, x: isize, parity: bool) -> i32 {
+    let (prev2, next2) = if parity { (prev, cur) } else { (cur, next) };
+
+    let c = cur.at(y - 1, x);
+    let e = cur.at(y + 1, x);
+    let d = (prev2.at(y, x) + next2.at(y, x)) >> 1;
+
+    let temporal_diff0 = (prev2.at(y, x) - next2.at(y, x)).abs();
+    let temporal_diff1 = ((prev.at(y - 1, x) - c).abs() + (prev.at(y + 1, x) - e).abs()) >> 1;
+    let temporal_diff2 = ((next.at(y - 1, x) - c).abs() + (next.at(y + 1, x) - e).abs()) >> 1;
+    let mut diff = max3(temporal_diff0 >> 1, temporal_diff1, temporal_diff2);
+
+    let mut spatial_pred = (c + e) >> 1;
+    let mut spatial_score = (cur.at(y - 1, x - 1) - cur.at(y + 1, x - 1)).abs()
+        + (c - e).abs()
+        + (cur.at(y - 1, x + 1) - cur.at(y + 1, x + 1)).abs()
+        - 1;
+
+    // Edge directed interpolation, only trying the steeper angle if the
+    // shallower one was an improvement.
+    for direction in [-1, 1] {
+        for step in 1..=2 {
+            let j = direction * step;
+            let score = (cur.at(y - 1, x - 1 + j) - cur.at(y + 1, x - 1 - j)).abs()
+                + (cur.at(y - 1, x + j) - cur.at(y + 1, x - j)).abs()
+                + (cur.at(y - 1, x + 1 + j) - cur.at(y + 1, x + 1 - j)).abs();
+            if score >= spatial_score {
+                break;
+            }
+            spatial_score = score;
+            spatial_pred = (cur.at(y - 1, x + j) + cur.at(y + 1, x - j)) >> 1;
+        }
+    }
+
+    let b = (prev2.at(y - 2, x) + next2.at(y - 2, x)) >> 1;
+    let f = (prev2.at(y + 2, x) + next2.at(y + 2, x)) >> 1;
+    let max = max3(d - e, d - c, (b - c).min(f - e));
+    let min = min3(d - e, d - c, (b - c).max(f - e));
+    diff = max3(diff, min, -max);
+
+    spatial_pred.clamp(d - diff, d + diff)
+}
+
+const BWDIF_COEF_LF: [i32; 2] = [4309, 213];
+const BWDIF_COEF_HF: [i32; 3] = [5570, 3801, 1016];
+const BWDIF_COEF_SP: [i32; 2] = [5077, 981];
+
+/// FFmpeg `bwdif`, which replaces yadif's spatial check with w3fdif style
+/// filtering.
+fn bwdif(prev: &Plane, cur: &Plane, next: &Plane, y: isize, x: isize, parity: bool) -> i32 {
+    let (prev2, next2) = if parity { (prev, cur) } else { (cur, next) };
+    let both = |offset: isize| prev2.at(y + offset, x) + next2.at(y + offset, x);
+
+    let c = cur.at(y - 1, x);
+    let e = cur.at(y + 1, x);
+    let d = both(0) >> 1;
+
+    let temporal_diff0 = (prev2.at(y, x) - next2.at(y, x)).abs();
+    let temporal_diff1 = ((prev.at(y - 1, x) - c).abs() + (prev.at(y + 1, x) - e).abs()) >> 1;
+    let temporal_diff2 = ((next.at(y - 1, x) - c).abs() + (next.at(y + 1, x) - e).abs()) >> 1;
+    let mut diff = max3(temporal_diff0 >> 1, temporal_diff1, temporal_diff2);
+
+    if diff == 0 {
+        return d;
+    }
+
+    let b = (both(-2) >> 1) - c;
+    let f = (both(2) >> 1) - e;
+    let dc = d - c;
+    let de = d - e;
+    let max = max3(de, dc, b.min(f));
+    let min = min3(de, dc, b.max(f));
+    diff = max3(diff, min, -max);
+
+    let outer = cur.at(y - 3, x) + cur.at(y + 3, x);
+    let interpolated = if (c - e).abs() > temporal_diff0 {
+        (((BWDIF_COEF_HF[0] * both(0) - BWDIF_COEF_HF[1] * (both(-2) + both(2))
+            + BWDIF_COEF_HF[2] * (both(-4) + both(4)))
+            >> 2)
+            + BWDIF_COEF_LF[0] * (c + e)
+            - BWDIF_COEF_LF[1] * outer)
+            >> 13
+    } else {
+        (BWDIF_COEF_SP[0] * (c + e) - BWDIF_COEF_SP[1] * outer) >> 13
+    };
+
+    interpolated.clamp(d - diff, d + diff)
+}
+
+/// Deinterlaces a stream of frames. Each frame is filtered with its
+/// neighbours, so output lags input by one frame.
+pub struct Deinterlacer {
+    mode: Deinterlace,
+    planes: [PlaneSize; 3],
+    bytes: usize,
+    max_value: i32,
+    /// Whether the top field is the one being interpolated.
+    parity: bool,
+    prev: Option<DecodedFrame>,
+    cur: Option<(usize, DecodedFrame)>,
+}
+
+impl Deinterlacer {
+    /// Creates a deinterlacer for frames in `format`. Progressive sources
+    /// are treated as top field first.
+    pub fn new(mode: Deinterlace, format: &SegmentFormat) -> Self {
+        Deinterlacer {
+            mode,
+            planes: plane_sizes(format.colorspace, format.width, format.height),
+            bytes: format.colorspace.get_bytes_per_sample(),
+            max_value: (1 << format.colorspace.get_bit_depth()) - 1,
+            parity: format.interlacing == Interlacing::BottomFieldFirst,
+            prev: None,
+            cur: None,
+        }
+    }
+
+    /// Adds the next frame, returning the previous one once it can be
+    /// filtered.
+    pub fn push(
+        &mut self,
+        frame_number: usize,
+        frame: DecodedFrame,
+    ) -> Option<(usize, DecodedFrame)> {
+        let (cur_number, cur) = self.cur.replace((frame_number, frame))?;
+
+        let next = &self.cur.as_ref().unwrap().1;
+        let filtered = self.filter(self.prev.as_ref().unwrap_or(&cur), &cur, next);
+        self.prev = Some(cur);
+
+        Some((cur_number, filtered))
+    }
+
+    /// Filters the last frame, using it as its own next frame.
+    pub fn flush(&mut self) -> Option<(usize, DecodedFrame)> {
+        let (cur_number, cur) = self.cur.take()?;
+        let filtered = self.filter(self.prev.as_ref().unwrap_or(&cur), &cur, &cur);
+        self.prev = Some(cur);
+
+        Some((cur_number, filtered))
+    }
+
+    fn filter(&self, prev: &DecodedFrame, cur: &DecodedFrame, next: &DecodedFrame) -> DecodedFrame {
+        let mut planes: [Vec<u8>; 3] = Default::default();
+
+        for (index, &size) in self.planes.iter().enumerate() {
+            planes[index] = self.filter_plane(
+                &prev.planes[index],
+                &cur.planes[index],
+                &next.planes[index],
+                size,
+            );
+        }
+
+        let alpha = match (&prev.alpha, &cur.alpha, &next.alpha) {
+            (Some(prev), Some(cur), Some(next)) => {
+                Some(self.filter_plane(prev, cur, next, self.planes[0]))
+            }
+            _ => cur.alpha.clone(),
+        };
+
+        DecodedFrame { planes, alpha }
+    }
+
+    fn filter_plane(&self, prev: &[u8], cur: &[u8], next: &[u8], size: PlaneSize) -> Vec<u8> {
+        let plane = |data| Plane::of(data, size, self.bytes);
+        let (prev, cur_plane, next) = (plane(prev), plane(cur), plane(next));
+
+        let mut out = cur.to_vec();
+
+        for y in 0..size.height {
+            // Lines of the kept field are copied as is.
+            if (y % 2 == 0) != self.parity {
+                continue;
+            }
+
+            for x in 0..size.width {
+                let (y, x) = (y as isize, x as isize);
+                let value = match self.mode {
+                    Deinterlace::Yadif => yadif(&prev, &cur_plane, &next, y, x, self.parity),
+                    Deinterlace::Bwdif => bwdif(&prev, &cur_plane, &next, y, x, self.parity),
+                }
+                .clamp(0, self.max_value);
+
+                let sample = y as usize * size.width + x as usize;
+                if self.bytes == 2 {
+                    out[sample * 2..sample * 2 + 2].copy_from_slice(&(value as u16).to_le_bytes());
+                } else {
+                    out[sample] = value as u8;
+                }
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ffms_segmenter::yuv4mpeg::ColorRange;
+    use y4m::{Colorspace, Ratio};
+
+    fn format() -> SegmentFormat {
+        SegmentFormat {
+            width: 8,
+            height: 8,
+            framerate: Ratio { num: 25, den: 1 },
+            colorspace: Colorspace::C420,
+            interlacing: Interlacing::TopFieldFirst,
+            color_range: ColorRange::Limited,
+            color: None,
+            sar: None,
+        }
+    }
+
+    fn frame(luma: impl Fn(usize) -> u8) -> DecodedFrame {
+        DecodedFrame {
+            planes: [(0..64).map(luma).collect(), vec![128; 16], vec![128; 16]],
+            alpha: None,
+        }
+    }
+
+    #[test]
+    fn static_content_is_preserved() {
+        for mode in [Deinterlace::Yadif, Deinterlace::Bwdif] {
+            let mut deinterlacer = Deinterlacer::new(mode, &format());
+            let still = || frame(|i| (i % 8 * 20) as u8);
+
+            assert!(deinterlacer.push(0, still()).is_none());
+            let (number, filtered) = deinterlacer.push(1, still()).unwrap();
+            assert_eq!(number, 0);
+            assert_eq!(filtered.planes, still().planes);
+            assert_eq!(deinterlacer.flush().unwrap().0, 1);
+        }
+    }
+
+    #[test]
+    fn moving_field_is_interpolated() {
+        // The bottom field of the middle frame belongs to a different
+        // picture, which shows up as combing against its neighbours.
+        let mut deinterlacer = Deinterlacer::new(Deinterlace::Yadif, &format());
+        let combed = frame(|i| if (i / 8) % 2 == 0 { 100 } else { 200 });
+
+        deinterlacer.push(0, frame(|_| 100));
+        deinterlacer.push(1, combed);
+        let (_, filtered) = deinterlacer.push(2, frame(|_| 100)).unwrap();
+
+        assert!(filtered.planes[0].iter().all(|&sample| sample == 100));
+    }
+}
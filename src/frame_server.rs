@@ -0,0 +1,283 @@
+use crate::Source;
+use ffms2::frame::Frame;
+use ffms_segmenter::log;
+use ffms_segmenter::prefetch::DecodedFrame;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+const OK: u8 = 0;
+const ERROR: u8 = 1;
+/// Set on a requested frame number to receive the planes in shared memory.
+const SHARED: u32 = 1 << 31;
+
+/// Where replies go. Shared memory replies pass a file descriptor along
+/// with their bytes, which needs the socket itself.
+trait Reply: Write {
+    fn send_with_fd(&mut self, bytes: &[u8], fd: &File) -> io::Result<()>;
+}
+
+fn write_block(writer: &mut impl Write, block: &[u8]) -> io::Result<()> {
+    writer.write_all(&(block.len() as u32).to_le_bytes())?;
+    writer.write_all(block)
+}
+
+/// A memory file holding the planes of `frame` back to back.
+#[cfg(target_os = "linux")]
+fn shared_memory(frame: &DecodedFrame) -> io::Result<File> {
+    use std::os::fd::FromRawFd;
+
+    let fd = unsafe { libc::memfd_create(c"ffms-segmenter-frame".as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    for plane in &frame.planes {
+        file.write_all(plane)?;
+    }
+
+    Ok(file)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn shared_memory(_frame: &DecodedFrame) -> io::Result<File> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Shared memory frames need Linux",
+    ))
+}
+
+/// Answers the requests of one client until it disconnects. `properties`
+/// goes out first as a length-prefixed message. Each request is a little
+/// endian `u32` frame number, answered with a status byte: 0 followed by
+/// the three planes of the frame, each a `u32` byte count and the samples,
+/// or 1 followed by a `u32` byte count and an error message. With the
+/// `SHARED` bit set, a 0 is followed by the three byte counts only and
+/// comes with the descriptor of a memory file holding the planes.
+fn serve_client(
+    mut reader: impl Read,
+    mut writer: impl Reply,
+    properties: &str,
+    mut decode: impl FnMut(usize) -> io::Result<DecodedFrame>,
+) -> io::Result<()> {
+    write_block(&mut writer, properties.as_bytes())?;
+    writer.flush()?;
+
+    loop {
+        let mut request = [0; 4];
+        match reader.read_exact(&mut request) {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            result => result?,
+        }
+
+        let request = u32::from_le_bytes(request);
+        let shared = request & SHARED != 0;
+
+        match decode((request & !SHARED) as usize) {
+            Ok(frame) if !shared => {
+                writer.write_all(&[OK])?;
+                for plane in &frame.planes {
+                    write_block(&mut writer, plane)?;
+                }
+            }
+            Ok(frame) => match shared_memory(&frame) {
+                Ok(memory) => {
+                    let mut reply = vec![OK];
+                    for plane in &frame.planes {
+                        reply.extend((plane.len() as u32).to_le_bytes());
+                    }
+                    writer.send_with_fd(&reply, &memory)?;
+                    continue;
+                }
+                Err(err) => {
+                    writer.write_all(&[ERROR])?;
+                    write_block(&mut writer, err.to_string().as_bytes())?;
+                }
+            },
+            Err(err) => {
+                writer.write_all(&[ERROR])?;
+                write_block(&mut writer, err.to_string().as_bytes())?;
+            }
+        }
+        writer.flush()?;
+    }
+}
+
+fn decode(source: &mut Source, frame_number: usize) -> io::Result<DecodedFrame> {
+    if frame_number >= source.total_frames {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Frame {} is past the end of the input, which has {} frames",
+                frame_number, source.total_frames
+            ),
+        ));
+    }
+
+    let mut frame = Frame::GetFrame(&mut source.video_source, frame_number).map_err(|err| {
+        io::Error::other(format!(
+            "Failed to decode frame {}: {:?}",
+            frame_number, err
+        ))
+    })?;
+    Ok(DecodedFrame::from_frame(&mut frame))
+}
+
+#[cfg(unix)]
+struct Connection {
+    stream: std::os::unix::net::UnixStream,
+    writer: io::BufWriter<std::os::unix::net::UnixStream>,
+}
+
+#[cfg(unix)]
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(unix)]
+impl Reply for Connection {
+    /// Sends `bytes` with `fd` as `SCM_RIGHTS` ancillary data.
+    fn send_with_fd(&mut self, bytes: &[u8], fd: &File) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        self.writer.flush()?;
+
+        let mut iov = libc::iovec {
+            iov_base: bytes.as_ptr() as *mut libc::c_void,
+            iov_len: bytes.len(),
+        };
+        let space = unsafe { libc::CMSG_SPACE(std::mem::size_of::<libc::c_int>() as u32) };
+        let mut control = vec![0u8; space as usize];
+
+        let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = space as _;
+
+        unsafe {
+            let header = libc::CMSG_FIRSTHDR(&message);
+            (*header).cmsg_level = libc::SOL_SOCKET;
+            (*header).cmsg_type = libc::SCM_RIGHTS;
+            (*header).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<libc::c_int>() as u32) as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(header) as *mut libc::c_int, fd.as_raw_fd());
+        }
+
+        let sent = unsafe { libc::sendmsg(self.stream.as_raw_fd(), &message, 0) };
+        match sent {
+            sent if sent < 0 => Err(io::Error::last_os_error()),
+            sent if (sent as usize) < bytes.len() => {
+                // The descriptor went with the first bytes.
+                self.stream.write_all(&bytes[sent as usize..])
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Listens on a Unix socket at `path`, replacing a stale socket file left
+/// there.
+#[cfg(unix)]
+pub fn bind(path: &Path) -> io::Result<std::os::unix::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    std::os::unix::net::UnixListener::bind(path)
+}
+
+/// Serves decoded frames of `source` on a Unix socket at `path`, one
+/// client at a time.
+#[cfg(unix)]
+pub fn serve(source: &mut Source, path: &Path) -> io::Result<()> {
+    let listener = bind(path)?;
+    log!(Info, "Serving frames on {}", path.display());
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let reader = stream.try_clone()?;
+        let connection = Connection {
+            writer: io::BufWriter::new(stream.try_clone()?),
+            stream,
+        };
+        let properties = source.properties.clone();
+
+        if let Err(err) = serve_client(reader, connection, &properties, |frame| {
+            decode(source, frame)
+        }) {
+            log!(Warn, "Frame server client failed: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn serve(_source: &mut Source, _path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--frame-server needs a Unix platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Seek, SeekFrom};
+
+    /// Writes the contents of shared memory inline.
+    impl Reply for &mut Vec<u8> {
+        fn send_with_fd(&mut self, bytes: &[u8], fd: &File) -> io::Result<()> {
+            self.extend(bytes);
+            let mut memory = fd.try_clone()?;
+            memory.seek(SeekFrom::Start(0))?;
+            memory.read_to_end(self).map(|_| ())
+        }
+    }
+
+    fn frames(frame: usize) -> io::Result<DecodedFrame> {
+        match frame {
+            2 => Ok(DecodedFrame {
+                planes: [vec![1, 2, 3, 4, 5, 6, 7, 8], vec![9, 10], vec![11, 12]],
+                alpha: None,
+            }),
+            _ => Err(io::Error::other("past the end")),
+        }
+    }
+
+    #[test]
+    fn answers_frame_requests() {
+        let requests = [2u32.to_le_bytes(), 9u32.to_le_bytes()].concat();
+        let mut out = Vec::new();
+        serve_client(&requests[..], &mut out, "4 2 3 1 25", frames).unwrap();
+
+        let mut expected = vec![10, 0, 0, 0];
+        expected.extend(b"4 2 3 1 25");
+        expected.extend([0, 8, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8]);
+        expected.extend([2, 0, 0, 0, 9, 10, 2, 0, 0, 0, 11, 12]);
+        expected.extend([1, 12, 0, 0, 0]);
+        expected.extend(b"past the end");
+        assert_eq!(out, expected);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn shares_frame_memory() {
+        let requests = (2 | SHARED).to_le_bytes();
+        let mut out = Vec::new();
+        serve_client(&requests[..], &mut out, "", frames).unwrap();
+
+        let mut expected = vec![0, 0, 0, 0, 0, 8, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0];
+        expected.extend([1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+        assert_eq!(out, expected);
+    }
+}
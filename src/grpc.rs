@@ -0,0 +1,160 @@
+use crate::transport::{parse_segment, Client, Properties, Reply};
+use ffms_segmenter::log;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::mpsc::Receiver;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+
+mod proto {
+    tonic::include_proto!("segmenter.v1");
+}
+
+use proto::segmenter_server::{Segmenter, SegmenterServer};
+use proto::{ExtractRequest, IndexRequest, InputInfo, SegmentWritten, StatusReply, StatusRequest};
+
+fn input_info(input: u32, message: &str) -> Option<InputInfo> {
+    let properties = Properties::parse(message)?;
+
+    Some(InputInfo {
+        input,
+        width: properties.width as u32,
+        height: properties.height as u32,
+        frames: properties.frames,
+        fps_num: properties.fps_num as u32,
+        fps_den: properties.fps_den as u32,
+    })
+}
+
+/// Waits for every message of a command.
+fn collect(replies: Receiver<Reply>) -> Result<Vec<String>, Status> {
+    let mut messages = Vec::new();
+
+    for reply in replies {
+        match reply {
+            Reply::Message(message) => messages.push(message),
+            Reply::Progress(_) => {}
+            Reply::Finished => return Ok(messages),
+        }
+    }
+
+    Err(Status::aborted("Session failed"))
+}
+
+struct Service {
+    client: Client,
+    /// Inputs opened through `Index`, after the ones opened at startup.
+    opened: Mutex<Vec<InputInfo>>,
+}
+
+impl Service {
+    fn request(&self, line: String) -> Result<Receiver<Reply>, Status> {
+        self.client
+            .request(&line)
+            .map(|(_, replies)| replies)
+            .map_err(|err| match err.kind() {
+                std::io::ErrorKind::ResourceBusy => Status::resource_exhausted(err.to_string()),
+                _ => Status::unavailable(err.to_string()),
+            })
+    }
+}
+
+#[tonic::async_trait]
+impl Segmenter for Service {
+    async fn index(&self, request: Request<IndexRequest>) -> Result<Response<InputInfo>, Status> {
+        let path = request.into_inner().path;
+        let replies = self.request(format!("open {}", path))?;
+
+        let messages = tokio::task::spawn_blocking(move || collect(replies))
+            .await
+            .map_err(|err| Status::internal(err.to_string()))??;
+
+        let info = messages
+            .iter()
+            .find_map(|message| {
+                let id = message.strip_prefix("opened ")?.split_whitespace().next()?;
+                input_info(id.parse().ok()?, messages.first()?)
+            })
+            .ok_or_else(|| Status::internal(format!("Failed to open {}", path)))?;
+
+        self.opened.lock().unwrap().push(info.clone());
+
+        Ok(Response::new(info))
+    }
+
+    type ExtractStream = ReceiverStream<Result<SegmentWritten, Status>>;
+
+    async fn extract(
+        &self,
+        request: Request<ExtractRequest>,
+    ) -> Result<Response<Self::ExtractStream>, Status> {
+        let ExtractRequest { input, start, end } = request.into_inner();
+        if start >= end {
+            return Err(Status::invalid_argument("start must be before end"));
+        }
+
+        let replies = self.request(format!("{} {} {}", input, start, end))?;
+        let (sender, receiver) = mpsc::channel(16);
+
+        tokio::task::spawn_blocking(move || {
+            for reply in replies {
+                let message = match reply {
+                    Reply::Message(message) => message,
+                    Reply::Progress(_) => continue,
+                    Reply::Finished => return,
+                };
+
+                let written = parse_segment(&message)
+                    .map(|(start, path)| SegmentWritten {
+                        start,
+                        path: path.to_string(),
+                    })
+                    .ok_or_else(|| Status::failed_precondition(message.clone()));
+
+                if sender.blocking_send(written).is_err() {
+                    return;
+                }
+            }
+
+            let _ = sender.blocking_send(Err(Status::aborted("Session failed")));
+        });
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn status(&self, _: Request<StatusRequest>) -> Result<Response<StatusReply>, Status> {
+        let mut inputs: Vec<InputInfo> = self
+            .client
+            .announced()
+            .iter()
+            .enumerate()
+            .filter_map(|(id, line)| input_info(id as u32, line))
+            .collect();
+        inputs.extend(self.opened.lock().unwrap().iter().cloned());
+
+        Ok(Response::new(StatusReply {
+            inputs,
+            pending: self.client.pending() as u32,
+        }))
+    }
+}
+
+/// Serves the gRPC API on `addr` until the server fails.
+pub fn serve(addr: SocketAddr, client: Client) -> io::Result<()> {
+    let service = Service {
+        client,
+        opened: Mutex::new(Vec::new()),
+    };
+
+    log!(Info, "Serving gRPC on {}", addr);
+
+    tokio::runtime::Runtime::new()?.block_on(async {
+        tonic::transport::Server::builder()
+            .add_service(SegmenterServer::new(service))
+            .serve(addr)
+            .await
+            .map_err(io::Error::other)
+    })
+}
@@ -0,0 +1,118 @@
+use ffms2::video::VideoProperties;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// HDR metadata of a stream, written next to each segment with
+/// `--hdr-metadata` because y4m has nowhere to carry it.
+///
+/// Only the static SMPTE ST 2086 mastering display and content light
+/// level values are available. The per-frame HDR10+ and Dolby Vision
+/// metadata are not exposed by the FFMS2 bindings, so encoders need to take
+/// those from the source.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HdrMetadata {
+    pub primaries: Option<Primaries>,
+    /// Minimum and maximum luminance in cd/m².
+    pub luminance: Option<(f64, f64)>,
+    /// MaxCLL and MaxFALL in cd/m².
+    pub content_light: Option<(u32, u32)>,
+}
+
+/// Mastering display chromaticities.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Primaries {
+    /// x of red, green and blue.
+    pub x: [f64; 3],
+    /// y of red, green and blue.
+    pub y: [f64; 3],
+    pub white_point: (f64, f64),
+}
+
+impl HdrMetadata {
+    pub fn from_properties(properties: &VideoProperties) -> Self {
+        HdrMetadata {
+            primaries: (properties.HasMasteringDisplayPrimaries != 0).then(|| Primaries {
+                x: properties.MasteringDisplayPrimariesX,
+                y: properties.MasteringDisplayPrimariesY,
+                white_point: (
+                    properties.MasteringDisplayWhitePointX,
+                    properties.MasteringDisplayWhitePointY,
+                ),
+            }),
+            luminance: (properties.HasMasteringDisplayLuminance != 0).then(|| {
+                (
+                    properties.MasteringDisplayMinLuminance,
+                    properties.MasteringDisplayMaxLuminance,
+                )
+            }),
+            content_light: (properties.HasContentLightLevel != 0).then(|| {
+                (
+                    properties.ContentLightLevelMax,
+                    properties.ContentLightLevelAverage,
+                )
+            }),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == HdrMetadata::default()
+    }
+
+    pub fn to_json(&self) -> String {
+        let primaries = match self.primaries {
+            Some(Primaries { x, y, white_point }) => format!(
+                "{{\"red\":[{},{}],\"green\":[{},{}],\"blue\":[{},{}],\"white_point\":[{},{}]}}",
+                x[0], y[0], x[1], y[1], x[2], y[2], white_point.0, white_point.1
+            ),
+            None => "null".to_string(),
+        };
+        let luminance = match self.luminance {
+            Some((min, max)) => format!("{{\"min\":{},\"max\":{}}}", min, max),
+            None => "null".to_string(),
+        };
+        let content_light = match self.content_light {
+            Some((max_cll, max_fall)) => {
+                format!("{{\"max_cll\":{},\"max_fall\":{}}}", max_cll, max_fall)
+            }
+            None => "null".to_string(),
+        };
+
+        format!(
+            "{{\"mastering_display\":{{\"primaries\":{},\"luminance\":{}}},\
+             \"content_light_level\":{},\"dynamic\":null}}",
+            primaries, luminance, content_light
+        )
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_json() + "\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_static_metadata_as_json() {
+        let metadata = HdrMetadata {
+            primaries: Some(Primaries {
+                x: [0.708, 0.17, 0.131],
+                y: [0.292, 0.797, 0.046],
+                white_point: (0.3127, 0.329),
+            }),
+            luminance: Some((0.005, 1000.0)),
+            content_light: None,
+        };
+
+        assert_eq!(
+            metadata.to_json(),
+            "{\"mastering_display\":{\"primaries\":{\"red\":[0.708,0.292],\
+             \"green\":[0.17,0.797],\"blue\":[0.131,0.046],\"white_point\":[0.3127,0.329]},\
+             \"luminance\":{\"min\":0.005,\"max\":1000}},\"content_light_level\":null,\
+             \"dynamic\":null}"
+        );
+        assert!(HdrMetadata::default().is_empty());
+    }
+}
@@ -0,0 +1,157 @@
+use crate::output::shell;
+use ffms_segmenter::log;
+use std::io;
+use std::process::{Child, ExitStatus, Stdio};
+
+/// A `--post-cmd` command still running for a segment.
+struct Running {
+    command: String,
+    child: Child,
+    start: usize,
+    path: String,
+}
+
+/// Runs the `--post-cmd` command after each segment, with at most `jobs` of
+/// them at once. Commands run in the background; the ones that failed are
+/// reported as `<start> <path> post-cmd failed: <reason>` lines once they
+/// are found to have exited.
+pub struct Hooks {
+    template: String,
+    jobs: usize,
+    running: Vec<Running>,
+}
+
+impl Hooks {
+    /// `template` is run by the shell with `{path}` replaced by the
+    /// segment's path, and `{start}` and `{end}` by its frame range.
+    pub fn new(template: &str, jobs: usize) -> Self {
+        Hooks {
+            template: template.to_string(),
+            jobs: jobs.max(1),
+            running: Vec::new(),
+        }
+    }
+
+    /// Starts the command for the segment `start..end` at `path`, first
+    /// waiting for one to exit if `jobs` are running. Returns the failures
+    /// of the commands that have exited.
+    pub fn run(&mut self, path: &str, start: usize, end: usize) -> io::Result<Vec<String>> {
+        let mut failures = self.reap(false);
+        while self.running.len() >= self.jobs {
+            failures.extend(self.reap(true));
+        }
+
+        let command = self
+            .template
+            .replace("{path}", path)
+            .replace("{start}", &start.to_string())
+            .replace("{end}", &end.to_string());
+        let child = shell(&command)
+            .stdin(Stdio::null())
+            .spawn()
+            .map_err(|err| io::Error::other(format!("Failed to run `{}`: {}", command, err)))?;
+
+        self.running.push(Running {
+            command,
+            child,
+            start,
+            path: path.to_string(),
+        });
+
+        Ok(failures)
+    }
+
+    /// Waits for every running command, returning the failures.
+    pub fn finish(&mut self) -> Vec<String> {
+        let mut failures = Vec::new();
+        while !self.running.is_empty() {
+            failures.extend(self.reap(true));
+        }
+        failures
+    }
+
+    /// Collects the commands that have exited, or with `wait` at least one,
+    /// returning the failures among them.
+    fn reap(&mut self, wait: bool) -> Vec<String> {
+        let mut failures = Vec::new();
+        let mut exited = 0;
+        let mut index = 0;
+
+        while index < self.running.len() {
+            let status = match self.running[index].child.try_wait() {
+                Ok(None) => {
+                    index += 1;
+                    continue;
+                }
+                Ok(Some(status)) => Ok(status),
+                Err(err) => Err(err),
+            };
+            let running = self.running.remove(index);
+            exited += 1;
+            failures.extend(check(&running, status));
+        }
+
+        if wait && exited == 0 && !self.running.is_empty() {
+            let mut running = self.running.remove(0);
+            let status = running.child.wait();
+            failures.extend(check(&running, status));
+        }
+
+        failures
+    }
+}
+
+/// Logs how `running` exited, returning its failure line if it failed.
+fn check(running: &Running, status: io::Result<ExitStatus>) -> Option<String> {
+    let reason = match status {
+        Ok(status) => {
+            log!(
+                Info,
+                "Post command for {} exited with {}",
+                running.path,
+                status
+            );
+            if status.success() {
+                return None;
+            }
+            status.to_string()
+        }
+        Err(err) => err.to_string(),
+    };
+
+    log!(Error, "`{}` failed: {}", running.command, reason);
+    Some(format!(
+        "{} {} post-cmd failed: {}",
+        running.start, running.path, reason
+    ))
+}
+
+impl Drop for Hooks {
+    /// Commands still running when the session ends are waited for rather
+    /// than left behind.
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_failed_commands() {
+        let mut hooks = Hooks::new("test {start}-{end} = 0-24 && test {path} = out/0-24.y4m", 2);
+        assert!(hooks.run("out/0-24.y4m", 0, 24).unwrap().is_empty());
+        assert!(hooks.run("out/24-48.y4m", 24, 48).unwrap().is_empty());
+
+        // The third waits for a slot, so the failure shows up here or at
+        // the end.
+        let mut failures = hooks.run("out/48-72.y4m", 48, 72).unwrap();
+        failures.extend(hooks.finish());
+        assert_eq!(failures.len(), 2);
+        assert!(failures
+            .iter()
+            .any(|line| line.starts_with("24 out/24-48.y4m post-cmd failed: ")));
+        assert!(hooks.running.is_empty());
+    }
+}
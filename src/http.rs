@@ -0,0 +1,460 @@
+use crate::metrics;
+use crate::transport::{parse_segment, Client, Properties, Reply};
+use ffms_segmenter::log;
+use ffms_segmenter::log::json_string;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Request bodies are small JSON objects; anything larger is refused.
+const MAX_BODY_LEN: usize = 64 * 1024;
+
+enum JobState {
+    Running,
+    Done,
+    Cancelled,
+    Failed(String),
+}
+
+/// A `POST /segments` request and the segments written for it so far.
+struct Job {
+    /// Id of the request for `Client::cancel`.
+    request: u64,
+    input: u64,
+    start: u64,
+    end: u64,
+    state: JobState,
+    /// Last frame written.
+    frame: Option<u64>,
+    segments: Vec<(u64, String)>,
+}
+
+type Jobs = Arc<Mutex<Vec<Job>>>;
+
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+struct HttpResponse {
+    status: &'static str,
+    content_type: &'static str,
+    body: Body,
+}
+
+enum Body {
+    Text(String),
+    File(File, u64),
+}
+
+impl HttpResponse {
+    fn json(status: &'static str, body: String) -> Self {
+        HttpResponse {
+            status,
+            content_type: "application/json",
+            body: Body::Text(body),
+        }
+    }
+
+    fn error(status: &'static str, message: &str) -> Self {
+        Self::json(status, format!("{{\"error\":{}}}", json_string(message)))
+    }
+
+    fn metrics() -> Self {
+        HttpResponse {
+            status: "200 OK",
+            content_type: "text/plain; version=0.0.4",
+            body: Body::Text(metrics::render()),
+        }
+    }
+}
+
+/// Returns the non-negative integer member `key` of a flat JSON object.
+/// Request bodies only ever carry numbers, so this is all the parsing
+/// needed.
+fn json_number(body: &str, key: &str) -> Option<u64> {
+    let quoted = format!("\"{}\"", key);
+    let after_key = &body[body.find(&quoted)? + quoted.len()..];
+    let value = after_key.trim_start().strip_prefix(':')?.trim_start();
+
+    let digits = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    value[..digits].parse().ok()
+}
+
+pub fn read_request(reader: &mut impl BufRead) -> io::Result<HttpRequest> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Malformed request line",
+            ))
+        }
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    let mut request = HttpRequest {
+        method,
+        path,
+        headers,
+        body: Vec::new(),
+    };
+
+    let content_length = match request.header("content-length") {
+        Some(value) => value
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid Content-Length"))?,
+        None => 0,
+    };
+
+    if content_length > MAX_BODY_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Request body is too large",
+        ));
+    }
+
+    request.body = vec![0; content_length];
+    reader.read_exact(&mut request.body)?;
+
+    Ok(request)
+}
+
+fn write_response(stream: &mut impl Write, response: HttpResponse) -> io::Result<()> {
+    let length = match response.body {
+        Body::Text(ref text) => text.len() as u64,
+        Body::File(_, length) => length,
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status, response.content_type, length
+    )?;
+
+    match response.body {
+        Body::Text(text) => stream.write_all(text.as_bytes())?,
+        Body::File(mut file, _) => {
+            io::copy(&mut file, stream)?;
+        }
+    }
+
+    stream.flush()
+}
+
+fn input_json(id: usize, properties: &Properties) -> String {
+    format!(
+        "{{\"input\":{},\"width\":{},\"height\":{},\"frames\":{},\"fps_num\":{},\"fps_den\":{}}}",
+        id,
+        properties.width,
+        properties.height,
+        properties.frames,
+        properties.fps_num,
+        properties.fps_den
+    )
+}
+
+fn job_json(id: usize, job: &Job) -> String {
+    let (state, error) = match job.state {
+        JobState::Running => ("running", String::new()),
+        JobState::Done => ("done", String::new()),
+        JobState::Cancelled => ("cancelled", String::new()),
+        JobState::Failed(ref message) => ("failed", format!(",\"error\":{}", json_string(message))),
+    };
+    let segments: Vec<String> = job
+        .segments
+        .iter()
+        .map(|(start, path)| format!("{{\"start\":{},\"path\":{}}}", start, json_string(path)))
+        .collect();
+    let frame = match job.frame {
+        Some(frame) => frame.to_string(),
+        None => "null".to_string(),
+    };
+
+    format!(
+        "{{\"id\":{},\"input\":{},\"start\":{},\"end\":{},\"status\":\"{}\",\"frame\":{},\"segments\":[{}]{}}}",
+        id,
+        job.input,
+        job.start,
+        job.end,
+        state,
+        frame,
+        segments.join(","),
+        error
+    )
+}
+
+/// Updates `jobs[id]` with the responses to its request until it finishes.
+fn track_job(jobs: Jobs, id: usize, replies: std::sync::mpsc::Receiver<Reply>) {
+    let mut finished = false;
+
+    for reply in replies {
+        let mut jobs = jobs.lock().unwrap();
+        let job = &mut jobs[id];
+
+        match reply {
+            Reply::Message(message) => match parse_segment(&message) {
+                Some((start, path)) => job.segments.push((start, path.to_string())),
+                // Sent for a queued and a running request.
+                None if message.starts_with("cancelled ") || message.ends_with(" cancelled") => {
+                    job.state = JobState::Cancelled
+                }
+                None => job.state = JobState::Failed(message),
+            },
+            Reply::Progress(frame) => job.frame = Some(frame as u64),
+            Reply::Finished => {
+                if let JobState::Running = job.state {
+                    job.state = JobState::Done;
+                }
+                finished = true;
+            }
+        }
+    }
+
+    if !finished {
+        jobs.lock().unwrap()[id].state = JobState::Failed("Session failed".to_string());
+    }
+}
+
+fn create_job(client: &Client, jobs: &Jobs, body: &[u8]) -> HttpResponse {
+    let body = String::from_utf8_lossy(body);
+    let (start, end) = match (json_number(&body, "start"), json_number(&body, "end")) {
+        (Some(start), Some(end)) if start < end => (start, end),
+        _ => {
+            return HttpResponse::error(
+                "400 Bad Request",
+                "Expected {\"start\":N,\"end\":M} with start before end",
+            )
+        }
+    };
+    let input = json_number(&body, "input").unwrap_or(0);
+    let mut line = format!("{} {} {}", input, start, end);
+    if let Some(priority) = json_number(&body, "priority") {
+        line.push_str(&format!(" priority={}", priority));
+    }
+
+    let (request, replies) = match client.request(&line) {
+        Ok(submitted) => submitted,
+        Err(err) if err.kind() == io::ErrorKind::ResourceBusy => {
+            return HttpResponse::error("429 Too Many Requests", &err.to_string())
+        }
+        Err(err) => return HttpResponse::error("503 Service Unavailable", &err.to_string()),
+    };
+
+    let id = {
+        let mut jobs = jobs.lock().unwrap();
+        jobs.push(Job {
+            request,
+            input,
+            start,
+            end,
+            state: JobState::Running,
+            frame: None,
+            segments: Vec::new(),
+        });
+        jobs.len() - 1
+    };
+
+    let jobs = jobs.clone();
+    thread::spawn(move || track_job(jobs, id, replies));
+
+    HttpResponse::json("202 Accepted", format!("{{\"id\":{}}}", id))
+}
+
+/// Serves `GET /segments/{id}/files/{n}`, the `n`th segment of a job.
+fn download(jobs: &Jobs, id: usize, n: &str) -> HttpResponse {
+    let path = {
+        let jobs = jobs.lock().unwrap();
+        let segment = n
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| jobs[id].segments.get(n));
+        match segment {
+            Some((_, path)) => path.clone(),
+            None => return HttpResponse::error("404 Not Found", "No such segment"),
+        }
+    };
+
+    match File::open(&path).and_then(|file| Ok((file.metadata()?.len(), file))) {
+        Ok((length, file)) => HttpResponse {
+            status: "200 OK",
+            content_type: "video/x-yuv4mpeg",
+            body: Body::File(file, length),
+        },
+        Err(err) => HttpResponse::error("500 Internal Server Error", &err.to_string()),
+    }
+}
+
+fn route(client: &Client, jobs: &Jobs, request: &HttpRequest) -> HttpResponse {
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+
+    match (request.method.as_str(), &segments[..]) {
+        ("GET", ["info"]) => {
+            let inputs: Vec<String> = client
+                .announced()
+                .iter()
+                .filter_map(|message| Properties::parse(message))
+                .enumerate()
+                .map(|(id, properties)| input_json(id, &properties))
+                .collect();
+
+            HttpResponse::json(
+                "200 OK",
+                format!(
+                    "{{\"inputs\":[{}],\"pending\":{}}}",
+                    inputs.join(","),
+                    client.pending()
+                ),
+            )
+        }
+        ("GET", ["metrics"]) => HttpResponse::metrics(),
+        ("GET", ["status"]) => HttpResponse::json("200 OK", client.status()),
+        ("POST", ["segments"]) => create_job(client, jobs, &request.body),
+        ("GET", ["segments", id, rest @ ..]) => {
+            let id = match id.parse::<usize>() {
+                Ok(id) if id < jobs.lock().unwrap().len() => id,
+                _ => return HttpResponse::error("404 Not Found", "No such job"),
+            };
+
+            match rest {
+                [] => HttpResponse::json("200 OK", job_json(id, &jobs.lock().unwrap()[id])),
+                ["files", n] => download(jobs, id, n),
+                _ => HttpResponse::error("404 Not Found", "Not found"),
+            }
+        }
+        ("DELETE", ["segments", id]) => {
+            let request = match id.parse::<usize>() {
+                Ok(id) => jobs.lock().unwrap().get(id).map(|job| job.request),
+                Err(_) => None,
+            };
+
+            match request {
+                Some(request) if client.cancel(request) => {
+                    HttpResponse::json("202 Accepted", format!("{{\"id\":{}}}", id))
+                }
+                Some(_) => HttpResponse::error("409 Conflict", "Job has already finished"),
+                None => HttpResponse::error("404 Not Found", "No such job"),
+            }
+        }
+        _ => HttpResponse::error("404 Not Found", "Not found"),
+    }
+}
+
+fn handle_connection(client: &Client, jobs: &Jobs, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut stream = stream;
+
+    let response = match read_request(&mut reader) {
+        Ok(request) => route(client, jobs, &request),
+        Err(err) => HttpResponse::error("400 Bad Request", &err.to_string()),
+    };
+
+    write_response(&mut stream, response)
+}
+
+/// Accepts connections on `listener`, handling each on its own thread.
+pub fn serve(listener: TcpListener, client: Client) {
+    let jobs: Jobs = Arc::new(Mutex::new(Vec::new()));
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                log!(Error, "HTTP accept failed: {}", err);
+                continue;
+            }
+        };
+
+        let client = client.clone();
+        let jobs = jobs.clone();
+        thread::spawn(move || {
+            if let Err(err) = handle_connection(&client, &jobs, stream) {
+                metrics::record_error();
+                log!(Error, "HTTP connection failed: {}", err);
+            }
+        });
+    }
+}
+
+/// Serves only `GET /metrics` on `listener`, for `--metrics` next to the
+/// other transports.
+pub fn serve_metrics(listener: TcpListener) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                log!(Error, "Metrics accept failed: {}", err);
+                continue;
+            }
+        };
+
+        thread::spawn(move || {
+            let mut reader = BufReader::new(stream.try_clone()?);
+            let response = match read_request(&mut reader) {
+                Ok(request) if request.method == "GET" && request.path == "/metrics" => {
+                    HttpResponse::metrics()
+                }
+                Ok(_) => HttpResponse::error("404 Not Found", "Not found"),
+                Err(err) => HttpResponse::error("400 Bad Request", &err.to_string()),
+            };
+
+            write_response(&mut &stream, response)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_numbers_from_json_bodies() {
+        let body = r#"{ "start" : 24, "end":48 }"#;
+
+        assert_eq!(json_number(body, "start"), Some(24));
+        assert_eq!(json_number(body, "end"), Some(48));
+        assert_eq!(json_number(body, "input"), None);
+        assert_eq!(json_number(r#"{"start":-1}"#, "start"), None);
+    }
+
+    #[test]
+    fn reads_request_with_body() {
+        let raw = b"POST /segments HTTP/1.1\r\nHost: x\r\nContent-Length: 9\r\n\r\n{\"a\":1}\r\n";
+        let request = read_request(&mut &raw[..]).unwrap();
+
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/segments");
+        assert_eq!(request.header("host"), Some("x"));
+        assert_eq!(request.body, b"{\"a\":1}\r\n");
+    }
+}
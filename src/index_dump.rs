@@ -0,0 +1,133 @@
+use crate::track_type_name;
+use ffms2::index::Index;
+use ffms2::track::{Track, TrackType};
+use ffms_segmenter::log::json_string;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// One track of the index as `--dump-index` writes it. Frame data is only
+/// kept for video tracks, the only ones FFMS2 has it for.
+pub struct TrackDump {
+    pub id: usize,
+    pub kind: &'static str,
+    /// PTS are in units of `num / den` milliseconds.
+    pub time_base: (i64, i64),
+    pub frames: usize,
+    /// The PTS of every frame, in presentation order, after FFMS2 has
+    /// filled in the ones the container left out.
+    pub pts: Vec<i64>,
+    /// The PTS of every frame as the container stored them.
+    pub original_pts: Vec<i64>,
+    pub keyframes: Vec<usize>,
+}
+
+impl TrackDump {
+    pub fn read(index: &Index, id: usize) -> Self {
+        let track = Track::TrackFromIndex(index, id);
+        let time_base = track.TimeBase();
+        let frames = track.NumFrames();
+        let video = matches!(track.TrackType(), TrackType::TYPE_VIDEO);
+
+        let infos: Vec<_> = match video {
+            true => (0..frames).map(|n| track.FrameInfo(n)).collect(),
+            false => Vec::new(),
+        };
+
+        TrackDump {
+            id,
+            kind: track_type_name(track.TrackType()),
+            time_base: (time_base.Num, time_base.Den),
+            frames,
+            pts: infos.iter().map(|info| info.PTS).collect(),
+            original_pts: infos.iter().map(|info| info.OriginalPTS).collect(),
+            keyframes: infos
+                .iter()
+                .enumerate()
+                .filter(|(_, info)| info.KeyFrame() != 0)
+                .map(|(n, _)| n)
+                .collect(),
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let list = |values: &mut dyn Iterator<Item = String>| values.collect::<Vec<_>>().join(",");
+
+        format!(
+            "{{\"id\":{},\"type\":\"{}\",\"time_base\":{{\"num\":{},\"den\":{}}},\"frames\":{},\
+             \"keyframes\":[{}],\"pts\":[{}],\"original_pts\":[{}]}}",
+            self.id,
+            self.kind,
+            self.time_base.0,
+            self.time_base.1,
+            self.frames,
+            list(&mut self.keyframes.iter().map(usize::to_string)),
+            list(&mut self.pts.iter().map(i64::to_string)),
+            list(&mut self.original_pts.iter().map(i64::to_string))
+        )
+    }
+}
+
+/// The `--dump-index` document for the index of `input`, whose video is
+/// track `video_track`. FFMS2 does not keep decode timestamps, so there
+/// are none.
+pub fn to_json(input: &Path, video_track: usize, tracks: &[TrackDump]) -> String {
+    let tracks: Vec<String> = tracks.iter().map(TrackDump::to_json).collect();
+
+    format!(
+        "{{\"input\":{},\"video_track\":{},\"tracks\":[{}]}}\n",
+        json_string(&input.to_string_lossy()),
+        video_track,
+        tracks.join(",")
+    )
+}
+
+/// Writes the `--dump-index` document of `index` to `path`.
+pub fn write(index: &Index, input: &Path, video_track: usize, path: &Path) -> io::Result<()> {
+    let tracks: Vec<TrackDump> = (0..index.NumTracks())
+        .map(|id| TrackDump::read(index, id))
+        .collect();
+
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(to_json(input, video_track, &tracks).as_bytes())?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_tracks_as_json() {
+        let tracks = [
+            TrackDump {
+                id: 0,
+                kind: "video",
+                time_base: (1, 1),
+                frames: 3,
+                pts: vec![0, 42, 83],
+                original_pts: vec![0, -1, 83],
+                keyframes: vec![0],
+            },
+            TrackDump {
+                id: 1,
+                kind: "audio",
+                time_base: (1, 48),
+                frames: 0,
+                pts: Vec::new(),
+                original_pts: Vec::new(),
+                keyframes: Vec::new(),
+            },
+        ];
+
+        assert_eq!(
+            to_json(Path::new("in \"a\".mkv"), 0, &tracks),
+            concat!(
+                r#"{"input":"in \"a\".mkv","video_track":0,"tracks":["#,
+                r#"{"id":0,"type":"video","time_base":{"num":1,"den":1},"frames":3,"keyframes":[0],"pts":[0,42,83],"original_pts":[0,-1,83]},"#,
+                r#"{"id":1,"type":"audio","time_base":{"num":1,"den":48},"frames":0,"keyframes":[],"pts":[],"original_pts":[]}"#,
+                "]}\n"
+            )
+        );
+    }
+}
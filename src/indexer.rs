@@ -0,0 +1,276 @@
+use ffms2::index::{Index, Indexer};
+use ffms2::track::TrackType;
+use ffms2::IndexErrorHandling;
+use std::io;
+use std::path::Path;
+
+/// The indexer of an input, opened with the `--source-opt`, `--enable-drefs`
+/// and `--use-absolute-path` demuxer options when there are any. The `ffms2`
+/// bindings only wrap `FFMS_CreateIndexer`, which opens files with FFmpeg's
+/// defaults, so those go through `FFMS_CreateIndexer2` in `ffms2-sys`
+/// instead.
+pub enum SourceIndexer {
+    Plain(Indexer),
+    #[cfg(feature = "demuxer-options")]
+    Options(options::Indexer),
+}
+
+impl SourceIndexer {
+    pub fn open(path: &Path, options: &[(String, String)]) -> io::Result<Self> {
+        if options.is_empty() {
+            return Indexer::new(path)
+                .map(SourceIndexer::Plain)
+                .map_err(|err| io::Error::other(format!("{:?}", err)));
+        }
+
+        #[cfg(feature = "demuxer-options")]
+        return options::Indexer::new(path, options).map(SourceIndexer::Options);
+        #[cfg(not(feature = "demuxer-options"))]
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Built without the demuxer-options feature, --source-opt, --enable-drefs and \
+             --use-absolute-path are unavailable",
+        ))
+    }
+
+    /// Indexes the tracks of `track_type` too, or not.
+    pub fn index_tracks(&self, track_type: TrackType, index: bool) {
+        match self {
+            SourceIndexer::Plain(indexer) => {
+                indexer.TrackTypeIndexSettings(track_type, usize::from(index))
+            }
+            #[cfg(feature = "demuxer-options")]
+            SourceIndexer::Options(indexer) => indexer.index_tracks(track_type, index),
+        }
+    }
+
+    /// Calls `callback` with the progress of indexing and `value`, as
+    /// `Indexer::ProgressCallback` does.
+    pub fn progress_callback<'a, F>(&'a mut self, callback: F, value: &'a mut usize)
+    where
+        F: FnMut(usize, usize, Option<&mut usize>) -> usize + 'static,
+    {
+        match self {
+            SourceIndexer::Plain(indexer) => indexer.ProgressCallback(callback, value),
+            #[cfg(feature = "demuxer-options")]
+            SourceIndexer::Options(indexer) => indexer.progress_callback(callback, value),
+        }
+    }
+
+    pub fn index(self, errors: IndexErrorHandling) -> io::Result<Index> {
+        match self {
+            SourceIndexer::Plain(indexer) => indexer
+                .DoIndexing2(errors)
+                .map_err(|err| io::Error::other(format!("{:?}", err))),
+            #[cfg(feature = "demuxer-options")]
+            SourceIndexer::Options(indexer) => indexer.index(errors),
+        }
+    }
+}
+
+/// Parses a `--source-opt key=value` demuxer option.
+pub fn parse_option(option: &str) -> Result<(String, String), String> {
+    match option.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("Expected key=value, not {}", option)),
+    }
+}
+
+#[cfg(feature = "demuxer-options")]
+mod options {
+    use ffms2::index::Index;
+    use ffms2::track::TrackType;
+    use ffms2::IndexErrorHandling;
+    use ffms2_sys::*;
+    use std::ffi::{CStr, CString};
+    use std::io;
+    use std::os::raw::{c_char, c_int, c_void};
+    use std::path::Path;
+
+    /// An `FFMS_ErrorInfo` with a buffer for its message.
+    struct ErrorInfo {
+        buffer: Vec<c_char>,
+        info: FFMS_ErrorInfo,
+    }
+
+    impl ErrorInfo {
+        fn new() -> Self {
+            let mut buffer = vec![0; 1024];
+            let info = FFMS_ErrorInfo {
+                ErrorType: 0,
+                SubType: 0,
+                BufferSize: buffer.len() as c_int,
+                Buffer: buffer.as_mut_ptr(),
+            };
+            ErrorInfo { buffer, info }
+        }
+
+        fn error(&self) -> io::Error {
+            let message = unsafe { CStr::from_ptr(self.buffer.as_ptr()) };
+            io::Error::other(message.to_string_lossy().into_owned())
+        }
+    }
+
+    type Callback = Box<dyn FnMut(usize, usize, Option<&mut usize>) -> usize>;
+
+    struct Progress {
+        callback: Callback,
+        value: *mut usize,
+    }
+
+    unsafe extern "C" fn report_progress(current: i64, total: i64, private: *mut c_void) -> c_int {
+        let progress = &mut *(private as *mut Progress);
+        let report = std::panic::AssertUnwindSafe(|| {
+            (progress.callback)(current as usize, total as usize, progress.value.as_mut())
+        });
+        // Unwinding into FFMS2 is undefined.
+        match std::panic::catch_unwind(report) {
+            Ok(cancel) => cancel as c_int,
+            Err(_) => std::process::abort(),
+        }
+    }
+
+    pub struct Indexer {
+        indexer: *mut FFMS_Indexer,
+        progress: Option<Box<Progress>>,
+    }
+
+    impl Indexer {
+        pub fn new(path: &Path, options: &[(String, String)]) -> io::Result<Self> {
+            let source = CString::new(path.to_string_lossy().as_bytes())?;
+            let strings = options
+                .iter()
+                .map(|(key, value)| {
+                    Ok((CString::new(key.as_str())?, CString::new(value.as_str())?))
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            let pairs: Vec<FFMS_KeyValuePair> = strings
+                .iter()
+                .map(|(key, value)| FFMS_KeyValuePair {
+                    Key: key.as_ptr(),
+                    Value: value.as_ptr(),
+                })
+                .collect();
+
+            let mut error = ErrorInfo::new();
+            let indexer = unsafe {
+                FFMS_CreateIndexer2(
+                    source.as_ptr(),
+                    pairs.as_ptr(),
+                    pairs.len() as c_int,
+                    &mut error.info,
+                )
+            };
+            if indexer.is_null() {
+                return Err(error.error());
+            }
+
+            Ok(Indexer {
+                indexer,
+                progress: None,
+            })
+        }
+
+        pub fn index_tracks(&self, track_type: TrackType, index: bool) {
+            let track_type = match track_type {
+                TrackType::TYPE_VIDEO => FFMS_TrackType::FFMS_TYPE_VIDEO,
+                TrackType::TYPE_AUDIO => FFMS_TrackType::FFMS_TYPE_AUDIO,
+                TrackType::TYPE_DATA => FFMS_TrackType::FFMS_TYPE_DATA,
+                TrackType::TYPE_SUBTITLE => FFMS_TrackType::FFMS_TYPE_SUBTITLE,
+                TrackType::TYPE_ATTACHMENT => FFMS_TrackType::FFMS_TYPE_ATTACHMENT,
+                TrackType::TYPE_UNKNOWN => FFMS_TrackType::FFMS_TYPE_UNKNOWN,
+            };
+            unsafe {
+                FFMS_TrackTypeIndexSettings(
+                    self.indexer,
+                    track_type as c_int,
+                    c_int::from(index),
+                    0,
+                )
+            };
+        }
+
+        pub fn progress_callback<F>(&mut self, callback: F, value: &mut usize)
+        where
+            F: FnMut(usize, usize, Option<&mut usize>) -> usize + 'static,
+        {
+            let mut progress = Box::new(Progress {
+                callback: Box::new(callback),
+                value,
+            });
+            unsafe {
+                FFMS_SetProgressCallback(
+                    self.indexer,
+                    Some(report_progress),
+                    &mut *progress as *mut Progress as *mut c_void,
+                )
+            };
+            self.progress = Some(progress);
+        }
+
+        pub fn index(mut self, errors: IndexErrorHandling) -> io::Result<Index> {
+            let errors = match errors {
+                IndexErrorHandling::IEH_ABORT => FFMS_IndexErrorHandling::FFMS_IEH_ABORT,
+                IndexErrorHandling::IEH_CLEAR_TRACK => {
+                    FFMS_IndexErrorHandling::FFMS_IEH_CLEAR_TRACK
+                }
+                IndexErrorHandling::IEH_STOP_TRACK => FFMS_IndexErrorHandling::FFMS_IEH_STOP_TRACK,
+                IndexErrorHandling::IEH_IGNORE => FFMS_IndexErrorHandling::FFMS_IEH_IGNORE,
+            };
+
+            let mut error = ErrorInfo::new();
+            // FFMS_DoIndexing2 frees the indexer, whether it succeeds or not.
+            let indexer = std::mem::replace(&mut self.indexer, std::ptr::null_mut());
+            let index = unsafe { FFMS_DoIndexing2(indexer, errors as c_int, &mut error.info) };
+            if index.is_null() {
+                return Err(error.error());
+            }
+
+            // The bindings only make an `Index` out of a file or a buffer.
+            let mut buffer = std::ptr::null_mut();
+            let mut size = 0;
+            let written =
+                unsafe { FFMS_WriteIndexToBuffer(&mut buffer, &mut size, index, &mut error.info) };
+            let result = match written {
+                0 => {
+                    let bytes = unsafe { std::slice::from_raw_parts(buffer, size as usize) };
+                    let index = Index::ReadIndexFromBuffer(bytes)
+                        .map_err(|err| io::Error::other(format!("{:?}", err)));
+                    unsafe { FFMS_FreeIndexBuffer(&mut buffer) };
+                    index
+                }
+                _ => Err(error.error()),
+            };
+            unsafe { FFMS_DestroyIndex(index) };
+
+            result
+        }
+    }
+
+    impl Drop for Indexer {
+        fn drop(&mut self) {
+            if !self.indexer.is_null() {
+                unsafe { FFMS_CancelIndexing(self.indexer) };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_demuxer_options() {
+        assert_eq!(
+            parse_option("enable_drefs=1"),
+            Ok(("enable_drefs".to_string(), "1".to_string()))
+        );
+        assert_eq!(
+            parse_option("decryption_key="),
+            Ok(("decryption_key".to_string(), String::new()))
+        );
+        assert!(parse_option("enable_drefs").is_err());
+        assert!(parse_option("=1").is_err());
+    }
+}
@@ -0,0 +1,141 @@
+use ffms_segmenter::log::json_string;
+use ffms_segmenter::yuv4mpeg::{ColorRange, Interlacing};
+use ffms_segmenter::RangeConvention;
+
+/// One track of the index.
+pub struct TrackInfo {
+    pub id: usize,
+    pub kind: &'static str,
+    pub frames: usize,
+}
+
+/// What is known about an input, sent by the `info` command and printed by
+/// `--info-json`.
+pub struct Info {
+    pub width: usize,
+    pub height: usize,
+    pub sar: (i32, i32),
+    pub frames: usize,
+    pub fps_num: i32,
+    pub fps_den: i32,
+    pub pixel_format: String,
+    pub interlacing: Interlacing,
+    pub color_range: ColorRange,
+    /// FFMS2 matrix, primaries and transfer codes of the first frame,
+    /// unknown with `--no-probe`.
+    pub color: Option<(i32, i32, i32)>,
+    pub video_track: usize,
+    pub tracks: Vec<TrackInfo>,
+    /// How the ends of requests for this input are counted.
+    pub ranges: RangeConvention,
+}
+
+impl Info {
+    /// The display aspect ratio, with square pixels when the sample aspect
+    /// ratio is unknown.
+    pub fn display_aspect(&self) -> (usize, usize) {
+        let (num, den) = match self.sar {
+            (num, den) if num > 0 && den > 0 => (num as usize, den as usize),
+            _ => (1, 1),
+        };
+        crate::reduce(self.width * num, self.height * den)
+    }
+
+    pub fn duration(&self) -> f64 {
+        match self.fps_num {
+            0 => 0.0,
+            fps_num => self.frames as f64 * self.fps_den as f64 / fps_num as f64,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let color = match self.color {
+            Some((matrix, primaries, transfer)) => format!(
+                "{{\"matrix\":{},\"primaries\":{},\"transfer\":{}}}",
+                matrix, primaries, transfer
+            ),
+            None => "null".to_string(),
+        };
+        let interlacing = match self.interlacing {
+            Interlacing::Progressive => "progressive",
+            Interlacing::TopFieldFirst => "top-field-first",
+            Interlacing::BottomFieldFirst => "bottom-field-first",
+        };
+        let range = match self.color_range {
+            ColorRange::Limited => "limited",
+            ColorRange::Full => "full",
+        };
+        let tracks: Vec<String> = self
+            .tracks
+            .iter()
+            .map(|track| {
+                format!(
+                    "{{\"track\":{},\"type\":\"{}\",\"frames\":{}}}",
+                    track.id, track.kind, track.frames
+                )
+            })
+            .collect();
+
+        let (dar_num, dar_den) = self.display_aspect();
+
+        format!(
+            "{{\"width\":{},\"height\":{},\"sar_num\":{},\"sar_den\":{},\"dar_num\":{},\
+             \"dar_den\":{},\"frames\":{},\"fps_num\":{},\"fps_den\":{},\"duration\":{:.3},\
+             \"pixel_format\":{},\"interlacing\":\"{}\",\"color_range\":\"{}\",\"color\":{},\
+             \"video_track\":{},\"tracks\":[{}],\"ranges\":\"{}\"}}",
+            self.width,
+            self.height,
+            self.sar.0,
+            self.sar.1,
+            dar_num,
+            dar_den,
+            self.frames,
+            self.fps_num,
+            self.fps_den,
+            self.duration(),
+            json_string(&self.pixel_format),
+            interlacing,
+            range,
+            color,
+            self.video_track,
+            tracks.join(","),
+            self.ranges.name()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_input_as_json() {
+        let info = Info {
+            width: 1920,
+            height: 1080,
+            sar: (1, 1),
+            frames: 48,
+            fps_num: 24000,
+            fps_den: 1001,
+            pixel_format: "yuv420p".to_string(),
+            interlacing: Interlacing::Progressive,
+            color_range: ColorRange::Limited,
+            color: None,
+            video_track: 0,
+            tracks: vec![TrackInfo {
+                id: 0,
+                kind: "video",
+                frames: 48,
+            }],
+            ranges: RangeConvention::Inclusive,
+        };
+
+        assert_eq!(
+            info.to_json(),
+            "{\"width\":1920,\"height\":1080,\"sar_num\":1,\"sar_den\":1,\"dar_num\":16,\
+             \"dar_den\":9,\"frames\":48,\"fps_num\":24000,\"fps_den\":1001,\"duration\":2.002,\
+             \"pixel_format\":\"yuv420p\",\"interlacing\":\"progressive\",\"color_range\":\"limited\",\"color\":null,\
+             \"video_track\":0,\"tracks\":[{\"track\":0,\"type\":\"video\",\"frames\":48}],\"ranges\":\"inclusive\"}"
+        );
+    }
+}
@@ -0,0 +1,209 @@
+use crate::transport::{parse_segment, Properties, Transport};
+use std::io::{self, BufRead, Write};
+
+const PROMPT: &str = "ffms-segmenter> ";
+
+const HELP: &str = "\
+Commands:
+  info [<input>]                      properties of the input as JSON
+  duration [<input>]                  frames, duration and first and last
+                                      timestamps of every track as JSON
+  frame [<input>] <n>                 type, pts, keyframe flag, size and
+                                      pixel format of a frame
+  keyframes [<start> <end>]           keyframes of the main input
+  extract [<input>] <start> <end> [<option>=<value>...]
+                                      write the segment start..end, with the
+                                      request options of the pipe protocol
+  open <path>                         open another input
+  help                                this list
+  quit                                leave";
+
+/// A prompt for people exploring an input by hand, for `--interactive`.
+/// Its commands are checked and turned into the pipe protocol's before the
+/// session sees them, so a typo is explained instead of ending the
+/// session, and responses are written out in words.
+pub struct Repl<R, W> {
+    reader: R,
+    writer: W,
+    #[cfg(feature = "readline")]
+    editor: Option<rustyline::DefaultEditor>,
+}
+
+impl<R: BufRead, W: Write> Repl<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Repl {
+            reader,
+            writer,
+            #[cfg(feature = "readline")]
+            editor: None,
+        }
+    }
+
+    /// Reads commands with line editing and history instead of plain lines.
+    #[cfg(feature = "readline")]
+    pub fn edited(mut self) -> Self {
+        self.editor = rustyline::DefaultEditor::new().ok();
+        self
+    }
+
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        #[cfg(feature = "readline")]
+        if let Some(ref mut editor) = self.editor {
+            use rustyline::error::ReadlineError;
+
+            return match editor.readline(PROMPT) {
+                Ok(line) => {
+                    let _ = editor.add_history_entry(line.as_str());
+                    Ok(Some(line))
+                }
+                // Ctrl-C drops the line being typed.
+                Err(ReadlineError::Interrupted) => Ok(Some(String::new())),
+                Err(ReadlineError::Eof) => Ok(None),
+                Err(err) => Err(io::Error::other(err)),
+            };
+        }
+
+        write!(self.writer, "{}", PROMPT)?;
+        self.writer.flush()?;
+
+        let mut line = String::new();
+        match self.reader.read_line(&mut line)? {
+            0 => Ok(None),
+            _ => Ok(Some(line)),
+        }
+    }
+}
+
+/// The protocol command for the REPL command `line`, or why there is none.
+fn translate(line: &str) -> Result<String, String> {
+    let (command, arguments) = line.split_once(' ').unwrap_or((line, ""));
+    let arguments = arguments.trim();
+    let numbers = |tokens: &[&str]| tokens.iter().all(|token| token.parse::<usize>().is_ok());
+
+    match command {
+        "info" if arguments.is_empty() || numbers(&[arguments]) => Ok(line.to_string()),
+        "info" => Err("Usage: info [<input>]".to_string()),
+        "duration" if arguments.is_empty() || numbers(&[arguments]) => Ok(line.to_string()),
+        "duration" => Err("Usage: duration [<input>]".to_string()),
+        "frame" => {
+            let tokens: Vec<&str> = arguments.split_whitespace().collect();
+            match tokens.len() {
+                1 | 2 if numbers(&tokens) => Ok(format!("frame {}", tokens.join(" "))),
+                _ => Err("Usage: frame [<input>] <n>".to_string()),
+            }
+        }
+        "keyframes" => match arguments.split_whitespace().collect::<Vec<&str>>()[..] {
+            [] => Ok("keyframes".to_string()),
+            [start, end] if numbers(&[start, end]) => Ok(format!("keyframes {} {}", start, end)),
+            _ => Err("Usage: keyframes [<start> <end>]".to_string()),
+        },
+        "extract" => {
+            let tokens: Vec<&str> = arguments.split_whitespace().collect();
+            let range = tokens
+                .iter()
+                .take_while(|token| !token.contains('='))
+                .count();
+            match range {
+                2 | 3 if numbers(&tokens[..range]) => Ok(tokens.join(" ")),
+                _ => {
+                    Err("Usage: extract [<input>] <start> <end> [<option>=<value>...]".to_string())
+                }
+            }
+        }
+        "open" if !arguments.is_empty() => Ok(line.to_string()),
+        "open" => Err("Usage: open <path>".to_string()),
+        _ => Err(format!(
+            "Unknown command `{}`, type `help` for the commands",
+            command
+        )),
+    }
+}
+
+/// `message` as a sentence, where it is one of the usual responses.
+fn describe(message: &str) -> String {
+    if let Some(properties) = Properties::parse(message) {
+        return format!(
+            "{}x{}, {} frames at {}/{} fps",
+            properties.width,
+            properties.height,
+            properties.frames,
+            properties.fps_num,
+            properties.fps_den
+        );
+    }
+    if let Some((start, path)) = parse_segment(message) {
+        return format!("Wrote {} from frame {}", path, start);
+    }
+    if let Some(reason) = message.strip_prefix("error ") {
+        return format!("Error: {}", reason);
+    }
+    if let Some(frames) = message.strip_prefix("keyframes") {
+        let frames: Vec<&str> = frames.split_whitespace().collect();
+        return format!("{} keyframes: {}", frames.len(), frames.join(" "));
+    }
+
+    message.to_string()
+}
+
+impl<R: BufRead, W: Write> Transport for Repl<R, W> {
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        loop {
+            let Some(line) = self.read_line()? else {
+                return Ok(None);
+            };
+
+            match line.trim() {
+                "" => {}
+                "quit" | "exit" => return Ok(None),
+                "help" => writeln!(self.writer, "{}", HELP)?,
+                line => match translate(line) {
+                    Ok(command) => return Ok(Some(command)),
+                    Err(reason) => writeln!(self.writer, "{}", reason)?,
+                },
+            }
+        }
+    }
+
+    fn send(&mut self, message: &str) -> io::Result<()> {
+        writeln!(self.writer, "{}", describe(message))?;
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn explains_commands_it_cannot_run() {
+        let input =
+            "help\n\nseek 10\nextract 0\nextract 1 0 24 step=2\ninfo\nkeyframes 0 48\nquit\ninfo\n";
+        let mut out = Vec::new();
+        let mut repl = Repl::new(input.as_bytes(), &mut out);
+
+        assert_eq!(repl.receive().unwrap().as_deref(), Some("1 0 24 step=2"));
+        assert_eq!(repl.receive().unwrap().as_deref(), Some("info"));
+        assert_eq!(repl.receive().unwrap().as_deref(), Some("keyframes 0 48"));
+        assert_eq!(repl.receive().unwrap(), None);
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("keyframes [<start> <end>]"));
+        assert!(out.contains("Unknown command `seek`, type `help` for the commands"));
+        assert!(out.contains("Usage: extract [<input>] <start> <end>"));
+    }
+
+    #[test]
+    fn describes_responses() {
+        assert_eq!(
+            describe("1920 1080 100 1 24"),
+            "1920x1080, 100 frames at 24/1 fps"
+        );
+        assert_eq!(describe("0 ./0-24.y4m"), "Wrote ./0-24.y4m from frame 0");
+        assert_eq!(describe("keyframes 0 48 96"), "3 keyframes: 0 48 96");
+        assert_eq!(
+            describe("error Invalid request, expected [<input>] <start> <end>: 0"),
+            "Error: Invalid request, expected [<input>] <start> <end>: 0"
+        );
+        assert_eq!(describe("opened 1 b.mkv"), "opened 1 b.mkv");
+    }
+}
@@ -0,0 +1,167 @@
+use crate::transport::Drainer;
+use ffms_segmenter::log;
+use std::io;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+pub const EXIT_CODE: i32 = ffms_segmenter::failure::INTERRUPTED;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Set by SIGTERM, for `drain_on_sigterm`.
+static TERMINATED: AtomicBool = AtomicBool::new(false);
+
+/// Set by a `cancel` of the request being handled.
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// The error `check` fails with after `cancel`.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("Cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Held while a segment is written, so the process never exits with a
+/// segment half done.
+static WRITING: Mutex<()> = Mutex::new(());
+
+/// Paths of the segments written so far, for the summary.
+static COMPLETED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+#[cfg(unix)]
+extern "C" fn on_sigint(_: libc::c_int) {
+    // A second Ctrl-C exits without waiting for the segment.
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(EXIT_CODE) };
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn on_sigterm(_: libc::c_int) {
+    // A second SIGTERM gives up on the drain as Ctrl-C would.
+    if TERMINATED.swap(true, Ordering::SeqCst) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Installs a SIGTERM handler for the server modes, so the process can be
+/// stopped by a service manager without losing work: new requests are
+/// refused, the queued ones and the one being written are finished, the
+/// final status is logged and the session ends as usual. Requests still
+/// pending after `timeout` are abandoned as on Ctrl-C.
+pub fn drain_on_sigterm(drainer: Drainer, timeout: Duration) {
+    #[cfg(unix)]
+    unsafe {
+        libc::signal(
+            libc::SIGTERM,
+            on_sigterm as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+
+    thread::spawn(move || {
+        while !TERMINATED.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(50));
+        }
+        log!(
+            Info,
+            "Terminated, draining {} pending requests",
+            drainer.pending()
+        );
+        drainer.drain();
+
+        thread::sleep(timeout);
+        log!(
+            Warn,
+            "Drain timed out after {:?}, abandoning {} pending requests",
+            timeout,
+            drainer.pending()
+        );
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    });
+}
+
+/// Installs the SIGINT handler. The segment being written when Ctrl-C is
+/// pressed is abandoned and its file removed, then the completed segments
+/// are listed and the process exits with `EXIT_CODE`.
+pub fn install() {
+    #[cfg(unix)]
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            on_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+
+    thread::spawn(|| {
+        while !INTERRUPTED.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(50));
+        }
+        exit();
+    });
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Fails with `ErrorKind::Interrupted` once Ctrl-C has been pressed, or
+/// with `Cancelled` once the request being handled is cancelled.
+pub fn check() -> io::Result<()> {
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        return Err(io::Error::new(io::ErrorKind::Interrupted, "Interrupted"));
+    }
+    if CANCELLED.load(Ordering::SeqCst) {
+        return Err(io::Error::other(Cancelled));
+    }
+
+    Ok(())
+}
+
+/// Abandons the segment being written at the next `check`.
+pub fn cancel() {
+    CANCELLED.store(true, Ordering::SeqCst);
+}
+
+pub fn clear_cancel() {
+    CANCELLED.store(false, Ordering::SeqCst);
+}
+
+pub fn is_cancelled(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|err| err.is::<Cancelled>())
+}
+
+/// Marks a segment as being written until the guard is dropped.
+pub fn writing() -> MutexGuard<'static, ()> {
+    lock(&WRITING)
+}
+
+pub fn record_segment(path: &str) {
+    lock(&COMPLETED).push(path.to_string());
+}
+
+/// Waits for the segment in progress, prints the summary and exits.
+pub fn exit() -> ! {
+    let _writing = lock(&WRITING);
+    let completed = lock(&COMPLETED);
+
+    log!(
+        Info,
+        "Interrupted after {} completed segments",
+        completed.len()
+    );
+    for path in completed.iter() {
+        log!(Info, "Completed: {}", path);
+    }
+
+    process::exit(EXIT_CODE)
+}
@@ -0,0 +1,346 @@
+use crate::{cfr, SegmentFormat, Source};
+use ffms_segmenter::prefetch::{DecodedFrame, Decoder};
+use ffms_segmenter::scale::{plane_sizes, PlaneSize};
+use ffms_segmenter::Interlacing;
+use std::io::{self, BufWriter, Write};
+use y4m::Ratio;
+
+/// Frames of video in each cycle of 3:2 pulldown, which holds `KEPT` film
+/// frames.
+const CYCLE: usize = 5;
+const KEPT: usize = 4;
+
+/// Where the second field of a matched frame comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Match {
+    Current,
+    Previous,
+    Next,
+}
+
+impl Match {
+    fn letter(self) -> char {
+        match self {
+            Match::Current => 'c',
+            Match::Previous => 'p',
+            Match::Next => 'n',
+        }
+    }
+}
+
+/// The field matches of `CYCLE` frames and the one dropped as a repeat.
+pub struct Cycle {
+    pub matches: Vec<Match>,
+    /// Position of the dropped frame, only in whole cycles.
+    pub dropped: Option<usize>,
+    /// The matched frames left once it is dropped.
+    pub frames: Vec<DecodedFrame>,
+}
+
+fn sample(plane: &[u8], bytes: usize, index: usize) -> i64 {
+    match bytes {
+        2 => u16::from_le_bytes([plane[index * 2], plane[index * 2 + 1]]) as i64,
+        _ => plane[index] as i64,
+    }
+}
+
+/// Pulls 3:2 telecined video back to its film frames: each frame keeps its
+/// first field and takes its second from whichever of itself and its
+/// neighbours combs least with it, then the frame of every cycle most alike
+/// the one before it is dropped as the repeat. Cycles start at frame 0.
+pub struct Ivtc<'a, D: ?Sized> {
+    decoder: &'a mut D,
+    planes: [PlaneSize; 3],
+    bytes: usize,
+    /// Difference between neighbouring lines above which they comb.
+    threshold: i64,
+    /// Line parity of the second field, 1 for the bottom field.
+    second_field: usize,
+    cycle: Option<(usize, Vec<DecodedFrame>)>,
+}
+
+impl<'a, D: Decoder + ?Sized> Ivtc<'a, D> {
+    /// Matches frames of `decoder` in `format`. Progressive sources are
+    /// treated as top field first.
+    pub fn new(decoder: &'a mut D, format: &SegmentFormat) -> Self {
+        Ivtc {
+            decoder,
+            planes: plane_sizes(format.colorspace, format.width, format.height),
+            bytes: format.colorspace.get_bytes_per_sample(),
+            threshold: 10 << (format.colorspace.get_bit_depth() - 8),
+            second_field: match format.interlacing {
+                Interlacing::BottomFieldFirst => 0,
+                _ => 1,
+            },
+            cycle: None,
+        }
+    }
+
+    /// `frame` with the lines of its second field taken from `other`.
+    fn weave(&self, frame: &DecodedFrame, other: &DecodedFrame) -> DecodedFrame {
+        let weave_plane = |plane: &[u8], other: &[u8], size: PlaneSize| {
+            let line = size.width * self.bytes;
+            let mut woven = plane.to_vec();
+            for y in (self.second_field..size.height).step_by(2) {
+                woven[y * line..(y + 1) * line].copy_from_slice(&other[y * line..(y + 1) * line]);
+            }
+            woven
+        };
+
+        let mut planes: [Vec<u8>; 3] = Default::default();
+        for (index, &size) in self.planes.iter().enumerate() {
+            planes[index] = weave_plane(&frame.planes[index], &other.planes[index], size);
+        }
+        let alpha = match (&frame.alpha, &other.alpha) {
+            (Some(alpha), Some(other)) => Some(weave_plane(alpha, other, self.planes[0])),
+            _ => frame.alpha.clone(),
+        };
+
+        DecodedFrame { planes, alpha }
+    }
+
+    /// Luma samples that stand out from both lines around them the same
+    /// way, as the lines of two pictures interleaved do.
+    fn combing(&self, frame: &DecodedFrame) -> usize {
+        let PlaneSize { width, height } = self.planes[0];
+        let luma = |y: usize, x: usize| sample(&frame.planes[0], self.bytes, y * width + x);
+
+        (1..height.saturating_sub(1))
+            .flat_map(|y| (0..width).map(move |x| (y, x)))
+            .filter(|&(y, x)| {
+                let above = luma(y, x) - luma(y - 1, x);
+                let below = luma(y, x) - luma(y + 1, x);
+                above * below > self.threshold * self.threshold
+            })
+            .count()
+    }
+
+    fn difference(&self, a: &DecodedFrame, b: &DecodedFrame) -> u64 {
+        let samples = a.planes[0].len().min(b.planes[0].len()) / self.bytes;
+        (0..samples)
+            .map(|index| {
+                (sample(&a.planes[0], self.bytes, index) - sample(&b.planes[0], self.bytes, index))
+                    .unsigned_abs()
+            })
+            .sum()
+    }
+
+    /// Matches and decimates cycle `index`.
+    pub fn cycle(&mut self, index: usize) -> io::Result<Cycle> {
+        let total = self.decoder.frames();
+        let first = index * CYCLE;
+        let end = (first + CYCLE).min(total);
+
+        // The cycle and a frame either side of it.
+        let before = first.saturating_sub(1);
+        let window = (before..(end + 1).min(total))
+            .map(|frame| self.decoder.decode(frame))
+            .collect::<io::Result<Vec<DecodedFrame>>>()?;
+        let at = |frame: usize| &window[frame - before];
+
+        let mut matches = Vec::with_capacity(end - first);
+        let mut frames = Vec::with_capacity(end - first);
+        for frame in first..end {
+            let mut best = (Match::Current, at(frame).clone());
+            let mut least = self.combing(&best.1);
+            let neighbours = [
+                (Match::Previous, frame.checked_sub(1)),
+                (Match::Next, Some(frame + 1).filter(|&next| next < total)),
+            ];
+            for (kind, neighbour) in neighbours {
+                if least == 0 {
+                    break;
+                }
+                let Some(neighbour) = neighbour else { continue };
+                let woven = self.weave(at(frame), at(neighbour));
+                let combing = self.combing(&woven);
+                if combing < least {
+                    (best, least) = ((kind, woven), combing);
+                }
+            }
+            matches.push(best.0);
+            frames.push(best.1);
+        }
+
+        let dropped = match frames.len() {
+            CYCLE => (0..CYCLE).min_by_key(|&position| match position {
+                0 if first == 0 => u64::MAX,
+                0 => self.difference(at(before), &frames[0]),
+                _ => self.difference(&frames[position - 1], &frames[position]),
+            }),
+            _ => None,
+        };
+        if let Some(position) = dropped {
+            frames.remove(position);
+        }
+
+        Ok(Cycle {
+            matches,
+            dropped,
+            frames,
+        })
+    }
+}
+
+impl<D: Decoder + ?Sized> Decoder for Ivtc<'_, D> {
+    fn decode(&mut self, frame: usize) -> io::Result<DecodedFrame> {
+        let index = frame / KEPT;
+        if self.cycle.as_ref().map(|(cached, _)| *cached) != Some(index) {
+            let frames = self.cycle(index)?.frames;
+            self.cycle = Some((index, frames));
+        }
+
+        let (_, frames) = self.cycle.as_ref().unwrap();
+        frames.get(frame % KEPT).cloned().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Frame {} is past the inverse telecined frames", frame),
+            )
+        })
+    }
+
+    fn frames(&self) -> usize {
+        let total = self.decoder.frames();
+        total / CYCLE * KEPT + total % CYCLE
+    }
+}
+
+/// Puts `source` on the timeline of its film frames for `--ivtc`, at 4/5
+/// of its frame rate.
+pub fn retime(source: &mut Source) {
+    let total = source.total_frames;
+    let Ratio { num, den } = source.format.framerate;
+    let framerate = match num % 5 {
+        0 => Ratio {
+            num: num / 5 * 4,
+            den,
+        },
+        _ => Ratio {
+            num: num * 4,
+            den: den * 5,
+        },
+    };
+
+    cfr::announce(source, total / CYCLE * KEPT + total % CYCLE, framerate);
+    source.ivtc = true;
+}
+
+/// Prints `<first> <last> <matches> <dropped>` for every cycle of five
+/// frames covering `start..end`, with a letter per frame for where its
+/// second field was matched from, `c`urrent, `p`revious or `n`ext, and the
+/// frame dropped as a repeat. 3:2 pulldown shows as two `p` or `n` matches
+/// a cycle. A last `telecined <cycles> <total>` line counts the cycles
+/// with a field matched from a neighbour.
+pub fn print(source: &mut Source, start: usize, end: usize) -> io::Result<()> {
+    let end = end.min(source.total_frames);
+    let format = source.format.clone();
+    let mut ivtc = Ivtc::new(&mut source.video_source, &format);
+    let mut out = BufWriter::new(io::stdout().lock());
+
+    let cycles = start / CYCLE..end.div_ceil(CYCLE);
+    let mut telecined = 0;
+    for index in cycles.clone() {
+        let cycle = ivtc.cycle(index)?;
+        let first = index * CYCLE;
+        let matches: String = cycle.matches.iter().map(|kind| kind.letter()).collect();
+        if cycle.matches.iter().any(|&kind| kind != Match::Current) {
+            telecined += 1;
+        }
+        let dropped = cycle.dropped.map_or_else(
+            || "-".to_string(),
+            |position| (first + position).to_string(),
+        );
+
+        writeln!(
+            out,
+            "{} {} {} {}",
+            first,
+            first + cycle.matches.len() - 1,
+            matches,
+            dropped
+        )?;
+    }
+    writeln!(out, "telecined {} {}", telecined, cycles.len())?;
+
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ffms_segmenter::yuv4mpeg::ColorRange;
+    use y4m::Colorspace;
+
+    /// Frames of fields from the pictures `top` and `bottom`, each a flat
+    /// level.
+    struct Telecined(Vec<(u8, u8)>);
+
+    impl Decoder for Telecined {
+        fn decode(&mut self, frame: usize) -> io::Result<DecodedFrame> {
+            let (top, bottom) = self.0[frame];
+            let luma = (0..64)
+                .map(|i| if (i / 8) % 2 == 0 { top } else { bottom })
+                .collect();
+            Ok(DecodedFrame {
+                planes: [luma, Vec::new(), Vec::new()],
+                alpha: None,
+            })
+        }
+
+        fn frames(&self) -> usize {
+            self.0.len()
+        }
+    }
+
+    #[test]
+    fn recovers_film_frames() {
+        let format = SegmentFormat {
+            width: 8,
+            height: 8,
+            framerate: Ratio {
+                num: 30000,
+                den: 1001,
+            },
+            colorspace: Colorspace::Cmono,
+            interlacing: Interlacing::TopFieldFirst,
+            color_range: ColorRange::Full,
+            color: None,
+            sar: None,
+        };
+        // Pictures A to H at 40 apart, as AA BB BC CD DD.
+        let (a, b, c, d, e, f, g, h) = (20, 60, 100, 140, 180, 220, 240, 10);
+        let mut source = Telecined(vec![
+            (a, a),
+            (b, b),
+            (b, c),
+            (c, d),
+            (d, d),
+            (e, e),
+            (f, f),
+            (f, g),
+            (g, h),
+            (h, h),
+            (a, a),
+        ]);
+        let mut ivtc = Ivtc::new(&mut source, &format);
+
+        let cycle = ivtc.cycle(0).unwrap();
+        assert_eq!(
+            cycle.matches,
+            [
+                Match::Current,
+                Match::Current,
+                Match::Previous,
+                Match::Previous,
+                Match::Current
+            ]
+        );
+        assert_eq!(cycle.dropped, Some(2));
+
+        assert_eq!(ivtc.frames(), 9);
+        let levels: Vec<u8> = (0..9)
+            .map(|frame| ivtc.decode(frame).unwrap().planes[0][8])
+            .collect();
+        assert_eq!(levels, [a, b, c, d, e, f, g, h, a]);
+    }
+}
@@ -0,0 +1,73 @@
+use crate::checksum::file_crc32;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Append-only record of completed segments, one
+/// `<start> <end> <bytes> <crc32>` line each, used to resume a batch after
+/// a crash.
+pub struct Journal {
+    file: File,
+    completed: HashMap<(usize, usize), (u64, u32)>,
+}
+
+fn parse_line(line: &str) -> Option<((usize, usize), (u64, u32))> {
+    let mut tokens = line.split_whitespace();
+    let start = tokens.next()?.parse().ok()?;
+    let end = tokens.next()?.parse().ok()?;
+    let bytes = tokens.next()?.parse().ok()?;
+    let crc = u32::from_str_radix(tokens.next()?, 16).ok()?;
+
+    Some(((start, end), (bytes, crc)))
+}
+
+impl Journal {
+    /// Opens the journal at `path`. When `resume` is set the existing
+    /// entries are kept, otherwise the journal is started afresh.
+    pub fn open(path: &Path, resume: bool) -> io::Result<Self> {
+        let mut completed = HashMap::new();
+
+        if resume {
+            match fs::read_to_string(path) {
+                // A torn last line from a crash is simply not an entry.
+                Ok(text) => completed.extend(text.lines().filter_map(parse_line)),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(resume)
+            .write(true)
+            .truncate(!resume)
+            .open(path)?;
+
+        Ok(Journal { file, completed })
+    }
+
+    /// Whether `start..end` was completed and `output` still matches what
+    /// was recorded.
+    pub fn verify(&self, start: usize, end: usize, output: &Path) -> io::Result<bool> {
+        let recorded = match self.completed.get(&(start, end)) {
+            Some(recorded) => *recorded,
+            None => return Ok(false),
+        };
+
+        match file_crc32(output) {
+            Ok(actual) => Ok(actual == recorded),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Records a completed segment and syncs it to disk.
+    pub fn record(&mut self, start: usize, end: usize, bytes: u64, crc: u32) -> io::Result<()> {
+        writeln!(self.file, "{} {} {} {:08x}", start, end, bytes, crc)?;
+        self.file.sync_data()?;
+        self.completed.insert((start, end), (bytes, crc));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,34 @@
+//! Frame accurate Y4M segments from anything FFMS2 can index.
+//!
+//! [`Segmenter`] opens and indexes a file and writes segments for
+//! [`SegmentRequest`]s, reporting progress as [`Event`]s. [`Protocol`]
+//! describes the line-based protocol spoken by the `ffms-segmenter` binary,
+//! for tools that drive it over stdin/stdout instead.
+//!
+//! Only the items re-exported at the crate root follow semver. The hidden
+//! modules are shared with the binary and may change at any time.
+
+#[doc(hidden)]
+pub mod checksum;
+#[doc(hidden)]
+pub mod failure;
+#[cfg(feature = "ffmpeg")]
+#[doc(hidden)]
+pub mod ffmpeg;
+#[doc(hidden)]
+pub mod log;
+#[doc(hidden)]
+pub mod prefetch;
+#[doc(hidden)]
+pub mod ranges;
+#[doc(hidden)]
+pub mod scale;
+#[doc(hidden)]
+pub mod yuv4mpeg;
+
+mod api;
+
+pub use api::{
+    Chroma, Event, Handshake, OutputFormat, Protocol, RangeConvention, SegmentRequest, Segmenter,
+};
+pub use yuv4mpeg::Interlacing;
@@ -0,0 +1,397 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::panic;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Logs a diagnostic message at a [`Level`], e.g.
+/// `log!(Warn, "Frame {} failed", frame)`. An event name and fields for
+/// `--log-format json` may come first:
+/// `log!(Warn, "decode_error" { frame: frame }, "Frame {} failed", frame)`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! log {
+    ($level:ident, $event:literal { $($key:ident: $value:expr),* $(,)? }, $($arg:tt)*) => {
+        $crate::log::write(
+            $crate::log::Level::$level,
+            $event,
+            &[$((stringify!($key), &$value as &dyn ::std::fmt::Display)),*],
+            format_args!($($arg)*),
+        )
+    };
+    ($level:ident, $($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::$level, "message", &[], format_args!($($arg)*))
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+}
+
+impl Level {
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+        }
+    }
+}
+
+/// How log lines are written, chosen with `--log-format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// The bare message on stderr, `<timestamp> <LEVEL> <message>` in the
+    /// log file.
+    Text,
+    /// One JSON object per line on both.
+    Json,
+}
+
+pub fn parse_format(format: &str) -> Result<Format, String> {
+    match format {
+        "text" => Ok(Format::Text),
+        "json" => Ok(Format::Json),
+        _ => Err(format!("Invalid log format: {}", format)),
+    }
+}
+
+/// A span of work that messages logged on its thread while it lasts
+/// belong to, such as indexing an input or writing a segment. Its name and
+/// fields are added to `--log-format json` events; text lines are left as
+/// they are. Built with the `tracing` feature, spans and messages are also
+/// handed to `tracing`, for whatever subscriber is installed.
+#[must_use = "the span ends when it is dropped"]
+pub struct Span {
+    #[cfg(feature = "tracing")]
+    _entered: tracing::span::EnteredSpan,
+}
+
+struct Open {
+    name: &'static str,
+    fields: Vec<(&'static str, String)>,
+}
+
+thread_local! {
+    static SPANS: RefCell<Vec<Open>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Starts the span `name`, which lasts until the returned guard is dropped.
+pub fn span(name: &'static str, fields: &[(&'static str, &dyn fmt::Display)]) -> Span {
+    let fields: Vec<(&'static str, String)> = fields
+        .iter()
+        .map(|(key, value)| (*key, value.to_string()))
+        .collect();
+
+    #[cfg(feature = "tracing")]
+    let entered = tracing::info_span!(
+        "span",
+        name,
+        fields = %fields
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<String>>()
+            .join(" ")
+    )
+    .entered();
+
+    SPANS.with(|spans| spans.borrow_mut().push(Open { name, fields }));
+
+    Span {
+        #[cfg(feature = "tracing")]
+        _entered: entered,
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        SPANS.with(|spans| spans.borrow_mut().pop());
+    }
+}
+
+/// The `span` names of the open spans of this thread, outermost first and
+/// joined with `.`, and their fields not already in `fields`.
+fn span_fields(fields: &[(&str, &dyn fmt::Display)]) -> Vec<(&'static str, String)> {
+    SPANS.with(|spans| {
+        let spans = spans.borrow();
+        if spans.is_empty() {
+            return Vec::new();
+        }
+
+        let names: Vec<&str> = spans.iter().map(|span| span.name).collect();
+        let mut joined = vec![("span", names.join("."))];
+        for (key, value) in spans.iter().flat_map(|span| &span.fields) {
+            let taken = |other: &str| other == *key;
+            if !fields.iter().any(|(other, _)| taken(other))
+                && !joined.iter().any(|(other, _)| taken(other))
+            {
+                joined.push((key, value.clone()));
+            }
+        }
+        joined
+    })
+}
+
+/// The `--log-file`, if any.
+static FILE: Mutex<Option<File>> = Mutex::new(None);
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub fn set_format(format: Format) {
+    JSON.store(format == Format::Json, Ordering::Relaxed);
+}
+
+/// Keeps every message, panics included, off stderr for `--quiet`. They
+/// still go to the log file.
+pub fn set_quiet() {
+    QUIET.store(true, Ordering::Relaxed);
+    panic::set_hook(Box::new(|info| {
+        write_file(&format_line(
+            Level::Error,
+            "panic",
+            &[],
+            format_args!("{}", info),
+        ));
+    }));
+}
+
+/// Appends every message to `path` as well as stderr, and panics too so a
+/// failed run leaves its reason in the log.
+pub fn to_file(path: &Path) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    *FILE.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(file);
+
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let line = format_line(Level::Error, "panic", &[], format_args!("{}", info));
+        write_file(&line);
+        default_hook(info);
+    }));
+
+    Ok(())
+}
+
+/// Writes a message to stderr, unless quiet, and the log file. In the text
+/// format stderr stays free of timestamps so existing wrappers can keep
+/// reading it.
+pub fn write(
+    level: Level,
+    event: &str,
+    fields: &[(&str, &dyn fmt::Display)],
+    message: fmt::Arguments,
+) {
+    #[cfg(feature = "tracing")]
+    forward(level, event, fields, message);
+
+    let line = format_line(level, event, fields, message);
+
+    if !QUIET.load(Ordering::Relaxed) {
+        if JSON.load(Ordering::Relaxed) {
+            eprintln!("{}", line);
+        } else {
+            eprintln!("{}", message);
+        }
+    }
+    write_file(&line);
+}
+
+/// Hands a message to `tracing` at the same level, with its event and
+/// fields.
+#[cfg(feature = "tracing")]
+fn forward(
+    level: Level,
+    event: &str,
+    fields: &[(&str, &dyn fmt::Display)],
+    message: fmt::Arguments,
+) {
+    let fields = fields
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<String>>()
+        .join(" ");
+    match level {
+        Level::Error => tracing::error!(event, fields = %fields, "{}", message),
+        Level::Warn => tracing::warn!(event, fields = %fields, "{}", message),
+        Level::Info => tracing::info!(event, fields = %fields, "{}", message),
+    }
+}
+
+fn format_line(
+    level: Level,
+    event: &str,
+    fields: &[(&str, &dyn fmt::Display)],
+    message: fmt::Arguments,
+) -> String {
+    let timestamp = timestamp(SystemTime::now());
+    if !JSON.load(Ordering::Relaxed) {
+        return format!("{} {} {}", timestamp, level.name(), message);
+    }
+
+    let spans = span_fields(fields);
+    let mut all = fields.to_vec();
+    all.extend(
+        spans
+            .iter()
+            .map(|(key, value)| (*key, value as &dyn fmt::Display)),
+    );
+    json_event(&timestamp, level, event, &all, &message.to_string())
+}
+
+/// One `--log-format json` line. Fields that are integers, like frame
+/// numbers, are written as JSON numbers and anything else as strings.
+pub fn json_event(
+    timestamp: &str,
+    level: Level,
+    event: &str,
+    fields: &[(&str, &dyn fmt::Display)],
+    message: &str,
+) -> String {
+    let mut line = format!(
+        "{{\"timestamp\":\"{}\",\"level\":\"{}\",\"event\":{},\"message\":{}",
+        timestamp,
+        level.name().to_lowercase(),
+        json_string(event),
+        json_string(message)
+    );
+
+    for (key, value) in fields {
+        let value = value.to_string();
+        let value = match value.parse::<i64>() {
+            Ok(_) => value,
+            Err(_) => json_string(&value),
+        };
+        line.push_str(&format!(",{}:{}", json_string(key), value));
+    }
+
+    line.push('}');
+    line
+}
+
+fn write_file(line: &str) {
+    let mut file = FILE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    if let Some(file) = file.as_mut() {
+        // A log that cannot be written must not stop the segmenter.
+        let _ = writeln!(file, "{}", line);
+    }
+}
+
+pub fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+/// Formats `time` as an RFC 3339 UTC timestamp with milliseconds.
+pub fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    let of_day = seconds % 86400;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        of_day / 3600,
+        of_day % 3600 / 60,
+        of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Gregorian date of a day count since 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn formats_utc_timestamps() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_millis(951_825_845_123)),
+            "2000-02-29T12:04:05.123Z"
+        );
+    }
+
+    #[test]
+    fn formats_json_events() {
+        assert_eq!(
+            json_event(
+                "1970-01-01T00:00:00.000Z",
+                Level::Warn,
+                "decode_error",
+                &[("frame", &12), ("input", &"a \"b\".mkv")],
+                "Frame 12 failed"
+            ),
+            "{\"timestamp\":\"1970-01-01T00:00:00.000Z\",\"level\":\"warn\",\
+             \"event\":\"decode_error\",\"message\":\"Frame 12 failed\",\
+             \"frame\":12,\"input\":\"a \\\"b\\\".mkv\"}"
+        );
+    }
+
+    #[test]
+    fn spans_add_their_fields() {
+        assert!(span_fields(&[]).is_empty());
+
+        let outer = span("segment", &[("input", &0), ("start", &24)]);
+        {
+            let _inner = span("upload", &[("start", &48), ("path", &"out/24-48.y4m")]);
+            assert_eq!(
+                span_fields(&[("input", &1)]),
+                [
+                    ("span", "segment.upload".to_string()),
+                    ("start", "24".to_string()),
+                    ("path", "out/24-48.y4m".to_string())
+                ]
+            );
+        }
+        assert_eq!(span_fields(&[]).len(), 3);
+        drop(outer);
+        assert!(span_fields(&[]).is_empty());
+    }
+}
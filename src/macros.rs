@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Nested expansions deeper than this are assumed to be recursive.
+const MAX_DEPTH: usize = 16;
+
+struct Macro {
+    params: Vec<String>,
+    /// Commands the macro expands to, as tokens.
+    body: Vec<Vec<String>>,
+}
+
+/// Stdin command macros.
+///
+/// The macro file has one definition per line in the form
+/// `<name> <params...> = <command>[; <command>...]`, e.g.
+/// `intro A = 0 A; A 240`. Parameters are substituted token by token and
+/// expansions may use other macros. Blank lines and lines starting with `#`
+/// are ignored.
+pub struct Macros {
+    macros: HashMap<String, Macro>,
+}
+
+impl Macros {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut macros = HashMap::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid macro on line {}: {}", number + 1, line),
+                )
+            };
+
+            let (head, body) = line.split_once('=').ok_or_else(invalid)?;
+            let mut head = head.split_whitespace().map(str::to_string);
+            let name = head.next().ok_or_else(invalid)?;
+
+            let body: Vec<Vec<String>> = body
+                .split(';')
+                .map(|command| command.split_whitespace().map(str::to_string).collect())
+                .filter(|command: &Vec<String>| !command.is_empty())
+                .collect();
+            if body.is_empty() {
+                return Err(invalid());
+            }
+
+            macros.insert(
+                name,
+                Macro {
+                    params: head.collect(),
+                    body,
+                },
+            );
+        }
+
+        Ok(Macros { macros })
+    }
+
+    /// Expands `line` into the commands to run, or returns `None` if it does
+    /// not start with a macro name.
+    pub fn expand(&self, line: &str) -> io::Result<Option<Vec<String>>> {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+
+        match tokens.first() {
+            Some(name) if self.macros.contains_key(*name) => {
+                let mut commands = Vec::new();
+                self.expand_into(&tokens, 0, &mut commands)?;
+                Ok(Some(commands))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn expand_into(&self, tokens: &[&str], depth: usize, out: &mut Vec<String>) -> io::Result<()> {
+        let definition = match tokens.first().and_then(|name| self.macros.get(*name)) {
+            Some(definition) => definition,
+            None => {
+                out.push(tokens.join(" "));
+                return Ok(());
+            }
+        };
+
+        if depth >= MAX_DEPTH {
+            return Err(io::Error::other(format!(
+                "Macro {} expands too deeply",
+                tokens[0]
+            )));
+        }
+
+        let args = &tokens[1..];
+        if args.len() != definition.params.len() {
+            return Err(io::Error::other(format!(
+                "Macro {} takes {} arguments, got {}",
+                tokens[0],
+                definition.params.len(),
+                args.len()
+            )));
+        }
+
+        for command in &definition.body {
+            let expanded: Vec<&str> = command
+                .iter()
+                .map(
+                    |token| match definition.params.iter().position(|param| param == token) {
+                        Some(index) => args[index],
+                        None => token.as_str(),
+                    },
+                )
+                .collect();
+
+            self.expand_into(&expanded, depth + 1, out)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_nested_macros() {
+        let macros = Macros::parse("pair A B = A B\nintro N = pair 0 N; pair N 240\n").unwrap();
+
+        assert_eq!(
+            macros.expand("intro 24").unwrap().unwrap(),
+            ["0 24", "24 240"]
+        );
+        assert!(macros.expand("0 24").unwrap().is_none());
+        assert!(macros.expand("pair 1").is_err());
+    }
+
+    #[test]
+    fn rejects_recursive_macros() {
+        let macros = Macros::parse("loop A = loop A").unwrap();
+
+        assert!(macros.expand("loop 1").is_err());
+    }
+}
@@ -1,42 +1,1167 @@
 use ffms2::frame::Frame;
 use ffms2::video::VideoSource;
-use std::fs::File;
-use std::path::PathBuf;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 use std::{thread, time};
 use structopt::StructOpt;
-use y4m::{encode, Colorspace, Frame as Y4MFrame, Ratio};
+use y4m::{Colorspace, Ratio};
 
 use ffms2::index::*;
 use ffms2::track::*;
 use ffms2::*;
 
+mod align;
+mod analysis;
+mod annotations;
+mod bench;
+mod black;
+mod cache;
+mod cfr;
+mod chapters;
+mod chunks;
+mod compare;
+mod complexity;
+mod concat;
+mod config;
+mod cuts;
+#[cfg(unix)]
+mod daemon;
+mod determinism;
+mod dry_run;
+mod duplicates;
+mod duration;
+mod filter;
+mod frame_server;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod hdr;
+mod hook;
+mod http;
+mod index_dump;
+mod indexer;
+mod info;
+mod interactive;
+mod interrupt;
+mod ivtc;
+mod journal;
+mod macros;
+mod manifest;
+mod mapped;
+mod metrics;
+mod mkv;
+mod nut;
+mod output;
+mod placement;
+mod png;
+mod pool;
+mod progress;
+mod quality;
+mod raw;
+mod rgb;
+mod rotate;
+mod segment;
+mod sequence;
+mod session;
+mod session_file;
+mod silence;
+mod stats;
+mod stills;
+mod subtitles;
+mod thumb;
+mod timecodes;
+mod transport;
+mod verify;
+mod websocket;
+mod zones;
+
+use ffms_segmenter::{
+    checksum, failure, log, prefetch, ranges, scale, yuv4mpeg, Handshake, Protocol, RangeConvention,
+};
+
+use annotations::{Annotations, Planner};
+use checksum::HashWriter;
+use failure::Failure;
+use indexer::SourceIndexer;
+use journal::Journal;
+use macros::Macros;
+use output::Output;
+use prefetch::DecodedFrame;
+use progress::{ProgressStream, SegmentEvents};
+use rotate::{Rotation, Rotator};
+use scale::{PlaneSize, Scaler};
+use segment::{encode_segment, y4m_writer, FrameSink, Repeated, Reversed, Stage};
+use session::{Event, Session, State};
+use session_file::SavedSession;
+use transport::{FramedTransport, LineTransport, Transport};
+use yuv4mpeg::{ColorRange, Interlacing};
+
 macro_rules! print_progress {
     ($cond:expr, $error:expr) => {
         if $cond {
-            eprintln!($error);
+            log!(Info, $error);
         }
     };
 }
 
+/// The `--on-decode-error` choice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DecodeErrorMode {
+    Abort,
+    Skip,
+    DuplicatePrevious,
+    Blank,
+}
+
+fn parse_decode_error_mode(mode: &str) -> Result<DecodeErrorMode, String> {
+    match mode {
+        "abort" => Ok(DecodeErrorMode::Abort),
+        "skip" => Ok(DecodeErrorMode::Skip),
+        "duplicate-previous" => Ok(DecodeErrorMode::DuplicatePrevious),
+        "blank" => Ok(DecodeErrorMode::Blank),
+        _ => Err(format!("Invalid decode error policy: {}", mode)),
+    }
+}
+
+/// The `--decoder` choice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    Ffms2,
+    Ffmpeg,
+}
+
+fn parse_backend(backend: &str) -> Result<Backend, String> {
+    match backend {
+        "ffms2" => Ok(Backend::Ffms2),
+        "ffmpeg" => Ok(Backend::Ffmpeg),
+        _ => Err(format!("Invalid decoder: {}", backend)),
+    }
+}
+
+/// The `--output-format` choice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Container {
+    Y4m,
+    Mkv,
+    Nut,
+    Raw,
+}
+
+/// The `--output-format` names of the containers.
+const CONTAINERS: [(&str, Container); 4] = [
+    ("y4m", Container::Y4m),
+    ("mkv", Container::Mkv),
+    ("nut", Container::Nut),
+    ("raw", Container::Raw),
+];
+
+fn parse_container(container: &str) -> Result<Container, String> {
+    CONTAINERS
+        .iter()
+        .find(|(name, _)| *name == container)
+        .map(|&(_, container)| container)
+        .ok_or_else(|| format!("Invalid output format: {}", container))
+}
+
+/// An `--audio-track` choice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AudioSelection {
+    Track(usize),
+    All,
+}
+
+fn parse_audio_selection(selection: &str) -> Result<AudioSelection, String> {
+    match selection {
+        "all" => Ok(AudioSelection::All),
+        track => track
+            .parse()
+            .map(AudioSelection::Track)
+            .map_err(|_| format!("Invalid audio track: {}", track)),
+    }
+}
+
+/// The `--color-range` choice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RangeSelection {
+    Limited,
+    Full,
+    Auto,
+}
+
+fn parse_range_selection(range: &str) -> Result<RangeSelection, String> {
+    match range {
+        "limited" => Ok(RangeSelection::Limited),
+        "full" => Ok(RangeSelection::Full),
+        "auto" => Ok(RangeSelection::Auto),
+        _ => Err(format!("Invalid color range: {}", range)),
+    }
+}
+
+/// The `--pix-fmt` choice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PixelFormat {
+    Auto,
+    Yuv420p,
+    Yuv422p,
+    Yuv444p,
+    Yuv420p10le,
+    Yuv422p10le,
+    Yuv444p10le,
+    Yuv420p12le,
+    Yuv422p12le,
+    Yuv444p12le,
+    Yuva420p,
+    Yuva422p,
+    Gbrp,
+    Gray,
+}
+
+/// The `--pix-fmt` names of the pixel formats, which are FFmpeg's.
+const PIX_FMTS: [(&str, PixelFormat); 14] = [
+    ("auto", PixelFormat::Auto),
+    ("yuv420p", PixelFormat::Yuv420p),
+    ("yuv422p", PixelFormat::Yuv422p),
+    ("yuv444p", PixelFormat::Yuv444p),
+    ("yuv420p10le", PixelFormat::Yuv420p10le),
+    ("yuv422p10le", PixelFormat::Yuv422p10le),
+    ("yuv444p10le", PixelFormat::Yuv444p10le),
+    ("yuv420p12le", PixelFormat::Yuv420p12le),
+    ("yuv422p12le", PixelFormat::Yuv422p12le),
+    ("yuv444p12le", PixelFormat::Yuv444p12le),
+    ("yuva420p", PixelFormat::Yuva420p),
+    ("yuva422p", PixelFormat::Yuva422p),
+    ("gbrp", PixelFormat::Gbrp),
+    ("gray", PixelFormat::Gray),
+];
+
+impl PixelFormat {
+    fn name(self) -> &'static str {
+        PIX_FMTS
+            .iter()
+            .find(|&&(_, format)| format == self)
+            .map(|&(name, _)| name)
+            .unwrap()
+    }
+}
+
+impl std::str::FromStr for PixelFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, String> {
+        PIX_FMTS
+            .iter()
+            .find(|(name, _)| *name == format)
+            .map(|&(_, format)| format)
+            .ok_or_else(|| format!("Invalid pixel format: {}", format))
+    }
+}
+
+/// The `--bit-depth` choice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BitDepth {
+    Keep,
+    Bits(u8),
+}
+
+fn parse_bit_depth(depth: &str) -> Result<BitDepth, String> {
+    match depth {
+        "keep" => Ok(BitDepth::Keep),
+        "8" | "10" | "12" => Ok(BitDepth::Bits(depth.parse().unwrap())),
+        _ => Err(format!("Invalid bit depth: {}", depth)),
+    }
+}
+
+/// The `--convert-range` choice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RangeConversion {
+    None,
+    ToLimited,
+    ToFull,
+}
+
+impl RangeConversion {
+    /// The range frames in `range` are written in.
+    fn target(self, range: ColorRange) -> ColorRange {
+        match self {
+            RangeConversion::None => range,
+            RangeConversion::ToLimited => ColorRange::Limited,
+            RangeConversion::ToFull => ColorRange::Full,
+        }
+    }
+}
+
+fn parse_range_conversion(conversion: &str) -> Result<RangeConversion, String> {
+    match conversion {
+        "none" => Ok(RangeConversion::None),
+        "to-limited" => Ok(RangeConversion::ToLimited),
+        "to-full" => Ok(RangeConversion::ToFull),
+        _ => Err(format!("Invalid range conversion: {}", conversion)),
+    }
+}
+
+/// How segment files are named, chosen with `--naming`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Naming {
+    /// `<start>-<end>` in frames.
+    Frames,
+    /// `<start>-<end>` as presentation times, e.g. `00h01m23s456`.
+    Timecode,
+}
+
+fn parse_naming(naming: &str) -> Result<Naming, String> {
+    match naming {
+        "frames" => Ok(Naming::Frames),
+        "timecode" => Ok(Naming::Timecode),
+        _ => Err(format!("Invalid naming: {}", naming)),
+    }
+}
+
+fn parse_range_convention(convention: &str) -> Result<RangeConvention, String> {
+    RangeConvention::from_name(convention)
+        .ok_or_else(|| format!("Invalid range convention: {}", convention))
+}
+
 #[derive(Debug, StructOpt)]
 struct CliArgs {
+    /// Read defaults for any of the options below from this TOML file, as
+    /// `<option> = <value>` lines such as `threads = 4` or
+    /// `output-folder = "/srv/segments"`. Options on the command line win
+    #[structopt(long = "config", parse(from_os_str))]
+    config: Option<PathBuf>,
     /// Set FFmpeg verbosity level
     #[structopt(short = "v", long = "verbose", default_value = "0")]
     verbose: usize,
+    /// Write nothing to stderr, not even errors, so it can be shared with an
+    /// encoder. stdout and --log-file are unaffected, and failures still
+    /// show in the exit code
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
     /// Disable progress reporting
     #[structopt(short = "p", long = "progress")]
     progress: bool,
-    /// The file to be indexed
+    /// Write indexing progress as JSON records with the percentage, elapsed
+    /// seconds and ETA to this already open file descriptor, e.g. 3 with
+    /// `3>progress.jsonl`. Unix only
+    #[structopt(long = "progress-fd")]
+    progress_fd: Option<i32>,
+    /// Write `queued`, `started`, `progress`, `finished` and `failed`
+    /// events of every segment as JSON lines to this already open file
+    /// descriptor, 2 for stderr. Unix only
+    #[structopt(long = "events-fd")]
+    events_fd: Option<i32>,
+    /// Also append diagnostics to this file, each line with a UTC
+    /// timestamp and a level. stdout is unaffected
+    #[structopt(long = "log-file", parse(from_os_str))]
+    log_file: Option<PathBuf>,
+    /// Write diagnostics as `text` or as `json`, one object per line with
+    /// timestamp, level, event, message and fields such as the segment
+    /// start or frame number. Applies to stderr and --log-file
+    #[structopt(
+        long = "log-format",
+        default_value = "text",
+        parse(try_from_str = log::parse_format)
+    )]
+    log_format: log::Format,
+    /// The file to be indexed, or an image sequence named by a frame
+    /// number pattern such as `plate.%04d.exr` or a glob such as `*.png`
     #[structopt(parse(from_os_str))]
     input_file: PathBuf,
-    // If errors should be ignored
-    #[structopt(short = "e", long = "ignore-errors", default_value = "0")]
-    ignore_errors: usize,
-    /// The output folder.
+    /// What indexing does on a decoding error: ignore it, stop indexing the
+    /// track, clear the track's index or abort. The numbers 0 to 3 name the
+    /// same, in that order
+    #[structopt(
+        short = "e",
+        long = "ignore-errors",
+        default_value = "ignore",
+        parse(try_from_str = parse_index_error_handling)
+    )]
+    ignore_errors: IndexErrorHandling,
+    /// Demuxer option passed to FFmpeg when opening the inputs, as
+    /// `key=value`, e.g. `--source-opt enable_drefs=1`. Repeatable. The
+    /// options are kept in the index, so --use-index opens the files with
+    /// them too. Needs the demuxer-options feature
+    #[structopt(
+        long = "source-opt",
+        number_of_values = 1,
+        parse(try_from_str = indexer::parse_option)
+    )]
+    source_options: Vec<(String, String)>,
+    /// Open the MOV or MP4 media that a reference file points to in other
+    /// files, as `--source-opt enable_drefs=1` does. Needs the
+    /// demuxer-options feature
+    #[structopt(long = "enable-drefs")]
+    enable_drefs: bool,
+    /// Follow the absolute paths of --enable-drefs references too, not just
+    /// the paths relative to the reference file
+    #[structopt(long = "use-absolute-path", requires = "enable-drefs")]
+    use_absolute_path: bool,
+    /// Additional input file. Requests of the form `<input> <start> <end>`
+    /// select it by position, 1 for the first --input
+    #[structopt(long = "input", number_of_values = 1, parse(from_os_str))]
+    inputs: Vec<PathBuf>,
+    /// Join the main input and every --input into one timeline, so
+    /// requests for `<start> <end>` of it can span reel boundaries. The
+    /// inputs must share their size, frame rate and pixel format, and a
+    /// single properties line is announced for all of them
+    #[structopt(
+        long = "concat",
+        requires = "inputs",
+        conflicts_with_all = &[
+            "timecodes", "pts", "subtitles", "complexity", "align-keyframes", "chapters",
+            "auto-split", "chunks", "watch", "evict-idle", "first-pass", "cfr", "ivtc"
+        ]
+    )]
+    concat: bool,
+    /// The output folder, created if it does not exist.
     /// Default to "." if not specified
     #[structopt(parse(from_os_str))]
     output_folder: Option<PathBuf>,
+    /// Number of decoder threads. Defaults to the cores available to the
+    /// process, which 0 also asks for
+    #[structopt(short = "t", long = "threads")]
+    threads: Option<usize>,
+    /// Index of the video track to segment. Defaults to the first video track
+    #[structopt(long = "track")]
+    track: Option<usize>,
+    /// Print `<track> <type> <frames>` for every track and exit. `tracks
+    /// <file>` describes them in more detail
+    #[structopt(long = "list-tracks")]
+    list_tracks: bool,
+    /// Set by the `tracks` subcommand.
+    #[structopt(skip)]
+    describe_tracks: bool,
+    /// Set by the `verify` subcommand, which reads the input file as a
+    /// --manifest.
+    #[structopt(skip)]
+    verify_manifest: bool,
+    /// Write the index of the input file to this path after indexing
+    #[structopt(long = "write-index", parse(from_os_str))]
+    write_index: Option<PathBuf>,
+    /// Write the keyframes, the PTS of every frame and the time base of
+    /// every track of the input file's index to this path as JSON after
+    /// indexing, for planners that do not link FFMS2
+    #[structopt(long = "dump-index", parse(from_os_str))]
+    dump_index: Option<PathBuf>,
+    /// Load the index of the input file from this path, written by
+    /// ffmsindex or --write-index, instead of indexing. Fails if the index
+    /// is for another file
+    #[structopt(long = "use-index", parse(from_os_str), conflicts_with = "watch")]
+    use_index: Option<PathBuf>,
+    /// Map the --use-index file into memory instead of reading it, so only
+    /// the pages FFMS2 touches are read, and they stay page cache rather
+    /// than memory of the process. Unix only
+    #[structopt(long = "mmap-index", requires = "use-index")]
+    mmap_index: bool,
+    /// Index the input, write --write-index if given, print the tracks like
+    /// --list-tracks and exit, to build indexes ahead of time
+    #[structopt(long = "index-only")]
+    index_only: bool,
+    /// Print a JSON description of the input with its size, frame rate,
+    /// pixel format, color properties and tracks, and exit. The `info
+    /// [input]` command sends the same document
+    #[structopt(long = "info-json")]
+    info_json: bool,
+    /// Print `<frame> <type> <pts> <keyframe>` for every frame in
+    /// START-END and exit
+    #[structopt(long = "stats", parse(try_from_str = stats::parse_range))]
+    stats: Option<(usize, usize)>,
+    /// Print a `<first> <last> <frames> <ssim>` line for every run of
+    /// consecutive identical frames in the range `<start>-<end>` and exit,
+    /// to find bad pulldown or dropped frames of captures
+    #[structopt(long = "duplicates", parse(try_from_str = stats::parse_range))]
+    duplicates: Option<(usize, usize)>,
+    /// Also count frames as duplicates of the one before them when their
+    /// luma SSIM to it reaches this score, such as 0.98 for noisy captures
+    #[structopt(
+        long = "duplicate-ssim",
+        requires = "duplicates",
+        parse(try_from_str = duplicates::parse_ssim)
+    )]
+    duplicate_ssim: Option<f64>,
+    /// Print a `<first> <last> <cut>` line for every interval of black
+    /// frames in the range `<start>-<end>` and exit, with the middle frame
+    /// of each as a candidate cut point for splitting at fades to black
+    #[structopt(long = "detect-black", parse(try_from_str = stats::parse_range))]
+    detect_black: Option<(usize, usize)>,
+    /// How far above black, as a share of the luma range, a pixel can be
+    /// and count as black. Frames are black when 98% of their pixels are
+    #[structopt(
+        long = "black-threshold",
+        default_value = "0.1",
+        parse(try_from_str = black::parse_threshold)
+    )]
+    black_threshold: f64,
+    /// Only report the black frames during which the first --audio-track
+    /// is also quieter than this level in dBFS, such as -50, so cut points
+    /// land on natural breaks
+    #[structopt(
+        long = "silence-threshold",
+        requires = "detect-black",
+        allow_hyphen_values = true,
+        parse(try_from_str = silence::parse_level)
+    )]
+    silence_threshold: Option<f64>,
+    /// Print a `<first> <last> <matches> <dropped>` line for every cycle of
+    /// five frames in the range `<start>-<end>` and exit, showing the field
+    /// matches and repeated frame 3:2 pulldown would be undone with by
+    /// --ivtc
+    #[structopt(long = "detect-telecine", parse(try_from_str = stats::parse_range))]
+    detect_telecine: Option<(usize, usize)>,
+    /// Decode the whole input once, write the keyframe flag and scene score
+    /// of every frame to this stats file, and exit. A later run given it
+    /// with --first-pass picks boundaries without analyzing again
+    #[structopt(long = "analyze", parse(from_os_str))]
+    analyze: Option<PathBuf>,
+    /// Read the stats an earlier --analyze run wrote for the input: end
+    /// --auto-split segments on the strongest scene change up to twice
+    /// their length, and add a `complexity=<mean diff>` token to the lines
+    /// listing --chunks
+    #[structopt(long = "first-pass", parse(from_os_str))]
+    first_pass: Option<PathBuf>,
+    /// Serve decoded frames of the input on a Unix socket at this path
+    /// instead of writing segments. Clients send a little endian u32 frame
+    /// number and receive a status byte, 0 followed by the three planes as
+    /// u32 byte counts and samples, or 1 followed by an error message.
+    /// Frame numbers with the top bit set are answered with the byte counts
+    /// only and a memfd holding the planes, passed as SCM_RIGHTS. Linux only
+    #[structopt(long = "frame-server", parse(from_os_str))]
+    frame_server: Option<PathBuf>,
+    /// FFMS2 seek mode used when requests jump around the file. With
+    /// `normal`, `unsafe` or `aggressive`, a segment that fails to decode,
+    /// or whose keyframes do not match the index, is written again with
+    /// `linear-no-rw`, which the input then keeps
+    #[structopt(
+        long = "seek-mode",
+        default_value = "normal",
+        possible_values = &["linear-no-rw", "linear", "normal", "unsafe", "aggressive"],
+        parse(try_from_str = parse_seek_mode)
+    )]
+    seek_mode: video::SeekMode,
+    /// Per-frame annotation file with `<start> <end> <label>` or
+    /// `<frame> <label>` lines
+    #[structopt(long = "annotations", parse(from_os_str))]
+    annotations: Option<PathBuf>,
+    /// Split requested ranges at the edges of annotations with this label
+    #[structopt(long = "split-label", number_of_values = 1)]
+    split_label: Vec<String>,
+    /// Leave frames covered by annotations with this label out of segments
+    #[structopt(long = "exclude-label", number_of_values = 1)]
+    exclude_label: Vec<String>,
+    /// Snap requested ranges to keyframes: `expand` to the enclosing ones,
+    /// `shrink` to the enclosed ones, or `error` to refuse unaligned ranges
+    #[structopt(
+        long = "align-keyframes",
+        possible_values = &["expand", "shrink", "error"],
+        parse(try_from_str = align::parse_align)
+    )]
+    align_keyframes: Option<align::Align>,
+    /// Range of the source samples: `limited`, `full`, or `auto` to follow
+    /// the range signalled by the source. The range is marked in the y4m
+    /// header as `XCOLORRANGE`, next to the matrix, primaries and transfer
+    /// of the source
+    #[structopt(
+        long = "color-range",
+        default_value = "limited",
+        possible_values = &["limited", "full", "auto"],
+        parse(try_from_str = parse_range_selection)
+    )]
+    color_range: RangeSelection,
+    /// Convert the samples of frames to this range instead of only marking
+    /// the one they are in: `to-limited` for full range camera files, or
+    /// `to-full`. The source range is the one --color-range settles on
+    #[structopt(
+        long = "convert-range",
+        default_value = "none",
+        possible_values = &["none", "to-limited", "to-full"],
+        parse(try_from_str = parse_range_conversion)
+    )]
+    convert_range: RangeConversion,
+    /// Write the alpha plane of yuva420p and yuva422p inputs next to each
+    /// segment as a monochrome `.alpha.y4m` stream, since y4m can only carry
+    /// alpha in 4:4:4
+    #[structopt(long = "alpha")]
+    alpha: bool,
+    /// Write segments as RGB instead of y4m: `raw` for packed rgb24 frames
+    /// in one file, or `png` for a folder of numbered PNGs that is always
+    /// written to the output folder. Required for gbrp inputs, which are
+    /// then written without a round trip through YUV
+    #[structopt(
+        long = "rgb",
+        possible_values = &["raw", "png"],
+        parse(try_from_str = rgb::parse_rgb_output)
+    )]
+    rgb: Option<rgb::RgbOutput>,
+    /// Container of segment files: `y4m`, the default, `mkv` for playable
+    /// clips of the uncompressed frames with the --audio-track tracks as
+    /// PCM, `nut` for the frames with their timestamps in NUT, or `raw` for
+    /// bare planar frames in `.yuv` files described by a `.json` next to
+    /// them. Audio is left out of `reverse` segments. Not with --rgb
+    #[structopt(
+        long = "output-format",
+        possible_values = &["y4m", "mkv", "nut", "raw"],
+        parse(try_from_str = parse_container),
+        conflicts_with = "rgb"
+    )]
+    output_format: Option<Container>,
+    /// Audio track to mux into mkv segments, by its --list-tracks number, or
+    /// `all`. Repeat it for several tracks. Defaults to the first audio
+    /// track
+    #[structopt(
+        long = "audio-track",
+        number_of_values = 1,
+        parse(try_from_str = parse_audio_selection)
+    )]
+    audio_tracks: Vec<AudioSelection>,
+    /// Sample rate of the audio of mkv segments. FFMS2 cannot resample, so
+    /// inputs at other rates fail instead of muxing audio at the wrong speed
+    #[structopt(long = "audio-rate")]
+    audio_rate: Option<u32>,
+    /// Mix the audio of mkv segments to 1, 2, 6 or 8 channels
+    #[structopt(long = "audio-channels", possible_values = &["1", "2", "6", "8"])]
+    audio_channels: Option<u32>,
+    /// Sample format of the audio of mkv segments
+    #[structopt(
+        long = "audio-sample-format",
+        possible_values = &["u8", "s16", "s32", "flt", "dbl"],
+        parse(try_from_str = mkv::parse_sample_format)
+    )]
+    audio_sample_format: Option<mkv::SampleFormat>,
+    /// Frame size and pixel format of the inputs as WIDTHxHEIGHT:PIXFMT,
+    /// e.g. `1920x1080:yuv420p10le`, for --no-probe
+    #[structopt(long = "source-format", parse(try_from_str = parse_source_format))]
+    source_format: Option<SourceFormat>,
+    /// Take the input format from --source-format instead of decoding the
+    /// first frame at startup. Frames are treated as progressive and
+    /// `--color-range auto` falls back to limited
+    #[structopt(long = "no-probe", requires = "source-format")]
+    no_probe: bool,
+    /// What to do with frames that fail to decode: `abort` the segment,
+    /// `skip` them, repeat the closest earlier frame with
+    /// `duplicate-previous`, or write a `blank` black frame. Substitutions are
+    /// logged
+    #[structopt(
+        long = "on-decode-error",
+        default_value = "abort",
+        possible_values = &["abort", "skip", "duplicate-previous", "blank"],
+        parse(try_from_str = parse_decode_error_mode)
+    )]
+    on_decode_error: DecodeErrorMode,
+    /// What decodes the frames of segments: `ffms2`, or `ffmpeg` through
+    /// ffmpeg-next, which finds the frames by the timestamps FFMS2 indexed.
+    /// Indexing and the other commands stay on FFMS2. Needs the ffmpeg
+    /// feature
+    #[structopt(
+        long = "decoder",
+        default_value = "ffms2",
+        possible_values = &["ffms2", "ffmpeg"],
+        parse(try_from_str = parse_backend)
+    )]
+    decoder: Backend,
+    /// Give up on a segment whose next frame takes longer than this many
+    /// seconds to decode, as some corrupt files make the decoder hang. The
+    /// request is answered with `<start> <end> timed out`, the input gets
+    /// a new decoder and the session carries on, exiting with status 8
+    #[structopt(long = "decode-timeout")]
+    decode_timeout: Option<u64>,
+    /// Give up on a segment that takes longer than this many seconds to
+    /// decode, as --decode-timeout does
+    #[structopt(long = "segment-timeout")]
+    segment_timeout: Option<u64>,
+    /// Deinterlace decoded frames before scaling and writing them
+    #[structopt(
+        long = "deinterlace",
+        possible_values = &["bwdif", "yadif"],
+        parse(try_from_str = filter::parse_deinterlace)
+    )]
+    deinterlace: Option<filter::Deinterlace>,
+    /// Inverse telecine 3:2 pulldown, matching the fields of each frame and
+    /// dropping one frame in five, for progressive segments at 4/5 of the
+    /// frame rate such as 24000/1001 from NTSC. Requests and the properties
+    /// line then count the film frames
+    #[structopt(
+        long = "ivtc",
+        conflicts_with_all = &[
+            "deinterlace", "cfr", "timecodes", "pts", "subtitles", "complexity",
+            "align-keyframes", "chapters", "auto-split", "chunks", "watch", "evict-idle",
+            "first-pass"
+        ]
+    )]
+    ivtc: bool,
+    /// Resize frames to WIDTHxHEIGHT, e.g. for proxy generation
+    #[structopt(long = "scale", parse(try_from_str = scale::parse_size))]
+    scale: Option<PlaneSize>,
+    /// Convert high bit depth frames to 8-bit
+    #[structopt(long = "scale-8bit")]
+    scale_8bit: bool,
+    /// Dither the frames of --scale-8bit instead of rounding them, to keep
+    /// gradients from banding: `ordered` adds a Bayer pattern, which the
+    /// GPU can do too, `error-diffusion` is Floyd-Steinberg on the CPU
+    #[structopt(
+        long = "dither",
+        default_value = "none",
+        possible_values = &["none", "ordered", "error-diffusion"],
+        parse(try_from_str = scale::parse_dither)
+    )]
+    dither: scale::Dither,
+    /// Pixel format FFMS2 converts decoded frames to, or `auto` to keep the
+    /// input's. `auto` only converts gray10le, which y4m cannot carry, to
+    /// yuv420p10le
+    #[structopt(
+        long = "pix-fmt",
+        default_value = "auto",
+        possible_values = &[
+            "auto", "yuv420p", "yuv422p", "yuv444p", "yuv420p10le", "yuv422p10le",
+            "yuv444p10le", "yuv420p12le", "yuv422p12le", "yuv444p12le", "yuva420p",
+            "yuva422p", "gbrp", "gray"
+        ]
+    )]
+    pix_fmt: PixelFormat,
+    /// Bit depth of the output, keeping the input's chroma subsampling, or
+    /// `keep` for the input's. Deeper gray is written as yuv with neutral
+    /// chroma, and alpha is dropped above 8 bits
+    #[structopt(
+        long = "bit-depth",
+        default_value = "keep",
+        possible_values = &["8", "10", "12", "keep"],
+        parse(try_from_str = parse_bit_depth)
+    )]
+    bit_depth: BitDepth,
+    /// Scale and convert frames on the GPU, falling back to the CPU if no
+    /// adapter is available
+    #[structopt(long = "gpu")]
+    gpu: bool,
+    /// Run the `<start> <end>` segment plan in this file twice without
+    /// writing output, report stages whose hashes differ, and exit
+    #[structopt(long = "determinism-check", parse(from_os_str))]
+    determinism_check: Option<PathBuf>,
+    /// Decoder threads for the second determinism run. Defaults to --threads
+    #[structopt(long = "determinism-threads")]
+    determinism_threads: Option<usize>,
+    /// Decode this many frames without writing output, print decode fps
+    /// and per-frame latency, and exit. Compare --threads and --seek-mode
+    /// values with it
+    #[structopt(long = "bench")]
+    bench: Option<usize>,
+    /// Frames decoded by --bench: `sequential` from the first frame,
+    /// `random` seeking to the same pseudo-random frames every run, or
+    /// `both`
+    #[structopt(
+        long = "bench-pattern",
+        default_value = "both",
+        parse(try_from_str = bench::parse_pattern)
+    )]
+    bench_pattern: bench::Pattern,
+    /// Write an mkvmerge timecodes v2 file next to each segment
+    #[structopt(long = "timecodes")]
+    timecodes: bool,
+    /// Write the original PTS and presentation time of every frame next to
+    /// each segment as `.pts.txt`, for muxers rebuilding VFR timing
+    #[structopt(long = "pts")]
+    pts: bool,
+    /// Write the stream's static HDR metadata (mastering display and
+    /// content light level) as JSON next to each segment, as y4m cannot
+    /// carry it. Per-frame HDR10+ and Dolby Vision metadata are not
+    /// available
+    #[structopt(long = "hdr-metadata")]
+    hdr_metadata: bool,
+    /// An `.srt` or `.ass` file of the main input whose cues are cut into a
+    /// retimed file of the same type next to each of its segments. Times
+    /// are taken as relative to the first frame. FFMS2 cannot read
+    /// subtitle tracks, so embedded ones need extracting first
+    #[structopt(long = "subtitles", parse(from_os_str))]
+    subtitles: Option<PathBuf>,
+    /// Write a `<frame> <crc32>` line per output frame next to each segment
+    #[structopt(long = "checksums")]
+    checksums: bool,
+    /// Write a `.complexity` report next to each segment with its length,
+    /// keyframe count, mean absolute luma difference between frames and
+    /// the average bitrate of the input, for budgeting encodes per chunk
+    #[structopt(long = "complexity")]
+    complexity: bool,
+    /// Write a cue file with the sequence number and segment count of the
+    /// request next to each segment
+    #[structopt(long = "cues")]
+    cues: bool,
+    /// Check frames for concealment garbage such as zeroed or saturated
+    /// planes and either record (`flag`) or `replace` them with the last
+    /// good frame. Rejected frames are listed next to each segment
+    #[structopt(
+        long = "quality-gate",
+        possible_values = &["flag", "replace"],
+        parse(try_from_str = quality::parse_mode)
+    )]
+    quality_gate: Option<quality::Mode>,
+    /// Whether the end of a `<start> <end>` request is the first frame after
+    /// the range (`exclusive`) or its last frame (`inclusive`), for requests
+    /// read from stdin, `--zones` and `--macros`. Segments are still named
+    /// and answered with exclusive ends
+    #[structopt(
+        long = "range-convention",
+        default_value = "exclusive",
+        possible_values = &["exclusive", "inclusive"],
+        parse(try_from_str = parse_range_convention)
+    )]
+    range_convention: RangeConvention,
+    /// Name segments after their first and end frame (`frames`), or the
+    /// presentation times of those frames from the start of the input
+    /// (`timecode`), as `00h01m23s456-00h02m00s000.y4m`. Requests are still
+    /// answered with frame numbers
+    #[structopt(
+        long = "naming",
+        default_value = "frames",
+        possible_values = &["frames", "timecode"],
+        parse(try_from_str = parse_naming)
+    )]
+    naming: Naming,
+    /// Process the `<start> <end>` requests in this file instead of reading
+    /// them from stdin, journaling completed segments to
+    /// `segments.journal` in the output folder
+    #[structopt(long = "zones", parse(from_os_str))]
+    zones: Option<PathBuf>,
+    /// Skip zones whose segments are in the journal and still match it
+    #[structopt(long = "resume", requires = "zones")]
+    resume: bool,
+    /// Overwrite segment files that already exist in the output folder.
+    /// Without it they are skipped and answered with `<start> <path>
+    /// skipped (exists)`, except with --resume, where the journal decides
+    #[structopt(long = "force")]
+    force: bool,
+    /// Restore the track, scale and unfinished requests from this file on
+    /// startup and save them to it on exit
+    #[structopt(long = "session", parse(from_os_str))]
+    session: Option<PathBuf>,
+    /// Write a JSON manifest of every segment written, with its path,
+    /// range, frame count, duration, size and CRC-32, to this path when
+    /// the session ends
+    #[structopt(long = "manifest", parse(from_os_str))]
+    manifest: Option<PathBuf>,
+    /// Write an HLS media playlist of the segments written, in frame order
+    /// with their durations, to this path when the session ends. Segment
+    /// paths are relative to the playlist's folder. Players need segments
+    /// in a container they stream, as with `--output-format mkv`
+    #[structopt(long = "hls-playlist", parse(from_os_str))]
+    hls_playlist: Option<PathBuf>,
+    /// File of `<name> <params...> = <command>[; <command>...]` macros that
+    /// can be used in place of stdin commands
+    #[structopt(long = "macros", parse(from_os_str))]
+    macros: Option<PathBuf>,
+    /// Treat the input as a file that is still being written: re-index it
+    /// when a request goes past the indexed frames and refuse requests
+    /// beyond the frames available so far
+    #[structopt(long = "watch")]
+    watch: bool,
+    /// Minimum number of seconds between re-indexes in --watch mode
+    #[structopt(long = "watch-interval", default_value = "5")]
+    watch_interval: u64,
+    /// Validate the requests from --zones or stdin, print the segments that
+    /// would be written with their sizes, and exit without decoding them
+    #[structopt(long = "dry-run")]
+    dry_run: bool,
+    /// Use length-prefixed messages on stdin and stdout instead of lines:
+    /// a little endian u32 byte count followed by the UTF-8 message
+    #[structopt(long = "binary")]
+    binary: bool,
+    /// Prompt for commands typed by hand instead of reading the pipe
+    /// protocol: `help`, `info`, `keyframes`, `extract`, `open` and `quit`.
+    /// Build with the readline feature for line editing and history
+    #[structopt(
+        long = "interactive",
+        conflicts_with_all = &[
+            "zones", "chapters", "auto-split", "grpc", "http", "websocket", "daemon", "binary",
+            "dry-run"
+        ]
+    )]
+    interactive: bool,
+    /// Write stdout responses as `plain` space separated fields, `json`
+    /// objects with a `type` such as `segment` or `properties`, or `tsv`
+    #[structopt(
+        long = "format",
+        default_value = "plain",
+        possible_values = &["plain", "json", "tsv"],
+        parse(try_from_str = transport::parse_format)
+    )]
+    format: transport::Format,
+    /// Send a `hello protocol=<n> version=<v> commands=... colorspaces=...
+    /// formats=...` line before anything else on stdout, so controllers can
+    /// check for features instead of the binary's version
+    #[structopt(
+        long = "handshake",
+        conflicts_with_all = &["grpc", "http", "websocket", "daemon"]
+    )]
+    handshake: bool,
+    /// Serve Index, Extract and Status RPCs on this address instead of
+    /// reading requests from stdin. Requires the `grpc` feature
+    #[structopt(long = "grpc")]
+    grpc: Option<std::net::SocketAddr>,
+    /// Serve a REST API on this address instead of reading requests from
+    /// stdin: `GET /info`, `POST /segments` with `{"start":N,"end":M}` and
+    /// an optional `"priority"`, `GET /segments/<id>`, `DELETE
+    /// /segments/<id>` to cancel, `GET /segments/<id>/files/<n>`, `GET
+    /// /status` and `GET /metrics`
+    #[structopt(long = "http")]
+    http: Option<std::net::SocketAddr>,
+    /// Accept WebSocket clients on this address instead of reading requests
+    /// from stdin. Clients send the stdin commands, and `cancel <id>` for
+    /// their requests, as text messages and receive JSON progress, segment
+    /// and completion events
+    #[structopt(long = "websocket")]
+    websocket: Option<std::net::SocketAddr>,
+    /// Run as a daemon serving any number of clients on a Unix socket at
+    /// this path instead of reading requests from stdin. Each connection is
+    /// greeted with `session <id>` and the properties of the open inputs,
+    /// and speaks the stdin commands and `cancel <id>`, which only the
+    /// server modes can act on; each request is answered with
+    /// `queued <request> <command>`, its responses prefixed with the
+    /// request id, and `finished <request>`. Unix only
+    #[structopt(long = "daemon", parse(from_os_str))]
+    daemon: Option<PathBuf>,
+    /// Close inputs no request of the server modes has used for this many
+    /// seconds, reopening them from their index when next requested
+    #[structopt(long = "evict-idle")]
+    evict_idle: Option<u64>,
+    /// Seconds the server modes take on SIGTERM to finish the queued
+    /// requests, refusing new ones, before abandoning the rest as on Ctrl-C
+    #[structopt(long = "drain-timeout", default_value = "25")]
+    drain_timeout: u64,
+    /// Serve Prometheus metrics as `GET /metrics` on this address, for the
+    /// modes without `--http`
+    #[structopt(long = "metrics")]
+    metrics: Option<std::net::SocketAddr>,
+    /// Queue at most this many requests from --grpc, --http, --websocket
+    /// and --daemon clients besides the one being written
+    #[structopt(long = "queue-capacity")]
+    queue_capacity: Option<usize>,
+    /// What a full --queue-capacity does with more requests: `reject` them
+    /// with a queue full error, or `block` the client until there is room
+    #[structopt(
+        long = "queue-full",
+        default_value = "reject",
+        possible_values = &["reject", "block"],
+        parse(try_from_str = transport::parse_queue_full)
+    )]
+    queue_full: transport::QueueFull,
+    /// Upload segments to `s3://bucket/prefix` or `gs://bucket/prefix`
+    /// instead of the output folder, which still receives sidecar files.
+    /// Requires the `cloud` feature
+    #[structopt(long = "output-url")]
+    output_url: Option<String>,
+    /// Pipe each segment into this shell command instead of writing a y4m
+    /// file, e.g. `aomenc -o {out}.ivf -`. `{out}` is the segment's path in
+    /// the output folder without extension; `{start}` and `{end}` are its
+    /// frame range. A failing command stops the session
+    #[structopt(long = "exec")]
+    exec: Option<String>,
+    /// Write every segment into one continuous y4m stream at this path,
+    /// usually a named pipe read by one long-lived encoder, instead of a
+    /// file per segment. Only the first segment's header is written, so
+    /// all of them must share a format. Responses gain `stream_start=<n>
+    /// stream_end=<n>`, the frames of the segment within the stream
+    #[structopt(
+        long = "stream",
+        parse(from_os_str),
+        conflicts_with_all = &["exec", "output-url", "compress", "mmap", "rgb", "resume"]
+    )]
+    stream: Option<PathBuf>,
+    /// Compress y4m and raw segments with zstd as they are written, at the
+    /// level after `zstd:` from 1 to 22, 3 by default. Segments are named
+    /// `.y4m.zst` or `.yuv.zst`. Needs the compress feature
+    #[structopt(
+        long = "compress",
+        parse(try_from_str = output::parse_compression),
+        conflicts_with_all = &["rgb", "mmap", "exec"]
+    )]
+    compress: Option<output::Compression>,
+    /// Follow the `<start> <path>` line of each segment written with
+    /// ` frames=<n> decode_fps=<fps> bytes=<n> write_mibps=<MiB/s>
+    /// peak_rss_mib=<MiB>`: the frames decoded, padding included, how fast
+    /// they decoded, the segment's size over the time taken to write it,
+    /// and the most memory the process has used so far
+    #[structopt(long = "segment-stats")]
+    segment_stats: bool,
+    /// Run this shell command after each segment is written, with `{path}`
+    /// replaced by its path and `{start}` and `{end}` by its frame range,
+    /// e.g. to upload it or submit it to an encoder. Commands run in the
+    /// background; failures are answered with `<start> <path> post-cmd
+    /// failed: <reason>`
+    #[structopt(long = "post-cmd")]
+    post_cmd: Option<String>,
+    /// Number of --post-cmd commands that may run at once
+    #[structopt(long = "post-jobs", default_value = "1")]
+    post_jobs: usize,
+    /// Write segments through a memory mapping of each file, preallocated
+    /// to the segment's size, instead of a write call per frame. Unix only
+    #[structopt(long = "mmap", conflicts_with_all = &["rgb", "exec", "output-url"])]
+    mmap: bool,
+    /// Write segments from a background thread that queues up to this many
+    /// MiB, so a slow filesystem or upload does not stall decoding until
+    /// the queue fills
+    #[structopt(long = "write-buffer")]
+    write_buffer: Option<usize>,
+    /// KiB of each segment collected before writing to its file, as many
+    /// small writes are slow on network filesystems. 0 writes unbuffered
+    #[structopt(long = "write-buffer-kb", default_value = "1024")]
+    write_buffer_kb: usize,
+    /// Split segments into several files wherever one would be larger than
+    /// this many bytes, for filesystems limited to 4 GiB files. With
+    /// --align-keyframes every part starts on a keyframe. Sizes count
+    /// padding but, for mkv and nut, neither audio nor container overhead
+    #[structopt(long = "max-segment-bytes")]
+    max_segment_bytes: Option<u64>,
+    /// Write one segment per chapter of the main input, read from its
+    /// Matroska chapters, instead of reading requests. Segments are named
+    /// `<nn>-<title>` after the chapters
+    #[structopt(
+        long = "chapters",
+        conflicts_with_all = &["zones", "grpc", "http", "websocket", "daemon", "binary"]
+    )]
+    chapters: bool,
+    /// Write the whole main input as keyframe aligned segments of at least
+    /// this many frames, or seconds with an `s` suffix such as `10s`,
+    /// instead of reading requests
+    #[structopt(
+        long = "auto-split",
+        parse(try_from_str = chunks::parse_length),
+        conflicts_with_all = &[
+            "chapters", "zones", "grpc", "http", "websocket", "daemon", "binary"
+        ]
+    )]
+    auto_split: Option<chunks::Length>,
+    /// Write the whole main input as segments starting at the frames
+    /// listed in this file instead of reading requests: one frame number
+    /// per line, an x264 qpfile, or the XviD pass stats of SCXviD
+    #[structopt(
+        long = "cuts",
+        parse(from_os_str),
+        conflicts_with_all = &[
+            "chapters", "zones", "auto-split", "grpc", "http", "websocket", "daemon", "binary",
+            "interactive"
+        ]
+    )]
+    cuts: Option<PathBuf>,
+    /// Write an x264/x265 qpfile forcing a keyframe at every segment start
+    /// of the --zones, --chapters, --auto-split or --cuts plan, so a full
+    /// file encode with it cuts where the segments do
+    #[structopt(long = "qpfile", parse(from_os_str))]
+    qpfile: Option<PathBuf>,
+    /// Write an ffmpeg `-force_key_frames` expression for the segment
+    /// starts of the plan to this path, as --qpfile does for x264
+    #[structopt(long = "force-key-frames", parse(from_os_str))]
+    force_key_frames: Option<PathBuf>,
+    /// Serve the chunks of the first input for av1an: an av1an scenes.json,
+    /// or `keyframes` to split at every keyframe. `chunks` lists them as
+    /// `chunk <index> <first frame> <last frame> <frames>` lines after a
+    /// `chunks <count>` line, and `chunk <index>` writes one
+    #[structopt(long = "chunks", parse(from_os_str))]
+    chunks: Option<PathBuf>,
+    /// Frames to include before each segment. Requests can override it with
+    /// a `pad-before=<n>` token. Segment files keep the name of the
+    /// requested range, and cue files record the padding actually applied
+    #[structopt(long = "pad-before", default_value = "0")]
+    pad_before: usize,
+    /// Frames to include after each segment, overridden by `pad-after=<n>`
+    #[structopt(long = "pad-after", default_value = "0")]
+    pad_after: usize,
+    /// Write frames as decoded, ignoring the rotation stored by phones and
+    /// some cameras. Otherwise frames are turned upright, and 90 and 270
+    /// degree turns swap the output width and height
+    #[structopt(long = "no-autorotate")]
+    no_autorotate: bool,
+    /// Frame rate numerator of the output, instead of the container's
+    #[structopt(long = "fps-num")]
+    fps_num: Option<i32>,
+    /// Frame rate denominator of the output, instead of the container's
+    #[structopt(long = "fps-den")]
+    fps_den: Option<i32>,
+    /// Frame rate of the output as `<num>/<den>` or a whole number, as
+    /// image sequences have none of their own
+    #[structopt(
+        long = "fps",
+        conflicts_with_all = &["fps-num", "fps-den"],
+        parse(try_from_str = parse_fps)
+    )]
+    fps: Option<(i32, i32)>,
+    /// Write segments at this constant frame rate, `<num>/<den>` or a whole
+    /// number, repeating and dropping frames of a variable rate input by
+    /// their PTS. Requests and the properties line then count frames of
+    /// that rate
+    #[structopt(
+        long = "cfr",
+        conflicts_with_all = &[
+            "fps", "fps-num", "fps-den", "timecodes", "pts", "subtitles", "complexity",
+            "align-keyframes", "chapters", "auto-split", "chunks", "watch", "evict-idle",
+            "first-pass"
+        ],
+        parse(try_from_str = parse_fps)
+    )]
+    cfr: Option<(i32, i32)>,
+    /// Write every Nth frame of each segment, with the frame rate divided
+    /// by N, for reduced-rate previews. Requests can override it with a
+    /// `step=<n>` token
+    #[structopt(long = "step", default_value = "1", parse(try_from_str = parse_step))]
+    step: usize,
+    /// Number of frames to decode ahead of the segment writer
+    #[structopt(long = "prefetch", default_value = "16")]
+    prefetch: usize,
+    /// Limit the memory used by decoded frames queued by --prefetch to this
+    /// many MiB
+    #[structopt(long = "cache-mb")]
+    cache_mb: Option<usize>,
+    /// Keep up to this many MiB of the most recently decoded frames of each
+    /// input, so overlapping or repeated requests are not decoded again
+    #[structopt(long = "frame-cache-mb")]
+    frame_cache_mb: Option<usize>,
+    /// Decoders to keep open per input. Each segment goes to the one that
+    /// reaches its start with the least decoding, which saves seeking when
+    /// requests jump around the timeline
+    #[structopt(long = "source-pool", default_value = "1")]
+    source_pool: usize,
+}
+
+fn parse_seek_mode(mode: &str) -> Result<video::SeekMode, String> {
+    match mode {
+        "linear-no-rw" => Ok(video::SeekMode::SEEK_LINEAR_NO_RW),
+        "linear" => Ok(video::SeekMode::SEEK_LINEAR),
+        "normal" => Ok(video::SeekMode::SEEK_NORMAL),
+        "unsafe" => Ok(video::SeekMode::SEEK_UNSAFE),
+        "aggressive" => Ok(video::SeekMode::SEEK_AGGRESSIVE),
+        _ => Err(format!("Unknown seek mode: {}", mode)),
+    }
+}
+
+fn parse_index_error_handling(handling: &str) -> Result<IndexErrorHandling, String> {
+    match handling {
+        "ignore" | "0" => Ok(IndexErrorHandling::IEH_IGNORE),
+        "stop-track" | "1" => Ok(IndexErrorHandling::IEH_STOP_TRACK),
+        "clear-track" | "2" => Ok(IndexErrorHandling::IEH_CLEAR_TRACK),
+        "abort" | "3" => Ok(IndexErrorHandling::IEH_ABORT),
+        _ => Err(format!(
+            "Unknown error handling: {} (expected ignore, stop-track, clear-track or abort)",
+            handling
+        )),
+    }
+}
+
+fn parse_step(step: &str) -> Result<usize, String> {
+    match step.parse::<usize>() {
+        Ok(step) if step > 0 => Ok(step),
+        _ => Err(format!(
+            "Invalid step, expected a positive number: {}",
+            step
+        )),
+    }
+}
+
+fn parse_fps(fps: &str) -> Result<(i32, i32), String> {
+    let (num, den) = fps.split_once('/').unwrap_or((fps, "1"));
+    match (num.parse::<i32>(), den.parse::<i32>()) {
+        (Ok(num), Ok(den)) if num > 0 && den > 0 => Ok((num, den)),
+        _ => Err(format!(
+            "Invalid frame rate, expected <num>/<den> or a number: {}",
+            fps
+        )),
+    }
 }
 
 fn update_progress(current: usize, total: usize, private: Option<&mut usize>) -> usize {
@@ -49,206 +1174,4342 @@ fn update_progress(current: usize, total: usize, private: Option<&mut usize>) ->
         *percent = percentage;
     }
 
-    eprintln!("Indexing, please wait... {}%", percentage);
+    log!(
+        Info,
+        "indexing" { percent: percentage },
+        "Indexing, please wait... {}%",
+        percentage
+    );
     0
 }
 
-fn do_indexing(args: &CliArgs, ignore_errors: IndexErrorHandling) -> std::io::Result<()> {
+fn decoder_threads(requested: Option<usize>) -> usize {
+    if let Some(threads) = requested.filter(|&threads| threads != 0) {
+        return threads;
+    }
+
+    // Counts the cores of the CPU set and cgroup quota the process runs in.
+    thread::available_parallelism()
+        .map(|threads| threads.get())
+        .unwrap_or(1)
+}
+
+/// A black frame in the decoded format of `source`, opaque if it has alpha.
+fn blank_frame(source: &Source) -> DecodedFrame {
+    let format = &source.format;
+    let bytes = format.colorspace.get_bytes_per_sample();
+    let depth = format.colorspace.get_bit_depth() as u32;
+
+    let plane = |size: &PlaneSize, value: u32| -> Vec<u8> {
+        let samples = size.width * size.height;
+        match bytes {
+            2 => (value as u16).to_le_bytes().repeat(samples),
+            _ => vec![value as u8; samples],
+        }
+    };
+
+    let (luma, chroma) = match (source.rgb, format.color_range) {
+        (true, _) => (0, 0),
+        (false, ColorRange::Limited) => (16 << (depth - 8), 128 << (depth - 8)),
+        (false, ColorRange::Full) => (0, 128 << (depth - 8)),
+    };
+    let [luma_size, chroma_size, _] =
+        scale::plane_sizes(format.colorspace, format.width, format.height);
+
+    DecodedFrame {
+        planes: [
+            plane(&luma_size, luma),
+            plane(&chroma_size, chroma),
+            plane(&chroma_size, chroma),
+        ],
+        alpha: source.alpha.then(|| plane(&luma_size, (1 << depth) - 1)),
+    }
+}
+
+fn decode_error_policy(args: &CliArgs, source: &Source) -> prefetch::OnDecodeError {
+    match args.on_decode_error {
+        DecodeErrorMode::Abort => prefetch::OnDecodeError::Abort,
+        DecodeErrorMode::Skip => prefetch::OnDecodeError::Skip,
+        DecodeErrorMode::DuplicatePrevious => prefetch::OnDecodeError::DuplicatePrevious,
+        DecodeErrorMode::Blank => prefetch::OnDecodeError::Blank(blank_frame(source)),
+    }
+}
+
+/// Number of frames to decode ahead, limited so the queued frames of
+/// `format` fit in `--cache-mb`. At least one frame is always queued.
+fn prefetch_depth(args: &CliArgs, format: &SegmentFormat) -> usize {
+    let cache_bytes = match args.cache_mb {
+        Some(cache_mb) => cache_mb * 1024 * 1024,
+        None => return args.prefetch,
+    };
+
+    let frame_bytes: usize = scale::plane_sizes(format.colorspace, format.width, format.height)
+        .iter()
+        .map(|plane| plane.width * plane.height * format.colorspace.get_bytes_per_sample())
+        .sum();
+
+    args.prefetch.min(cache_bytes / frame_bytes.max(1)).max(1)
+}
+
+struct Source {
+    /// Position of the input in the session, 0 for the main input.
+    id: usize,
+    video_source: VideoSource,
+    /// `--seek-mode`, until decoding with it fails and the source falls
+    /// back to linear seeking.
+    seek_mode: video::SeekMode,
+    track: Track,
+    format: SegmentFormat,
+    /// Keyframe numbers, only collected for `--align-keyframes`.
+    keyframes: Vec<usize>,
+    /// Whether decoded frames carry an alpha plane.
+    alpha: bool,
+    /// Whether decoded frames are gbrp, stored as C444 planes in G, B, R
+    /// order.
+    rgb: bool,
+    /// The `<width> <height> <frames> <fps den> <fps num>` line announced
+    /// when the input is opened.
+    properties: String,
+    info: info::Info,
+    /// Turn applied to frames before they are written.
+    rotation: Rotation,
+    hdr: hdr::HdrMetadata,
+    /// The `--subtitles` of the main input.
+    subtitles: Option<subtitles::Subtitles>,
+    /// Size of the input file in bytes.
+    size: u64,
+    /// The `--audio-track` tracks, only opened for `--output-format mkv`.
+    audio: Vec<mkv::AudioTrack>,
+    total_frames: usize,
+    /// The joint timeline of the main input with `--concat`, which
+    /// `total_frames` then counts.
+    timeline: Option<concat::Timeline>,
+    /// The source frame shown at each frame of the `--cfr` timeline, which
+    /// `total_frames` then counts.
+    cfr: Option<Vec<usize>>,
+    /// Whether `total_frames` counts the film frames of `--ivtc`.
+    ivtc: bool,
+    /// Recently decoded frames, by their number on the joint timeline.
+    frame_cache: cache::FrameCache,
+    /// The `--decoder ffmpeg` decoder of segments, used instead of
+    /// `video_source`.
+    ffmpeg: Option<Box<dyn prefetch::Decoder + Send>>,
+}
+
+fn index_file(
+    args: &CliArgs,
+    input: &Path,
+    ignore_errors: IndexErrorHandling,
+) -> std::io::Result<Index> {
+    if !input.exists() && !sequence::is_sequence(input) {
+        return Err(failure::tag(
+            Failure::InputNotFound,
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Input {} not found", input.display()),
+            ),
+        ));
+    }
+
+    // VapourSynth input is out of scope, see the README.
+    if input
+        .extension()
+        .is_some_and(|extension| extension == "vpy")
+    {
+        return Err(failure::tag(
+            Failure::Unsupported,
+            std::io::Error::other(format!(
+                "{} is a VapourSynth script, which is not supported as input; render it \
+                 first, e.g. with vspipe",
+                input.display()
+            )),
+        ));
+    }
+
+    if let Some(path) = args.use_index.as_ref().filter(|_| input == args.input_file) {
+        let index = load_index(path, input, args.mmap_index)?;
+        dump_index(args, input, &index)?;
+        return Ok(index);
+    }
+
     let mut progress = 0;
+    let _span = log::span("indexing", &[("input", &input.display())]);
+
+    let mut indexer = SourceIndexer::open(input, &source_options(args)).map_err(|err| {
+        failure::tag(
+            Failure::Unsupported,
+            std::io::Error::other(format!("Failed to open {}: {}", input.display(), err)),
+        )
+    })?;
+    if container(args) == Container::Mkv || args.describe_tracks || args.silence_threshold.is_some()
+    {
+        indexer.index_tracks(TrackType::TYPE_AUDIO, true);
+    }
+
+    let stream = match args.progress_fd {
+        Some(fd) => Some(ProgressStream::from_fd(fd, &input.to_string_lossy())?),
+        None => None,
+    };
+    let finished = match stream {
+        Some(ref stream) => Some(stream.try_clone()?),
+        None => None,
+    };
+
+    if args.progress || stream.is_some() {
+        let text = args.progress;
+        let mut stream = stream;
+        if text {
+            update_progress(0, 100, None);
+        }
+        indexer.progress_callback(
+            move |current, total, private| {
+                if let Some(ref mut stream) = stream {
+                    // A GUI that stopped listening must not stop indexing.
+                    let _ = stream.indexing(current, total);
+                }
+                if text {
+                    update_progress(current, total, private)
+                } else {
+                    0
+                }
+            },
+            &mut progress,
+        );
+    }
 
-    let indexer = Indexer::new(&args.input_file).unwrap();
+    let index = indexer.index(ignore_errors).map_err(|err| {
+        failure::tag(
+            Failure::Indexing,
+            std::io::Error::other(format!("Failed to index {}: {}", input.display(), err)),
+        )
+    })?;
 
-    if args.progress {
-        update_progress(0, 100, None);
-        indexer.ProgressCallback(update_progress, &mut progress);
+    if let Some(mut finished) = finished {
+        let _ = finished.indexing(1, 1);
     }
 
-    let index = indexer.DoIndexing2(ignore_errors).unwrap();
+    // Only the main input's index is written, not those of --input or
+    // `open`.
+    if let Some(path) = args
+        .write_index
+        .as_ref()
+        .filter(|_| input == args.input_file)
+    {
+        index.WriteIndex(path).map_err(|err| {
+            std::io::Error::other(format!(
+                "Failed to write the index to {}: {:?}",
+                path.display(),
+                err
+            ))
+        })?;
+        log!(Info, "Wrote the index to {}", path.display());
+    }
+    dump_index(args, input, &index)?;
 
     print_progress!(args.progress, "Video indexed!");
 
-    let video_track_id = index.FirstTrackOfType(TrackType::TYPE_VIDEO).unwrap();
+    Ok(index)
+}
 
-    let ref mut video_source = VideoSource::new(
-        &args.input_file,
-        video_track_id,
-        &index,
-        8,
-        video::SeekMode::SEEK_NORMAL,
-    )
-    .unwrap();
+fn track_type_name(track_type: TrackType) -> &'static str {
+    match track_type {
+        TrackType::TYPE_UNKNOWN => "unknown",
+        TrackType::TYPE_VIDEO => "video",
+        TrackType::TYPE_AUDIO => "audio",
+        TrackType::TYPE_DATA => "data",
+        TrackType::TYPE_SUBTITLE => "subtitle",
+        TrackType::TYPE_ATTACHMENT => "attachment",
+    }
+}
 
-    let video_properties = video_source.GetVideoProperties();
+fn tracks(index: &Index) -> Vec<info::TrackInfo> {
+    (0..index.NumTracks())
+        .map(|id| {
+            let track = Track::TrackFromIndex(index, id);
+            info::TrackInfo {
+                id,
+                kind: track_type_name(track.TrackType()),
+                frames: track.NumFrames(),
+            }
+        })
+        .collect()
+}
 
-    let total_frames = video_properties.NumFrames;
+/// Seconds to the start of the first frame of `track` and to the end of
+/// its last, taken to last as long as the one before it.
+fn pts_times(track: &Track) -> Option<(f64, f64)> {
+    (track.NumFrames() > 0).then(|| {
+        let start = timecodes::frame_time_ms(track, 0) / 1000.0;
+        (
+            start,
+            start + timecodes::elapsed_ms(track, track.NumFrames()) / 1000.0,
+        )
+    })
+}
 
-    let prop_frame = Frame::GetFrame(video_source, 0).unwrap();
+/// The timing of every track of `input` for the `duration` command. The
+/// video and audio ends are FFMS2's, which knows how long the last frame
+/// lasts; older FFMS2 versions leave that out, and then it is estimated
+/// from the PTS like other tracks.
+fn track_times(input: &Input) -> Vec<duration::TrackTimes> {
+    tracks(&input._index)
+        .into_iter()
+        .map(|info| {
+            let track = Track::TrackFromIndex(&input._index, info.id);
+            let time_base = track.TimeBase();
+            let frames = track.NumFrames();
+            let mut times = duration::TrackTimes {
+                id: info.id,
+                kind: info.kind,
+                frames,
+                time_base: (time_base.Num, time_base.Den),
+                pts: (frames > 0)
+                    .then(|| (track.FrameInfo(0).PTS, track.FrameInfo(frames - 1).PTS)),
+                times: pts_times(&track),
+                samples: None,
+            };
 
-    println!(
-        "{} {} {} {} {}",
-        prop_frame.EncodedWidth,
-        prop_frame.EncodedHeight,
-        total_frames,
-        video_properties.FPSDenominator,
-        video_properties.FPSNumerator
-    );
+            if info.id == input.source.info.video_track {
+                let properties = input.source.video_source.GetVideoProperties();
+                if properties.LastEndTime > 0.0 {
+                    times.times = Some((properties.FirstTime, properties.LastEndTime));
+                }
+            } else if info.kind == "audio" && frames > 0 {
+                // FFMS_DELAY_NO_SHIFT, timing the samples as stored.
+                match audio::AudioSource::new(&input.path, info.id, &input._index, -3) {
+                    Ok(audio) => {
+                        let properties = audio.GetAudioProperties();
+                        if properties.LastEndTime > 0.0 {
+                            times.times = Some((properties.FirstTime, properties.LastEndTime));
+                        }
+                        times.samples = Some((properties.NumSamples, properties.SampleRate));
+                    }
+                    Err(err) => log!(Warn, "Failed to open audio track {}: {:?}", info.id, err),
+                }
+            }
 
-    eprintln!("Pixel format: {}", prop_frame.ConvertedPixelFormat);
+            times
+        })
+        .collect()
+}
 
-    let yuv420p = Frame::GetPixFmt("yuv420p");
-    let yuv422p = Frame::GetPixFmt("yuv422p");
-    let yuv420p10le = Frame::GetPixFmt("yuv420p10le");
+/// Writes `--dump-index` for the index of the main input.
+fn dump_index(args: &CliArgs, input: &Path, index: &Index) -> std::io::Result<()> {
+    let Some(path) = args
+        .dump_index
+        .as_ref()
+        .filter(|_| input == args.input_file)
+    else {
+        return Ok(());
+    };
 
-    let width = prop_frame.EncodedWidth as usize;
-    let height = prop_frame.EncodedHeight as usize;
+    index_dump::write(index, input, select_video_track(args, index)?, path).map_err(|err| {
+        std::io::Error::new(
+            err.kind(),
+            format!("Failed to write {}: {}", path.display(), err),
+        )
+    })?;
+    log!(Info, "Wrote the index data to {}", path.display());
+    Ok(())
+}
 
-    eprintln!("Original width: {}", width);
-    eprintln!("Original height: {}", height);
+/// Reads a saved index, through a memory mapping if `mapped`, and checks
+/// that it was made from `input`.
+fn load_index(path: &Path, input: &Path, mapped: bool) -> std::io::Result<Index> {
+    let index = match mapped {
+        // FFMS2 copies what it needs, so the mapping goes right after.
+        true => {
+            let map = mapped::Mapped::open(path).map_err(|err| {
+                std::io::Error::new(
+                    err.kind(),
+                    format!("Failed to map the index {}: {}", path.display(), err),
+                )
+            })?;
+            Index::ReadIndexFromBuffer(&map)
+        }
+        false => Index::new(path),
+    };
+    let index = index.map_err(|err| {
+        failure::tag(
+            Failure::Indexing,
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Failed to read the index {}: {:?}", path.display(), err),
+            ),
+        )
+    })?;
+    index.IndexBelongsToFile(input).map_err(|err| {
+        failure::tag(
+            Failure::BadArguments,
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Index {} is not for {}: {:?}",
+                    path.display(),
+                    input.display(),
+                    err
+                ),
+            ),
+        )
+    })?;
+    log!(Info, "Loaded the index from {}", path.display());
 
-    let scaled_width = prop_frame.ScaledWidth;
-    let scaled_height = prop_frame.ScaledHeight;
+    Ok(index)
+}
 
-    eprintln!("Scaled width: {}", scaled_width);
-    eprintln!("Scaled height: {}", scaled_height);
+/// Prints one `<track> <type> <frames>` line per track in the index.
+fn list_tracks(index: &Index) {
+    for track in tracks(index) {
+        println!("{} {} {}", track.id, track.kind, track.frames);
+    }
+}
 
-    let framerate = Ratio {
-        num: video_properties.FPSNumerator as usize,
-        den: video_properties.FPSDenominator as usize,
+/// The size, pixel format and frame count of video track `id`.
+fn describe_video(
+    args: &CliArgs,
+    input: &Path,
+    index: &Index,
+    id: usize,
+) -> std::io::Result<String> {
+    let mut video_source = VideoSource::new(input, id, index, 1, args.seek_mode)
+        .map_err(|err| std::io::Error::other(format!("{:?}", err)))?;
+    let frames = video_source.GetVideoProperties().NumFrames;
+    let probe = Probe::decode(&mut video_source)?;
+
+    Ok(format!(
+        "{}x{} {} {} frames",
+        probe.width,
+        probe.height,
+        pixel_format_name(probe.pixel_format),
+        frames
+    ))
+}
+
+/// The sample rate, channels and sample count of audio track `id`.
+fn describe_audio(input: &Path, index: &Index, id: usize) -> std::io::Result<String> {
+    // FFMS_DELAY_NO_SHIFT, counting the samples as stored.
+    let audio_source = audio::AudioSource::new(input, id, index, -3)
+        .map_err(|err| std::io::Error::other(format!("{:?}", err)))?;
+    let properties = audio_source.GetAudioProperties();
+
+    Ok(format!(
+        "{} Hz {} channels {} samples",
+        properties.SampleRate, properties.Channels, properties.NumSamples
+    ))
+}
+
+/// A `<track> <type> <details>` line for every track in the index, for the
+/// `tracks` subcommand and command, with the size and pixel format of
+/// video tracks and the sample rate and channels of audio tracks. FFMS2
+/// exposes neither codecs nor languages. Tracks that fail to open, like
+/// audio tracks of a --use-index index made without them, get their frame
+/// count only.
+fn describe_tracks(args: &CliArgs, input: &Path, index: &Index) -> Vec<String> {
+    tracks(index)
+        .into_iter()
+        .map(|track| {
+            let details = match track.kind {
+                "video" => describe_video(args, input, index, track.id),
+                "audio" => describe_audio(input, index, track.id),
+                _ => Ok(format!("{} frames", track.frames)),
+            };
+            let details = details.unwrap_or_else(|err| {
+                log!(Warn, "Failed to open track {}: {}", track.id, err);
+                format!("{} frames", track.frames)
+            });
+            format!("{} {} {}", track.id, track.kind, details)
+        })
+        .collect()
+}
+
+fn select_video_track(args: &CliArgs, index: &Index) -> std::io::Result<usize> {
+    let track_id = match args.track {
+        Some(track_id) => track_id,
+        None => {
+            return index.FirstTrackOfType(TrackType::TYPE_VIDEO).map_err(|_| {
+                let audio = (0..index.NumTracks()).any(|id| {
+                    matches!(
+                        Track::TrackFromIndex(index, id).TrackType(),
+                        TrackType::TYPE_AUDIO
+                    )
+                });
+                let message = match audio {
+                    true => {
+                        "No video track found, only audio. Audio-only inputs cannot be segmented"
+                    }
+                    false => "No video track found",
+                };
+                failure::tag(Failure::NoVideo, std::io::Error::other(message))
+            })
+        }
     };
 
-    video_source
-        .SetInputFormatV(1 as usize, video::ColorRanges::CR_MPEG, yuv420p as usize)
-        .unwrap();
+    if track_id >= index.NumTracks() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Track {} does not exist, the file has {} tracks",
+                track_id,
+                index.NumTracks()
+            ),
+        ));
+    }
 
-    thread::sleep(time::Duration::from_millis(100));
+    match Track::TrackFromIndex(index, track_id).TrackType() {
+        TrackType::TYPE_VIDEO => Ok(track_id),
+        other => Err(std::io::Error::other(format!(
+            "Track {} is a {} track, not a video track",
+            track_id,
+            track_type_name(other)
+        ))),
+    }
+}
 
-    let prop_frame = Frame::GetFrame(video_source, 1).unwrap();
+/// The y4m colorspace frames of each pixel format are written in, and
+/// whether they have an alpha plane. gbrp frames are written as RGB.
+const COLORSPACES: [(&str, Colorspace, bool); 13] = [
+    ("yuv420p", Colorspace::C420, false),
+    ("yuv420p10le", Colorspace::C420p10, false),
+    ("yuv420p12le", Colorspace::C420p12, false),
+    ("yuv422p", Colorspace::C422, false),
+    ("yuv422p10le", Colorspace::C422p10, false),
+    ("yuv422p12le", Colorspace::C422p12, false),
+    ("yuv444p", Colorspace::C444, false),
+    ("yuv444p10le", Colorspace::C444p10, false),
+    ("yuv444p12le", Colorspace::C444p12, false),
+    ("gray", Colorspace::Cmono, false),
+    ("yuva420p", Colorspace::C420, true),
+    ("yuva422p", Colorspace::C422, true),
+    ("gbrp", Colorspace::C444, false),
+];
 
-    eprintln!("Pixel format: {}", prop_frame.ConvertedPixelFormat);
-    eprintln!("Colorspace: {}", prop_frame.ColorSpace);
+/// Pixel formats that inputs can be in.
+const PIXEL_FORMATS: [&str; 14] = [
+    "yuv420p",
+    "yuv422p",
+    "yuv444p",
+    "yuv420p10le",
+    "yuv422p10le",
+    "yuv444p10le",
+    "yuv420p12le",
+    "yuv422p12le",
+    "yuv444p12le",
+    "yuva420p",
+    "yuva422p",
+    "gbrp",
+    "gray",
+    "gray10le",
+];
 
-    let y4m_colorspace = {
-        if prop_frame.ConvertedPixelFormat == yuv420p {
-            Colorspace::C420
-        } else if prop_frame.ConvertedPixelFormat == yuv420p10le {
-            Colorspace::C420p10
-        } else if prop_frame.ConvertedPixelFormat == yuv422p {
-            Colorspace::C422
-        } else {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Unsupported colorspace: ".to_owned()
-                    + &prop_frame.ConvertedPixelFormat.to_string(),
-            ));
+/// Name of an FFmpeg pixel format, or its number if it is not one of
+/// `PIXEL_FORMATS`.
+fn pixel_format_name(pixel_format: i32) -> String {
+    PIXEL_FORMATS
+        .iter()
+        .find(|name| Frame::GetPixFmt(name) == pixel_format)
+        .map_or_else(|| pixel_format.to_string(), |name| name.to_string())
+}
+
+/// The pixel format `--bit-depth` turns `name` into: the one of `depth`
+/// bits with the same chroma subsampling.
+fn pixel_format_at_depth(name: &str, depth: u8) -> std::io::Result<String> {
+    let unsupported = || {
+        failure::tag(
+            Failure::Unsupported,
+            std::io::Error::other(format!("Cannot write {} at {} bits", name, depth)),
+        )
+    };
+    let family = match name {
+        "yuva420p" | "yuva422p" if depth == 8 => return Ok(name.to_string()),
+        "yuv420p" | "yuv420p10le" | "yuv420p12le" | "yuva420p" => {
+            ["yuv420p", "yuv420p10le", "yuv420p12le"]
         }
+        "yuv422p" | "yuv422p10le" | "yuv422p12le" | "yuva422p" => {
+            ["yuv422p", "yuv422p10le", "yuv422p12le"]
+        }
+        "yuv444p" | "yuv444p10le" | "yuv444p12le" => ["yuv444p", "yuv444p10le", "yuv444p12le"],
+        // y4m has no deeper monochrome colorspace.
+        "gray" | "gray10le" => ["gray", "yuv420p10le", "yuv420p12le"],
+        _ => return Err(unsupported()),
     };
 
-    let line_size = match y4m_colorspace {
-        Colorspace::C420 => [width, width / 4, width / 4, 0],
-        Colorspace::C420p10 => [width * 2, (width / 4) * 2, (width / 4) * 2, 0],
-        Colorspace::C422 => [width, width / 2, width / 2, 0],
-        _ => {
+    Ok(match depth {
+        8 => family[0],
+        10 => family[1],
+        12 => family[2],
+        _ => return Err(unsupported()),
+    }
+    .to_string())
+}
+
+/// Frame size and pixel format given with `--source-format`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SourceFormat {
+    width: usize,
+    height: usize,
+    pixel_format: String,
+}
+
+fn parse_source_format(format: &str) -> Result<SourceFormat, String> {
+    let invalid = || {
+        format!(
+            "Invalid source format, expected WIDTHxHEIGHT:PIXFMT: {}",
+            format
+        )
+    };
+    let (size, pixel_format) = format.split_once(':').ok_or_else(invalid)?;
+    let size = scale::parse_size(size).map_err(|_| invalid())?;
+
+    if pixel_format.is_empty() {
+        return Err(invalid());
+    }
+
+    Ok(SourceFormat {
+        width: size.width,
+        height: size.height,
+        pixel_format: pixel_format.to_string(),
+    })
+}
+
+/// What the rest of startup needs to know about the frames of an input.
+struct Probe {
+    width: usize,
+    height: usize,
+    pixel_format: i32,
+    interlacing: Interlacing,
+    /// The range flagged on the first frame, unknown without a probe.
+    color_range: Option<i32>,
+    /// Matrix, primaries and transfer of the first frame.
+    color: Option<(i32, i32, i32)>,
+}
+
+impl Probe {
+    /// Decodes the first frame of `video_source`.
+    fn decode(video_source: &mut VideoSource) -> std::io::Result<Self> {
+        let frame = Frame::GetFrame(video_source, 0).map_err(|err| {
+            failure::tag(
+                Failure::Decode,
+                std::io::Error::other(format!("Failed to decode the first frame: {:?}", err)),
+            )
+        })?;
+
+        Ok(Probe {
+            width: frame.EncodedWidth as usize,
+            height: frame.EncodedHeight as usize,
+            pixel_format: frame.ConvertedPixelFormat,
+            interlacing: Interlacing::from_frame_props(frame.InterlacedFrame, frame.TopFieldFirst),
+            color_range: Some(frame.ColorRange),
+            color: Some((
+                frame.ColorSpace,
+                frame.ColorPrimaries,
+                frame.TransferCharateristics,
+            )),
+        })
+    }
+
+    /// Takes the format from `--source-format`. Frames are assumed to be
+    /// progressive.
+    fn from_source_format(format: &SourceFormat) -> std::io::Result<Self> {
+        let pixel_format = Frame::GetPixFmt(&format.pixel_format);
+        if pixel_format < 0 {
             return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Unsupported colorspace",
-            ))
+                std::io::ErrorKind::InvalidInput,
+                format!("Unknown pixel format {}", format.pixel_format),
+            ));
         }
-    };
 
-    eprintln!("Line size: {:?}", line_size);
+        Ok(Probe {
+            width: format.width,
+            height: format.height,
+            pixel_format,
+            interlacing: Interlacing::Progressive,
+            color_range: None,
+            color: None,
+        })
+    }
+}
 
-    let mut input = String::new();
-    loop {
-        input.clear();
-        let _ = std::io::stdin().read_line(&mut input);
+/// Creates a video source for the selected track. `index` must outlive the
+/// returned source.
+fn open_source(
+    args: &CliArgs,
+    input: &Path,
+    id: usize,
+    index: &Index,
+    threads: usize,
+) -> std::io::Result<Source> {
+    open_source_seeking(args, input, id, index, threads, args.seek_mode)
+}
 
-        let now = Instant::now();
+/// `open_source` with the decoder seeking by `seek_mode`.
+fn open_source_seeking(
+    args: &CliArgs,
+    input: &Path,
+    id: usize,
+    index: &Index,
+    threads: usize,
+    seek_mode: video::SeekMode,
+) -> std::io::Result<Source> {
+    let video_track_id = select_video_track(args, index)?;
 
-        input = input.trim().to_string();
+    let mut video_source = VideoSource::new(input, video_track_id, index, threads, seek_mode)
+        .map_err(|err| {
+            failure::tag(
+                Failure::Unsupported,
+                std::io::Error::other(format!("Failed to open {}: {:?}", input.display(), err)),
+            )
+        })?;
 
-        let start_end_str = input.split(" ").collect::<Vec<&str>>();
+    let video_properties = video_source.GetVideoProperties();
 
-        let start = start_end_str[0].parse::<usize>().unwrap();
-        let end = {
-            let end = start_end_str[1].parse::<usize>().unwrap();
+    let total_frames = video_properties.NumFrames;
 
-            if end as i32 > total_frames {
-                total_frames as usize
-            } else {
-                end
-            }
-        };
+    let probe = match args.source_format {
+        Some(ref source_format) if args.no_probe => Probe::from_source_format(source_format)?,
+        _ => Probe::decode(&mut video_source)?,
+    };
 
-        eprintln!("Input: {}", input);
-        eprintln!("Reading segment {} to {}", start, end);
+    let interlacing = probe.interlacing;
+    let width = probe.width;
+    let height = probe.height;
 
-        // join args.output_folder and start and end
-        // default to current directory
-        let ref outpath = format!(
-            "{}/{}-{}.y4m",
-            match args.output_folder {
-                Some(ref folder) => folder.to_str().unwrap(),
-                None => ".",
-            },
-            start,
-            end
-        );
+    let fps_num = args
+        .fps_num
+        .or(args.fps.map(|(num, _)| num))
+        .unwrap_or(video_properties.FPSNumerator);
+    let fps_den = args
+        .fps_den
+        .or(args.fps.map(|(_, den)| den))
+        .unwrap_or(video_properties.FPSDenominator);
+    if fps_num <= 0 || fps_den <= 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Invalid frame rate {}/{}, pass --fps-num and --fps-den",
+                fps_num, fps_den
+            ),
+        ));
+    }
 
-        let mut outfile = File::create(outpath).unwrap();
+    let properties = format!(
+        "{} {} {} {} {}",
+        width, height, total_frames, fps_den, fps_num
+    );
 
-        let mut encoder = encode(width, height, framerate)
-            .with_colorspace(y4m_colorspace)
-            .write_header(&mut outfile)
-            .unwrap();
+    let yuv420p10le = Frame::GetPixFmt("yuv420p10le");
+    let gbrp = Frame::GetPixFmt("gbrp");
+    let gray10le = Frame::GetPixFmt("gray10le");
+
+    log!(Info, "Original width: {}", width);
+    log!(Info, "Original height: {}", height);
 
-        for i in start..end {
-            let mut frame = Frame::GetFrame(video_source, i).unwrap();
+    let framerate = Ratio {
+        num: fps_num as usize,
+        den: fps_den as usize,
+    };
 
-            // Work around for bug in FFMS2 Rust bindings
-            frame.Linesize[1] /= 2;
-            frame.Linesize[2] /= 2;
+    let rotation = if args.no_autorotate {
+        Rotation::NONE
+    } else {
+        Rotation::from_ffms(video_properties.Rotation)
+    };
+    if rotation != Rotation::NONE {
+        log!(Info, "Rotating frames by {} degrees", rotation.degrees());
+    }
 
-            let pixel_data: Vec<Option<&[u8]>> = frame.get_pixel_data();
+    let hdr = hdr::HdrMetadata::from_properties(&video_properties);
+    if args.hdr_metadata {
+        log!(
+            Warn,
+            "y4m does not carry HDR metadata and per-frame HDR10+ and Dolby Vision \
+             metadata cannot be read, only static metadata is written to .hdr.json files"
+        );
+        if hdr.is_empty() {
+            log!(Warn, "Input has no static HDR metadata");
+        }
+    }
 
-            let frame = Y4MFrame::new(
-                [
-                    pixel_data[0].unwrap(),
-                    pixel_data[1].unwrap(),
-                    pixel_data[2].unwrap(),
-                ],
-                None,
+    let color_range = match (args.color_range, probe.color_range) {
+        (RangeSelection::Limited, _) => ColorRange::Limited,
+        (RangeSelection::Full, _) => ColorRange::Full,
+        (RangeSelection::Auto, Some(color_range)) => ColorRange::from_frame_props(color_range),
+        (RangeSelection::Auto, None) => {
+            log!(
+                Warn,
+                "Color range cannot be detected without a probe, assuming limited"
             );
+            ColorRange::Limited
+        }
+    };
+    log!(Info, "Color range: {:?}", color_range);
+
+    let input_range = match color_range {
+        ColorRange::Limited => video::ColorRanges::CR_MPEG,
+        ColorRange::Full => video::ColorRanges::CR_JPEG,
+    };
+    video_source
+        .SetInputFormatV(1, input_range, probe.pixel_format as usize)
+        .map_err(|err| {
+            failure::tag(
+                Failure::Unsupported,
+                std::io::Error::other(format!(
+                    "Failed to set the input format of {} to {}: {:?}",
+                    input.display(),
+                    pixel_format_name(probe.pixel_format),
+                    err
+                )),
+            )
+        })?;
 
-            encoder.write_frame(&frame).unwrap();
+    let mut pixel_format = probe.pixel_format;
+
+    let target = match (args.pix_fmt, args.bit_depth) {
+        (PixelFormat::Auto, BitDepth::Keep) => None,
+        (PixelFormat::Auto, BitDepth::Bits(depth)) => Some(pixel_format_at_depth(
+            &pixel_format_name(pixel_format),
+            depth,
+        )?),
+        (format, BitDepth::Keep) => Some(format.name().to_string()),
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--pix-fmt and --bit-depth cannot be combined",
+            ))
         }
+    };
+    let target = match target {
+        None => None,
+        Some(name) => match Frame::GetPixFmt(&name) {
+            format if format < 0 => {
+                return Err(failure::tag(
+                    Failure::Unsupported,
+                    std::io::Error::other(format!("FFMS2 does not know pixel format {}", name)),
+                ))
+            }
+            format => Some(format),
+        },
+    };
+    // y4m has no 10-bit monochrome colorspace, so gray10le is converted to
+    // yuv420p10le, whose chroma planes are neutral.
+    let target = target.or((pixel_format == gray10le).then_some(yuv420p10le));
+
+    if let Some(target) = target.filter(|&target| target != pixel_format) {
+        let (from, to) = (pixel_format_name(pixel_format), pixel_format_name(target));
+        log!(Info, "Writing {} as {}", from, to);
+        video_source
+            .SetOutputFormatV2(
+                &mut vec![target],
+                width,
+                height,
+                frame::Resizers::RESIZER_POINT,
+            )
+            .map_err(|err| {
+                std::io::Error::other(format!("Failed to convert {} to {}: {:?}", from, to, err))
+            })?;
+        pixel_format = target;
+    }
+
+    log!(Info, "Pixel format: {}", pixel_format);
 
-        eprintln!("Time taken: {:?}", now.elapsed());
+    let rgb = pixel_format == gbrp;
+    let (y4m_colorspace, alpha) = COLORSPACES
+        .iter()
+        .find(|(name, ..)| Frame::GetPixFmt(name) == pixel_format)
+        .map(|&(_, colorspace, alpha)| (colorspace, alpha))
+        .ok_or_else(|| {
+            failure::tag(
+                Failure::Unsupported,
+                std::io::Error::other(
+                    "Unsupported colorspace: ".to_owned() + &pixel_format.to_string(),
+                ),
+            )
+        })?;
+
+    let alpha_line_size = if alpha { width } else { 0 };
+    let line_size = match y4m_colorspace {
+        Colorspace::C420 => [width, width / 4, width / 4, alpha_line_size],
+        Colorspace::C420p10 | Colorspace::C420p12 => {
+            [width * 2, (width / 4) * 2, (width / 4) * 2, 0]
+        }
+        Colorspace::C422p10 | Colorspace::C422p12 => {
+            [width * 2, (width / 2) * 2, (width / 2) * 2, 0]
+        }
+        Colorspace::C422 => [width, width / 2, width / 2, alpha_line_size],
+        Colorspace::C444 => [width, width, width, 0],
+        Colorspace::C444p10 | Colorspace::C444p12 => [width * 2, width * 2, width * 2, 0],
+        Colorspace::Cmono => [width, 0, 0, 0],
+        _ => {
+            return Err(failure::tag(
+                Failure::Unsupported,
+                std::io::Error::other("Unsupported colorspace"),
+            ))
+        }
+    };
 
-        println!("{} {}", start, outpath);
+    log!(Info, "Line size: {:?}", line_size);
+    if rgb && args.rgb.is_none() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Input is gbrp, pass --rgb raw or --rgb png to write it",
+        ));
     }
-}
+    if alpha && !args.alpha {
+        log!(Warn, "Input has an alpha plane, pass --alpha to write it");
+    }
+    log!(Info, "Interlacing: {}", interlacing);
 
-fn main() {
-    let args = CliArgs::from_args();
+    let format = SegmentFormat {
+        width,
+        height,
+        framerate,
+        colorspace: y4m_colorspace,
+        interlacing,
+        color_range,
+        color: probe.color,
+        sar: match (video_properties.SARNum, video_properties.SARDen) {
+            (num, den) if num > 0 && den > 0 => Some((num as usize, den as usize)),
+            _ => None,
+        },
+    };
 
-    FFMS2::Init();
+    let info = info::Info {
+        width,
+        height,
+        sar: (video_properties.SARNum, video_properties.SARDen),
+        frames: total_frames as usize,
+        fps_num,
+        fps_den,
+        pixel_format: pixel_format_name(probe.pixel_format),
+        interlacing,
+        color_range,
+        color: probe.color,
+        video_track: video_track_id,
+        tracks: tracks(index),
+        ranges: args.range_convention,
+    };
 
-    let level = match args.verbose {
-        0 => LogLevels::LOG_QUIET,
-        1 => LogLevels::LOG_WARNING,
-        2 => LogLevels::LOG_INFO,
-        3 => LogLevels::LOG_VERBOSE,
-        _ => LogLevels::LOG_DEBUG,
+    let subtitles = match args.subtitles {
+        Some(ref path) if id == 0 => Some(subtitles::Subtitles::load(path)?),
+        _ => None,
     };
 
-    Log::SetLogLevel(level);
+    let audio = match container(args) {
+        Container::Mkv => open_audio(args, input, index, video_track_id)?,
+        _ if args.silence_threshold.is_some() => open_audio(args, input, index, video_track_id)?,
+        Container::Y4m | Container::Nut | Container::Raw => Vec::new(),
+    };
+
+    let track = Track::TrackFromVideo(&mut video_source);
+    let keyframes = match args.align_keyframes {
+        Some(_) => align::keyframes(&track, total_frames as usize),
+        None => Vec::new(),
+    };
+    let ffmpeg = match args.decoder {
+        Backend::Ffms2 => None,
+        Backend::Ffmpeg => Some(ffmpeg_decoder(
+            input,
+            video_track_id,
+            &track,
+            total_frames as usize,
+            &pixel_format_name(pixel_format),
+            (width, height),
+            threads,
+        )?),
+    };
+
+    Ok(Source {
+        id,
+        video_source,
+        seek_mode,
+        track,
+        keyframes,
+        format,
+        alpha,
+        rgb,
+        properties,
+        info,
+        rotation,
+        hdr,
+        size: sequence::size(input)?,
+        subtitles,
+        audio,
+        total_frames: total_frames as usize,
+        timeline: None,
+        cfr: None,
+        ivtc: false,
+        frame_cache: cache::FrameCache::new(
+            args.frame_cache_mb.unwrap_or(0) << 20,
+            sequential_overlap(args),
+        ),
+        ffmpeg,
+    })
+}
+
+/// Opens the `--decoder ffmpeg` decoder of stream `stream` of `input`,
+/// whose frames FFMS2 indexed as `track`.
+#[cfg(feature = "ffmpeg")]
+fn ffmpeg_decoder(
+    input: &Path,
+    stream: usize,
+    track: &Track,
+    frames: usize,
+    pixel_format: &str,
+    size: (usize, usize),
+    threads: usize,
+) -> std::io::Result<Box<dyn prefetch::Decoder + Send>> {
+    let frames = (0..frames)
+        .map(|n| {
+            let info = track.FrameInfo(n);
+            (info.PTS, info.KeyFrame() != 0)
+        })
+        .collect();
+    let source = ffms_segmenter::ffmpeg::FfmpegSource::open(
+        input,
+        stream,
+        frames,
+        pixel_format,
+        size,
+        threads,
+    )?;
+    Ok(Box::new(source))
+}
+
+#[cfg(not(feature = "ffmpeg"))]
+fn ffmpeg_decoder(
+    _input: &Path,
+    _stream: usize,
+    _track: &Track,
+    _frames: usize,
+    _pixel_format: &str,
+    _size: (usize, usize),
+    _threads: usize,
+) -> std::io::Result<Box<dyn prefetch::Decoder + Send>> {
+    Err(std::io::Error::other(
+        "Built without the ffmpeg feature, --decoder ffmpeg is unavailable",
+    ))
+}
+
+/// Frames of the request before that a request starting where it ended
+/// decodes again: its padding, and the neighbours the deinterlacer and
+/// the field matcher look at.
+fn sequential_overlap(args: &CliArgs) -> usize {
+    let neighbours = match args.deinterlace.is_some() || args.ivtc {
+        true => 2,
+        false => 0,
+    };
+    args.pad_before + args.pad_after + neighbours
+}
 
-    let ignore_errors = match args.ignore_errors {
-        0 => IndexErrorHandling::IEH_IGNORE,
-        1 => IndexErrorHandling::IEH_STOP_TRACK,
-        2 => IndexErrorHandling::IEH_CLEAR_TRACK,
-        _ => IndexErrorHandling::IEH_ABORT,
+/// The source frame that `frame` of the timeline of `source` starts at, the
+/// end of the input past its last frame.
+fn source_frame(source: &Source, frame: usize) -> usize {
+    let frame = match source.cfr {
+        Some(ref frames) => frames.get(frame).copied().unwrap_or(source.info.frames),
+        None if source.ivtc => frame * 5 / 4,
+        None => frame,
     };
+    frame.min(source.info.frames)
+}
+
+/// The audio tracks `--audio-track` selects, by default the first indexed
+/// one.
+fn select_audio_tracks(args: &CliArgs, index: &Index) -> std::io::Result<Vec<usize>> {
+    let audio_tracks: Vec<usize> = tracks(index)
+        .into_iter()
+        .filter(|track| track.kind == "audio" && track.frames > 0)
+        .map(|track| track.id)
+        .collect();
 
-    do_indexing(&args, ignore_errors).unwrap();
+    if args.audio_tracks.contains(&AudioSelection::All) {
+        return Ok(audio_tracks);
+    }
+    if args.audio_tracks.is_empty() {
+        return Ok(audio_tracks.into_iter().take(1).collect());
+    }
+
+    let mut selected = Vec::new();
+    for selection in &args.audio_tracks {
+        let AudioSelection::Track(id) = *selection else {
+            continue;
+        };
+        if !audio_tracks.contains(&id) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Track {} is not an indexed audio track", id),
+            ));
+        }
+        if !selected.contains(&id) {
+            selected.push(id);
+        }
+    }
+
+    Ok(selected)
+}
+
+/// Opens the `--audio-track` tracks with their samples counted from the
+/// first frame of `video_track_id`, converted as `--audio-*` asks. Segments
+/// go without audio if there is none, but not if it cannot be converted.
+fn open_audio(
+    args: &CliArgs,
+    input: &Path,
+    index: &Index,
+    video_track_id: usize,
+) -> std::io::Result<Vec<mkv::AudioTrack>> {
+    let track_ids = select_audio_tracks(args, index)?;
+    if track_ids.is_empty() {
+        log!(Warn, "No indexed audio track, segments will be video only");
+    }
+
+    let output = mkv::AudioOutput {
+        sample_rate: args.audio_rate,
+        channels: args.audio_channels,
+        sample_format: args.audio_sample_format,
+    };
+    let mut opened = Vec::new();
+    for track_id in track_ids {
+        let audio = match audio::AudioSource::new(input, track_id, index, video_track_id as isize) {
+            Ok(audio) => audio,
+            Err(err) => {
+                log!(Warn, "Failed to open audio track {}: {:?}", track_id, err);
+                continue;
+            }
+        };
+        let audio = mkv::AudioTrack::new(audio, &output)
+            .map_err(|err| failure::tag(Failure::Unsupported, err))?;
+        log!(Info, "Muxing audio track {}", track_id);
+        opened.push(audio);
+    }
+
+    Ok(opened)
+}
+
+fn build_planner(args: &CliArgs) -> std::io::Result<Option<Planner>> {
+    match args.annotations {
+        Some(ref path) => Ok(Some(Planner::new(
+            Annotations::load(path)?,
+            args.split_label.clone(),
+            args.exclude_label.clone(),
+        ))),
+        None if !args.split_label.is_empty() || !args.exclude_label.is_empty() => Err(
+            std::io::Error::other("--split-label and --exclude-label require --annotations"),
+        ),
+        None => Ok(None),
+    }
+}
+
+/// Returns the scaler and the format of the frames it produces, if the
+/// arguments ask for resizing or bit depth conversion.
+fn build_scaler(
+    args: &CliArgs,
+    format: &SegmentFormat,
+) -> std::io::Result<Option<(Scaler, SegmentFormat)>> {
+    let color_range = args.convert_range.target(format.color_range);
+    if args.scale.is_none() && !args.scale_8bit && color_range == format.color_range {
+        return Ok(None);
+    }
+
+    let src = PlaneSize {
+        width: format.width,
+        height: format.height,
+    };
+    let dst = args.scale.unwrap_or(src);
+    let colorspace = if args.scale_8bit {
+        scale::to_8bit(format.colorspace)
+    } else {
+        format.colorspace
+    };
+
+    let scaler = Scaler::new(format.colorspace, src, dst, colorspace, args.gpu)?
+        .dithered(args.dither)
+        .converting_range(format.color_range, color_range);
+
+    Ok(Some((
+        scaler,
+        SegmentFormat {
+            width: dst.width,
+            height: dst.height,
+            framerate: format.framerate,
+            colorspace,
+            interlacing: format.interlacing,
+            color_range,
+            color: format.color,
+            // Pixels grow wider as the width shrinks more than the height.
+            sar: format.sar.map(|(num, den)| {
+                reduce(num * src.width * dst.height, den * src.height * dst.width)
+            }),
+        },
+    )))
+}
+
+/// `num/den` in lowest terms.
+fn reduce(num: usize, den: usize) -> (usize, usize) {
+    match nut::gcd(num, den) {
+        0 => (num, den),
+        gcd => (num / gcd, den / gcd),
+    }
+}
+
+/// The scaler to apply, if any, and the format of the frames written out.
+fn output_format(
+    args: &CliArgs,
+    source: &Source,
+) -> std::io::Result<(Option<Scaler>, SegmentFormat)> {
+    if source.rgb && args.convert_range != RangeConversion::None {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "--convert-range only applies to YUV inputs, not gbrp",
+        ));
+    }
+    let (scaler, mut format) = match build_scaler(args, &source.format)? {
+        Some((scaler, format)) => (Some(scaler), format),
+        None => (None, source.format.clone()),
+    };
+
+    if args.deinterlace.is_some() || args.ivtc {
+        format.interlacing = Interlacing::Progressive;
+    }
+
+    source.rotation.check(format.colorspace)?;
+    if source.rotation.swaps_dimensions() {
+        (format.width, format.height) = (format.height, format.width);
+        format.sar = format.sar.map(|(num, den)| (den, num));
+    }
+
+    Ok((scaler, format))
+}
+
+/// Applies a saved session to `args`, returning the requests it left
+/// unfinished. Settings given on the command line take precedence.
+fn restore_session(args: &mut CliArgs) -> std::io::Result<Vec<String>> {
+    let saved = match args.session {
+        Some(ref path) => match SavedSession::load(path)? {
+            Some(saved) => saved,
+            None => return Ok(Vec::new()),
+        },
+        None => return Ok(Vec::new()),
+    };
+
+    if saved
+        .input
+        .as_ref()
+        .is_some_and(|input| *input != args.input_file)
+    {
+        log!(
+            Warn,
+            "Saved session is for {}, not restoring it",
+            saved.input.unwrap().display()
+        );
+        return Ok(Vec::new());
+    }
+
+    log!(
+        Info,
+        "Restoring session with {} pending requests",
+        saved.pending.len()
+    );
+
+    args.track = args.track.or(saved.track);
+    args.scale = args.scale.or(saved.scale);
+    if args.inputs.is_empty() {
+        args.inputs = saved.sources;
+    }
+
+    Ok(saved.pending)
+}
+
+fn do_indexing(
+    args: &CliArgs,
+    ignore_errors: IndexErrorHandling,
+    pending: Vec<String>,
+) -> std::io::Result<()> {
+    let mut session = Session::new();
+    let mut saved = SavedSession {
+        input: Some(args.input_file.clone()),
+        track: args.track,
+        scale: args.scale,
+        sources: args.inputs.clone(),
+        pending,
+    };
+
+    let mut manifest = manifest::Manifest::default();
+    let result = run_session(&mut session, args, ignore_errors, &mut saved, &mut manifest);
+
+    if result.is_err() && session.state() != State::Closing {
+        session.handle(Event::Failed)?;
+    }
+
+    if let Some(ref path) = args.session {
+        saved.save(path)?;
+    }
+
+    if let Some(ref path) = args.manifest {
+        manifest.write(path)?;
+    }
+    if let Some(ref path) = args.hls_playlist {
+        manifest.write_hls(path)?;
+    }
+
+    result
+}
+
+/// Puts the main input and every `--input` on one timeline kept by the
+/// main input, which then announces its length.
+fn join_inputs(args: &CliArgs, inputs: &mut Inputs) -> std::io::Result<()> {
+    if container(args) == Container::Mkv {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "--concat cannot write mkv segments, whose audio is cut from one input",
+        ));
+    }
+
+    let joined: Vec<&Input> = inputs.open_inputs().collect();
+    concat::check_formats(&joined.iter().map(|input| &input.format).collect::<Vec<_>>())?;
+    let timeline = concat::Timeline::new(joined.iter().map(|input| input.source.total_frames));
+
+    let main = &mut inputs.get(args, 0)?.source;
+    main.total_frames = timeline.total();
+    let mut properties: Vec<String> = main.properties.split(' ').map(str::to_string).collect();
+    properties[2] = timeline.total().to_string();
+    main.properties = properties.join(" ");
+    main.timeline = Some(timeline);
+
+    Ok(())
+}
+
+/// An open input with the scaler and format of its segments.
+struct Input {
+    // Declared before `_index` so the sources are dropped first.
+    source: Source,
+    pool: pool::Pool<VideoSource>,
+    warmup: Option<cache::Warmup>,
+    _index: Index,
+    path: PathBuf,
+    scaler: Option<Scaler>,
+    format: SegmentFormat,
+    last_indexed: Instant,
+}
+
+impl Input {
+    fn open(
+        args: &CliArgs,
+        path: &Path,
+        id: usize,
+        ignore_errors: IndexErrorHandling,
+    ) -> std::io::Result<Self> {
+        Input::with_index(args, path, id, index_file(args, path, ignore_errors)?)
+    }
+
+    fn with_index(args: &CliArgs, path: &Path, id: usize, index: Index) -> std::io::Result<Self> {
+        let mut source = open_source(args, path, id, &index, decoder_threads(args.threads))?;
+        if let Some(fps) = args.cfr {
+            cfr::retime(&mut source, fps);
+        }
+        if args.ivtc {
+            ivtc::retime(&mut source);
+        }
+        let (scaler, format) = output_format(args, &source)?;
+
+        Ok(Input {
+            source,
+            pool: open_pool(args, path, &index, args.seek_mode)?,
+            warmup: None,
+            _index: index,
+            path: path.to_path_buf(),
+            scaler,
+            format,
+            last_indexed: Instant::now(),
+        })
+    }
+
+    /// Re-indexes a growing input to pick up frames written since.
+    fn reindex(
+        &mut self,
+        args: &CliArgs,
+        ignore_errors: IndexErrorHandling,
+    ) -> std::io::Result<()> {
+        let index = index_file(args, &self.path, ignore_errors)?;
+        let seek_mode = self.source.seek_mode;
+        self.source = open_source_seeking(
+            args,
+            &self.path,
+            self.source.id,
+            &index,
+            decoder_threads(args.threads),
+            seek_mode,
+        )?;
+        self.pool = open_pool(args, &self.path, &index, seek_mode)?;
+        // The old index may only go once the sources using it are gone.
+        self._index = index;
+        self.last_indexed = Instant::now();
+
+        Ok(())
+    }
+
+    /// Starts decoding `start..end`, padded like requests are, into the
+    /// frame cache on a decoder of its own, for the `prefetch` command.
+    /// The warm-up before it is finished first.
+    fn prefetch(&mut self, args: &CliArgs, start: usize, end: usize) -> std::io::Result<()> {
+        if let Some(warmup) = self.warmup.take() {
+            warmup.finish(&mut self.source.frame_cache);
+        }
+        let decoder = VideoSource::new(
+            &self.path,
+            select_video_track(args, &self._index)?,
+            &self._index,
+            decoder_threads(args.threads),
+            self.source.seek_mode,
+        )
+        .map_err(|err| {
+            std::io::Error::other(format!("Failed to open {}: {:?}", self.path.display(), err))
+        })?;
+
+        // The cache holds the source frames `--cfr` and `--ivtc` are made of.
+        let first = source_frame(&self.source, start.saturating_sub(args.pad_before));
+        let last = source_frame(&self.source, end + args.pad_after);
+        self.warmup = Some(cache::Warmup::spawn(
+            decoder,
+            first,
+            last,
+            args.frame_cache_mb.unwrap_or(0) << 20,
+        ));
+
+        Ok(())
+    }
+
+    /// Moves the frames the `prefetch` warm-up has decoded into the frame
+    /// cache before a request for `start..end`, waiting for the rest if the
+    /// two overlap so they are not decoded twice.
+    fn take_warmed(&mut self, start: usize, end: usize) {
+        let Some(ref warmup) = self.warmup else {
+            return;
+        };
+        let (first, last) = (
+            source_frame(&self.source, start),
+            source_frame(&self.source, end),
+        );
+        let done = if warmup.start < last && first < warmup.end {
+            log!(
+                Info,
+                "Waiting for the prefetch of {} to {}",
+                warmup.start,
+                warmup.end
+            );
+            true
+        } else {
+            warmup.drain(&mut self.source.frame_cache)
+        };
+        if done {
+            if let Some(warmup) = self.warmup.take() {
+                warmup.finish(&mut self.source.frame_cache);
+            }
+        }
+    }
+
+    /// Replaces a decoder that `--decode-timeout` gave up on with a new one.
+    /// The stuck one is leaked, as its decode is still running.
+    fn replace_hung(&mut self, args: &CliArgs) -> std::io::Result<()> {
+        if !prefetch::is_hung(&self.source.video_source) {
+            return Ok(());
+        }
+        let mut fresh = open_source_seeking(
+            args,
+            &self.path,
+            self.source.id,
+            &self._index,
+            decoder_threads(args.threads),
+            self.source.seek_mode,
+        )?;
+
+        // The track belongs to the decoder it was taken from.
+        std::mem::swap(&mut self.source.video_source, &mut fresh.video_source);
+        std::mem::swap(&mut self.source.track, &mut fresh.track);
+        prefetch::release_hung(&self.source.video_source);
+        let Source { video_source, .. } = fresh;
+        std::mem::forget(video_source);
+        log!(Info, "Opened a new decoder for input {}", self.source.id);
+
+        Ok(())
+    }
+
+    /// Closes the decoders, keeping the index to reopen them from.
+    fn close(self) -> Index {
+        let Input {
+            source,
+            pool,
+            warmup,
+            _index,
+            ..
+        } = self;
+        drop(warmup);
+        drop(source);
+        drop(pool);
+        _index
+    }
+}
+
+enum Slot {
+    Open(Box<Input>),
+    Closed(Index),
+}
+
+/// The inputs of a session by id. Inputs closed by `--evict-idle` keep
+/// their id and are reopened when next requested.
+struct Inputs {
+    slots: Vec<Option<Slot>>,
+    paths: Vec<PathBuf>,
+    last_used: Vec<Instant>,
+}
+
+impl Inputs {
+    fn new() -> Self {
+        Inputs {
+            slots: Vec::new(),
+            paths: Vec::new(),
+            last_used: Vec::new(),
+        }
+    }
+
+    /// Opens `path` as the next input, returning its id.
+    fn open(
+        &mut self,
+        args: &CliArgs,
+        path: &Path,
+        ignore_errors: IndexErrorHandling,
+    ) -> std::io::Result<usize> {
+        let id = self.slots.len();
+        let input = Input::open(args, path, id, ignore_errors)?;
+        self.slots.push(Some(Slot::Open(Box::new(input))));
+        self.paths.push(path.to_path_buf());
+        self.last_used.push(Instant::now());
+
+        Ok(id)
+    }
+
+    fn get(&mut self, args: &CliArgs, id: usize) -> std::io::Result<&mut Input> {
+        let slot = self
+            .slots
+            .get_mut(id)
+            .ok_or_else(|| std::io::Error::other(format!("Unknown input {}", id)))?;
+        if let Some(Slot::Closed(index)) = slot.take_if(|slot| matches!(slot, Slot::Closed(_))) {
+            log!(Info, "Reopening input {}", id);
+            let input = Input::with_index(args, &self.paths[id], id, index)?;
+            *slot = Some(Slot::Open(Box::new(input)));
+        }
+        self.last_used[id] = Instant::now();
+
+        match slot {
+            Some(Slot::Open(input)) => Ok(&mut **input),
+            // Left empty only by a failed reopen.
+            _ => Err(std::io::Error::other(format!(
+                "Input {} failed to reopen",
+                id
+            ))),
+        }
+    }
+
+    /// The main input and the decoders of the inputs following it on its
+    /// `--concat` timeline.
+    fn concat(&mut self) -> std::io::Result<(&mut Input, Vec<&mut VideoSource>)> {
+        let closed = || std::io::Error::other("Concatenated inputs cannot be closed");
+        let (first, rest) = self.slots.split_first_mut().ok_or_else(closed)?;
+        let Some(Slot::Open(first)) = first else {
+            return Err(closed());
+        };
+        let joined = first
+            .source
+            .timeline
+            .as_ref()
+            .map_or(1, |timeline| timeline.inputs());
+
+        let following = rest[..joined - 1]
+            .iter_mut()
+            .map(|slot| match slot {
+                Some(Slot::Open(input)) => Ok(&mut input.source.video_source),
+                _ => Err(closed()),
+            })
+            .collect::<std::io::Result<_>>()?;
+
+        Ok((&mut **first, following))
+    }
+
+    /// Replaces the stuck decoders of the open inputs, as
+    /// `Input::replace_hung`.
+    fn replace_hung(&mut self, args: &CliArgs) -> std::io::Result<()> {
+        for slot in &mut self.slots {
+            if let Some(Slot::Open(input)) = slot {
+                input.replace_hung(args)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn open_inputs(&self) -> impl Iterator<Item = &Input> {
+        self.slots.iter().filter_map(|slot| match slot {
+            Some(Slot::Open(input)) => Some(&**input),
+            _ => None,
+        })
+    }
+
+    /// Closes the decoders of the inputs no request has used for `idle`.
+    fn evict(&mut self, idle: time::Duration) {
+        for (id, slot) in self.slots.iter_mut().enumerate() {
+            if matches!(slot, Some(Slot::Open(_))) && self.last_used[id].elapsed() >= idle {
+                log!(Info, "Closing input {}, idle for {:?}", id, idle);
+                if let Some(Slot::Open(input)) = slot.take() {
+                    *slot = Some(Slot::Closed(input.close()));
+                }
+            }
+        }
+    }
+}
+
+/// Whether `seek_mode` seeks to the requested frames rather than decoding
+/// up to them.
+fn seeks(seek_mode: video::SeekMode) -> bool {
+    matches!(
+        seek_mode,
+        video::SeekMode::SEEK_NORMAL
+            | video::SeekMode::SEEK_UNSAFE
+            | video::SeekMode::SEEK_AGGRESSIVE
+    )
+}
+
+/// Reopens the decoder of `source` with `SEEK_LINEAR_NO_RW`, for inputs
+/// FFMS2 cannot seek in. The frames cached so far may be the wrong ones
+/// and are dropped. `index` must outlive the decoder.
+fn seek_linearly(
+    args: &CliArgs,
+    source: &mut Source,
+    input: &Path,
+    index: &Index,
+) -> std::io::Result<()> {
+    let mut linear = open_source_seeking(
+        args,
+        input,
+        source.id,
+        index,
+        decoder_threads(args.threads),
+        video::SeekMode::SEEK_LINEAR_NO_RW,
+    )?;
+
+    // The track belongs to the decoder it was taken from.
+    std::mem::swap(&mut source.video_source, &mut linear.video_source);
+    std::mem::swap(&mut source.track, &mut linear.track);
+    std::mem::swap(&mut source.frame_cache, &mut linear.frame_cache);
+    source.seek_mode = linear.seek_mode;
+
+    Ok(())
+}
+
+/// The spare decoders of `--source-pool`. `index` must outlive them.
+fn open_pool(
+    args: &CliArgs,
+    input: &Path,
+    index: &Index,
+    seek_mode: video::SeekMode,
+) -> std::io::Result<pool::Pool<VideoSource>> {
+    let video_track_id = select_video_track(args, index)?;
+
+    let spares = (1..args.source_pool)
+        .map(|_| {
+            VideoSource::new(
+                input,
+                video_track_id,
+                index,
+                decoder_threads(args.threads),
+                seek_mode,
+            )
+            .map_err(|err| {
+                std::io::Error::other(format!("Failed to open {}: {:?}", input.display(), err))
+            })
+        })
+        .collect::<std::io::Result<_>>()?;
+
+    Ok(pool::Pool::new(spares))
+}
+
+/// The channel the server modes feed the session through, bounded by
+/// `--queue-capacity`.
+fn server_transport(args: &CliArgs) -> (transport::ChannelTransport, transport::Client) {
+    let (transport, client) = match args.queue_capacity {
+        Some(capacity) => transport::bounded_channel_transport(capacity, args.queue_full),
+        None => transport::channel_transport(),
+    };
+    interrupt::drain_on_sigterm(
+        client.drainer(),
+        time::Duration::from_secs(args.drain_timeout),
+    );
+
+    // Idle inputs are checked a few times per `--evict-idle`.
+    match args.evict_idle {
+        Some(idle) => {
+            let check = time::Duration::from_secs(idle).max(time::Duration::from_secs(4)) / 4;
+            (transport.idle_command(check), client)
+        }
+        None => (transport, client),
+    }
+}
+
+/// Starts the `--grpc` server on its own thread, feeding the session
+/// through a channel.
+#[cfg(feature = "grpc")]
+fn grpc_transport(args: &CliArgs) -> std::io::Result<transport::ChannelTransport> {
+    let (transport, client) = server_transport(args);
+    let addr = args.grpc.unwrap();
+
+    thread::spawn(move || {
+        if let Err(err) = grpc::serve(addr, client) {
+            log!(Error, "gRPC server failed: {}", err);
+        }
+    });
+
+    Ok(transport)
+}
+
+#[cfg(not(feature = "grpc"))]
+fn grpc_transport(_args: &CliArgs) -> std::io::Result<transport::ChannelTransport> {
+    Err(std::io::Error::other(
+        "Built without the grpc feature, --grpc is unavailable",
+    ))
+}
+
+/// A command of the session, other than a range request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Open,
+    Prefetch,
+    Thumb,
+    Frames,
+    Info,
+    Duration,
+    Tracks,
+    Frame,
+    Keyframes,
+    Chunks,
+    Chunk,
+    Compare,
+    Status,
+    Cancel,
+}
+
+/// The commands, by the first word of their lines.
+const COMMANDS: [(&str, Command); 14] = [
+    ("open", Command::Open),
+    ("prefetch", Command::Prefetch),
+    ("thumb", Command::Thumb),
+    ("frames", Command::Frames),
+    ("info", Command::Info),
+    ("duration", Command::Duration),
+    ("tracks", Command::Tracks),
+    ("frame", Command::Frame),
+    ("keyframes", Command::Keyframes),
+    ("chunks", Command::Chunks),
+    ("chunk", Command::Chunk),
+    ("compare", Command::Compare),
+    ("status", Command::Status),
+    ("cancel", Command::Cancel),
+];
+
+/// Splits a command line into its command and arguments, or returns `None`
+/// for a range request.
+fn parse_command(input: &str) -> Option<(Command, &str)> {
+    let (word, arguments) = input
+        .trim()
+        .split_once(char::is_whitespace)
+        .unwrap_or((input.trim(), ""));
+    COMMANDS
+        .iter()
+        .find(|(name, _)| *name == word)
+        .map(|&(_, command)| (command, arguments.trim()))
+}
+
+fn command_name(command: Command) -> &'static str {
+    COMMANDS
+        .iter()
+        .find(|&&(_, named)| named == command)
+        .map_or("", |(name, _)| name)
+}
+
+/// Whether `--handshake` announces `command`. Requests from stdin run one
+/// at a time, with none left to cancel by the time `cancel` is read, and
+/// the chunk commands only have chunks to list with `--chunks`.
+fn announced(args: &CliArgs, command: Command) -> bool {
+    match command {
+        Command::Cancel => false,
+        Command::Chunks | Command::Chunk => args.chunks.is_some(),
+        _ => true,
+    }
+}
+
+/// Answers a malformed command with an `error` line instead of ending the
+/// session. Returns the parsed value, or `None` once answered.
+fn answer_invalid<T>(
+    transport: &mut dyn Transport,
+    parsed: std::io::Result<T>,
+) -> std::io::Result<Option<T>> {
+    match parsed {
+        Ok(parsed) => Ok(Some(parsed)),
+        Err(err) if err.kind() == std::io::ErrorKind::InvalidInput => {
+            log!(Warn, "{}", err);
+            transport.send(&format!("error {}", err))?;
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Parses the `<path>` argument of `open`.
+fn parse_open(arguments: &str) -> std::io::Result<PathBuf> {
+    match arguments {
+        "" => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Invalid open command, expected <path>",
+        )),
+        path => Ok(PathBuf::from(path)),
+    }
+}
+
+/// Parses the `<id>` argument of `cancel`.
+fn parse_cancel(arguments: &str) -> std::io::Result<&str> {
+    match arguments.split_whitespace().collect::<Vec<&str>>()[..] {
+        [id] => Ok(id),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid cancel command, expected <id>: {}", arguments),
+        )),
+    }
+}
+
+/// Checks that `command`, which takes no arguments, was given none.
+fn parse_bare(command: Command, arguments: &str) -> std::io::Result<()> {
+    match arguments {
+        "" => Ok(()),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Invalid {} command, expected no arguments: {}",
+                command_name(command),
+                arguments
+            ),
+        )),
+    }
+}
+
+/// Parses the optional `[<input>]` argument of `info`, `tracks` and
+/// `duration`, 0 for the main input.
+fn parse_input_id(arguments: &str) -> std::io::Result<usize> {
+    match arguments {
+        "" => Ok(0),
+        id => id.parse().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unknown input {}", id),
+            )
+        }),
+    }
+}
+
+/// Parses the `[<input>] <frame>` arguments of `frame`.
+fn parse_frame(arguments: &str) -> std::io::Result<(usize, usize)> {
+    let numbers = arguments
+        .split_whitespace()
+        .map(str::parse::<usize>)
+        .collect::<Result<Vec<usize>, _>>();
+    match numbers.as_deref() {
+        Ok(&[frame]) => Ok((0, frame)),
+        Ok(&[id, frame]) => Ok((id, frame)),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Invalid frame command, expected [<input>] <frame>: {}",
+                arguments
+            ),
+        )),
+    }
+}
+
+/// Parses the optional `[<start> <end>]` arguments of `keyframes`.
+fn parse_keyframes(arguments: &str) -> std::io::Result<Option<(usize, usize)>> {
+    let bounds = arguments
+        .split_whitespace()
+        .map(str::parse::<usize>)
+        .collect::<Result<Vec<usize>, _>>();
+    match bounds.as_deref() {
+        Ok([]) => Ok(None),
+        Ok(&[start, end]) if start < end => Ok(Some((start, end))),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Invalid keyframes command, expected [<start> <end>]: {}",
+                arguments
+            ),
+        )),
+    }
+}
+
+/// Parses the `<index>` argument of `chunk` into the frames of that chunk.
+fn parse_chunk(arguments: &str, chunks: &[(usize, usize)]) -> std::io::Result<(usize, usize)> {
+    arguments
+        .parse::<usize>()
+        .ok()
+        .and_then(|index| chunks.get(index).copied())
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unknown chunk {}", arguments),
+            )
+        })
+}
+
+/// Parses the `<frame> <path.png> [WIDTHxHEIGHT]` arguments of `thumb`.
+fn parse_thumb(arguments: &str) -> std::io::Result<(usize, &str, Option<PlaneSize>)> {
+    let invalid = |reason: String| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid thumb command, {}: {}", reason, arguments),
+        )
+    };
+
+    match arguments.split_whitespace().collect::<Vec<&str>>()[..] {
+        [frame, path, ref size @ ..] if size.len() <= 1 => {
+            let frame = frame
+                .parse()
+                .map_err(|_| invalid("expected a frame number".to_string()))?;
+            let size = size
+                .first()
+                .map(|size| scale::parse_size(size))
+                .transpose()
+                .map_err(invalid)?;
+
+            Ok((frame, path, size))
+        }
+        _ => Err(invalid(
+            "expected <frame> <path.png> [WIDTHxHEIGHT]".to_string(),
+        )),
+    }
+}
+
+/// Parses the `<start> <end> <encoded>` arguments of `compare`.
+fn parse_compare(arguments: &str) -> std::io::Result<(usize, usize, &str)> {
+    let invalid = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Invalid compare command, expected <start> <end> <encoded>: {}",
+                arguments
+            ),
+        )
+    };
+
+    let mut parts = arguments.trim().splitn(3, ' ');
+    let start = parts.next().and_then(|start| start.parse::<usize>().ok());
+    let end = parts.next().and_then(|end| end.parse::<usize>().ok());
+    match (start, end, parts.next().map(str::trim)) {
+        (Some(start), Some(end), Some(encoded)) if start < end && !encoded.is_empty() => {
+            Ok((start, end, encoded))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Binds the `--http` address and serves it on its own thread, feeding the
+/// session through a channel.
+fn http_transport(args: &CliArgs) -> std::io::Result<transport::ChannelTransport> {
+    let listener = std::net::TcpListener::bind(args.http.unwrap())?;
+    let (transport, client) = server_transport(args);
+
+    log!(Info, "Serving HTTP on {}", listener.local_addr()?);
+    thread::spawn(move || http::serve(listener, client));
+
+    Ok(transport)
+}
+
+/// Binds the `--websocket` address and serves it on its own thread,
+/// feeding the session through a channel.
+fn websocket_transport(args: &CliArgs) -> std::io::Result<transport::ChannelTransport> {
+    let listener = std::net::TcpListener::bind(args.websocket.unwrap())?;
+    let (transport, client) = server_transport(args);
+
+    log!(Info, "Serving WebSocket on {}", listener.local_addr()?);
+    thread::spawn(move || websocket::serve(listener, client));
+
+    Ok(transport)
+}
+
+/// Starts the `--daemon` control socket on its own thread, feeding the
+/// session through a channel.
+#[cfg(unix)]
+fn daemon_transport(args: &CliArgs) -> std::io::Result<transport::ChannelTransport> {
+    let path = args.daemon.as_ref().unwrap();
+    let listener = frame_server::bind(path)?;
+    let (transport, client) = server_transport(args);
+
+    log!(Info, "Serving sessions on {}", path.display());
+    thread::spawn(move || daemon::serve(listener, client));
+
+    Ok(transport)
+}
+
+#[cfg(not(unix))]
+fn daemon_transport(_args: &CliArgs) -> std::io::Result<transport::ChannelTransport> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "--daemon needs a Unix platform",
+    ))
+}
+
+/// The `hello` line sent first with `--handshake`.
+fn handshake(args: &CliArgs) -> Handshake {
+    let mut handshake = Handshake::new(Protocol::LATEST, env!("CARGO_PKG_VERSION"));
+    handshake.commands = std::iter::once("range")
+        .chain(
+            COMMANDS
+                .iter()
+                .filter(|&&(_, command)| announced(args, command))
+                .map(|(name, _)| *name),
+        )
+        .map(String::from)
+        .collect();
+    // As in the `C` header parameter, which y4m writes from the variant.
+    for &(_, colorspace, _) in &COLORSPACES {
+        let name = format!("{:?}", colorspace)[1..].to_string();
+        if !handshake.colorspaces.contains(&name) {
+            handshake.colorspaces.push(name);
+        }
+    }
+    handshake.formats = CONTAINERS
+        .iter()
+        .map(|(name, _)| name.to_string())
+        .chain(["rgb", "png"].map(String::from))
+        .collect();
+    handshake.ranges = Some(args.range_convention);
+    handshake
+}
+
+/// Parses the `<start> <end> <folder> [tiff|exr]` arguments of `frames`.
+fn parse_frames(arguments: &str) -> std::io::Result<(usize, usize, &str, stills::StillFormat)> {
+    let invalid = |reason: String| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid frames command, {}: {}", reason, arguments),
+        )
+    };
+
+    match arguments.split_whitespace().collect::<Vec<&str>>()[..] {
+        [start, end, folder, ref format @ ..] if format.len() <= 1 => {
+            let frame = |frame: &str| {
+                frame
+                    .parse()
+                    .map_err(|_| invalid("expected frame numbers".to_string()))
+            };
+            let (start, end) = (frame(start)?, frame(end)?);
+            let format = format
+                .first()
+                .map(|format| stills::parse_still_format(format))
+                .transpose()
+                .map_err(invalid)?
+                .unwrap_or(stills::StillFormat::Tiff);
+
+            Ok((start, end, folder, format))
+        }
+        _ => Err(invalid(
+            "expected <start> <end> <folder> [tiff|exr]".to_string(),
+        )),
+    }
+}
+
+/// Runs requests until input closes. `saved` tracks the additional inputs
+/// and unfinished requests for `--session`.
+fn run_session(
+    session: &mut Session,
+    args: &CliArgs,
+    ignore_errors: IndexErrorHandling,
+    saved: &mut SavedSession,
+    manifest: &mut manifest::Manifest,
+) -> std::io::Result<()> {
+    let planner = build_planner(args)?;
+    if args.compress.is_some() && !matches!(container(args), Container::Y4m | Container::Raw) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "--compress only applies to y4m and raw segments",
+        ));
+    }
+    if args.naming == Naming::Timecode && args.concat {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "--naming timecode cannot name segments of --concat timelines",
+        ));
+    }
+    let planned =
+        args.zones.is_some() || args.chapters || args.auto_split.is_some() || args.cuts.is_some();
+    if (args.qpfile.is_some() || args.force_key_frames.is_some()) && !planned {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "--qpfile and --force-key-frames need a plan: --zones, --chapters, --auto-split or --cuts",
+        ));
+    }
+    if args.decoder == Backend::Ffmpeg
+        && (args.concat || args.decode_timeout.is_some() || args.segment_timeout.is_some())
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "--decoder ffmpeg cannot decode --concat timelines or watch --decode-timeout and --segment-timeout",
+        ));
+    }
+    if args.stream.is_some() && container(args) != Container::Y4m {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "--stream only writes y4m segments",
+        ));
+    }
+    let output = output::open(
+        args.output_url.as_deref(),
+        args.exec.as_deref(),
+        args.stream.as_deref(),
+        output_folder(args),
+        args.write_buffer_kb * 1024,
+        args.compress,
+    )?;
+    let macros = args.macros.as_deref().map(Macros::load).transpose()?;
+    let mut hooks = args
+        .post_cmd
+        .as_deref()
+        .map(|template| hook::Hooks::new(template, args.post_jobs));
+    let mut events = args.events_fd.map(SegmentEvents::from_fd).transpose()?;
+    // Segments given up on by --decode-timeout and --segment-timeout.
+    let mut timed_out = 0;
+    // Frames written to the --stream so far.
+    let mut stream_frames = 0;
+
+    let stdout = std::io::stdout();
+    let (mut transport, mut journal): (Box<dyn Transport>, _) = match args.zones {
+        Some(ref zones) => {
+            let requests: String = zones::read(zones)?
+                .into_iter()
+                .map(|(start, end)| args.range_convention.format_range(start, end) + "\n")
+                .collect();
+            let journal_path = output_folder(args).join("segments.journal");
+
+            (
+                Box::new(
+                    LineTransport::new(std::io::Cursor::new(requests), stdout.lock())
+                        .formatted(args.format),
+                ),
+                Some(Journal::open(&journal_path, args.resume)?),
+            )
+        }
+        // The chapter and split requests are queued once the input is open.
+        None if args.chapters || args.auto_split.is_some() || args.cuts.is_some() => (
+            Box::new(
+                LineTransport::new(std::io::Cursor::new(String::new()), stdout.lock())
+                    .formatted(args.format),
+            ),
+            None,
+        ),
+        None if args.grpc.is_some() => (Box::new(grpc_transport(args)?), None),
+        None if args.http.is_some() => (Box::new(http_transport(args)?), None),
+        None if args.websocket.is_some() => (Box::new(websocket_transport(args)?), None),
+        None if args.daemon.is_some() => (Box::new(daemon_transport(args)?), None),
+        None if args.interactive => {
+            let repl = interactive::Repl::new(std::io::stdin().lock(), stdout.lock());
+            #[cfg(feature = "readline")]
+            let repl = repl.edited();
+            (Box::new(repl), None)
+        }
+        None if args.binary => (
+            Box::new(
+                FramedTransport::new(std::io::stdin().lock(), stdout.lock()).formatted(args.format),
+            ),
+            None,
+        ),
+        None => (
+            Box::new(
+                LineTransport::new(std::io::stdin().lock(), stdout.lock()).formatted(args.format),
+            ),
+            None,
+        ),
+    };
+
+    if let Some(addr) = args.metrics {
+        let listener = std::net::TcpListener::bind(addr)?;
+        log!(Info, "Serving metrics on {}", listener.local_addr()?);
+        thread::spawn(move || http::serve_metrics(listener));
+    }
+
+    if args.handshake {
+        transport.send(&handshake(args).format())?;
+    }
+
+    let mut inputs = Inputs::new();
+    inputs.open(args, &args.input_file, ignore_errors)?;
+    for path in &args.inputs {
+        inputs.open(args, path, ignore_errors)?;
+    }
+    if args.concat {
+        join_inputs(args, &mut inputs)?;
+    }
+    // Concatenated inputs are announced as the one timeline.
+    let announced = if args.concat { 1 } else { usize::MAX };
+    for input in inputs.open_inputs().take(announced) {
+        transport.send(&input.source.properties)?;
+    }
+
+    let chunks = match args.chunks {
+        Some(ref spec) => {
+            let source = &inputs.get(args, 0)?.source;
+            chunks::load(spec, &source.track, source.total_frames)?
+        }
+        None => Vec::new(),
+    };
+    let first_pass = match args.first_pass {
+        Some(ref path) => Some(analysis::Analysis::load(
+            path,
+            inputs.get(args, 0)?.source.total_frames,
+        )?),
+        None => None,
+    };
+
+    // Requests restored from a saved session run before any new ones. Each
+    // request stays in `pending` until it finishes, so the one in flight is
+    // always the first entry.
+    let pending = &mut saved.pending;
+    let mut restored_remaining = pending.len();
+
+    session.handle(Event::Opened)?;
+
+    // Commands that run before reading more input: restored requests, then
+    // the results of macro expansion.
+    let mut queued: VecDeque<String> = pending.iter().cloned().collect();
+    if args.chapters && restored_remaining == 0 {
+        let source = &inputs.get(args, 0)?.source;
+        let chapters = chapters::read(&args.input_file)?;
+        if chapters.is_empty() {
+            log!(Warn, "{} has no chapters", args.input_file.display());
+        }
+        queued.extend(chapters::requests(
+            &chapters,
+            &source.track,
+            source.total_frames,
+            args.range_convention,
+        ));
+    }
+    if let Some(length) = args.auto_split.filter(|_| restored_remaining == 0) {
+        let source = &inputs.get(args, 0)?.source;
+        let scores = first_pass.as_ref().map(|analysis| analysis.scores());
+        let segments = chunks::auto_split(
+            &source.track,
+            source.total_frames,
+            length,
+            scores.as_deref().unwrap_or_default(),
+        );
+        log!(Info, "Splitting the input into {} segments", segments.len());
+        queued.extend(
+            segments
+                .into_iter()
+                .map(|(start, end)| args.range_convention.format_range(start, end)),
+        );
+    }
+    if let Some(path) = args.cuts.as_ref().filter(|_| restored_remaining == 0) {
+        let total = inputs.get(args, 0)?.source.total_frames;
+        let segments = cuts::segments(&cuts::read(path)?, total);
+        log!(
+            Info,
+            "Cutting the input into {} segments from {}",
+            segments.len(),
+            path.display()
+        );
+        queued.extend(
+            segments
+                .into_iter()
+                .map(|(start, end)| args.range_convention.format_range(start, end)),
+        );
+    }
+    if restored_remaining == 0 && (args.qpfile.is_some() || args.force_key_frames.is_some()) {
+        // Every planned request starts with its first frame.
+        let keyframes = match args.zones {
+            Some(ref zones) => {
+                placement::keyframes(zones::read(zones)?.into_iter().map(|(start, _)| start))
+            }
+            None => placement::keyframes(
+                queued
+                    .iter()
+                    .filter_map(|request| request.split_whitespace().next()?.parse().ok()),
+            ),
+        };
+        placement::write(
+            args.qpfile.as_deref(),
+            args.force_key_frames.as_deref(),
+            &keyframes,
+        )?;
+    }
+
+    let mut input;
+    while session.state() != State::Closing {
+        match queued.pop_front() {
+            Some(command) => input = command,
+            None => {
+                // Everything queued by the previous command has run.
+                transport.finish()?;
+
+                match transport.receive()? {
+                    Some(command) => input = command,
+                    None => {
+                        session.handle(Event::InputClosed)?;
+                        continue;
+                    }
+                }
+            }
+        }
+
+        if let Some(ref macros) = macros {
+            if let Some(commands) = macros.expand(&input)? {
+                for command in commands.into_iter().rev() {
+                    queued.push_front(command);
+                }
+                continue;
+            }
+        }
+
+        // Several ranges on one line are queued together, so they all run
+        // before the next line is read.
+        if let Some(commands) = ranges::split_ranges(&input) {
+            for command in commands.into_iter().rev() {
+                queued.push_front(command);
+            }
+            continue;
+        }
+
+        // Sent by the server transports while idle, with `--evict-idle`.
+        if input.trim() == transport::IDLE_COMMAND {
+            if let Some(idle) = args.evict_idle {
+                inputs.evict(time::Duration::from_secs(idle));
+            }
+            continue;
+        }
+
+        session.handle(Event::RequestReceived)?;
+
+        let now = Instant::now();
+
+        input = input.trim().to_string();
+
+        log!(Info, "Input: {}", input);
+
+        if restored_remaining > 0 {
+            restored_remaining -= 1;
+        } else {
+            pending.push(input.clone());
+        }
+
+        if let Some((command, arguments)) = parse_command(&input) {
+            // `chunk` becomes the range request of its chunk, the others
+            // finish here.
+            let mut request = None;
+            let transport = &mut *transport;
+
+            match command {
+                Command::Open => {
+                    if let Some(path) = answer_invalid(transport, parse_open(arguments))? {
+                        let id = inputs.open(args, &path, ignore_errors)?;
+                        transport.send(&inputs.get(args, id)?.source.properties)?;
+                        transport.send(&format!("opened {} {}", id, path.display()))?;
+                        saved.sources.push(path);
+                    }
+                }
+                Command::Prefetch => {
+                    let parsed = parse_request(arguments, args.range_convention);
+                    if let Some((input_id, start, end)) = answer_invalid(transport, parsed)? {
+                        let current = inputs.get(args, input_id)?;
+                        let refused = match (args.frame_cache_mb, &current.source.timeline) {
+                            (None | Some(0), _) => {
+                                Some("prefetch needs a --frame-cache-mb cache to fill")
+                            }
+                            (_, Some(_)) => Some("prefetch cannot warm up --concat timelines"),
+                            _ if start >= end => Some("prefetch needs the start before the end"),
+                            _ => None,
+                        };
+                        match refused {
+                            Some(reason) => transport.send(&format!("error {}", reason))?,
+                            None => {
+                                current.prefetch(args, start, end)?;
+                                transport.send(&format!("prefetching {} {}", start, end))?;
+                            }
+                        }
+                    }
+                }
+                Command::Thumb => {
+                    if let Some((frame, path, size)) =
+                        answer_invalid(transport, parse_thumb(arguments))?
+                    {
+                        let source = &mut inputs.get(args, 0)?.source;
+                        thumb::write(source, frame, path.as_ref(), size, args.gpu)?;
+                        transport.send(&format!("thumb {} {}", frame, path))?;
+                    }
+                }
+                Command::Frames => {
+                    if let Some((start, end, folder, format)) =
+                        answer_invalid(transport, parse_frames(arguments))?
+                    {
+                        let source = &mut inputs.get(args, 0)?.source;
+                        let written = stills::write(source, start, end, folder.as_ref(), format)?;
+                        transport.send(&format!(
+                            "frames {} {} {}",
+                            start,
+                            start + written,
+                            folder
+                        ))?;
+                    }
+                }
+                // Server mode clients handle `cancel` and `status` themselves,
+                // so nothing read here is ever queued or running. Requests
+                // from stdin run one at a time, with none left to cancel by
+                // the time this is read, so stdin does not announce `cancel`.
+                Command::Cancel => {
+                    if let Some(id) = answer_invalid(transport, parse_cancel(arguments))? {
+                        transport.send(&format!("cancel {} unknown", id))?;
+                    }
+                }
+                // Stdin requests have no ids, and only this one is running.
+                Command::Status => {
+                    if let Some(()) = answer_invalid(transport, parse_bare(command, arguments))? {
+                        transport.send("{\"requests\":[]}")?;
+                    }
+                }
+                Command::Compare => {
+                    if let Some((start, end, encoded)) =
+                        answer_invalid(transport, parse_compare(arguments))?
+                    {
+                        let input = inputs.get(args, 0)?;
+                        let end = end.min(input.source.total_frames);
+                        let scores =
+                            compare::run(args, ignore_errors, input, start, end, encoded.as_ref())?;
+                        compare::write_report(
+                            segment_path(args, &input.source, start, end, "compare").as_ref(),
+                            &scores,
+                        )?;
+
+                        let (psnr, ssim) = compare::aggregate(&scores, input.format.colorspace);
+                        transport.send(&format!(
+                            "compare {} {} frames={} psnr={:.3} ssim={:.5}",
+                            start,
+                            end,
+                            scores.len(),
+                            psnr,
+                            ssim
+                        ))?;
+                    }
+                }
+                Command::Info => {
+                    if let Some(id) = answer_invalid(transport, parse_input_id(arguments))? {
+                        transport.send(&inputs.get(args, id)?.source.info.to_json())?;
+                    }
+                }
+                Command::Tracks => {
+                    if let Some(id) = answer_invalid(transport, parse_input_id(arguments))? {
+                        let current = inputs.get(args, id)?;
+                        let lines = describe_tracks(args, &current.path, &current._index);
+                        for line in &lines {
+                            transport.send(&format!("track {}", line))?;
+                        }
+                        transport.send(&format!("tracks {}", lines.len()))?;
+                    }
+                }
+                Command::Duration => {
+                    if let Some(id) = answer_invalid(transport, parse_input_id(arguments))? {
+                        let current = inputs.get(args, id)?;
+                        let tracks = track_times(current);
+                        let video = tracks
+                            .iter()
+                            .find(|track| track.id == current.source.info.video_track)
+                            .ok_or_else(|| {
+                                std::io::Error::other("The video track is not in the index")
+                            })?;
+                        transport.send(&duration::to_json(id, video, &tracks))?;
+                    }
+                }
+                Command::Frame => {
+                    if let Some((id, frame)) = answer_invalid(transport, parse_frame(arguments))? {
+                        let described =
+                            stats::describe_frame(&mut inputs.get(args, id)?.source, frame);
+                        if let Some(description) = answer_invalid(transport, described)? {
+                            transport.send(&description)?;
+                        }
+                    }
+                }
+                Command::Keyframes => {
+                    if let Some(bounds) = answer_invalid(transport, parse_keyframes(arguments))? {
+                        let source = &inputs.get(args, 0)?.source;
+                        let range = match bounds {
+                            Some((start, end)) => start..end.min(source.total_frames),
+                            None => 0..source.total_frames,
+                        };
+                        let keyframes: Vec<String> = range
+                            .filter(|&n| source.track.FrameInfo(n).KeyFrame() != 0)
+                            .map(|n| n.to_string())
+                            .collect();
+                        transport.send(format!("keyframes {}", keyframes.join(" ")).trim_end())?;
+                    }
+                }
+                Command::Chunks => {
+                    if let Some(()) = answer_invalid(transport, parse_bare(command, arguments))? {
+                        transport.send(&format!("chunks {}", chunks.len()))?;
+                        for (index, &(start, end)) in chunks.iter().enumerate() {
+                            let complexity = first_pass
+                                .as_ref()
+                                .map(|analysis| {
+                                    format!(" complexity={:.3}", analysis.complexity(start, end))
+                                })
+                                .unwrap_or_default();
+                            transport.send(&format!(
+                                "chunk {} {} {} {}{}",
+                                index,
+                                start,
+                                end - 1,
+                                end - start,
+                                complexity
+                            ))?;
+                        }
+                    }
+                }
+                Command::Chunk => {
+                    request = answer_invalid(transport, parse_chunk(arguments, &chunks))?
+                        .map(|(start, end)| args.range_convention.format_range(start, end));
+                }
+            }
+
+            match request {
+                Some(request) => input = request,
+                None => {
+                    pending.remove(0);
+                    session.handle(Event::RequestFinished)?;
+                    continue;
+                }
+            }
+        }
+
+        let parsed = split_options(args, &input).and_then(|(options, command)| {
+            Ok((options, parse_request(&command, args.range_convention)?))
+        });
+        let (options, (input_id, start, requested_end)) =
+            match answer_invalid(&mut *transport, parsed)? {
+                Some(parsed) => parsed,
+                None => {
+                    pending.remove(0);
+                    session.handle(Event::RequestFinished)?;
+                    continue;
+                }
+            };
+        let _span = log::span(
+            "segment",
+            &[
+                ("input", &input_id),
+                ("start", &start),
+                ("end", &requested_end),
+            ],
+        );
+
+        if start >= requested_end {
+            transport.send(&format!(
+                "{} {} is empty, the start must come before the end",
+                start, requested_end
+            ))?;
+            pending.remove(0);
+            session.handle(Event::RequestFinished)?;
+            continue;
+        }
+
+        let (current, mut following) = match args.concat {
+            true if input_id == 0 => inputs.concat()?,
+            _ => (inputs.get(args, input_id)?, Vec::new()),
+        };
+
+        // A growing input is re-indexed at most once per interval and only
+        // when a request reaches past the frames indexed so far.
+        if args.watch
+            && requested_end > current.source.total_frames
+            && current.last_indexed.elapsed() >= time::Duration::from_secs(args.watch_interval)
+        {
+            log!(Info, "Re-indexing growing input {}", input_id);
+            current.reindex(args, ignore_errors)?;
+        }
+
+        current.take_warmed(start, requested_end);
+        let source = &mut current.source;
+
+        if args.watch && requested_end > source.total_frames {
+            transport.send(&format!(
+                "{} {} not yet available, {} frames available",
+                start, requested_end, source.total_frames
+            ))?;
+            pending.remove(0);
+            session.handle(Event::RequestFinished)?;
+            continue;
+        }
+
+        if start >= source.total_frames {
+            transport.send(&format!(
+                "{} {} starts past the {} frames of input {}",
+                start, requested_end, source.total_frames, input_id
+            ))?;
+            pending.remove(0);
+            session.handle(Event::RequestFinished)?;
+            continue;
+        }
+
+        let end = requested_end.min(source.total_frames);
+        if end < requested_end {
+            log!(
+                Warn,
+                "Request {} {} ends past the {} frames of input {}, writing {} to {}",
+                start,
+                requested_end,
+                source.total_frames,
+                input_id,
+                start,
+                end
+            );
+        }
+
+        let (start, end) = match args.align_keyframes {
+            Some(mode) => {
+                match align::align(mode, &source.keyframes, source.total_frames, start, end) {
+                    Ok(aligned) => aligned,
+                    Err(reason) => {
+                        transport.send(&format!("{} {} {}", start, end, reason))?;
+                        pending.remove(0);
+                        session.handle(Event::RequestFinished)?;
+                        continue;
+                    }
+                }
+            }
+            None => (start, end),
+        };
+
+        let segments = match planner {
+            Some(ref planner) => planner.plan(start, end),
+            None => vec![(start, end)],
+        };
+        let segments =
+            match limit_segment_bytes(args, &current.format, &options, &source.keyframes, segments)
+            {
+                Ok(segments) => segments,
+                Err(reason) => {
+                    transport.send(&format!("{} {} {}", start, end, reason))?;
+                    pending.remove(0);
+                    session.handle(Event::RequestFinished)?;
+                    continue;
+                }
+            };
+
+        let total = segments.len();
+
+        if let Some(ref mut events) = events {
+            for &(start, end) in &segments {
+                events.queued(source.id, start, end)?;
+            }
+        }
+
+        for (sequence, (start, end)) in segments.into_iter().enumerate() {
+            if let Some(ref journal) = journal {
+                let path = segment_path(args, source, start, end, segment_extension(args));
+                let outpath = path.to_string_lossy().into_owned();
+                if args.resume && journal.verify(start, end, &path)? {
+                    log!(
+                        Info,
+                        "segment_skipped" { input: source.id, start: start, end: end },
+                        "Segment {} to {} already completed",
+                        start,
+                        end
+                    );
+                    if args.manifest.is_some() || args.hls_playlist.is_some() {
+                        let (bytes, crc) = checksum::file_crc32(&path)?;
+                        let padding = options.padding.clamp(start, end, source.total_frames);
+                        manifest.push(manifest_entry(
+                            &current.format,
+                            source.id,
+                            (start, end),
+                            padding,
+                            (options.step, options.repeat),
+                            outpath.clone(),
+                            (bytes, crc),
+                        ));
+                    }
+                    if let Some(ref mut events) = events {
+                        events.finished(source.id, start, end, &outpath, true)?;
+                    }
+                    transport.send(&format!("{} {}", start, outpath))?;
+                    continue;
+                }
+            }
+
+            let name = part_name(options.name.as_deref(), sequence, total);
+            let range = segment_range(args, source, start, end);
+            let stem = segment_stem(source.id, &range, name.as_deref());
+            if let Some(existing) = existing_segment(args, &stem) {
+                log!(
+                    Warn,
+                    "segment_skipped" { input: source.id, start: start, end: end },
+                    "Skipping segment {} to {}, {} already exists",
+                    start,
+                    end,
+                    existing
+                );
+                if let Some(ref mut events) = events {
+                    events.finished(source.id, start, end, &existing, true)?;
+                }
+                transport.send(&format!("{} {} skipped (exists)", start, existing))?;
+                continue;
+            }
+
+            let padding = options.padding.clamp(start, end, source.total_frames);
+            log!(
+                Info,
+                "segment_started" { input: source.id, start: start, end: end },
+                "Reading segment {} to {} with {} frames before and {} after",
+                start,
+                end,
+                padding.before,
+                padding.after
+            );
+
+            if let Some(ref mut events) = events {
+                events.started(source.id, start, end)?;
+            }
+            let segment_start = Instant::now();
+
+            let first = source_frame(source, start - padding.before);
+            current.pool.route(&mut source.video_source, first);
+            let _writing = interrupt::writing();
+            let result = loop {
+                let result = write_segment(
+                    args,
+                    output.as_ref(),
+                    source,
+                    &mut following,
+                    &current.format,
+                    current.scaler.as_ref(),
+                    start,
+                    end,
+                    RequestOptions {
+                        padding,
+                        name: name.clone(),
+                        ..options.clone()
+                    },
+                    &mut |frame| {
+                        transport.progress(frame);
+                        if let Some(ref mut events) = events {
+                            events.progress(frame);
+                        }
+                    },
+                );
+
+                // Broken AVI and TS files often seek to the wrong frames or
+                // fail to decode after a seek, but decode from the start.
+                match result {
+                    Err(err) if failure::is(&err, Failure::Decode) && seeks(source.seek_mode) => {
+                        log!(
+                            Warn,
+                            "seek_fallback" { input: source.id, start: start, end: end },
+                            "{}, writing segment {} to {} again with linear seeking",
+                            err,
+                            start,
+                            end
+                        );
+                        seek_linearly(args, source, &current.path, &current._index)?;
+                        current.pool =
+                            open_pool(args, &current.path, &current._index, source.seek_mode)?;
+                    }
+                    result => break result,
+                }
+            };
+            let segment = match result {
+                // The partial segment is removed with its upload. The rest
+                // of the request is dropped too.
+                Err(err) if interrupt::is_cancelled(&err) => {
+                    log!(Info, "Cancelled segment {} to {}", start, end);
+                    if let Some(ref mut events) = events {
+                        events.failed(source.id, start, end, &err.to_string())?;
+                    }
+                    current.pool.finished(start - padding.before);
+                    transport.send(&format!("{} {} cancelled", start, end))?;
+                    break;
+                }
+                // The rest of the request is dropped, and the stuck
+                // decoder replaced once it is done.
+                Err(err) if failure::is(&err, Failure::Timeout) => {
+                    log!(
+                        Error,
+                        "segment_timeout" { input: source.id, start: start, end: end },
+                        "{}, abandoning segment {} to {}",
+                        err,
+                        start,
+                        end
+                    );
+                    metrics::record_error();
+                    if let Some(ref mut events) = events {
+                        events.failed(source.id, start, end, &err.to_string())?;
+                    }
+                    current.pool.finished(start - padding.before);
+                    transport.send(&format!("{} {} timed out", start, end))?;
+                    timed_out += 1;
+                    break;
+                }
+                Err(err) => {
+                    metrics::record_error();
+                    if let Some(ref mut events) = events {
+                        events.failed(source.id, start, end, &err.to_string())?;
+                    }
+                    return Err(err);
+                }
+                Ok(segment) => segment,
+            };
+            current.pool.finished(end + padding.after);
+            let outpath = segment.path;
+
+            if args.cues {
+                write_cue(args, source, start, end, padding, sequence, total)?;
+            }
+
+            if let Some(ref mut journal) = journal {
+                journal.record(start, end, segment.bytes, segment.crc)?;
+            }
+
+            let elapsed = segment_start.elapsed();
+            metrics::record_segment(
+                end - start + padding.before + padding.after,
+                segment.bytes,
+                elapsed,
+            );
+            let stats = metrics::SegmentStats {
+                frames: segment.decoded,
+                decode_time: segment.decode_time,
+                bytes: segment.bytes,
+                elapsed,
+                peak_memory: metrics::peak_memory(),
+            };
+            log!(
+                Info,
+                "segment_written" {
+                    input: source.id,
+                    start: start,
+                    end: end,
+                    path: outpath,
+                    millis: elapsed.as_millis(),
+                },
+                "Time taken: {:?},{}",
+                elapsed,
+                stats.fields()
+            );
+
+            let entry = manifest_entry(
+                &current.format,
+                source.id,
+                (start, end),
+                padding,
+                (options.step, options.repeat),
+                outpath.clone(),
+                (segment.bytes, segment.crc),
+            );
+            let mut fields = match args.segment_stats {
+                true => stats.fields(),
+                false => String::new(),
+            };
+            if args.stream.is_some() {
+                fields.push_str(&format!(
+                    " stream_start={} stream_end={}",
+                    stream_frames,
+                    stream_frames + entry.frames
+                ));
+                stream_frames += entry.frames;
+            }
+            manifest.push(entry);
+            if let Some(ref mut events) = events {
+                events.finished(source.id, start, end, &outpath, false)?;
+            }
+            transport.send(&format!("{} {}{}", start, outpath, fields))?;
+            interrupt::record_segment(&outpath);
+
+            if let Some(ref mut hooks) = hooks {
+                for failure in hooks.run(&outpath, start, end)? {
+                    transport.send(&failure)?;
+                }
+            }
+        }
+
+        if timed_out > 0 {
+            inputs.replace_hung(args)?;
+        }
+
+        log!(Info, "Request completed in {:?}", now.elapsed());
+
+        pending.remove(0);
+
+        session.handle(Event::RequestFinished)?;
+    }
+
+    if let Some(ref mut hooks) = hooks {
+        for failure in hooks.finish() {
+            transport.send(&failure)?;
+        }
+    }
+
+    if timed_out > 0 {
+        return Err(failure::tag(
+            Failure::Timeout,
+            std::io::Error::other(format!("{} segments timed out", timed_out)),
+        ));
+    }
+
+    Ok(())
+}
+
+#[derive(Clone)]
+struct SegmentFormat {
+    width: usize,
+    height: usize,
+    framerate: Ratio,
+    colorspace: Colorspace,
+    interlacing: Interlacing,
+    color_range: ColorRange,
+    /// Matrix, primaries and transfer of the first frame, signalled in y4m
+    /// headers.
+    color: Option<(i32, i32, i32)>,
+    /// Sample aspect ratio, unknown when FFMS2 reports none.
+    sar: Option<(usize, usize)>,
+}
+
+impl SegmentFormat {
+    /// The format of every `step`th frame, at a frame rate `step` times
+    /// lower.
+    fn stepped(&self, step: usize) -> SegmentFormat {
+        SegmentFormat {
+            framerate: Ratio {
+                num: self.framerate.num,
+                den: self.framerate.den * step,
+            },
+            ..self.clone()
+        }
+    }
+
+    fn y4m_parameters(&self) -> yuv4mpeg::Parameters {
+        yuv4mpeg::Parameters {
+            color: yuv4mpeg::Color {
+                range: self.color_range,
+                properties: self.color,
+            },
+            sar: self.sar,
+        }
+    }
+}
+
+/// The output folder, defaulting to the current directory. Paths in it are
+/// joined rather than formatted, so they keep the platform's separators and
+/// any bytes that are not UTF-8.
+fn output_folder(args: &CliArgs) -> &Path {
+    args.output_folder.as_deref().unwrap_or(Path::new("."))
+}
+
+/// The `--output-format`, y4m unless another is given. It has no clap
+/// default, which would count as given and conflict with `--rgb`.
+fn container(args: &CliArgs) -> Container {
+    args.output_format.unwrap_or(Container::Y4m)
+}
+
+/// The demuxer options of `--source-opt`, then those of `--enable-drefs`
+/// and `--use-absolute-path`.
+fn source_options(args: &CliArgs) -> Vec<(String, String)> {
+    let mut options = args.source_options.clone();
+    for (set, key) in [
+        (args.enable_drefs, "enable_drefs"),
+        (args.use_absolute_path, "use_absolute_path"),
+    ] {
+        if set {
+            options.push((key.to_string(), "1".to_string()));
+        }
+    }
+    options
+}
+
+/// Creates the output folder and its parents if they are missing, and
+/// checks a file can be written in it, so a bad folder fails at startup
+/// rather than at the first segment.
+fn prepare_output_folder(args: &CliArgs) -> std::io::Result<()> {
+    let folder = output_folder(args);
+    let unwritable = |err: std::io::Error| {
+        std::io::Error::new(
+            err.kind(),
+            format!(
+                "Output folder {} is not writable: {}",
+                folder.display(),
+                err
+            ),
+        )
+    };
+
+    fs::create_dir_all(folder).map_err(unwritable)?;
+    let probe = folder.join(format!(".ffms-segmenter-{}", std::process::id()));
+    File::create(&probe).map_err(unwritable)?;
+    fs::remove_file(probe)
+}
+
+/// Name without extension of the files produced for segment `start..end`
+/// of input `source`, unless the request gave it a `name`. Segments of
+/// additional inputs are prefixed with the input's position.
+fn segment_stem(source: usize, range: &str, name: Option<&str>) -> String {
+    match (name, source) {
+        (Some(name), _) => name.to_string(),
+        (None, 0) => range.to_string(),
+        (None, _) => format!("{}-{}", source, range),
+    }
+}
+
+/// The `start..end` part of segment names, in frames or as the times of
+/// those frames of `source` with `--naming timecode`.
+fn segment_range(args: &CliArgs, source: &Source, start: usize, end: usize) -> String {
+    match args.naming {
+        Naming::Frames => format!("{}-{}", start, end),
+        Naming::Timecode => {
+            let time = |frame: usize| {
+                let ms = timecodes::elapsed_ms(&source.track, source_frame(source, frame));
+                timecodes::file_timecode(ms.round().max(0.0) as u64)
+            };
+            format!("{}-{}", time(start), time(end))
+        }
+    }
+}
+
+/// The `name` of part `sequence` of a request planned as `total` segments.
+/// Each part gets a name of its own.
+fn part_name(name: Option<&str>, sequence: usize, total: usize) -> Option<String> {
+    name.map(|name| match total {
+        1 => name.to_string(),
+        _ => format!("{}-{}", name, sequence + 1),
+    })
+}
+
+/// Name of the file with `extension` produced for segment `start..end` of
+/// input `source`.
+fn segment_name(
+    args: &CliArgs,
+    source: &Source,
+    start: usize,
+    end: usize,
+    extension: &str,
+) -> String {
+    let range = segment_range(args, source, start, end);
+    format!("{}.{}", segment_stem(source.id, &range, None), extension)
+}
+
+/// Extension of segment files, `y4m` unless `--rgb` or `--output-format`
+/// is given, and followed by `.zst` with `--compress`.
+fn segment_extension(args: &CliArgs) -> &'static str {
+    match (args.rgb, container(args), args.compress.is_some()) {
+        (Some(output), _, _) => rgb::extension(output),
+        (None, Container::Mkv, _) => "mkv",
+        (None, Container::Y4m, false) => "y4m",
+        (None, Container::Y4m, true) => "y4m.zst",
+        (None, Container::Nut, _) => "nut",
+        (None, Container::Raw, false) => "yuv",
+        (None, Container::Raw, true) => "yuv.zst",
+    }
+}
+
+/// Path of the segment file named `stem` if it is already in the output
+/// folder and must not be overwritten. Uploads and `--exec` commands are
+/// never checked, and neither are the `.part` files of unfinished segments.
+fn existing_segment(args: &CliArgs, stem: &str) -> Option<String> {
+    if args.force
+        || args.resume
+        || args.output_url.is_some()
+        || args.exec.is_some()
+        || args.stream.is_some()
+    {
+        return None;
+    }
+
+    let path = output_folder(args).join(format!("{}.{}", stem, segment_extension(args)));
+    path.exists().then(|| path.to_string_lossy().into_owned())
+}
+
+/// Path of `segment_name` in the output folder.
+fn segment_path(
+    args: &CliArgs,
+    source: &Source,
+    start: usize,
+    end: usize,
+    extension: &str,
+) -> PathBuf {
+    output_folder(args).join(segment_name(args, source, start, end, extension))
+}
+
+/// Frames written around a segment for encoders that need context across
+/// its boundaries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Padding {
+    before: usize,
+    after: usize,
+}
+
+impl Padding {
+    /// Limits the padding to the frames that exist around `start..end`.
+    fn clamp(self, start: usize, end: usize, total_frames: usize) -> Padding {
+        Padding {
+            before: self.before.min(start),
+            after: self.after.min(total_frames.saturating_sub(end)),
+        }
+    }
+}
+
+/// Per-request overrides of the segment options.
+#[derive(Debug, Clone)]
+struct RequestOptions {
+    /// File name of the segment without extension.
+    name: Option<String>,
+    padding: Padding,
+    /// Write the frames last to first.
+    reverse: bool,
+    /// Write every `step`th frame.
+    step: usize,
+    /// Write the frames this many times in a row.
+    repeat: usize,
+}
+
+/// Parses the `[<input>] <start> <end>` of a request, with its end made
+/// exclusive from `convention`, echoing the request back in the error if it
+/// is not one.
+fn parse_request(
+    request: &str,
+    convention: RangeConvention,
+) -> std::io::Result<(usize, usize, usize)> {
+    let invalid = |reason: String| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid request, {}: {}", reason, request),
+        )
+    };
+    let number = |name: &str, token: &str| {
+        token.parse::<usize>().map_err(|_| {
+            invalid(format!(
+                "expected a whole number for the {}, not {}",
+                name, token
+            ))
+        })
+    };
+
+    let (input, start, end) = match request.split_whitespace().collect::<Vec<&str>>()[..] {
+        [start, end] => (0, number("start", start)?, number("end", end)?),
+        [input, start, end] => (
+            number("input", input)?,
+            number("start", start)?,
+            number("end", end)?,
+        ),
+        _ => return Err(invalid("expected [<input>] <start> <end>".to_string())),
+    };
+
+    Ok((input, start, convention.exclusive_end(end)))
+}
+
+/// Splits the `pad-before=<n>`, `pad-after=<n>`, `step=<n>`, `repeat=<n>`,
+/// `name=<name>` and `reverse` options off a request, returning them and
+/// the remaining command.
+fn split_options(args: &CliArgs, request: &str) -> std::io::Result<(RequestOptions, String)> {
+    let mut options = RequestOptions {
+        name: None,
+        padding: Padding {
+            before: args.pad_before,
+            after: args.pad_after,
+        },
+        reverse: false,
+        step: args.step,
+        repeat: 1,
+    };
+    let mut command = Vec::new();
+
+    for token in request.split_whitespace() {
+        let (key, value) = match token.split_once('=') {
+            Some(option) => option,
+            None if token == "reverse" => {
+                options.reverse = true;
+                continue;
+            }
+            None => {
+                command.push(token);
+                continue;
+            }
+        };
+
+        let invalid = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid request option {}", token),
+            )
+        };
+
+        if key == "name" {
+            // Names stay within the output folder.
+            let allowed = |c: char| c.is_alphanumeric() || "-_.".contains(c);
+            if value.is_empty() || value.starts_with('.') || !value.chars().all(allowed) {
+                return Err(invalid());
+            }
+            options.name = Some(value.to_string());
+            continue;
+        }
+
+        let value = value.parse::<usize>().map_err(|_| invalid())?;
+
+        match key {
+            "pad-before" => options.padding.before = value,
+            "pad-after" => options.padding.after = value,
+            "step" if value == 0 => return Err(invalid()),
+            "step" => options.step = value,
+            "repeat" if value == 0 => return Err(invalid()),
+            "repeat" => options.repeat = value,
+            // Orders the server queue; the session itself ignores it.
+            "priority" => {}
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Unknown request option {}", key),
+                ))
+            }
+        }
+    }
+
+    Ok((options, command.join(" ")))
+}
+
+/// A written segment, with the size and CRC-32 of its contents. `path` is
+/// where the output backend put it.
+struct WrittenSegment {
+    path: String,
+    bytes: u64,
+    crc: u32,
+    /// Frames taken from the decoder and the time it spent on them.
+    decoded: usize,
+    decode_time: time::Duration,
+}
+
+/// `sink` writing the frames of a segment in the order `options` ask for:
+/// `repeat` times over, then last to first if `reverse`.
+fn arranged<'a>(
+    sink: Box<dyn FrameSink + 'a>,
+    options: &RequestOptions,
+) -> Box<dyn FrameSink + 'a> {
+    let sink: Box<dyn FrameSink + 'a> = match options.repeat {
+        1 => sink,
+        times => Box::new(Repeated::new(sink, times)),
+    };
+    match options.reverse {
+        true => Box::new(Reversed::new(sink)),
+        false => sink,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn write_segment(
+    args: &CliArgs,
+    output: &dyn output::Output,
+    source: &mut Source,
+    following: &mut [&mut VideoSource],
+    format: &SegmentFormat,
+    scaler: Option<&Scaler>,
+    start: usize,
+    end: usize,
+    options: RequestOptions,
+    progress: &mut dyn FnMut(usize),
+) -> std::io::Result<WrittenSegment> {
+    let padding = options.padding;
+    let format = &format.stepped(options.step);
+    // The padded frames are part of the file but not of its name.
+    let (first, last) = (start - padding.before, end + padding.after);
+    let frames = (last - first).div_ceil(options.step) * options.repeat;
+
+    check_free_space(args, segment_bytes(args, format, frames))?;
+
+    let range = segment_range(args, source, start, end);
+    let stem = segment_stem(source.id, &range, options.name.as_deref());
+    let sidecar = |extension: &str| output_folder(args).join(format!("{}.{}", stem, extension));
+    let name = format!("{}.{}", stem, segment_extension(args));
+    let upload: Box<dyn output::Upload> = match args.rgb {
+        Some(rgb::RgbOutput::Png) => Box::new(rgb::PngSequence::create(
+            output_folder(args).join(&name),
+            format.width,
+            format.height,
+        )?),
+        _ if args.mmap => output::MappedFile::create(
+            output_folder(args).join(&name),
+            segment_bytes(args, format, frames),
+        )?,
+        _ => output.create(&name)?,
+    };
+    let upload = match args.write_buffer {
+        Some(mib) => output::Background::wrap(upload, mib.max(1)),
+        None => upload,
+    };
+    let mut outfile = HashWriter::wrap(upload);
+
+    let mut checksums = Vec::new();
+    let mut complexity = args
+        .complexity
+        .then(|| complexity::Complexity::new(format.colorspace));
+    let mut gate = args
+        .quality_gate
+        .map(|mode| quality::Gate::new(mode, format.colorspace));
+
+    let mut deinterlacer = args
+        .deinterlace
+        .map(|mode| filter::Deinterlacer::new(mode, &source.format));
+
+    let mut alpha_writer = if args.alpha && source.alpha {
+        // The sidecar goes through a folder output so it is also removed
+        // if the segment is abandoned.
+        let folder = output::Folder::new(output_folder(args)).buffered(args.write_buffer_kb * 1024);
+        Some(yuv4mpeg::Writer::new(
+            folder.create(&format!("{}.alpha.y4m", stem))?,
+            format.width,
+            format.height,
+            format.framerate,
+            Colorspace::Cmono,
+            format.interlacing,
+            ColorRange::Full,
+        )?)
+    } else {
+        None
+    };
+    let mut alpha = alpha_writer
+        .as_mut()
+        .map(|writer| arranged(Box::new(writer), &options));
+    let mut alpha_result = Ok(());
+
+    let on_error = decode_error_policy(args, source);
+    let rotator = Rotator::new(
+        source.rotation,
+        format.colorspace,
+        format.width,
+        format.height,
+    );
+    let writer: Box<dyn FrameSink + '_> = match args.rgb {
+        Some(_) => Box::new(rgb::Writer::new(&mut outfile, format, source.rgb)?),
+        None if container(args) == Container::Mkv => {
+            let audio = match source.audio.is_empty() {
+                false if options.reverse => {
+                    log!(
+                        Warn,
+                        "Writing reversed segment {} to {} without audio",
+                        start,
+                        end
+                    );
+                    Vec::new()
+                }
+                false if options.repeat > 1 => {
+                    log!(
+                        Warn,
+                        "Writing repeated segment {} to {} without audio",
+                        start,
+                        end
+                    );
+                    Vec::new()
+                }
+                _ => {
+                    // The audio of a `--cfr` or `--ivtc` segment is cut at
+                    // the source frames it starts and ends with.
+                    let (first, last) = (source_frame(source, first), source_frame(source, last));
+                    source
+                        .audio
+                        .iter()
+                        .map(|audio| {
+                            mkv::Audio::read(audio, &source.track, first, last, source.info.frames)
+                        })
+                        .collect::<std::io::Result<_>>()?
+                }
+            };
+            Box::new(mkv::Writer::new(&mut outfile, format, frames, audio)?)
+        }
+        None if container(args) == Container::Nut => {
+            Box::new(nut::Writer::new(&mut outfile, format)?)
+        }
+        None if container(args) == Container::Raw => {
+            Box::new(raw::Writer::new(&mut outfile, format))
+        }
+        None => Box::new(y4m_writer(&mut outfile, format)?),
+    };
+    let mut writer = arranged(writer, &options);
+
+    let mut sources = vec![&mut source.video_source];
+    sources.extend(following.iter_mut().map(|source| &mut **source));
+    // Frames are only checked where a mismatch is not skipped or replaced
+    // by --on-decode-error, and seeking can still fall back.
+    let verified =
+        seeks(source.seek_mode) && matches!(args.on_decode_error, DecodeErrorMode::Abort);
+    let watchdog = prefetch::Watchdog {
+        frame: args.decode_timeout.map(time::Duration::from_secs),
+        deadline: args
+            .segment_timeout
+            .map(|seconds| Instant::now() + time::Duration::from_secs(seconds)),
+    };
+    let mut concat = concat::Concat::new(sources, source.timeline.as_ref())
+        .verified(verified)
+        .watched(watchdog);
+    let segments: &mut (dyn prefetch::Decoder + Send) = match source.ffmpeg {
+        Some(ref mut ffmpeg) => ffmpeg.as_mut(),
+        None => &mut concat,
+    };
+    let mut decoder = cache::Cached::new(segments, &mut source.frame_cache);
+    let (mut retimed, mut ivtc);
+    let decoder: &mut (dyn prefetch::Decoder + Send) = match source.cfr {
+        Some(ref frames) => {
+            retimed = cfr::Retimed::new(&mut decoder, frames);
+            &mut retimed
+        }
+        None if source.ivtc => {
+            ivtc = ivtc::Ivtc::new(&mut decoder, &source.format);
+            &mut ivtc
+        }
+        None => &mut decoder,
+    };
+
+    let mut timed = metrics::Timed::new(decoder);
+
+    encode_segment(
+        writer.as_mut(),
+        &mut timed,
+        deinterlacer.as_mut(),
+        scaler,
+        rotator.as_ref(),
+        gate.as_mut(),
+        first,
+        last,
+        options.step,
+        prefetch_depth(args, &source.format),
+        on_error,
+        &mut |stage, frame_number, frame| {
+            if stage == Stage::Output {
+                if args.checksums {
+                    checksums.push((frame_number, checksum::frame_crc32(frame)));
+                }
+                if let Some(ref mut complexity) = complexity {
+                    complexity.push(frame);
+                }
+                if let (Some(writer), Some(plane)) = (alpha.as_mut(), frame.alpha.as_ref()) {
+                    if alpha_result.is_ok() {
+                        alpha_result = writer.write_frame(&DecodedFrame {
+                            planes: [plane.clone(), Vec::new(), Vec::new()],
+                            alpha: None,
+                        });
+                    }
+                }
+                progress(frame_number);
+            }
+        },
+    )?;
+    let (decoded, decode_time) = (timed.frames, timed.time);
+    writer.finish()?;
+    drop(writer);
+    alpha_result?;
+    if let Some(ref mut writer) = alpha {
+        writer.finish()?;
+    }
+    drop(alpha);
+    if let Some(writer) = alpha_writer {
+        writer.into_inner().finish()?;
+    }
+
+    if args.checksums {
+        let checksums_path = sidecar("crc32");
+        let mut file = BufWriter::new(File::create(checksums_path)?);
+        for (frame_number, crc) in checksums {
+            writeln!(file, "{} {:08x}", frame_number, crc)?;
+        }
+        file.flush()?;
+    }
+
+    if let Some(gate) = gate {
+        if !gate.rejections().is_empty() {
+            log!(
+                Warn,
+                "{} frames of segment {} to {} failed the quality gate",
+                gate.rejections().len(),
+                start,
+                end
+            );
+        }
+        gate.write_report(sidecar("quality").as_ref())?;
+    }
+
+    if let Some(complexity) = complexity {
+        let frame_seconds = source.format.framerate.den as f64 / source.format.framerate.num as f64;
+        let keyframes = (start..end)
+            .filter(|&n| source.track.FrameInfo(n).KeyFrame() != 0)
+            .count();
+        let input_seconds = source.total_frames as f64 * frame_seconds;
+        let input_kbps = match input_seconds {
+            seconds if seconds > 0.0 => source.size as f64 * 8.0 / 1000.0 / seconds,
+            _ => 0.0,
+        };
+        complexity.write_report(
+            sidecar("complexity").as_ref(),
+            end - start,
+            (end - start) as f64 * frame_seconds,
+            keyframes,
+            input_kbps,
+        )?;
+    }
+
+    if args.hdr_metadata {
+        source.hdr.write(sidecar("hdr.json").as_ref())?;
+    }
+
+    if container(args) == Container::Raw && args.rgb.is_none() {
+        raw::write_descriptor(format, frames, sidecar("json").as_ref())?;
+    }
+
+    if let Some(ref subtitles) = source.subtitles {
+        if options.reverse {
+            log!(
+                Warn,
+                "Writing reversed segment {} to {} without subtitles",
+                start,
+                end
+            );
+        } else {
+            let time = |frame| {
+                (timecodes::frame_time_ms(&source.track, frame)
+                    - timecodes::frame_time_ms(&source.track, 0))
+                .max(0.0)
+                .round() as u64
+            };
+            let end_time = (last < source.total_frames).then(|| time(last));
+            subtitles.write(
+                sidecar(subtitles.extension()).as_ref(),
+                time(first),
+                end_time,
+            )?;
+        }
+    }
+
+    if args.timecodes {
+        let timecodes_path = sidecar("timecodes.txt");
+        timecodes::write_v2(
+            &source.track,
+            first,
+            last,
+            options.step,
+            timecodes_path.as_ref(),
+        )?;
+    }
+
+    if args.pts {
+        timecodes::write_pts(
+            &source.track,
+            first,
+            last,
+            options.step,
+            sidecar("pts.txt").as_ref(),
+        )?;
+    }
+
+    let bytes = outfile.bytes;
+    let crc = outfile.crc.finish();
+
+    Ok(WrittenSegment {
+        path: outfile.into_inner().finish()?,
+        bytes,
+        crc,
+        decoded,
+        decode_time,
+    })
+}
+
+/// The `--manifest` entry of a segment of `start..end` written with
+/// `padding` and every `step`th frame, `repeat` times over.
+fn manifest_entry(
+    format: &SegmentFormat,
+    input: usize,
+    (start, end): (usize, usize),
+    padding: Padding,
+    (step, repeat): (usize, usize),
+    path: String,
+    (bytes, crc): (u64, u32),
+) -> manifest::Entry {
+    let frames = (end + padding.after - (start - padding.before)).div_ceil(step) * repeat;
+    let framerate = format.stepped(step).framerate;
+
+    manifest::Entry {
+        path,
+        input,
+        start,
+        end,
+        frames,
+        seconds: frames as f64 * framerate.den as f64 / framerate.num as f64,
+        bytes,
+        crc,
+    }
+}
+
+/// Writes the position of a segment within its request so concatenation
+/// tools can detect missing or reordered chunks.
+fn write_cue(
+    args: &CliArgs,
+    source: &Source,
+    start: usize,
+    end: usize,
+    padding: Padding,
+    sequence: usize,
+    total: usize,
+) -> std::io::Result<()> {
+    let mut file = File::create(segment_path(args, source, start, end, "cue"))?;
+    writeln!(file, "sequence {}", sequence)?;
+    writeln!(file, "total {}", total)?;
+    writeln!(file, "frames {} {}", start, end)?;
+    writeln!(file, "pad {} {}", padding.before, padding.after)
+}
+
+/// Size of a segment of `frames` frames in `format`. Mkv and nut segments
+/// are counted without the audio and container overhead.
+fn segment_bytes(args: &CliArgs, format: &SegmentFormat, frames: usize) -> u64 {
+    match args.rgb {
+        Some(_) => (frames * format.width * format.height * 3) as u64,
+        None if container(args) != Container::Y4m => (frames * mkv::frame_bytes(format)) as u64,
+        None => yuv4mpeg::stream_size(
+            format.width,
+            format.height,
+            format.framerate,
+            format.colorspace,
+            format.interlacing,
+            format.y4m_parameters(),
+            frames,
+        ),
+    }
+}
+
+/// Splits the `segments` of a request into parts that stay within
+/// `--max-segment-bytes` when written with `options`, each starting on
+/// one of `keyframes` with `--align-keyframes`. Returns a message
+/// describing why the request was refused on failure.
+fn limit_segment_bytes(
+    args: &CliArgs,
+    format: &SegmentFormat,
+    options: &RequestOptions,
+    keyframes: &[usize],
+    segments: Vec<(usize, usize)>,
+) -> Result<Vec<(usize, usize)>, String> {
+    let Some(limit) = args.max_segment_bytes else {
+        return Ok(segments);
+    };
+
+    // Sizes grow by the same amount with every frame after the header.
+    let format = format.stepped(options.step);
+    let frame = segment_bytes(args, &format, 2) - segment_bytes(args, &format, 1);
+    let header = segment_bytes(args, &format, 1) - frame;
+    let padding = options.padding.before + options.padding.after;
+    let max =
+        (limit.saturating_sub(header) / frame.max(1)) as usize / options.repeat * options.step;
+    if max <= padding {
+        return Err(format!(
+            "--max-segment-bytes {} cannot hold a single frame",
+            limit
+        ));
+    }
+
+    let keyframes = args.align_keyframes.map(|_| keyframes);
+    let mut parts = Vec::new();
+    for (start, end) in segments {
+        parts.extend(align::split(start, end, max - padding, keyframes)?);
+    }
+
+    Ok(parts)
+}
+
+/// Fails if a segment of about `bytes` would not fit in the output folder,
+/// before anything is decoded. Segments handed to `--output-url` or
+/// `--exec` are not checked.
+fn check_free_space(args: &CliArgs, bytes: u64) -> std::io::Result<()> {
+    let local = matches!(args.rgb, Some(rgb::RgbOutput::Png))
+        || args.mmap
+        || (args.output_url.is_none() && args.exec.is_none());
+    if !local {
+        return Ok(());
+    }
+
+    let free = output::free_space(output_folder(args))?;
+    if bytes > free {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::StorageFull,
+            format!(
+                "Not enough space in {} for a segment of about {} MiB, {} MiB free",
+                output_folder(args).display(),
+                bytes >> 20,
+                free >> 20
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Exits with the code for the failure of an error instead of panicking.
+trait OrExit<T> {
+    fn or_exit(self) -> T;
+}
+
+impl<T> OrExit<T> for std::io::Result<T> {
+    fn or_exit(self) -> T {
+        self.unwrap_or_else(|err| {
+            log!(Error, "{}", err);
+            std::process::exit(failure::exit_code(&err))
+        })
+    }
+}
+
+/// Prints a command line error, exiting with the bad arguments code. Help
+/// and version requests exit successfully.
+fn usage_error(err: structopt::clap::Error) -> ! {
+    if !err.use_stderr() {
+        err.exit();
+    }
+    eprintln!("{}", err.message);
+    std::process::exit(Failure::BadArguments.exit_code())
+}
+
+/// The subcommands, given as the first argument before the options.
+#[derive(Clone, Copy, PartialEq)]
+enum Subcommand {
+    Tracks,
+    Verify,
+}
+
+/// The command line without its subcommand, and which one was given.
+fn command_line() -> (Vec<std::ffi::OsString>, Option<Subcommand>) {
+    let mut argv: Vec<std::ffi::OsString> = std::env::args_os().collect();
+    let subcommand = match argv.get(1).and_then(|arg| arg.to_str()) {
+        Some("tracks") => Some(Subcommand::Tracks),
+        Some("verify") => Some(Subcommand::Verify),
+        _ => None,
+    };
+    if subcommand.is_some() {
+        argv.remove(1);
+    }
+
+    (argv, subcommand)
+}
+
+/// Parses the command line. Options it leaves out are taken from the
+/// `--config` file, and then from `FFMS_SEGMENTER_*` variables, so the
+/// command line wins over the file, which wins over the environment.
+fn parse_args() -> CliArgs {
+    let (mut argv, subcommand) = command_line();
+    let describe_tracks = subcommand == Some(Subcommand::Tracks);
+    let verify_manifest = subcommand == Some(Subcommand::Verify);
+    let matches = CliArgs::clap()
+        .get_matches_from_safe(&argv)
+        .unwrap_or_else(|err| usage_error(err));
+    let mut args = CliArgs::from_clap(&matches);
+    args.describe_tracks = describe_tracks;
+    args.verify_manifest = verify_manifest;
+
+    let env = config::from_env(std::env::vars());
+    let path = args.config.clone().or_else(|| {
+        env.iter()
+            .find(|(key, _)| key == "config")
+            .map(|(_, value)| PathBuf::from(value.to_argument()))
+    });
+    let mut entries = match path {
+        Some(ref path) => config::load(path).or_exit(),
+        None => Vec::new(),
+    };
+    for (key, value) in env {
+        if key != "config" && !entries.iter().any(|(set, _)| *set == key) {
+            entries.push((key, value));
+        }
+    }
+    if entries.is_empty() {
+        return args;
+    }
+
+    let (options, output_folder) =
+        config::arguments(&entries, |key| matches.occurrences_of(key) > 0).or_exit();
+
+    argv.splice(1..1, options);
+    argv.extend(output_folder);
+
+    let matches = CliArgs::clap()
+        .get_matches_from_safe(argv)
+        .unwrap_or_else(|err| usage_error(err));
+    CliArgs {
+        describe_tracks,
+        verify_manifest,
+        ..CliArgs::from_clap(&matches)
+    }
+}
+
+/// Replaces the image sequences among the inputs with links FFMS2 can
+/// open, kept for as long as the returned links live.
+fn link_sequences(args: &mut CliArgs) -> std::io::Result<Vec<sequence::Links>> {
+    let mut links = Vec::new();
+
+    for (i, input) in std::iter::once(&mut args.input_file)
+        .chain(args.inputs.iter_mut())
+        .enumerate()
+        .filter(|(_, input)| sequence::is_sequence(input))
+    {
+        let dir = std::env::temp_dir().join(format!(
+            "ffms-segmenter-{}-sequence-{}",
+            std::process::id(),
+            i
+        ));
+        let sequence = sequence::Links::new(input, dir)?;
+        if args.fps.is_none() && args.fps_num.is_none() {
+            log!(
+                Warn,
+                "{} is an image sequence, pass --fps to time it",
+                input.display()
+            );
+        }
+        *input = sequence.input.clone();
+        links.push(sequence);
+    }
+
+    Ok(links)
+}
+
+fn main() {
+    let mut args = parse_args();
+
+    log::set_format(args.log_format);
+    if let Some(ref log_file) = args.log_file {
+        log::to_file(log_file).or_exit();
+    }
+    if args.quiet {
+        log::set_quiet();
+    }
+
+    // Reading segments back needs no FFMS2.
+    if args.verify_manifest {
+        let verified = verify::run(&args.input_file).or_exit();
+        std::process::exit(if verified { 0 } else { 1 });
+    }
+
+    FFMS2::Init();
+
+    let level = match args.verbose {
+        _ if args.quiet => LogLevels::LOG_QUIET,
+        0 => LogLevels::LOG_QUIET,
+        1 => LogLevels::LOG_WARNING,
+        2 => LogLevels::LOG_INFO,
+        3 => LogLevels::LOG_VERBOSE,
+        _ => LogLevels::LOG_DEBUG,
+    };
+
+    Log::SetLogLevel(level);
+
+    let ignore_errors = args.ignore_errors;
+
+    let _links = link_sequences(&mut args).or_exit();
+
+    if let Some(frames) = args.bench {
+        bench::run(&args, ignore_errors, frames, args.bench_pattern).or_exit();
+        return;
+    }
+
+    if let Some(ref plan) = args.determinism_check {
+        let deterministic =
+            determinism::check(&args, ignore_errors, plan, args.determinism_threads).or_exit();
+        std::process::exit(if deterministic { 0 } else { 1 });
+    }
+
+    if args.dry_run {
+        let valid = dry_run::run(&args, ignore_errors).or_exit();
+        std::process::exit(if valid { 0 } else { 1 });
+    }
+
+    if args.info_json {
+        let index = index_file(&args, &args.input_file, ignore_errors).or_exit();
+        let source = open_source(
+            &args,
+            &args.input_file,
+            0,
+            &index,
+            decoder_threads(args.threads),
+        )
+        .or_exit();
+        println!("{}", source.info.to_json());
+        return;
+    }
+
+    if args.describe_tracks {
+        let index = index_file(&args, &args.input_file, ignore_errors).or_exit();
+        for line in describe_tracks(&args, &args.input_file, &index) {
+            println!("{}", line);
+        }
+        return;
+    }
+
+    if args.list_tracks || args.index_only {
+        list_tracks(&index_file(&args, &args.input_file, ignore_errors).or_exit());
+        return;
+    }
+
+    if let Some((start, end)) = args.stats {
+        let index = index_file(&args, &args.input_file, ignore_errors).or_exit();
+        let mut source = open_source(
+            &args,
+            &args.input_file,
+            0,
+            &index,
+            decoder_threads(args.threads),
+        )
+        .or_exit();
+        stats::print(&mut source, start, end).or_exit();
+        return;
+    }
+
+    if let Some(ref path) = args.analyze {
+        let index = index_file(&args, &args.input_file, ignore_errors).or_exit();
+        let mut source = open_source(
+            &args,
+            &args.input_file,
+            0,
+            &index,
+            decoder_threads(args.threads),
+        )
+        .or_exit();
+        analysis::Analysis::run(&mut source)
+            .and_then(|analysis| analysis.write(path))
+            .or_exit();
+        return;
+    }
+
+    if let Some((start, end)) = args.detect_telecine {
+        let index = index_file(&args, &args.input_file, ignore_errors).or_exit();
+        let mut source = open_source(
+            &args,
+            &args.input_file,
+            0,
+            &index,
+            decoder_threads(args.threads),
+        )
+        .or_exit();
+        ivtc::print(&mut source, start, end).or_exit();
+        return;
+    }
+
+    if let Some((start, end)) = args.duplicates {
+        let index = index_file(&args, &args.input_file, ignore_errors).or_exit();
+        let mut source = open_source(
+            &args,
+            &args.input_file,
+            0,
+            &index,
+            decoder_threads(args.threads),
+        )
+        .or_exit();
+        duplicates::print(&mut source, start, end, args.duplicate_ssim).or_exit();
+        return;
+    }
+
+    if let Some((start, end)) = args.detect_black {
+        let index = index_file(&args, &args.input_file, ignore_errors).or_exit();
+        let mut source = open_source(
+            &args,
+            &args.input_file,
+            0,
+            &index,
+            decoder_threads(args.threads),
+        )
+        .or_exit();
+        black::print(
+            &mut source,
+            start,
+            end,
+            args.black_threshold,
+            args.silence_threshold,
+        )
+        .or_exit();
+        return;
+    }
+
+    if let Some(ref path) = args.frame_server {
+        let index = index_file(&args, &args.input_file, ignore_errors).or_exit();
+        let mut source = open_source(
+            &args,
+            &args.input_file,
+            0,
+            &index,
+            decoder_threads(args.threads),
+        )
+        .or_exit();
+        frame_server::serve(&mut source, path).or_exit();
+        return;
+    }
+
+    prepare_output_folder(&args).or_exit();
+    let pending = restore_session(&mut args).or_exit();
+
+    interrupt::install();
+    match do_indexing(&args, ignore_errors, pending) {
+        Err(err) if err.kind() == std::io::ErrorKind::Interrupted => interrupt::exit(),
+        result => result.or_exit(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rgb_output() {
+        let args = CliArgs::from_iter_safe(["ffms-segmenter", "--rgb", "raw", "in.mkv"]).unwrap();
+        assert_eq!(args.rgb, Some(rgb::RgbOutput::Raw));
+        assert_eq!(container(&args), Container::Y4m);
+        assert_eq!(segment_extension(&args), "rgb");
+
+        assert!(CliArgs::from_iter_safe([
+            "ffms-segmenter",
+            "--rgb",
+            "png",
+            "--output-format",
+            "mkv",
+            "in.mkv"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn parses_decoder() {
+        let args = CliArgs::from_iter_safe(["ffms-segmenter", "in.mkv"]).unwrap();
+        assert_eq!(args.decoder, Backend::Ffms2);
+
+        let args =
+            CliArgs::from_iter_safe(["ffms-segmenter", "--decoder", "ffmpeg", "in.mkv"]).unwrap();
+        assert_eq!(args.decoder, Backend::Ffmpeg);
+        assert!(
+            CliArgs::from_iter_safe(["ffms-segmenter", "--decoder", "libav", "in.mkv"]).is_err()
+        );
+    }
+
+    #[test]
+    fn parses_pixel_format() {
+        let args = CliArgs::from_iter_safe(["ffms-segmenter", "in.mkv"]).unwrap();
+        assert_eq!(args.pix_fmt, PixelFormat::Auto);
+
+        for (name, format) in PIX_FMTS {
+            let args =
+                CliArgs::from_iter_safe(["ffms-segmenter", "--pix-fmt", name, "in.mkv"]).unwrap();
+            assert_eq!(args.pix_fmt, format);
+            assert_eq!(format.name(), name);
+        }
+        assert!(
+            CliArgs::from_iter_safe(["ffms-segmenter", "--pix-fmt", "nv12", "in.mkv"]).is_err()
+        );
+    }
+
+    #[test]
+    fn converts_pixel_formats_to_bit_depths() {
+        for (name, depth, converted) in [
+            ("yuv420p10le", 8, "yuv420p"),
+            ("yuv422p", 10, "yuv422p10le"),
+            ("yuv444p", 12, "yuv444p12le"),
+            ("yuv420p12le", 12, "yuv420p12le"),
+            ("yuva420p", 8, "yuva420p"),
+            ("yuva422p", 10, "yuv422p10le"),
+            ("gray", 8, "gray"),
+            ("gray10le", 10, "yuv420p10le"),
+            ("gray", 12, "yuv420p12le"),
+        ] {
+            assert_eq!(
+                pixel_format_at_depth(name, depth).unwrap(),
+                converted,
+                "{} at {} bits",
+                name,
+                depth
+            );
+        }
+
+        for (name, depth) in [
+            ("yuv420p", 16),
+            ("yuv444p10le", 16),
+            ("gbrp", 10),
+            ("nv12", 8),
+        ] {
+            let err = pixel_format_at_depth(name, depth).unwrap_err();
+            assert!(
+                failure::is(&err, Failure::Unsupported),
+                "{} at {} bits",
+                name,
+                depth
+            );
+        }
+
+        assert_eq!(parse_bit_depth("keep"), Ok(BitDepth::Keep));
+        assert_eq!(parse_bit_depth("10"), Ok(BitDepth::Bits(10)));
+        assert!(parse_bit_depth("16").is_err());
+    }
+
+    #[test]
+    fn parses_requests() {
+        let exclusive = RangeConvention::Exclusive;
+        assert_eq!(parse_request("0 100", exclusive).unwrap(), (0, 0, 100));
+        assert_eq!(
+            parse_request("2 240 360", exclusive).unwrap(),
+            (2, 240, 360)
+        );
+        assert_eq!(
+            parse_request("0 99", RangeConvention::Inclusive).unwrap(),
+            (0, 0, 100)
+        );
+        // Empty ranges parse; the session answers them as empty.
+        assert_eq!(parse_request("100 100", exclusive).unwrap(), (0, 100, 100));
+        assert_eq!(parse_request("360 240", exclusive).unwrap(), (0, 360, 240));
+
+        for request in [
+            "0 ten",
+            "zero 100",
+            "x 0 100",
+            "-1 100",
+            "0",
+            "",
+            "0 100 240 360",
+        ] {
+            let err = parse_request(request, exclusive).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput, "{}", request);
+            assert!(err.to_string().ends_with(request), "{}", err);
+        }
+    }
+
+    #[test]
+    fn splits_request_options() {
+        let args =
+            CliArgs::from_iter_safe(["ffms-segmenter", "--pad-before", "4", "in.mkv"]).unwrap();
+        let (options, command) = split_options(
+            &args,
+            "1 0 100 name=intro_v2 pad-after=8 step=2 repeat=3 reverse priority=5",
+        )
+        .unwrap();
+        assert_eq!(command, "1 0 100");
+        assert_eq!(options.name.as_deref(), Some("intro_v2"));
+        assert_eq!(
+            options.padding,
+            Padding {
+                before: 4,
+                after: 8
+            }
+        );
+        assert_eq!(
+            (options.step, options.repeat, options.reverse),
+            (2, 3, true)
+        );
+
+        let (options, command) = split_options(&args, "0 100").unwrap();
+        assert_eq!(command, "0 100");
+        assert_eq!(options.name, None);
+        assert_eq!(
+            (options.step, options.repeat, options.reverse),
+            (1, 1, false)
+        );
+
+        for request in [
+            "0 100 step=0",
+            "0 100 repeat=0",
+            "0 100 step=two",
+            "0 100 pad-before=-1",
+            "0 100 name=",
+            "0 100 name=.hidden",
+            "0 100 name=../escape",
+            "0 100 name=a/b",
+        ] {
+            let err = split_options(&args, request).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput, "{}", request);
+            assert!(
+                err.to_string().starts_with("Invalid request option"),
+                "{}",
+                err
+            );
+        }
+
+        let err = split_options(&args, "0 100 speed=2").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "Unknown request option speed");
+    }
+
+    #[test]
+    fn skips_existing_segments_without_force() {
+        let folder =
+            std::env::temp_dir().join(format!("ffms-segmenter-existing-{}", std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        let output = folder.to_str().unwrap();
+        fs::write(folder.join("0-100.y4m"), b"YUV4MPEG2").unwrap();
+        fs::write(folder.join("100-200.y4m.part"), b"YUV4MPEG2").unwrap();
+
+        let args = CliArgs::from_iter_safe(["ffms-segmenter", "in.mkv", output]).unwrap();
+        assert_eq!(
+            existing_segment(&args, "0-100"),
+            Some(folder.join("0-100.y4m").to_string_lossy().into_owned())
+        );
+        // An unfinished segment is written again.
+        assert_eq!(existing_segment(&args, "100-200"), None);
+        assert_eq!(existing_segment(&args, "200-300"), None);
+
+        let args =
+            CliArgs::from_iter_safe(["ffms-segmenter", "--force", "in.mkv", output]).unwrap();
+        assert_eq!(existing_segment(&args, "0-100"), None);
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn parses_index_error_handling() {
+        // The bindings' enum is not PartialEq, so its variants are compared
+        // by name.
+        for (handlings, expected) in [
+            (["ignore", "0"], "IEH_IGNORE"),
+            (["stop-track", "1"], "IEH_STOP_TRACK"),
+            (["clear-track", "2"], "IEH_CLEAR_TRACK"),
+            (["abort", "3"], "IEH_ABORT"),
+        ] {
+            for handling in handlings {
+                let parsed = parse_index_error_handling(handling).unwrap();
+                assert_eq!(format!("{:?}", parsed), expected, "{}", handling);
+            }
+        }
+
+        for handling in ["4", "-1", "Abort", "stop", ""] {
+            assert!(
+                parse_index_error_handling(handling).is_err(),
+                "{}",
+                handling
+            );
+        }
+        assert!(CliArgs::from_iter_safe(["ffms-segmenter", "-e", "skip", "in.mkv"]).is_err());
+    }
+
+    #[test]
+    fn forwards_dref_options() {
+        let args = CliArgs::from_iter_safe([
+            "ffms-segmenter",
+            "--source-opt",
+            "probesize=5000000",
+            "--enable-drefs",
+            "--use-absolute-path",
+            "in.mov",
+        ])
+        .unwrap();
+        assert_eq!(
+            source_options(&args),
+            [
+                ("probesize".to_string(), "5000000".to_string()),
+                ("enable_drefs".to_string(), "1".to_string()),
+                ("use_absolute_path".to_string(), "1".to_string()),
+            ]
+        );
+
+        assert!(
+            CliArgs::from_iter_safe(["ffms-segmenter", "--use-absolute-path", "in.mov"]).is_err()
+        );
+    }
+
+    #[test]
+    fn handshake_lists_every_colorspace() {
+        let args = CliArgs::from_iter_safe(["ffms-segmenter", "in.mkv"]).unwrap();
+        assert_eq!(
+            handshake(&args).colorspaces,
+            [
+                "420", "420p10", "420p12", "422", "422p10", "422p12", "444", "444p10", "444p12",
+                "mono"
+            ]
+        );
+    }
+
+    #[test]
+    fn handshake_lists_every_container() {
+        let args = CliArgs::from_iter_safe(["ffms-segmenter", "in.mkv"]).unwrap();
+        assert_eq!(
+            handshake(&args).formats,
+            ["y4m", "mkv", "nut", "raw", "rgb", "png"]
+        );
+        for (name, container) in CONTAINERS {
+            assert_eq!(parse_container(name), Ok(container));
+        }
+    }
+
+    #[test]
+    fn dispatches_every_command() {
+        for &(name, command) in &COMMANDS {
+            assert_eq!(
+                parse_command(&format!("{}  1 2 ", name)),
+                Some((command, "1 2"))
+            );
+            assert_eq!(parse_command(name), Some((command, "")));
+            assert_eq!(command_name(command), name);
+        }
+        assert_eq!(parse_command("0 100"), None);
+        assert_eq!(parse_command("framesx 0 10 out"), None);
+    }
+
+    #[test]
+    fn handshake_lists_chunk_commands_with_chunks() {
+        let args = CliArgs::from_iter_safe(["ffms-segmenter", "in.mkv"]).unwrap();
+        assert_eq!(
+            handshake(&args).commands,
+            [
+                "range",
+                "open",
+                "prefetch",
+                "thumb",
+                "frames",
+                "info",
+                "duration",
+                "tracks",
+                "frame",
+                "keyframes",
+                "compare",
+                "status"
+            ]
+        );
+
+        let args = CliArgs::from_iter_safe(["ffms-segmenter", "--chunks", "chunks.txt", "in.mkv"])
+            .unwrap();
+        let commands = handshake(&args).commands;
+        assert!(commands.iter().any(|command| command == "chunks"));
+        assert!(commands.iter().any(|command| command == "chunk"));
+        assert!(!commands.iter().any(|command| command == "cancel"));
+    }
+
+    #[test]
+    fn answers_malformed_commands() {
+        let chunks = [(0, 24), (24, 48)];
+        let mut out = Vec::new();
+        {
+            let mut transport = LineTransport::new(std::io::empty(), &mut out);
+            for line in [
+                "thumb ten out.png",
+                "thumb 10",
+                "frames 0 ten out",
+                "frames 0 10 out gif",
+            ] {
+                let (command, arguments) = parse_command(line).unwrap();
+                let parsed = match command {
+                    Command::Thumb => parse_thumb(arguments).map(drop),
+                    _ => parse_frames(arguments).map(drop),
+                };
+                assert!(answer_invalid(&mut transport, parsed).unwrap().is_none());
+            }
+            for line in ["chunk 2", "chunk one"] {
+                let (_, arguments) = parse_command(line).unwrap();
+                let parsed = parse_chunk(arguments, &chunks);
+                assert!(answer_invalid(&mut transport, parsed).unwrap().is_none());
+            }
+
+            let parsed = parse_chunk("1", &chunks);
+            assert_eq!(
+                answer_invalid(&mut transport, parsed).unwrap(),
+                Some((24, 48))
+            );
+            let failed: std::io::Result<()> = Err(std::io::Error::other("disk full"));
+            assert!(answer_invalid(&mut transport, failed).is_err());
+        }
+
+        let replies = String::from_utf8(out).unwrap();
+        assert_eq!(replies.lines().count(), 6);
+        assert!(replies.lines().all(|line| line.starts_with("error ")));
+        assert!(replies.ends_with("error Unknown chunk one\n"));
+    }
 }
@@ -0,0 +1,317 @@
+use ffms_segmenter::log::json_string;
+use std::fs;
+use std::io;
+use std::iter::Peekable;
+use std::path::Path;
+use std::str::Chars;
+
+/// Writes `text` to `path`, replacing it whole so a reader never sees
+/// half of it.
+fn replace(path: &Path, text: &str) -> io::Result<()> {
+    let partial = path.with_extension("partial");
+    fs::write(&partial, text)?;
+    fs::rename(partial, path)
+}
+
+/// One segment of the `--manifest`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub path: String,
+    pub input: usize,
+    /// The requested range, without padding.
+    pub start: usize,
+    pub end: usize,
+    /// Frames in the file, padding included and `step` applied.
+    pub frames: usize,
+    pub seconds: f64,
+    pub bytes: u64,
+    pub crc: u32,
+}
+
+/// Every segment written in a session, in the order they were written,
+/// for the steps that assemble them afterwards.
+#[derive(Debug, Default)]
+pub struct Manifest {
+    entries: Vec<Entry>,
+}
+
+impl Manifest {
+    pub fn push(&mut self, entry: Entry) {
+        self.entries.push(entry);
+    }
+
+    pub fn to_json(&self) -> String {
+        let segments: Vec<String> = self
+            .entries
+            .iter()
+            .map(|entry| {
+                format!(
+                    "{{\"path\":{},\"input\":{},\"start\":{},\"end\":{},\"frames\":{},\
+                     \"duration\":{:.6},\"bytes\":{},\"crc32\":\"{:08x}\"}}",
+                    json_string(&entry.path),
+                    entry.input,
+                    entry.start,
+                    entry.end,
+                    entry.frames,
+                    entry.seconds,
+                    entry.bytes,
+                    entry.crc
+                )
+            })
+            .collect();
+
+        format!("{{\"segments\":[{}]}}", segments.join(","))
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        replace(path, &(self.to_json() + "\n"))
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Reads back a manifest `to_json` wrote. Any other JSON, even if
+    /// equivalent, is refused.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut scanner = Scanner {
+            chars: text.chars().peekable(),
+        };
+        let entries = scanner
+            .segments()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid manifest"))?;
+
+        Ok(Manifest { entries })
+    }
+
+    pub fn read(path: &Path) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// An HLS media playlist of the segments in frame order, with paths
+    /// relative to `folder` where they are inside it. Each segment starts
+    /// its timestamps afresh, so a discontinuity comes before every one
+    /// but the first.
+    pub fn to_hls(&self, folder: &Path) -> String {
+        let mut entries: Vec<&Entry> = self.entries.iter().collect();
+        entries.sort_by_key(|entry| (entry.input, entry.start));
+
+        let target = entries
+            .iter()
+            .map(|entry| entry.seconds.ceil() as u64)
+            .max()
+            .unwrap_or(0);
+        let mut playlist = format!(
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{}\n\
+             #EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n",
+            target
+        );
+        for (i, entry) in entries.iter().enumerate() {
+            if i > 0 {
+                playlist.push_str("#EXT-X-DISCONTINUITY\n");
+            }
+            let path = Path::new(&entry.path);
+            let path = path.strip_prefix(folder).unwrap_or(path);
+            playlist.push_str(&format!(
+                "#EXTINF:{:.6},\n{}\n",
+                entry.seconds,
+                path.display()
+            ));
+        }
+        playlist.push_str("#EXT-X-ENDLIST\n");
+
+        playlist
+    }
+
+    /// Writes the `--hls-playlist` to `path`, referring to the segments
+    /// next to it.
+    pub fn write_hls(&self, path: &Path) -> io::Result<()> {
+        let folder = path.parent().unwrap_or(Path::new(""));
+        replace(path, &self.to_hls(folder))
+    }
+}
+
+/// Walks the JSON `Manifest::to_json` writes: an object whose `segments`
+/// are flat objects of strings and numbers.
+struct Scanner<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl Scanner<'_> {
+    fn peek(&mut self) -> Option<char> {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+        self.chars.peek().copied()
+    }
+
+    fn expect(&mut self, c: char) -> Option<()> {
+        (self.peek()? == c).then(|| self.chars.next()).map(drop)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.expect('"')?;
+
+        let mut value = String::new();
+        loop {
+            match self.chars.next()? {
+                '"' => return Some(value),
+                '\\' => value.push(match self.chars.next()? {
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    'u' => {
+                        let digits: String = self.chars.by_ref().take(4).collect();
+                        char::from_u32(u32::from_str_radix(&digits, 16).ok()?)?
+                    }
+                    c => c,
+                }),
+                c => value.push(c),
+            }
+        }
+    }
+
+    /// A number, or a string without its quotes.
+    fn value(&mut self) -> Option<String> {
+        if self.peek()? == '"' {
+            return self.string();
+        }
+
+        let mut value = String::new();
+        while let Some(c) = self
+            .chars
+            .next_if(|c| !matches!(c, ',' | '}' | ']') && !c.is_whitespace())
+        {
+            value.push(c);
+        }
+        Some(value)
+    }
+
+    fn entry(&mut self) -> Option<Entry> {
+        self.expect('{')?;
+
+        let mut members = Vec::new();
+        loop {
+            let key = self.string()?;
+            self.expect(':')?;
+            members.push((key, self.value()?));
+            match self.peek()? {
+                ',' => self.chars.next(),
+                _ => break self.expect('}')?,
+            };
+        }
+        let member = |key: &str| {
+            members
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value.as_str())
+        };
+
+        Some(Entry {
+            path: member("path")?.to_string(),
+            input: member("input")?.parse().ok()?,
+            start: member("start")?.parse().ok()?,
+            end: member("end")?.parse().ok()?,
+            frames: member("frames")?.parse().ok()?,
+            seconds: member("duration")?.parse().ok()?,
+            bytes: member("bytes")?.parse().ok()?,
+            crc: u32::from_str_radix(member("crc32")?, 16).ok()?,
+        })
+    }
+
+    fn segments(&mut self) -> Option<Vec<Entry>> {
+        self.expect('{')?;
+        (self.string()? == "segments").then_some(())?;
+        self.expect(':')?;
+        self.expect('[')?;
+
+        let mut entries = Vec::new();
+        if self.expect(']').is_none() {
+            loop {
+                entries.push(self.entry()?);
+                match self.peek()? {
+                    ',' => self.chars.next(),
+                    _ => break self.expect(']')?,
+                };
+            }
+        }
+        self.expect('}')?;
+
+        self.peek().is_none().then_some(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_segments() {
+        let mut manifest = Manifest::default();
+        assert_eq!(manifest.to_json(), "{\"segments\":[]}");
+
+        manifest.push(Entry {
+            path: "out/0-48.y4m".to_string(),
+            input: 0,
+            start: 0,
+            end: 48,
+            frames: 48,
+            seconds: 2.002,
+            bytes: 4_500_000,
+            crc: 0xbeef,
+        });
+        assert_eq!(
+            manifest.to_json(),
+            "{\"segments\":[{\"path\":\"out/0-48.y4m\",\"input\":0,\"start\":0,\"end\":48,\
+             \"frames\":48,\"duration\":2.002000,\"bytes\":4500000,\"crc32\":\"0000beef\"}]}"
+        );
+    }
+
+    #[test]
+    fn reads_its_own_json() {
+        let mut manifest = Manifest::default();
+        for (start, path) in [(0, "out/0-48.y4m"), (48, "out/\"48\"\\\t.y4m")] {
+            manifest.push(Entry {
+                path: path.to_string(),
+                input: 1,
+                start,
+                end: start + 48,
+                frames: 24,
+                seconds: 1.001,
+                bytes: 4_500_000,
+                crc: 0xdead_beef,
+            });
+        }
+
+        let read = Manifest::parse(&(manifest.to_json() + "\n")).unwrap();
+        assert_eq!(read.entries(), manifest.entries());
+        assert!(Manifest::parse("{\"segments\":[]}")
+            .unwrap()
+            .entries()
+            .is_empty());
+        assert!(Manifest::parse("{\"segments\":[{\"path\":\"a.y4m\"}]}").is_err());
+        assert!(Manifest::parse("{\"segments\":[]} trailing").is_err());
+    }
+
+    #[test]
+    fn writes_hls_playlists() {
+        let mut manifest = Manifest::default();
+        for (start, end) in [(48, 96), (0, 48)] {
+            manifest.push(Entry {
+                path: format!("out/{}-{}.mkv", start, end),
+                input: 0,
+                start,
+                end,
+                frames: 48,
+                seconds: 2.002,
+                bytes: 0,
+                crc: 0,
+            });
+        }
+
+        assert_eq!(
+            manifest.to_hls(Path::new("out")),
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:3\n#EXT-X-MEDIA-SEQUENCE:0\n\
+             #EXT-X-PLAYLIST-TYPE:VOD\n#EXTINF:2.002000,\n0-48.mkv\n#EXT-X-DISCONTINUITY\n\
+             #EXTINF:2.002000,\n48-96.mkv\n#EXT-X-ENDLIST\n"
+        );
+    }
+}
@@ -0,0 +1,109 @@
+use std::io;
+use std::path::Path;
+
+/// A file mapped read-only into memory, for `--mmap-index`. Its pages are
+/// read as they are first touched and stay page cache the kernel can
+/// reclaim, instead of a copy of the whole file on the heap.
+#[cfg(unix)]
+pub struct Mapped {
+    map: *mut libc::c_void,
+    len: usize,
+}
+
+#[cfg(unix)]
+impl Mapped {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        use std::os::fd::AsRawFd;
+
+        let file = std::fs::File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        // Empty mappings are refused, and there is nothing to map.
+        if len == 0 {
+            return Ok(Mapped {
+                map: std::ptr::null_mut(),
+                len,
+            });
+        }
+
+        let map = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // Indexes are read front to back, so the kernel may read ahead.
+        unsafe { libc::madvise(map, len, libc::MADV_SEQUENTIAL) };
+
+        Ok(Mapped { map, len })
+    }
+}
+
+#[cfg(unix)]
+impl std::ops::Deref for Mapped {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.len {
+            0 => &[],
+            len => unsafe { std::slice::from_raw_parts(self.map as *const u8, len) },
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Mapped {
+    fn drop(&mut self) {
+        if !self.map.is_null() {
+            unsafe { libc::munmap(self.map, self.len) };
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub struct Mapped;
+
+#[cfg(not(unix))]
+impl Mapped {
+    pub fn open(_path: &Path) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "--mmap-index needs a Unix platform",
+        ))
+    }
+}
+
+#[cfg(not(unix))]
+impl std::ops::Deref for Mapped {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &[]
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_files() {
+        let path =
+            std::env::temp_dir().join(format!("ffms-segmenter-mapped-{}", std::process::id()));
+
+        std::fs::write(&path, b"ffindex").unwrap();
+        assert_eq!(&*Mapped::open(&path).unwrap(), b"ffindex");
+
+        std::fs::write(&path, b"").unwrap();
+        assert!(Mapped::open(&path).unwrap().is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(Mapped::open(&path).is_err());
+    }
+}
@@ -0,0 +1,247 @@
+use ffms_segmenter::prefetch::{DecodedFrame, Decoder};
+use std::fmt::Write;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+static SEGMENTS: AtomicU64 = AtomicU64::new(0);
+static FRAMES: AtomicU64 = AtomicU64::new(0);
+static BYTES: AtomicU64 = AtomicU64::new(0);
+static ERRORS: AtomicU64 = AtomicU64::new(0);
+
+const SECONDS_BUCKETS: [f64; 8] = [0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+const FPS_BUCKETS: [f64; 8] = [10.0, 25.0, 50.0, 100.0, 200.0, 400.0, 800.0, 1600.0];
+
+/// A Prometheus histogram with fixed upper bounds.
+struct Histogram {
+    bounds: &'static [f64],
+    /// Observations at or below each bound, not yet cumulative.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    const fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            counts: Vec::new(),
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        self.counts.resize(self.bounds.len(), 0);
+        if let Some(bucket) = self.bounds.iter().position(|&bound| value <= bound) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+
+        let mut cumulative = 0;
+        for (bucket, bound) in self.bounds.iter().enumerate() {
+            cumulative += self.counts.get(bucket).copied().unwrap_or(0);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum);
+        let _ = writeln!(out, "{}_count {}", name, self.count);
+    }
+}
+
+static SEGMENT_SECONDS: Mutex<Histogram> = Mutex::new(Histogram::new(&SECONDS_BUCKETS));
+static DECODE_FPS: Mutex<Histogram> = Mutex::new(Histogram::new(&FPS_BUCKETS));
+
+fn lock(histogram: &Mutex<Histogram>) -> MutexGuard<'_, Histogram> {
+    histogram
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Records a segment of `frames` decoded frames written as `bytes`.
+pub fn record_segment(frames: usize, bytes: u64, elapsed: Duration) {
+    SEGMENTS.fetch_add(1, Ordering::Relaxed);
+    FRAMES.fetch_add(frames as u64, Ordering::Relaxed);
+    BYTES.fetch_add(bytes, Ordering::Relaxed);
+
+    let seconds = elapsed.as_secs_f64();
+    lock(&SEGMENT_SECONDS).observe(seconds);
+    if seconds > 0.0 {
+        lock(&DECODE_FPS).observe(frames as f64 / seconds);
+    }
+}
+
+pub fn record_error() {
+    ERRORS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The most memory the process has held resident at once, in bytes.
+#[cfg(unix)]
+pub fn peak_memory() -> Option<u64> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return None;
+    }
+
+    // Kilobytes everywhere but macOS.
+    let scale = if cfg!(target_os = "macos") { 1 } else { 1024 };
+    Some(usage.ru_maxrss as u64 * scale)
+}
+
+#[cfg(not(unix))]
+pub fn peak_memory() -> Option<u64> {
+    None
+}
+
+/// Counts the frames a decoder hands out and the time spent decoding them.
+pub struct Timed<'a, D: ?Sized> {
+    decoder: &'a mut D,
+    pub frames: usize,
+    pub time: Duration,
+}
+
+impl<'a, D: Decoder + ?Sized> Timed<'a, D> {
+    pub fn new(decoder: &'a mut D) -> Self {
+        Timed {
+            decoder,
+            frames: 0,
+            time: Duration::ZERO,
+        }
+    }
+}
+
+impl<D: Decoder + ?Sized> Decoder for Timed<'_, D> {
+    fn decode(&mut self, frame: usize) -> io::Result<DecodedFrame> {
+        let started = Instant::now();
+        let decoded = self.decoder.decode(frame)?;
+        self.time += started.elapsed();
+        self.frames += 1;
+        Ok(decoded)
+    }
+
+    fn frames(&self) -> usize {
+        self.decoder.frames()
+    }
+}
+
+/// How writing one segment went, for `--segment-stats`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SegmentStats {
+    /// Frames decoded, padding and deinterlacer context included.
+    pub frames: usize,
+    pub decode_time: Duration,
+    pub bytes: u64,
+    pub elapsed: Duration,
+    pub peak_memory: Option<u64>,
+}
+
+impl SegmentStats {
+    /// ` frames=<n> decode_fps=<fps> bytes=<n> write_mibps=<MiB/s>
+    /// peak_rss_mib=<MiB>`, appended to the line announcing the segment.
+    /// Peak memory is left out where it is not known.
+    pub fn fields(&self) -> String {
+        let per_second = |amount: f64, time: Duration| match time.as_secs_f64() {
+            seconds if seconds > 0.0 => amount / seconds,
+            _ => 0.0,
+        };
+
+        let mut fields = format!(
+            " frames={} decode_fps={:.1} bytes={} write_mibps={:.1}",
+            self.frames,
+            per_second(self.frames as f64, self.decode_time),
+            self.bytes,
+            per_second(self.bytes as f64 / (1 << 20) as f64, self.elapsed)
+        );
+        if let Some(peak) = self.peak_memory {
+            let _ = write!(fields, " peak_rss_mib={}", peak >> 20);
+        }
+        fields
+    }
+}
+
+/// The Prometheus text exposition of every metric, served as `/metrics`.
+pub fn render() -> String {
+    let mut out = String::new();
+
+    let counters = [
+        ("segmenter_segments_total", "Segments written", &SEGMENTS),
+        (
+            "segmenter_frames_decoded_total",
+            "Frames decoded for segments, padding included",
+            &FRAMES,
+        ),
+        (
+            "segmenter_bytes_written_total",
+            "Bytes of segments written",
+            &BYTES,
+        ),
+        (
+            "segmenter_errors_total",
+            "Segments and connections that failed",
+            &ERRORS,
+        ),
+    ];
+    for (name, help, counter) in counters {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+    }
+
+    lock(&SEGMENT_SECONDS).render(
+        &mut out,
+        "segmenter_segment_seconds",
+        "Time to write a segment",
+    );
+    lock(&DECODE_FPS).render(
+        &mut out,
+        "segmenter_decode_fps",
+        "Frames decoded per second while writing a segment",
+    );
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let mut histogram = Histogram::new(&[1.0, 5.0]);
+        histogram.observe(0.5);
+        histogram.observe(2.0);
+        histogram.observe(9.0);
+
+        let mut out = String::new();
+        histogram.render(&mut out, "t", "Test");
+
+        assert_eq!(
+            out,
+            "# HELP t Test\n# TYPE t histogram\nt_bucket{le=\"1\"} 1\n\
+             t_bucket{le=\"5\"} 2\nt_bucket{le=\"+Inf\"} 3\nt_sum 11.5\nt_count 3\n"
+        );
+    }
+
+    #[test]
+    fn formats_segment_stats() {
+        let stats = SegmentStats {
+            frames: 50,
+            decode_time: Duration::from_millis(500),
+            bytes: 3 << 20,
+            elapsed: Duration::from_secs(2),
+            peak_memory: Some(300 << 20),
+        };
+        assert_eq!(
+            stats.fields(),
+            " frames=50 decode_fps=100.0 bytes=3145728 write_mibps=1.5 peak_rss_mib=300"
+        );
+        assert!(peak_memory().is_none_or(|peak| peak > 0));
+    }
+}
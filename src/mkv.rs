@@ -0,0 +1,633 @@
+use crate::timecodes::frame_sample;
+use crate::SegmentFormat;
+use ffms2::audio::AudioSource;
+use ffms2::track::Track;
+use ffms_segmenter::prefetch::DecodedFrame;
+use ffms_segmenter::scale::plane_sizes;
+use ffms_segmenter::yuv4mpeg::{ColorRange, Interlacing};
+use std::io::{self, Write};
+use y4m::{Colorspace, Ratio};
+
+/// Matroska `Segment` size meaning "until the end of the file", so the
+/// file can be streamed without seeking back.
+const UNKNOWN_SIZE: u64 = 0x00ff_ffff_ffff_ffff;
+
+const VIDEO_TRACK: u64 = 1;
+/// Number of the first audio track, the others following it.
+const AUDIO_TRACK: u64 = 2;
+
+/// Samples decoded per call.
+const AUDIO_CHUNK: usize = 1 << 16;
+
+/// Length of the EBML variable length integer holding `value`.
+fn vint_len(value: u64) -> usize {
+    (1..8).find(|len| value < (1 << (7 * len)) - 1).unwrap_or(8)
+}
+
+fn vint(value: u64) -> Vec<u8> {
+    let len = vint_len(value);
+    let marked = value | 1 << (7 * len);
+    marked.to_be_bytes()[8 - len..].to_vec()
+}
+
+/// Element ID and size, for an element whose body is written separately.
+fn header(id: u32, size: u64) -> Vec<u8> {
+    let id_len = 4 - id.leading_zeros() as usize / 8;
+    let mut out = id.to_be_bytes()[4 - id_len..].to_vec();
+    out.extend(vint(size));
+    out
+}
+
+fn element(id: u32, body: &[u8]) -> Vec<u8> {
+    let mut out = header(id, body.len() as u64);
+    out.extend_from_slice(body);
+    out
+}
+
+fn uint(id: u32, value: u64) -> Vec<u8> {
+    let len = (8 - value.leading_zeros() as usize / 8).max(1);
+    element(id, &value.to_be_bytes()[8 - len..])
+}
+
+fn float(id: u32, value: f64) -> Vec<u8> {
+    element(id, &value.to_be_bytes())
+}
+
+fn master(id: u32, children: &[Vec<u8>]) -> Vec<u8> {
+    element(id, &children.concat())
+}
+
+/// The `V_UNCOMPRESSED` FourCC FFmpeg reads each colorspace from.
+pub fn fourcc(colorspace: Colorspace) -> [u8; 4] {
+    match colorspace {
+        Colorspace::Cmono => *b"Y800",
+        Colorspace::C420 | Colorspace::C420jpeg | Colorspace::C420paldv | Colorspace::C420mpeg2 => {
+            *b"I420"
+        }
+        Colorspace::C420p10 => [b'Y', b'3', 11, 10],
+        Colorspace::C420p12 => [b'Y', b'3', 11, 12],
+        Colorspace::C422 => *b"Y42B",
+        Colorspace::C422p10 => [b'Y', b'3', 10, 10],
+        Colorspace::C422p12 => [b'Y', b'3', 10, 12],
+        Colorspace::C444 => *b"444P",
+        Colorspace::C444p10 => [b'Y', b'3', 0, 10],
+        Colorspace::C444p12 => [b'Y', b'3', 0, 12],
+    }
+}
+
+/// Bytes of one uncompressed frame of `format`.
+pub fn frame_bytes(format: &SegmentFormat) -> usize {
+    let bytes = format.colorspace.get_bytes_per_sample();
+    plane_sizes(format.colorspace, format.width, format.height)
+        .iter()
+        .map(|plane| plane.width * plane.height * bytes)
+        .sum()
+}
+
+/// An FFMS2 sample format, in the order of the `FFMS_SampleFormat` codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleFormat {
+    U8,
+    S16,
+    S32,
+    Flt,
+    Dbl,
+}
+
+impl SampleFormat {
+    fn from_code(code: i32) -> Option<Self> {
+        match code {
+            0 => Some(SampleFormat::U8),
+            1 => Some(SampleFormat::S16),
+            2 => Some(SampleFormat::S32),
+            3 => Some(SampleFormat::Flt),
+            4 => Some(SampleFormat::Dbl),
+            _ => None,
+        }
+    }
+
+    fn to_ffms(self) -> ffms2::SampleFormat {
+        match self {
+            SampleFormat::U8 => ffms2::SampleFormat::FMT_U8,
+            SampleFormat::S16 => ffms2::SampleFormat::FMT_S16,
+            SampleFormat::S32 => ffms2::SampleFormat::FMT_S32,
+            SampleFormat::Flt => ffms2::SampleFormat::FMT_FLT,
+            SampleFormat::Dbl => ffms2::SampleFormat::FMT_DBL,
+        }
+    }
+
+    /// The Matroska codec and bytes of one sample.
+    fn codec(self) -> (&'static str, u32) {
+        match self {
+            SampleFormat::U8 => ("A_PCM/INT/LIT", 1),
+            SampleFormat::S16 => ("A_PCM/INT/LIT", 2),
+            SampleFormat::S32 => ("A_PCM/INT/LIT", 4),
+            SampleFormat::Flt => ("A_PCM/FLOAT/IEEE", 4),
+            SampleFormat::Dbl => ("A_PCM/FLOAT/IEEE", 8),
+        }
+    }
+}
+
+pub fn parse_sample_format(format: &str) -> Result<SampleFormat, String> {
+    match format {
+        "u8" => Ok(SampleFormat::U8),
+        "s16" => Ok(SampleFormat::S16),
+        "s32" => Ok(SampleFormat::S32),
+        "flt" => Ok(SampleFormat::Flt),
+        "dbl" => Ok(SampleFormat::Dbl),
+        _ => Err(format!("Unknown sample format: {}", format)),
+    }
+}
+
+/// The FFmpeg channel layout of `channels` channels: mono, stereo, 5.1 or
+/// 7.1.
+fn channel_layout(channels: u32) -> Option<i64> {
+    match channels {
+        1 => Some(0x4),
+        2 => Some(0x3),
+        6 => Some(0x3f),
+        8 => Some(0x63f),
+        _ => None,
+    }
+}
+
+/// What `--audio-rate`, `--audio-channels` and `--audio-sample-format` ask
+/// muxed audio to be converted to. Unset fields keep those of the input.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AudioOutput {
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u32>,
+    pub sample_format: Option<SampleFormat>,
+}
+
+/// An audio track opened for muxing, with the format its samples are
+/// decoded in.
+pub struct AudioTrack {
+    source: AudioSource,
+    sample_format: SampleFormat,
+    sample_rate: u32,
+    channels: u32,
+}
+
+impl AudioTrack {
+    /// Has FFMS2 convert the samples of `source` to `output`. FFMS2 mixes
+    /// channels and converts sample formats but cannot change the sample
+    /// rate, so asking for another rate fails rather than muxing audio that
+    /// plays at the wrong speed.
+    pub fn new(source: AudioSource, output: &AudioOutput) -> io::Result<Self> {
+        let properties = source.GetAudioProperties();
+        let mut track = AudioTrack {
+            sample_format: SampleFormat::from_code(properties.SampleFormat).ok_or_else(|| {
+                io::Error::other(format!(
+                    "Unsupported audio sample format {}",
+                    properties.SampleFormat
+                ))
+            })?,
+            sample_rate: properties.SampleRate as u32,
+            channels: properties.Channels as u32,
+            source,
+        };
+
+        if output.sample_rate.is_none()
+            && output.channels.is_none()
+            && output.sample_format.is_none()
+        {
+            return Ok(track);
+        }
+
+        let mut options = track.source.CreateResampleOptions();
+        if let Some(sample_format) = output.sample_format {
+            options.set_sample_format(&sample_format.to_ffms());
+            track.sample_format = sample_format;
+        }
+        if let Some(channels) = output.channels {
+            let layout = channel_layout(channels).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Cannot mix audio to {} channels, only 1, 2, 6 or 8",
+                        channels
+                    ),
+                )
+            })?;
+            options.set_channel_layout(layout);
+            track.channels = channels;
+        }
+        if let Some(sample_rate) = output.sample_rate {
+            options.SampleRate = sample_rate as i32;
+            track.sample_rate = sample_rate;
+        }
+
+        track.source.SetOutputFormatA(&options).map_err(|err| {
+            io::Error::other(format!(
+                "Failed to convert audio to {} Hz, {} channels, {:?}: {:?}",
+                track.sample_rate, track.channels, track.sample_format, err
+            ))
+        })?;
+
+        Ok(track)
+    }
+}
+
+/// Interleaved little-endian PCM to mux next to the frames of a segment.
+pub struct Audio {
+    codec: &'static str,
+    sample_rate: u32,
+    channels: u32,
+    bit_depth: u32,
+    samples: Vec<u8>,
+}
+
+impl Audio {
+    /// Decodes the audio played from frame `start` until frame `end`, or
+    /// the end of the stream when `end` is the last frame. `audio` must have
+    /// been opened with its delay relative to the video track of `track`.
+    pub fn read(
+        audio: &AudioTrack,
+        track: &Track,
+        start: usize,
+        end: usize,
+        total_frames: usize,
+    ) -> io::Result<Audio> {
+        let sample_rate = audio.sample_rate;
+        let total = audio.source.GetAudioProperties().NumSamples.max(0) as usize;
+
+        let sample_at =
+            |frame| (frame_sample(track, frame, sample_rate).max(0) as usize).min(total);
+        let first = sample_at(start);
+        let last = match end {
+            end if end < total_frames => sample_at(end),
+            _ => total,
+        };
+
+        let (codec, bytes) = audio.sample_format.codec();
+
+        let range = (first, last, total);
+        let source = &audio.source;
+        let samples = match audio.sample_format {
+            SampleFormat::U8 => read_samples(source, range, u8::to_le_bytes)?,
+            SampleFormat::S16 => read_samples(source, range, i16::to_le_bytes)?,
+            SampleFormat::S32 => read_samples(source, range, i32::to_le_bytes)?,
+            SampleFormat::Flt => read_samples(source, range, f32::to_le_bytes)?,
+            SampleFormat::Dbl => read_samples(source, range, f64::to_le_bytes)?,
+        };
+
+        Ok(Audio {
+            codec,
+            sample_rate,
+            channels: audio.channels,
+            bit_depth: bytes * 8,
+            samples,
+        })
+    }
+
+    fn frame_len(&self) -> usize {
+        (self.channels * self.bit_depth / 8) as usize
+    }
+
+    /// The samples, interleaved, scaled to -1 to 1.
+    pub fn to_f32(&self) -> Vec<f32> {
+        let float = self.codec == "A_PCM/FLOAT/IEEE";
+        match (float, self.bit_depth) {
+            (false, 8) => self
+                .samples
+                .iter()
+                .map(|&sample| (sample as f32 - 128.0) / 128.0)
+                .collect(),
+            (false, 16) => self
+                .samples
+                .chunks_exact(2)
+                .map(|sample| i16::from_le_bytes([sample[0], sample[1]]) as f32 / 32768.0)
+                .collect(),
+            (false, _) => self
+                .samples
+                .chunks_exact(4)
+                .map(|sample| i32::from_le_bytes(sample.try_into().unwrap()) as f32 / 2147483648.0)
+                .collect(),
+            (true, 32) => self
+                .samples
+                .chunks_exact(4)
+                .map(|sample| f32::from_le_bytes(sample.try_into().unwrap()))
+                .collect(),
+            (true, _) => self
+                .samples
+                .chunks_exact(8)
+                .map(|sample| f64::from_le_bytes(sample.try_into().unwrap()) as f32)
+                .collect(),
+        }
+    }
+
+    fn track_entry(&self, number: u64) -> Vec<u8> {
+        master(
+            0xae,
+            &[
+                uint(0xd7, number),
+                uint(0x73c5, number),
+                uint(0x83, 2),
+                uint(0x9c, 0),
+                element(0x86, self.codec.as_bytes()),
+                master(
+                    0xe1,
+                    &[
+                        float(0xb5, self.sample_rate as f64),
+                        uint(0x9f, self.channels as u64),
+                        uint(0x6264, self.bit_depth as u64),
+                    ],
+                ),
+            ],
+        )
+    }
+}
+
+/// Samples `first..last` of a stream of `total` as little-endian bytes.
+fn read_samples<T, const N: usize>(
+    audio: &AudioSource,
+    (first, last, total): (usize, usize, usize),
+    to_le: fn(T) -> [u8; N],
+) -> io::Result<Vec<u8>> {
+    let mut samples = Vec::new();
+    let mut position = first;
+
+    while position < last {
+        // The bindings refuse to read the whole stream in one call.
+        let count = (last - position).min(AUDIO_CHUNK).min(total - 1);
+        if count == 0 {
+            break;
+        }
+        let chunk = audio
+            .GetAudio::<T>(position, count)
+            .map_err(|err| io::Error::other(format!("Failed to decode audio: {:?}", err)))?;
+        samples.extend(chunk.into_iter().flat_map(to_le));
+        position += count;
+    }
+
+    Ok(samples)
+}
+
+/// `--output-format mkv` writer: uncompressed frames and PCM audio in
+/// Matroska, which players open directly. Each frame gets a cluster of its
+/// own with the audio played during it.
+pub struct Writer<W: Write> {
+    sink: W,
+    framerate: Ratio,
+    frame_bytes: usize,
+    audio: Vec<Audio>,
+    /// Frames written so far.
+    frames: usize,
+    /// Bytes of each of `audio` written so far.
+    audio_written: Vec<usize>,
+}
+
+impl<W: Write> Writer<W> {
+    /// Starts a file of `frames` frames of `format`, with one track for
+    /// each of `audio`.
+    pub fn new(
+        mut sink: W,
+        format: &SegmentFormat,
+        frames: usize,
+        audio: Vec<Audio>,
+    ) -> io::Result<Self> {
+        let framerate = format.framerate;
+        let frame_ns = 1e9 * framerate.den as f64 / framerate.num as f64;
+
+        let ebml = master(
+            0x1a45_dfa3,
+            &[
+                uint(0x4286, 1),
+                uint(0x42f7, 1),
+                uint(0x42f2, 4),
+                uint(0x42f3, 8),
+                element(0x4282, b"matroska"),
+                uint(0x4287, 4),
+                uint(0x4285, 2),
+            ],
+        );
+
+        let app = concat!("ffms-segmenter ", env!("CARGO_PKG_VERSION")).as_bytes();
+        let info = master(
+            0x1549_a966,
+            &[
+                uint(0x2a_d7b1, 1_000_000),
+                float(0x4489, frames as f64 * frame_ns / 1e6),
+                element(0x4d80, app),
+                element(0x5741, app),
+            ],
+        );
+
+        let (flag_interlaced, field_order) = match format.interlacing {
+            Interlacing::Progressive => (2, 0),
+            Interlacing::TopFieldFirst => (1, 1),
+            Interlacing::BottomFieldFirst => (1, 6),
+        };
+        let range = match format.color_range {
+            ColorRange::Limited => 1,
+            ColorRange::Full => 2,
+        };
+        let video = master(
+            0xae,
+            &[
+                uint(0xd7, VIDEO_TRACK),
+                uint(0x73c5, VIDEO_TRACK),
+                uint(0x83, 1),
+                uint(0x9c, 0),
+                element(0x86, b"V_UNCOMPRESSED"),
+                uint(0x23_e383, frame_ns.round() as u64),
+                master(
+                    0xe0,
+                    &[
+                        uint(0xb0, format.width as u64),
+                        uint(0xba, format.height as u64),
+                        uint(0x9a, flag_interlaced),
+                        uint(0x9d, field_order),
+                        element(0x2e_b524, &fourcc(format.colorspace)),
+                        master(0x55b0, &[uint(0x55b9, range)]),
+                    ],
+                ),
+            ],
+        );
+
+        let mut tracks = vec![video];
+        tracks.extend(
+            (AUDIO_TRACK..)
+                .zip(&audio)
+                .map(|(number, audio)| audio.track_entry(number)),
+        );
+
+        sink.write_all(&ebml)?;
+        sink.write_all(&header(0x1853_8067, UNKNOWN_SIZE))?;
+        sink.write_all(&info)?;
+        sink.write_all(&master(0x1654_ae6b, &tracks))?;
+
+        Ok(Writer {
+            sink,
+            framerate,
+            frame_bytes: frame_bytes(format),
+            audio_written: vec![0; audio.len()],
+            audio,
+            frames: 0,
+        })
+    }
+
+    /// Timestamp of frame `n` in milliseconds.
+    fn timestamp(&self, n: usize) -> u64 {
+        (n as f64 * 1000.0 * self.framerate.den as f64 / self.framerate.num as f64).round() as u64
+    }
+
+    /// End of the samples of `audio` played until frame `n`, in bytes.
+    fn audio_until(&self, audio: &Audio, n: usize) -> usize {
+        let sample = (n as f64 * audio.sample_rate as f64 * self.framerate.den as f64
+            / self.framerate.num as f64)
+            .round() as usize;
+        (sample * audio.frame_len()).min(audio.samples.len())
+    }
+
+    /// Writes a cluster at frame `n` holding `planes` as a video block, if
+    /// any, and the audio up to frame `n + 1` or to the end.
+    fn write_cluster(&mut self, n: usize, planes: &[&[u8]], last: bool) -> io::Result<()> {
+        let audio_ends: Vec<usize> = self
+            .audio
+            .iter()
+            .map(|audio| match last {
+                true => audio.samples.len(),
+                false => self.audio_until(audio, n + 1),
+            })
+            .collect();
+        let audio_lens: Vec<usize> = audio_ends
+            .iter()
+            .zip(&self.audio_written)
+            .map(|(end, written)| end.saturating_sub(*written))
+            .collect();
+        if planes.is_empty() && audio_lens.iter().all(|len| *len == 0) {
+            return Ok(());
+        }
+        let video_len: usize = planes.iter().map(|plane| plane.len()).sum();
+
+        // Track number, timestamp relative to the cluster and keyframe flag.
+        let block_header = |track| [&vint(track)[..], &[0, 0, 0x80]].concat();
+        let block_len = |len: usize| {
+            let size = (len + 4) as u64;
+            header(0xa3, size).len() as u64 + size
+        };
+
+        let timestamp = uint(0xe7, self.timestamp(n));
+        let mut size = timestamp.len() as u64;
+        if !planes.is_empty() {
+            size += block_len(video_len);
+        }
+        for len in audio_lens.iter().filter(|len| **len > 0) {
+            size += block_len(*len);
+        }
+
+        self.sink.write_all(&header(0x1f43_b675, size))?;
+        self.sink.write_all(&timestamp)?;
+        if !planes.is_empty() {
+            self.sink.write_all(&header(0xa3, (video_len + 4) as u64))?;
+            self.sink.write_all(&block_header(VIDEO_TRACK))?;
+            for plane in planes {
+                self.sink.write_all(plane)?;
+            }
+        }
+        for (i, number) in (0..self.audio.len()).zip(AUDIO_TRACK..) {
+            let (written, end) = (self.audio_written[i], audio_ends[i]);
+            if end > written {
+                self.sink
+                    .write_all(&header(0xa3, (end - written + 4) as u64))?;
+                self.sink.write_all(&block_header(number))?;
+                self.sink.write_all(&self.audio[i].samples[written..end])?;
+                self.audio_written[i] = end;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn write_frame(&mut self, frame: &DecodedFrame) -> io::Result<()> {
+        let len: usize = frame.planes.iter().map(Vec::len).sum();
+        if len != self.frame_bytes {
+            return Err(io::Error::other(format!(
+                "Frame is {} bytes, expected {}",
+                len, self.frame_bytes
+            )));
+        }
+
+        let planes = [&frame.planes[0][..], &frame.planes[1], &frame.planes[2]];
+        self.write_cluster(self.frames, &planes, false)?;
+        self.frames += 1;
+
+        Ok(())
+    }
+
+    /// Writes the audio left after the last frame.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.write_cluster(self.frames, &[], true)?;
+        self.sink.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_ebml_elements() {
+        assert_eq!(vint(1), [0x81]);
+        assert_eq!(vint(126), [0xfe]);
+        // 127 is reserved as an unknown size in one byte.
+        assert_eq!(vint(127), [0x40, 0x7f]);
+        assert_eq!(header(0x1853_8067, UNKNOWN_SIZE), {
+            let mut unknown = vec![0x18, 0x53, 0x80, 0x67, 0x01];
+            unknown.extend([0xff; 7]);
+            unknown
+        });
+        assert_eq!(uint(0xd7, 0), [0xd7, 0x81, 0x00]);
+        assert_eq!(
+            uint(0x2a_d7b1, 1_000_000),
+            [0x2a, 0xd7, 0xb1, 0x83, 0x0f, 0x42, 0x40]
+        );
+    }
+
+    #[test]
+    fn spreads_audio_over_frames() {
+        let format = SegmentFormat {
+            width: 2,
+            height: 2,
+            framerate: Ratio { num: 25, den: 1 },
+            colorspace: Colorspace::Cmono,
+            interlacing: Interlacing::Progressive,
+            color_range: ColorRange::Full,
+            color: None,
+            sar: None,
+        };
+        let audio = Audio {
+            codec: "A_PCM/INT/LIT",
+            sample_rate: 100,
+            channels: 1,
+            bit_depth: 8,
+            samples: (0..10).collect(),
+        };
+        let mut out = Vec::new();
+        let mut writer = Writer::new(&mut out, &format, 2, vec![audio]).unwrap();
+        let frame = DecodedFrame {
+            planes: [vec![9; 4], Vec::new(), Vec::new()],
+            alpha: None,
+        };
+
+        writer.write_frame(&frame).unwrap();
+        assert_eq!(writer.audio_written, [4]);
+        writer.write_frame(&frame).unwrap();
+        assert_eq!(writer.audio_written, [8]);
+        writer.finish().unwrap();
+        assert_eq!(writer.audio_written, [10]);
+        assert!(writer
+            .write_frame(&DecodedFrame {
+                planes: [vec![9; 3], Vec::new(), Vec::new()],
+                alpha: None,
+            })
+            .is_err());
+
+        // The last cluster holds only the two samples left over.
+        assert!(out.ends_with(&[
+            0x1f, 0x43, 0xb6, 0x75, 0x8b, 0xe7, 0x81, 80, 0xa3, 0x86, 0x82, 0, 0, 0x80, 8, 9
+        ]));
+    }
+}
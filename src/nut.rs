@@ -0,0 +1,250 @@
+use crate::mkv::{fourcc, frame_bytes};
+use crate::SegmentFormat;
+use ffms_segmenter::prefetch::DecodedFrame;
+use std::io::{self, Write};
+
+const FILE_ID: &[u8] = b"nut/multimedia container\0";
+
+const MAIN_STARTCODE: u64 = 0x4e4d_7a56_1f5f_04ad;
+const STREAM_STARTCODE: u64 = 0x4e53_1140_5bf2_f9db;
+const SYNCPOINT_STARTCODE: u64 = 0x4e4b_e4ad_eeca_4569;
+
+const FLAG_KEY: u64 = 1;
+const FLAG_CODED_PTS: u64 = 8;
+const FLAG_SIZE_MSB: u64 = 32;
+const FLAG_CHECKSUM: u64 = 64;
+/// What every frame code means: a keyframe of stream 0 carrying its full
+/// PTS, its size and a header checksum, which frames larger than twice
+/// `MAX_DISTANCE` need.
+const FRAME_FLAGS: u64 = FLAG_KEY | FLAG_CODED_PTS | FLAG_SIZE_MSB | FLAG_CHECKSUM;
+
+/// Most bytes between syncpoints, for a reader resyncing after damage.
+const MAX_DISTANCE: u64 = 32768;
+/// Bits of the PTS a coded PTS below `1 << MSB_PTS_SHIFT` would carry.
+/// Every PTS is coded in full, above it.
+const MSB_PTS_SHIFT: u64 = 7;
+
+/// The `v` coding of NUT: 7 bits a byte, most significant first, with the
+/// top bit set on every byte but the last.
+fn v(value: u64) -> Vec<u8> {
+    let len = (1..10).find(|len| value >> (7 * len) == 0).unwrap_or(10);
+    (0..len)
+        .rev()
+        .map(|i| {
+            let byte = (value >> (7 * i)) as u8 & 0x7f;
+            if i > 0 {
+                byte | 0x80
+            } else {
+                byte
+            }
+        })
+        .collect()
+}
+
+/// The `s` coding: signed values interleaved onto `v`, 0, 1, -1, 2, ...
+fn s(value: i64) -> Vec<u8> {
+    match value {
+        value if value > 0 => v(2 * value as u64 - 1),
+        value => v(2 * value.unsigned_abs()),
+    }
+}
+
+/// The `vb` coding: a length and the bytes.
+fn vb(bytes: &[u8]) -> Vec<u8> {
+    let mut out = v(bytes.len() as u64);
+    out.extend_from_slice(bytes);
+    out
+}
+
+/// CRC-32 with the generator 0x04C11DB7, most significant bit first,
+/// starting from 0 and not inverted, as NUT checksums are.
+fn crc(data: &[u8]) -> u32 {
+    let mut crc = 0u32;
+    for &byte in data {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = match crc & 0x8000_0000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x04c1_1db7,
+            };
+        }
+    }
+    crc
+}
+
+/// A packet with `startcode` around `body`: its size, a checksum of the
+/// header once the size passes 4096 bytes, and a checksum of the body.
+fn packet(startcode: u64, body: &[u8]) -> Vec<u8> {
+    let mut out = startcode.to_be_bytes().to_vec();
+    let forward_ptr = body.len() as u64 + 4;
+    out.extend(v(forward_ptr));
+    if forward_ptr > 4096 {
+        let header_checksum = crc(&out);
+        out.extend(header_checksum.to_be_bytes());
+    }
+    out.extend_from_slice(body);
+    out.extend(crc(body).to_be_bytes());
+    out
+}
+
+pub fn gcd(a: usize, b: usize) -> usize {
+    match b {
+        0 => a,
+        b => gcd(b, a % b),
+    }
+}
+
+/// The main header: one stream and time base, and the frame code table,
+/// every code but 'N' standing for `FRAME_FLAGS`.
+fn main_header(format: &SegmentFormat) -> Vec<u8> {
+    let framerate = format.framerate;
+    let divisor = gcd(framerate.num, framerate.den).max(1);
+
+    let mut body = Vec::new();
+    body.extend(v(3));
+    body.extend(v(1));
+    body.extend(v(MAX_DISTANCE));
+    body.extend(v(1));
+    body.extend(v((framerate.den / divisor) as u64));
+    body.extend(v((framerate.num / divisor) as u64));
+    // Flags, then 6 fields: PTS delta, size multiplier, stream, size LSB,
+    // reserved count and the number of codes meaning the same.
+    body.extend(v(FRAME_FLAGS));
+    body.extend(v(6));
+    body.extend(s(0));
+    body.extend(v(1));
+    body.extend(v(0));
+    body.extend(v(0));
+    body.extend(v(0));
+    body.extend(v(255));
+    // No elision headers beyond the empty one.
+    body.extend(v(0));
+
+    packet(MAIN_STARTCODE, &body)
+}
+
+fn stream_header(format: &SegmentFormat) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend(v(0));
+    // Video.
+    body.extend(v(0));
+    body.extend(vb(&fourcc(format.colorspace)));
+    body.extend(v(0));
+    body.extend(v(MSB_PTS_SHIFT));
+    body.extend(v(1));
+    body.extend(v(0));
+    // FLAG_FIXED_FPS.
+    body.extend(v(1));
+    body.extend(vb(&[]));
+    body.extend(v(format.width as u64));
+    body.extend(v(format.height as u64));
+    // Unknown sample aspect ratio and colorspace.
+    body.extend(v(0));
+    body.extend(v(0));
+    body.extend(v(0));
+
+    packet(STREAM_STARTCODE, &body)
+}
+
+/// `--output-format nut` writer: uncompressed frames in NUT, which FFmpeg
+/// reads with their pixel format and timestamps, high bit depths
+/// included. Each frame comes after a syncpoint of its own.
+pub struct Writer<W: Write> {
+    sink: W,
+    frame_bytes: usize,
+    /// Frames written so far, the PTS of the next one.
+    frames: u64,
+    /// Bytes written so far.
+    position: u64,
+    /// Where the last syncpoint started.
+    last_syncpoint: Option<u64>,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(mut sink: W, format: &SegmentFormat) -> io::Result<Self> {
+        let headers = [FILE_ID.to_vec(), main_header(format), stream_header(format)].concat();
+        sink.write_all(&headers)?;
+
+        Ok(Writer {
+            sink,
+            frame_bytes: frame_bytes(format),
+            frames: 0,
+            position: headers.len() as u64,
+            last_syncpoint: None,
+        })
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.sink.write_all(bytes)?;
+        self.position += bytes.len() as u64;
+        Ok(())
+    }
+
+    pub fn write_frame(&mut self, frame: &DecodedFrame) -> io::Result<()> {
+        let len: usize = frame.planes.iter().map(Vec::len).sum();
+        if len != self.frame_bytes {
+            return Err(io::Error::other(format!(
+                "Frame is {} bytes, expected {}",
+                len, self.frame_bytes
+            )));
+        }
+
+        // The global PTS of the frame in the only time base, and the
+        // distance back to the previous syncpoint in 16 byte units.
+        let mut syncpoint = v(self.frames);
+        syncpoint.extend(v(self
+            .last_syncpoint
+            .map_or(0, |last| (self.position - last) / 16)));
+        self.last_syncpoint = Some(self.position);
+        self.write(&packet(SYNCPOINT_STARTCODE, &syncpoint))?;
+
+        let mut header = vec![0];
+        header.extend(v(self.frames + (1 << MSB_PTS_SHIFT)));
+        header.extend(v(len as u64));
+        let checksum = crc(&header);
+        header.extend(checksum.to_be_bytes());
+        self.write(&header)?;
+        for plane in &frame.planes {
+            self.write(plane)?;
+        }
+        self.frames += 1;
+
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.sink.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_numbers() {
+        assert_eq!(v(0), [0]);
+        assert_eq!(v(127), [0x7f]);
+        assert_eq!(v(128), [0x81, 0x00]);
+        assert_eq!(v(32768), [0x82, 0x80, 0x00]);
+        assert_eq!(s(0), [0]);
+        assert_eq!(s(1), [1]);
+        assert_eq!(s(-1), [2]);
+        // CRC-32/POSIX without its final inversion.
+        assert_eq!(crc(b"123456789"), !0x765e_7680);
+    }
+
+    #[test]
+    fn checksums_packets() {
+        let body = [1, 2, 3];
+        let out = packet(SYNCPOINT_STARTCODE, &body);
+        assert_eq!(&out[..8], SYNCPOINT_STARTCODE.to_be_bytes());
+        assert_eq!(out[8], 7);
+        assert_eq!(&out[9..12], body);
+        // A checksum over the body and itself comes out 0.
+        assert_eq!(crc(&out[9..]), 0);
+
+        let out = packet(SYNCPOINT_STARTCODE, &[0; 5000]);
+        assert_eq!(crc(&out[..14]), 0);
+    }
+}
@@ -0,0 +1,950 @@
+use ffms_segmenter::log;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// Size of the chunks handed to a background writer.
+const BACKGROUND_CHUNK: usize = 1 << 20;
+
+/// Bytes buffered before segment files are written to, unless
+/// `Folder::buffered` says otherwise.
+const FILE_BUFFER: usize = 1 << 20;
+
+/// Where a segment at `path` is written until it is finished, so nothing
+/// watching the folder sees it half written.
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+/// Bytes an unprivileged process can still write to the filesystem holding
+/// `folder`.
+#[cfg(unix)]
+pub fn free_space(folder: &Path) -> io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(folder.as_os_str().as_bytes())?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+/// Unknown off Unix, where nothing is checked.
+#[cfg(not(unix))]
+pub fn free_space(_folder: &Path) -> io::Result<u64> {
+    Ok(u64::MAX)
+}
+
+/// Where segment files are written.
+pub trait Output {
+    /// Starts writing the segment called `name`.
+    fn create(&self, name: &str) -> io::Result<Box<dyn Upload>>;
+}
+
+/// A segment being written.
+pub trait Upload: Write + Send {
+    /// Completes the segment, returning where it can be read from.
+    fn finish(self: Box<Self>) -> io::Result<String>;
+}
+
+/// Writes segments to a local folder.
+pub struct Folder {
+    path: PathBuf,
+    buffer: usize,
+}
+
+impl Folder {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Folder {
+            path: path.into(),
+            buffer: FILE_BUFFER,
+        }
+    }
+
+    /// Collects `bytes` of a segment before each write to its file, so the
+    /// many small header and plane writes of a frame do not each go to the
+    /// filesystem. 0 writes them as they come.
+    pub fn buffered(mut self, bytes: usize) -> Self {
+        self.buffer = bytes;
+        self
+    }
+}
+
+struct FileUpload {
+    file: BufWriter<File>,
+    path: PathBuf,
+    part: PathBuf,
+    finished: bool,
+}
+
+impl Output for Folder {
+    fn create(&self, name: &str) -> io::Result<Box<dyn Upload>> {
+        let path = self.path.join(name);
+        let part = part_path(&path);
+
+        Ok(Box::new(FileUpload {
+            file: BufWriter::with_capacity(self.buffer, File::create(&part)?),
+            path,
+            part,
+            finished: false,
+        }))
+    }
+}
+
+impl Write for FileUpload {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        self.file.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Upload for FileUpload {
+    fn finish(mut self: Box<Self>) -> io::Result<String> {
+        self.file.flush()?;
+        fs::rename(&self.part, &self.path)?;
+        self.finished = true;
+
+        Ok(self.path.to_string_lossy().into_owned())
+    }
+}
+
+impl Drop for FileUpload {
+    /// A segment abandoned after an error or Ctrl-C is removed rather than
+    /// left truncated.
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = fs::remove_file(&self.part);
+        }
+    }
+}
+
+/// Writes a segment into a memory mapping of its file, preallocated to the
+/// expected size, instead of a `write` call per frame. The mapping grows if
+/// the segment turns out larger, and the file is cut to what was written
+/// when it finishes.
+#[cfg(unix)]
+pub struct MappedFile {
+    file: File,
+    path: PathBuf,
+    part: PathBuf,
+    map: *mut u8,
+    capacity: usize,
+    len: usize,
+    finished: bool,
+}
+
+// Nothing else holds the mapping.
+#[cfg(unix)]
+unsafe impl Send for MappedFile {}
+
+#[cfg(unix)]
+impl MappedFile {
+    pub fn create(path: impl Into<PathBuf>, size_hint: u64) -> io::Result<Box<dyn Upload>> {
+        let path = path.into();
+        let part = part_path(&path);
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&part)?;
+
+        let mut mapped = MappedFile {
+            file,
+            path,
+            part,
+            map: std::ptr::null_mut(),
+            capacity: 0,
+            len: 0,
+            finished: false,
+        };
+        mapped.map(size_hint.max(1 << 16) as usize)?;
+
+        Ok(Box::new(mapped))
+    }
+
+    /// Replaces the mapping with one of `capacity` bytes.
+    fn map(&mut self, capacity: usize) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        self.unmap();
+        self.file.set_len(capacity as u64)?;
+        #[cfg(target_os = "linux")]
+        match unsafe { libc::posix_fallocate(self.file.as_raw_fd(), 0, capacity as libc::off_t) } {
+            0 => {}
+            err => return Err(io::Error::from_raw_os_error(err)),
+        }
+
+        let map = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                capacity,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                self.file.as_raw_fd(),
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.map = map as *mut u8;
+        self.capacity = capacity;
+        Ok(())
+    }
+
+    fn unmap(&mut self) {
+        if !self.map.is_null() {
+            unsafe { libc::munmap(self.map as *mut libc::c_void, self.capacity) };
+            self.map = std::ptr::null_mut();
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub struct MappedFile;
+
+#[cfg(not(unix))]
+impl MappedFile {
+    pub fn create(_path: impl Into<PathBuf>, _size_hint: u64) -> io::Result<Box<dyn Upload>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "--mmap needs a Unix platform",
+        ))
+    }
+}
+
+#[cfg(unix)]
+impl Write for MappedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.len + buf.len() > self.capacity {
+            self.map((self.capacity * 2).max(self.len + buf.len()))?;
+        }
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(buf.as_ptr(), self.map.add(self.len), buf.len());
+        }
+        self.len += buf.len();
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl Upload for MappedFile {
+    fn finish(mut self: Box<Self>) -> io::Result<String> {
+        self.unmap();
+        self.file.set_len(self.len as u64)?;
+        fs::rename(&self.part, &self.path)?;
+        self.finished = true;
+
+        Ok(self.path.to_string_lossy().into_owned())
+    }
+}
+
+#[cfg(unix)]
+impl Drop for MappedFile {
+    /// Removed when abandoned, like a `FileUpload`.
+    fn drop(&mut self) {
+        self.unmap();
+        if !self.finished {
+            let _ = fs::remove_file(&self.part);
+        }
+    }
+}
+
+enum Chunk {
+    Data(Vec<u8>),
+    Finish,
+}
+
+/// Hands the writes of an upload to a thread of its own, so a slow disk or
+/// network holds up the decoder only once `capacity` chunks of up to
+/// `BACKGROUND_CHUNK` bytes are queued.
+pub struct Background {
+    sender: Option<SyncSender<Chunk>>,
+    writer: Option<JoinHandle<io::Result<String>>>,
+    pending: Vec<u8>,
+}
+
+impl Background {
+    pub fn wrap(upload: Box<dyn Upload>, capacity: usize) -> Box<dyn Upload> {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+
+        Box::new(Background {
+            sender: Some(sender),
+            writer: Some(thread::spawn(move || Self::run(upload, receiver))),
+            pending: Vec::with_capacity(BACKGROUND_CHUNK),
+        })
+    }
+
+    /// An upload whose chunks stop without a `Finish` is dropped rather
+    /// than finished, which removes it like any abandoned upload.
+    fn run(mut upload: Box<dyn Upload>, receiver: Receiver<Chunk>) -> io::Result<String> {
+        for chunk in receiver {
+            match chunk {
+                Chunk::Data(data) => upload.write_all(&data)?,
+                Chunk::Finish => return upload.finish(),
+            }
+        }
+
+        Err(io::Error::other("Segment abandoned"))
+    }
+
+    fn send(&mut self, chunk: Chunk) -> io::Result<()> {
+        match self.sender.as_ref().unwrap().send(chunk) {
+            Ok(()) => Ok(()),
+            // The writer only hangs up after failing.
+            Err(_) => Err(self
+                .join()
+                .err()
+                .unwrap_or_else(|| io::Error::other("Background writer stopped"))),
+        }
+    }
+
+    fn join(&mut self) -> io::Result<String> {
+        self.sender = None;
+        match self.writer.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(io::Error::other("Background writer panicked")),
+            None => Err(io::Error::other("Background writer stopped")),
+        }
+    }
+}
+
+impl Write for Background {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_vectored(&[io::IoSlice::new(buf)])
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        for buf in bufs {
+            self.pending.extend_from_slice(buf);
+        }
+        if self.pending.len() >= BACKGROUND_CHUNK {
+            let data = std::mem::replace(&mut self.pending, Vec::with_capacity(BACKGROUND_CHUNK));
+            self.send(Chunk::Data(data))?;
+        }
+
+        Ok(bufs.iter().map(|buf| buf.len()).sum())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Upload for Background {
+    fn finish(mut self: Box<Self>) -> io::Result<String> {
+        if !self.pending.is_empty() {
+            let data = std::mem::take(&mut self.pending);
+            self.send(Chunk::Data(data))?;
+        }
+        self.send(Chunk::Finish)?;
+
+        self.join()
+    }
+}
+
+impl Drop for Background {
+    /// Waits for an abandoned upload to be removed.
+    fn drop(&mut self) {
+        if self.writer.is_some() {
+            let _ = self.join();
+        }
+    }
+}
+
+/// Pipes each segment into a child encoder instead of writing it.
+pub struct Exec {
+    template: String,
+    folder: PathBuf,
+}
+
+impl Exec {
+    /// `template` is run by the shell with `{out}` replaced by the segment's
+    /// path in `folder` without extension, and `{start}` and `{end}` by its
+    /// frame range.
+    pub fn new(template: &str, folder: impl Into<PathBuf>) -> Self {
+        Exec {
+            template: template.to_string(),
+            folder: folder.into(),
+        }
+    }
+}
+
+struct ExecUpload {
+    command: String,
+    child: Option<Child>,
+    stdin: Option<ChildStdin>,
+    out: String,
+}
+
+pub fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.args(["/C", command]);
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.args(["-c", command]);
+        shell
+    }
+}
+
+impl Output for Exec {
+    fn create(&self, name: &str) -> io::Result<Box<dyn Upload>> {
+        let stem = name.strip_suffix(".y4m").unwrap_or(name);
+        let out = self.folder.join(stem).to_string_lossy().into_owned();
+
+        // Names are `[<input>-]<start>-<end>`.
+        let mut range = stem.rsplit('-');
+        let end = range.next().unwrap_or_default();
+        let start = range.next().unwrap_or_default();
+
+        let command = self
+            .template
+            .replace("{out}", &out)
+            .replace("{start}", start)
+            .replace("{end}", end);
+
+        let mut child = shell(&command)
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|err| io::Error::other(format!("Failed to run `{}`: {}", command, err)))?;
+
+        Ok(Box::new(ExecUpload {
+            command,
+            stdin: child.stdin.take(),
+            child: Some(child),
+            out,
+        }))
+    }
+}
+
+impl Write for ExecUpload {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdin.as_mut().unwrap().write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        self.stdin.as_mut().unwrap().write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdin.as_mut().unwrap().flush()
+    }
+}
+
+impl Upload for ExecUpload {
+    fn finish(mut self: Box<Self>) -> io::Result<String> {
+        // Closing stdin signals the end of the stream.
+        drop(self.stdin.take());
+        let status = self.child.take().unwrap().wait()?;
+
+        log!(Info, "Encoder for {} exited with {}", self.out, status);
+
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "`{}` failed with {}",
+                self.command, status
+            )));
+        }
+
+        Ok(self.out.clone())
+    }
+}
+
+impl Drop for ExecUpload {
+    /// A segment abandoned after an error must not leave the encoder
+    /// waiting on its input.
+    fn drop(&mut self) {
+        drop(self.stdin.take());
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+#[cfg(feature = "cloud")]
+mod bucket {
+    use super::{Output, Upload};
+    use object_store::path::Path;
+    use object_store::{ObjectStore, WriteMultipart};
+    use std::io::{self, Write};
+    use std::sync::Arc;
+    use tokio::runtime::Runtime;
+
+    /// Parts are uploaded as they fill; writing waits once this many are in
+    /// flight, which bounds the memory held per segment.
+    const MAX_CONCURRENT_PARTS: usize = 4;
+
+    /// Writes segments to an S3 or GCS bucket with multipart uploads.
+    /// Credentials come from the usual `AWS_*` and `GOOGLE_*` variables.
+    pub struct Bucket {
+        store: Arc<dyn ObjectStore>,
+        prefix: Path,
+        url: String,
+        runtime: Arc<Runtime>,
+    }
+
+    impl Bucket {
+        pub fn open(url: &str) -> io::Result<Self> {
+            let parsed = url::Url::parse(url)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            let options = std::env::vars().map(|(key, value)| (key.to_ascii_lowercase(), value));
+            let (store, prefix) =
+                object_store::parse_url_opts(&parsed, options).map_err(io::Error::other)?;
+
+            Ok(Bucket {
+                store: Arc::from(store),
+                prefix,
+                url: url.trim_end_matches('/').to_string(),
+                runtime: Arc::new(Runtime::new()?),
+            })
+        }
+    }
+
+    struct BucketUpload {
+        writer: WriteMultipart,
+        runtime: Arc<Runtime>,
+        location: String,
+    }
+
+    impl Output for Bucket {
+        fn create(&self, name: &str) -> io::Result<Box<dyn Upload>> {
+            let upload = self
+                .runtime
+                .block_on(self.store.put_multipart(&self.prefix.child(name)))
+                .map_err(io::Error::other)?;
+
+            Ok(Box::new(BucketUpload {
+                writer: WriteMultipart::new(upload),
+                runtime: self.runtime.clone(),
+                location: format!("{}/{}", self.url, name),
+            }))
+        }
+    }
+
+    impl Write for BucketUpload {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.runtime
+                .block_on(self.writer.wait_for_capacity(MAX_CONCURRENT_PARTS))
+                .map_err(io::Error::other)?;
+
+            // Filled parts are spawned onto the runtime.
+            let _guard = self.runtime.enter();
+            self.writer.write(buf);
+
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Upload for BucketUpload {
+        fn finish(self: Box<Self>) -> io::Result<String> {
+            let BucketUpload {
+                writer,
+                runtime,
+                location,
+            } = *self;
+
+            runtime
+                .block_on(writer.finish())
+                .map_err(io::Error::other)?;
+
+            Ok(location)
+        }
+    }
+}
+
+/// zstd compression of segment streams, for `--compress`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    pub level: i32,
+}
+
+/// Parses `zstd` or `zstd:<level>`, with levels from 1 to 22 and 3 by
+/// default.
+pub fn parse_compression(compression: &str) -> Result<Compression, String> {
+    let level = match compression.split_once(':') {
+        None if compression == "zstd" => Some(3),
+        Some(("zstd", level)) => level.parse().ok().filter(|level| (1..=22).contains(level)),
+        _ => None,
+    };
+
+    level
+        .map(|level| Compression { level })
+        .ok_or_else(|| format!("Invalid compression, expected zstd[:1-22]: {}", compression))
+}
+
+#[cfg(feature = "compress")]
+mod compressed {
+    use super::{Compression, Output, Upload};
+    use std::io::{self, Write};
+    use zstd::stream::write::Encoder;
+
+    /// Compresses every segment of another output as one zstd frame while
+    /// it is written.
+    pub struct Compressed {
+        pub output: Box<dyn Output>,
+        pub compression: Compression,
+    }
+
+    struct CompressedUpload {
+        encoder: Encoder<'static, Box<dyn Upload>>,
+    }
+
+    impl Output for Compressed {
+        fn create(&self, name: &str) -> io::Result<Box<dyn Upload>> {
+            let upload = self.output.create(name)?;
+            let mut encoder = Encoder::new(upload, self.compression.level)?;
+            encoder.include_checksum(true)?;
+
+            Ok(Box::new(CompressedUpload { encoder }))
+        }
+    }
+
+    impl Write for CompressedUpload {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.encoder.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.encoder.flush()
+        }
+    }
+
+    impl Upload for CompressedUpload {
+        fn finish(self: Box<Self>) -> io::Result<String> {
+            self.encoder.finish()?.finish()
+        }
+    }
+}
+
+/// Wraps `output` in `compression` if given.
+fn compress(
+    output: Box<dyn Output>,
+    compression: Option<Compression>,
+) -> io::Result<Box<dyn Output>> {
+    match compression {
+        None => Ok(output),
+        #[cfg(feature = "compress")]
+        Some(compression) => Ok(Box::new(compressed::Compressed {
+            output,
+            compression,
+        })),
+        #[cfg(not(feature = "compress"))]
+        Some(_) => Err(io::Error::other(
+            "Built without the compress feature, --compress is unavailable",
+        )),
+    }
+}
+
+/// Writes every segment into one continuous y4m stream, for `--stream`.
+/// The header of the first segment starts the stream and those of the
+/// following ones, which must match it, are left out.
+pub struct Stream {
+    state: Arc<Mutex<StreamState>>,
+}
+
+struct StreamState {
+    file: BufWriter<File>,
+    path: String,
+    header: Option<Vec<u8>>,
+    /// Set when a segment was abandoned halfway, leaving part of it in the
+    /// stream.
+    cut: bool,
+}
+
+impl Stream {
+    /// Opens `path`, which waits for a reader if it is a named pipe.
+    pub fn open(path: &Path, buffer: usize) -> io::Result<Self> {
+        Ok(Stream {
+            state: Arc::new(Mutex::new(StreamState {
+                file: BufWriter::with_capacity(buffer, File::create(path)?),
+                path: path.to_string_lossy().into_owned(),
+                header: None,
+                cut: false,
+            })),
+        })
+    }
+}
+
+struct StreamUpload {
+    state: Arc<Mutex<StreamState>>,
+    /// The segment's header until its newline has been written.
+    header: Vec<u8>,
+    in_header: bool,
+    written: bool,
+    finished: bool,
+}
+
+impl Output for Stream {
+    fn create(&self, _name: &str) -> io::Result<Box<dyn Upload>> {
+        if self.state.lock().unwrap().cut {
+            return Err(io::Error::other(
+                "The stream ends in an abandoned segment, no more can follow",
+            ));
+        }
+
+        Ok(Box::new(StreamUpload {
+            state: self.state.clone(),
+            header: Vec::new(),
+            in_header: true,
+            written: false,
+            finished: false,
+        }))
+    }
+}
+
+impl Write for StreamUpload {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if !self.in_header {
+            self.written = true;
+            return state.file.write(buf);
+        }
+
+        let Some(newline) = buf.iter().position(|&byte| byte == b'\n') else {
+            self.header.extend_from_slice(buf);
+            return Ok(buf.len());
+        };
+        self.header.extend_from_slice(&buf[..=newline]);
+        self.in_header = false;
+
+        match state.header {
+            None => {
+                state.file.write_all(&self.header)?;
+                state.header = Some(self.header.clone());
+            }
+            Some(ref header) if *header == self.header => {}
+            Some(ref header) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Segment header `{}` does not match the stream's `{}`",
+                        String::from_utf8_lossy(&self.header).trim_end(),
+                        String::from_utf8_lossy(header).trim_end()
+                    ),
+                ))
+            }
+        }
+        Ok(newline + 1)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.state.lock().unwrap().file.flush()
+    }
+}
+
+impl Upload for StreamUpload {
+    /// Flushes the segment so the reader has all of it.
+    fn finish(mut self: Box<Self>) -> io::Result<String> {
+        let mut state = self.state.lock().unwrap();
+        state.file.flush()?;
+        self.finished = true;
+
+        Ok(state.path.clone())
+    }
+}
+
+impl Drop for StreamUpload {
+    fn drop(&mut self) {
+        if self.written && !self.finished {
+            self.state.lock().unwrap().cut = true;
+        }
+    }
+}
+
+/// Opens the `stream`, `url` (`s3://bucket/prefix` or `gs://bucket/prefix`)
+/// or the `exec` encoder command if given, otherwise the local `folder`,
+/// written `buffer` bytes at a time, with segments compressed by
+/// `compression` unless they are streamed.
+pub fn open(
+    url: Option<&str>,
+    exec: Option<&str>,
+    stream: Option<&Path>,
+    folder: &Path,
+    buffer: usize,
+    compression: Option<Compression>,
+) -> io::Result<Box<dyn Output>> {
+    if let Some(path) = stream {
+        return Ok(Box::new(Stream::open(path, buffer)?));
+    }
+
+    let output: Box<dyn Output> = match (url, exec) {
+        (Some(_), Some(_)) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--output-url and --exec cannot be combined",
+            ))
+        }
+        #[cfg(feature = "cloud")]
+        (Some(url), None) => Box::new(bucket::Bucket::open(url)?),
+        #[cfg(not(feature = "cloud"))]
+        (Some(_), None) => {
+            return Err(io::Error::other(
+                "Built without the cloud feature, --output-url is unavailable",
+            ))
+        }
+        (None, Some(exec)) => Box::new(Exec::new(exec, folder)),
+        (None, None) => Box::new(Folder::new(folder).buffered(buffer)),
+    };
+
+    compress(output, compression)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exec_reports_exit_status() {
+        let exec = Exec::new("test {start}-{end} = 24-48 && cat > /dev/null", "out");
+        let mut upload = exec.create("1-24-48.y4m").unwrap();
+        upload.write_all(b"YUV4MPEG2").unwrap();
+        assert_eq!(upload.finish().unwrap(), "out/1-24-48");
+
+        let failing = Exec::new("cat > /dev/null; exit 3", "out");
+        let upload = failing.create("0-10.y4m").unwrap();
+        assert!(upload.finish().is_err());
+    }
+
+    #[test]
+    fn streams_segments_under_one_header() {
+        let path =
+            std::env::temp_dir().join(format!("ffms-segmenter-stream-{}.y4m", std::process::id()));
+        let stream = Stream::open(&path, 0).unwrap();
+
+        for frames in [&b"FRAME\nab"[..], b"FRAME\ncdFRAME\nef"] {
+            let mut upload = stream.create("0-1.y4m").unwrap();
+            upload.write_all(b"YUV4MPEG2 W2 H1 ").unwrap();
+            upload.write_all(b"F24:1 Cmono\n").unwrap();
+            upload.write_all(frames).unwrap();
+            assert_eq!(upload.finish().unwrap(), path.to_string_lossy());
+        }
+
+        let mut upload = stream.create("2-3.y4m").unwrap();
+        assert!(upload.write_all(b"YUV4MPEG2 W4 H1 F24:1 Cmono\n").is_err());
+        drop(upload);
+
+        let mut upload = stream.create("2-3.y4m").unwrap();
+        upload
+            .write_all(b"YUV4MPEG2 W2 H1 F24:1 Cmono\nFRA")
+            .unwrap();
+        drop(upload);
+        assert!(stream.create("3-4.y4m").is_err());
+
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            b"YUV4MPEG2 W2 H1 F24:1 Cmono\nFRAME\nabFRAME\ncdFRAME\nefFRA"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn parses_compression_levels() {
+        assert_eq!(parse_compression("zstd"), Ok(Compression { level: 3 }));
+        assert_eq!(parse_compression("zstd:19"), Ok(Compression { level: 19 }));
+        assert!(parse_compression("zstd:0").is_err());
+        assert!(parse_compression("gzip").is_err());
+    }
+
+    #[test]
+    fn reports_free_space() {
+        assert!(free_space(&std::env::temp_dir()).unwrap() > 0);
+        assert!(free_space(Path::new("/nonexistent/folder")).is_err());
+    }
+
+    #[test]
+    fn abandoned_files_are_removed() {
+        let folder = Folder::new(std::env::temp_dir());
+        let path = std::env::temp_dir().join(format!("{}-abandoned.y4m", std::process::id()));
+        let name = path.file_name().unwrap().to_str().unwrap();
+
+        let mut upload = folder.create(name).unwrap();
+        upload.write_all(b"YUV4MPEG2").unwrap();
+        assert!(part_path(&path).exists() && !path.exists());
+        drop(upload);
+        assert!(!part_path(&path).exists() && !path.exists());
+
+        let upload = folder.create(name).unwrap();
+        upload.finish().unwrap();
+        assert!(path.exists() && !part_path(&path).exists());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn background_writes_reach_the_upload() {
+        let folder = Folder::new(std::env::temp_dir());
+        let path = std::env::temp_dir().join(format!("{}-background.y4m", std::process::id()));
+        let name = path.file_name().unwrap().to_str().unwrap();
+
+        let mut upload = Background::wrap(folder.create(name).unwrap(), 1);
+        let frame = vec![3; BACKGROUND_CHUNK + 10];
+        upload.write_all(&frame).unwrap();
+        upload.write_all(&frame).unwrap();
+        assert_eq!(upload.finish().unwrap(), path.to_string_lossy());
+        let frame_len = frame.len() as u64;
+        assert_eq!(fs::read(&path).unwrap(), [frame.clone(), frame].concat());
+
+        // An abandoned rewrite leaves the finished file as it was.
+        let mut upload = Background::wrap(folder.create(name).unwrap(), 1);
+        upload.write_all(b"YUV4MPEG2").unwrap();
+        drop(upload);
+        assert_eq!(fs::metadata(&path).unwrap().len(), frame_len * 2);
+        assert!(!part_path(&path).exists());
+        fs::remove_file(&path).unwrap();
+
+        let failing = Exec::new("exit 1", "out");
+        let mut upload = Background::wrap(failing.create("0-10.y4m").unwrap(), 1);
+        let _ = upload.write_all(&vec![0; BACKGROUND_CHUNK * 4]);
+        assert!(upload.finish().is_err());
+    }
+
+    #[test]
+    fn mapped_files_grow_and_are_cut_to_size() {
+        let path = std::env::temp_dir().join(format!("{}-mapped.y4m", std::process::id()));
+
+        let mut upload = MappedFile::create(&path, 4).unwrap();
+        let frame = vec![7; 50_000];
+        upload.write_all(b"YUV4MPEG2\n").unwrap();
+        upload.write_all(&frame).unwrap();
+        upload.write_all(&frame).unwrap();
+        upload.finish().unwrap();
+
+        let written = fs::read(&path).unwrap();
+        assert_eq!(written.len(), 100_010);
+        assert_eq!(&written[..10], b"YUV4MPEG2\n");
+        assert!(written[10..].iter().all(|&byte| byte == 7));
+        fs::remove_file(&path).unwrap();
+
+        drop(MappedFile::create(&path, 0).unwrap());
+        assert!(!path.exists());
+    }
+}
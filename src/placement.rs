@@ -0,0 +1,62 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The sorted, distinct segment starts of a plan, where a full file encode
+/// needs its keyframes to cut the same way as the segments.
+pub fn keyframes(starts: impl IntoIterator<Item = usize>) -> Vec<usize> {
+    let mut keyframes: Vec<usize> = starts.into_iter().collect();
+    keyframes.sort_unstable();
+    keyframes.dedup();
+    keyframes
+}
+
+/// An x264 or x265 qpfile forcing an IDR frame at each of `keyframes`,
+/// leaving the quantizer to the encoder.
+pub fn qpfile(keyframes: &[usize]) -> String {
+    keyframes
+        .iter()
+        .map(|frame| format!("{} I -1\n", frame))
+        .collect()
+}
+
+/// An ffmpeg `-force_key_frames` expression matching `keyframes` by frame
+/// number, so it holds for variable frame rate inputs too.
+pub fn force_key_frames(keyframes: &[usize]) -> String {
+    let terms: Vec<String> = keyframes
+        .iter()
+        .map(|frame| format!("eq(n,{})", frame))
+        .collect();
+    format!("expr:{}\n", terms.join("+"))
+}
+
+/// Writes the `--qpfile` and `--force-key-frames` files that were asked for.
+pub fn write(
+    qpfile_path: Option<&Path>,
+    expression_path: Option<&Path>,
+    keyframes: &[usize],
+) -> io::Result<()> {
+    if let Some(path) = qpfile_path {
+        fs::write(path, qpfile(keyframes))?;
+    }
+    if let Some(path) = expression_path {
+        fs::write(path, force_key_frames(keyframes))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn places_keyframes_at_segment_starts() {
+        let keyframes = keyframes([96, 0, 240, 96]);
+        assert_eq!(keyframes, [0, 96, 240]);
+        assert_eq!(qpfile(&keyframes), "0 I -1\n96 I -1\n240 I -1\n");
+        assert_eq!(
+            force_key_frames(&keyframes),
+            "expr:eq(n,0)+eq(n,96)+eq(n,240)\n"
+        );
+    }
+}
@@ -0,0 +1,118 @@
+use ffms_segmenter::checksum::Crc32;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+const SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+/// Largest payload of a stored deflate block.
+const MAX_STORED_BLOCK: usize = 65535;
+
+fn write_chunk(out: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let mut crc = Crc32::new();
+    crc.update(kind);
+    crc.update(data);
+
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    out.write_all(&crc.finish().to_be_bytes())
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+/// Wraps `data` in a zlib stream of uncompressed blocks. Thumbnails are
+/// small and written once, so compression is not worth a dependency.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let blocks = data.len().div_ceil(MAX_STORED_BLOCK).max(1);
+    let mut out = Vec::with_capacity(data.len() + blocks * 5 + 6);
+    out.extend_from_slice(&[0x78, 0x01]);
+
+    let mut chunks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if chunks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(chunk) = chunks.next() {
+        let last = chunks.peek().is_none();
+        let length = chunk.len() as u16;
+
+        out.push(last as u8);
+        out.extend_from_slice(&length.to_le_bytes());
+        out.extend_from_slice(&(!length).to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+/// Encodes 8-bit RGB pixels, `width * height * 3` bytes in row order.
+pub fn encode_rgb(out: &mut impl Write, width: usize, height: usize, rgb: &[u8]) -> io::Result<()> {
+    if rgb.len() != width * height * 3 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Expected {} bytes of RGB, got {}",
+                width * height * 3,
+                rgb.len()
+            ),
+        ));
+    }
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8-bit truecolor, deflate, adaptive filtering, no interlace.
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    // Every scanline uses filter type 0 (none).
+    let mut scanlines = Vec::with_capacity(height * (width * 3 + 1));
+    for row in rgb.chunks(width * 3) {
+        scanlines.push(0);
+        scanlines.extend_from_slice(row);
+    }
+
+    out.write_all(SIGNATURE)?;
+    write_chunk(out, b"IHDR", &header)?;
+    write_chunk(out, b"IDAT", &zlib_stored(&scanlines))?;
+    write_chunk(out, b"IEND", &[])
+}
+
+pub fn write_rgb(path: &Path, width: usize, height: usize, rgb: &[u8]) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    encode_rgb(&mut out, width, height, rgb)?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_single_pixel() {
+        let mut out = Vec::new();
+        encode_rgb(&mut out, 1, 1, &[255, 0, 0]).unwrap();
+
+        assert_eq!(&out[..8], SIGNATURE);
+        assert_eq!(&out[12..16], b"IHDR");
+        // IHDR CRC of a 1x1 8-bit RGB image.
+        assert_eq!(out[29..33], [0x90, 0x77, 0x53, 0xde]);
+        assert_eq!(&out[out.len() - 8..out.len() - 4], b"IEND");
+    }
+
+    #[test]
+    fn adler32_matches_zlib() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+    }
+}
@@ -0,0 +1,69 @@
+/// Spare decoders of one input for `--source-pool`, each left where its
+/// last segment ended, so requests jumping around the timeline don't keep
+/// seeking a single decoder back and forth.
+pub struct Pool<S> {
+    spares: Vec<(S, usize)>,
+    /// Frame the active decoder stopped at.
+    position: usize,
+}
+
+impl<S> Pool<S> {
+    pub fn new(spares: Vec<S>) -> Self {
+        Pool {
+            spares: spares.into_iter().map(|spare| (spare, 0)).collect(),
+            position: 0,
+        }
+    }
+
+    /// Swaps `active` for the decoder closest to `start`. Decoders at or
+    /// before `start` come first, as they reach it by decoding forward.
+    pub fn route(&mut self, active: &mut S, start: usize) {
+        let cost = |position: usize| (position > start, position.abs_diff(start));
+
+        let best = self
+            .spares
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, (_, position))| cost(*position))
+            .filter(|(_, (_, position))| cost(*position) < cost(self.position))
+            .map(|(i, _)| i);
+
+        if let Some(i) = best {
+            let (spare, position) = &mut self.spares[i];
+            std::mem::swap(active, spare);
+            std::mem::swap(&mut self.position, position);
+        }
+    }
+
+    /// Records where the active decoder stopped.
+    pub fn finished(&mut self, end: usize) {
+        self.position = end;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_to_the_nearest_earlier_decoder() {
+        let mut pool = Pool::new(vec!["b", "c"]);
+        let mut active = "a";
+
+        pool.route(&mut active, 5000);
+        assert_eq!(active, "a");
+        pool.finished(5240);
+
+        // Going back takes a spare rather than seeking `a`.
+        pool.route(&mut active, 100);
+        assert_eq!(active, "b");
+        pool.finished(340);
+
+        pool.route(&mut active, 5240);
+        assert_eq!(active, "a");
+        pool.finished(5480);
+
+        pool.route(&mut active, 400);
+        assert_eq!(active, "b");
+    }
+}
@@ -0,0 +1,467 @@
+use crate::failure::{self, Failure};
+use ffms2::frame::Frame;
+use ffms2::track::Track;
+use ffms2::video::VideoSource;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Mutex, OnceLock};
+use std::thread::{self, Scope, ScopedJoinHandle};
+use std::time::{Duration, Instant};
+
+/// A decoded frame whose planes have been copied out of the FFMS2 frame
+/// buffer, so it stays valid after the source decodes the next frame.
+#[derive(Clone)]
+pub struct DecodedFrame {
+    pub planes: [Vec<u8>; 3],
+    /// Alpha plane, the size of the luma plane, for sources that have one.
+    pub alpha: Option<Vec<u8>>,
+}
+
+/// Bytes per sample and the horizontal and vertical chroma subsampling
+/// shifts of a pixel format.
+pub type Layout = (usize, u32, u32);
+
+/// Layouts of the pixel formats frames are written in.
+pub(crate) const LAYOUTS: [(&str, usize, u32, u32); 14] = [
+    ("yuv420p", 1, 1, 1),
+    ("yuv422p", 1, 1, 0),
+    ("yuv444p", 1, 0, 0),
+    ("yuv420p10le", 2, 1, 1),
+    ("yuv422p10le", 2, 1, 0),
+    ("yuv444p10le", 2, 0, 0),
+    ("yuv420p12le", 2, 1, 1),
+    ("yuv422p12le", 2, 1, 0),
+    ("yuv444p12le", 2, 0, 0),
+    ("yuva420p", 1, 1, 1),
+    ("yuva422p", 1, 1, 0),
+    ("gbrp", 1, 0, 0),
+    ("gray", 1, 0, 0),
+    ("gray10le", 2, 0, 0),
+];
+
+fn layout(pixel_format: i32) -> Option<Layout> {
+    static FORMATS: OnceLock<Vec<(i32, Layout)>> = OnceLock::new();
+
+    FORMATS
+        .get_or_init(|| {
+            LAYOUTS
+                .iter()
+                .map(|&(name, bytes, x, y)| (Frame::GetPixFmt(name), (bytes, x, y)))
+                .collect()
+        })
+        .iter()
+        .find(|(format, _)| *format == pixel_format)
+        .map(|&(_, layout)| layout)
+}
+
+/// The rows of `row_bytes` bytes that start every `stride` bytes of
+/// `plane`, without the padding decoders leave at the end of rows.
+fn pack_rows(plane: &[u8], stride: usize, row_bytes: usize) -> Vec<u8> {
+    if stride == row_bytes {
+        return plane.to_vec();
+    }
+
+    let mut packed = Vec::with_capacity(plane.len() / stride.max(1) * row_bytes + row_bytes);
+    for row in plane.chunks(stride) {
+        packed.extend_from_slice(&row[..row_bytes.min(row.len())]);
+    }
+    packed
+}
+
+/// Bytes per row and rows of plane `i` of a `width`x`height` frame.
+fn extent(
+    i: usize,
+    width: usize,
+    height: usize,
+    (bytes, x_shift, y_shift): Layout,
+) -> (usize, usize) {
+    let (columns, rows) = match i {
+        1 | 2 => (
+            (width + (1 << x_shift) - 1) >> x_shift,
+            (height + (1 << y_shift) - 1) >> y_shift,
+        ),
+        _ => (width, height),
+    };
+    (columns * bytes, rows)
+}
+
+impl DecodedFrame {
+    pub fn from_frame(frame: &mut Frame) -> Self {
+        let resolution = frame.get_frame_resolution();
+        let (width, height) = (resolution.width as usize, resolution.height as usize);
+
+        if let Some(layout) = layout(frame.ConvertedPixelFormat) {
+            let planes = std::array::from_fn(|i| {
+                let stride = usize::try_from(frame.Linesize[i]).ok().filter(|&s| s > 0)?;
+                let (row_bytes, rows) = extent(i, width, height, layout);
+                if rows == 0 || frame.Data[i].is_null() || stride < row_bytes {
+                    return None;
+                }
+                // The last row may end at its samples rather than its stride.
+                let data = unsafe {
+                    std::slice::from_raw_parts(frame.Data[i], stride * (rows - 1) + row_bytes)
+                };
+                Some((data, stride))
+            });
+
+            if let Some(decoded) = DecodedFrame::from_planes(planes, width, height, layout) {
+                return decoded;
+            }
+        }
+
+        // Work around for bug in FFMS2 Rust bindings. Full resolution
+        // chroma, as in gbrp, is sized correctly.
+        if frame.Linesize[1] != frame.Linesize[0] {
+            frame.Linesize[1] /= 2;
+            frame.Linesize[2] /= 2;
+        }
+
+        let pixel_data: Vec<Option<&[u8]>> = frame.get_pixel_data();
+
+        DecodedFrame {
+            planes: [
+                pixel_data[0].unwrap().to_vec(),
+                // Monochrome frames have no chroma planes.
+                pixel_data[1].map_or_else(Vec::new, <[u8]>::to_vec),
+                pixel_data[2].map_or_else(Vec::new, <[u8]>::to_vec),
+            ],
+            alpha: pixel_data[3].map(<[u8]>::to_vec),
+        }
+    }
+
+    /// Copies a `width`x`height` frame of `layout` out of its planes, each
+    /// given as its data and the bytes from the start of one row to the
+    /// next, dropping the padding at the end of rows. Missing chroma planes
+    /// are left empty; without a luma plane there is no frame.
+    pub fn from_planes(
+        planes: [Option<(&[u8], usize)>; 4],
+        width: usize,
+        height: usize,
+        layout: Layout,
+    ) -> Option<Self> {
+        let plane = |i: usize| -> Option<Vec<u8>> {
+            let (data, stride) = planes[i]?;
+            let (row_bytes, rows) = extent(i, width, height, layout);
+            if rows == 0 || stride < row_bytes || data.len() < stride * (rows - 1) + row_bytes {
+                return None;
+            }
+            Some(pack_rows(
+                &data[..stride * (rows - 1) + row_bytes],
+                stride,
+                row_bytes,
+            ))
+        };
+
+        Some(DecodedFrame {
+            // Monochrome frames have no chroma planes.
+            planes: [
+                plane(0)?,
+                plane(1).unwrap_or_default(),
+                plane(2).unwrap_or_default(),
+            ],
+            alpha: plane(3),
+        })
+    }
+}
+
+/// What the decoder does with a frame that fails to decode. Substitutions
+/// are logged to stderr.
+#[derive(Clone)]
+pub enum OnDecodeError {
+    /// Stop with the error.
+    Abort,
+    /// Leave the frame out of the segment.
+    Skip,
+    /// Repeat the closest earlier frame that decodes.
+    DuplicatePrevious,
+    /// Write this frame in its place.
+    Blank(DecodedFrame),
+}
+
+/// Where prefetched frames come from: FFMS2, or FFmpeg for `--decoder
+/// ffmpeg`. Indexes and tracks are still read through FFMS2.
+pub trait Decoder {
+    fn decode(&mut self, frame: usize) -> std::io::Result<DecodedFrame>;
+
+    /// Number of frames there are to decode.
+    fn frames(&self) -> usize;
+}
+
+fn get_frame(source: &mut VideoSource, i: usize) -> std::io::Result<Frame> {
+    Frame::GetFrame(source, i).map_err(|err| {
+        failure::tag(
+            Failure::Decode,
+            std::io::Error::other(format!("Failed to decode frame {}: {:?}", i, err)),
+        )
+    })
+}
+
+/// Decodes frame `i` of `source`, failing if the frame and the index
+/// disagree on whether it is a keyframe. FFMS2 frames carry no timestamps,
+/// so that is what is left to tell a seek that landed on the wrong frame.
+pub fn decode_verified(source: &mut VideoSource, i: usize) -> std::io::Result<DecodedFrame> {
+    let indexed = Track::TrackFromVideo(source).FrameInfo(i).KeyFrame() != 0;
+    let mut frame = get_frame(source, i)?;
+
+    if (frame.KeyFrame != 0) != indexed {
+        let reason = match indexed {
+            true => "is a keyframe in the index but was decoded as another frame",
+            false => "was decoded as a keyframe the index does not list",
+        };
+        return Err(failure::tag(
+            Failure::Decode,
+            std::io::Error::other(format!("Frame {} {}", i, reason)),
+        ));
+    }
+
+    Ok(DecodedFrame::from_frame(&mut frame))
+}
+
+/// Limits on decoding, for `--decode-timeout` and `--segment-timeout`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Watchdog {
+    /// How long one frame may take.
+    pub frame: Option<Duration>,
+    /// When the whole segment has to be decoded by.
+    pub deadline: Option<Instant>,
+}
+
+impl Watchdog {
+    pub fn is_set(&self) -> bool {
+        self.frame.is_some() || self.deadline.is_some()
+    }
+
+    /// How long the next frame may take to decode.
+    pub fn timeout(&self, now: Instant) -> Option<Duration> {
+        let left = self
+            .deadline
+            .map(|deadline| deadline.saturating_duration_since(now));
+        match (self.frame, left) {
+            (Some(frame), Some(left)) => Some(frame.min(left)),
+            (frame, left) => frame.or(left),
+        }
+    }
+}
+
+/// Addresses of the sources `decode_watched` gave up on while FFMS2 was
+/// still decoding, which must not be decoded from or dropped.
+static HUNG: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+fn address(source: &VideoSource) -> usize {
+    source as *const VideoSource as usize
+}
+
+/// Whether a decode of `source` timed out and may still be running.
+pub fn is_hung(source: &VideoSource) -> bool {
+    HUNG.lock().unwrap().contains(&address(source))
+}
+
+/// Forgets that the source in the place of `source` hung, once the hung
+/// one has been swapped out and leaked.
+pub fn release_hung(source: &VideoSource) {
+    HUNG.lock().unwrap().retain(|&hung| hung != address(source));
+}
+
+fn timeout_error(message: String) -> std::io::Error {
+    failure::tag(
+        Failure::Timeout,
+        std::io::Error::new(std::io::ErrorKind::TimedOut, message),
+    )
+}
+
+struct SourcePointer(*mut VideoSource);
+
+// The pointer is only used by the one thread decoding through it.
+unsafe impl Send for SourcePointer {}
+
+/// Decodes frame `i` of `source` as `decode_verified` or `decode` do, but
+/// on a thread of its own, failing with a `Failure::Timeout` error once the
+/// `watchdog` runs out. Some corrupt files make FFMS2 decode forever, and
+/// there is no stopping it: the thread is left behind and `source` marked
+/// hung, so later calls fail at once until it is swapped for a new source
+/// and leaked.
+pub fn decode_watched(
+    source: &mut VideoSource,
+    i: usize,
+    verified: bool,
+    watchdog: &Watchdog,
+) -> std::io::Result<DecodedFrame> {
+    if is_hung(source) {
+        return Err(timeout_error(format!(
+            "Cannot decode frame {}, the decoder is still stuck on an earlier frame",
+            i
+        )));
+    }
+    let Some(timeout) = watchdog.timeout(Instant::now()) else {
+        return match verified {
+            true => decode_verified(source, i),
+            false => source.decode(i),
+        };
+    };
+    if timeout.is_zero() {
+        return Err(timeout_error(format!(
+            "The segment was not decoded by its deadline, stopped at frame {}",
+            i
+        )));
+    }
+
+    let (sender, receiver) = sync_channel(1);
+    let pointer = SourcePointer(source);
+    thread::spawn(move || {
+        let pointer = pointer;
+        // The caller waits for this decode, or leaves the source hung and
+        // leaks it, so it outlives the thread either way.
+        let source = unsafe { &mut *pointer.0 };
+        let decoded = match verified {
+            true => decode_verified(source, i),
+            false => source.decode(i),
+        };
+        let _ = sender.send(decoded);
+    });
+
+    match receiver.recv_timeout(timeout) {
+        Ok(decoded) => decoded,
+        Err(RecvTimeoutError::Timeout) => {
+            HUNG.lock().unwrap().push(address(source));
+            Err(timeout_error(format!(
+                "Frame {} did not decode within {:?}",
+                i, timeout
+            )))
+        }
+        Err(RecvTimeoutError::Disconnected) => Err(std::io::Error::other(format!(
+            "The decoder of frame {} panicked",
+            i
+        ))),
+    }
+}
+
+impl Decoder for VideoSource {
+    fn decode(&mut self, i: usize) -> std::io::Result<DecodedFrame> {
+        Ok(DecodedFrame::from_frame(&mut get_frame(self, i)?))
+    }
+
+    fn frames(&self) -> usize {
+        self.GetVideoProperties().NumFrames as usize
+    }
+}
+
+/// Spawns a thread on `scope` that decodes `start..end` from `video_source`
+/// into a queue holding at most `depth` frames, so decoding runs ahead of
+/// whoever drains the returned receiver. Frames are sent with their number,
+/// which skips ahead when `on_error` drops a frame.
+///
+/// Decoding stops early if the receiver is dropped.
+pub fn spawn<'scope, D: Decoder + Send + ?Sized>(
+    scope: &'scope Scope<'scope, '_>,
+    video_source: &'scope mut D,
+    start: usize,
+    end: usize,
+    depth: usize,
+    on_error: OnDecodeError,
+) -> (
+    Receiver<(usize, DecodedFrame)>,
+    ScopedJoinHandle<'scope, std::io::Result<()>>,
+) {
+    let (sender, receiver) = sync_channel(depth.max(1));
+
+    let handle = scope.spawn(move || decode_range(video_source, start, end, &on_error, sender));
+
+    (receiver, handle)
+}
+
+fn decode_range(
+    video_source: &mut (impl Decoder + ?Sized),
+    start: usize,
+    end: usize,
+    on_error: &OnDecodeError,
+    sender: SyncSender<(usize, DecodedFrame)>,
+) -> std::io::Result<()> {
+    let mut previous: Option<DecodedFrame> = None;
+
+    for i in start..end {
+        let frame = match (video_source.decode(i), on_error) {
+            (Ok(frame), _) => frame,
+            // A timed out source cannot be decoded from again.
+            (Err(err), _) if failure::is(&err, Failure::Timeout) => return Err(err),
+            (Err(err), OnDecodeError::Abort) => return Err(err),
+            (Err(err), OnDecodeError::Skip) => {
+                crate::log!(
+                    Warn,
+                    "decode_error" { frame: i, action: "skip" },
+                    "{}, skipping it",
+                    err
+                );
+                continue;
+            }
+            (Err(err), OnDecodeError::DuplicatePrevious) => {
+                let earlier = match previous {
+                    Some(ref frame) => Some(frame.clone()),
+                    None => (0..i).rev().find_map(|j| video_source.decode(j).ok()),
+                };
+                match earlier {
+                    Some(frame) => {
+                        crate::log!(
+                            Warn,
+                            "decode_error" { frame: i, action: "duplicate-previous" },
+                            "{}, repeating the previous frame",
+                            err
+                        );
+                        frame
+                    }
+                    None => return Err(err),
+                }
+            }
+            (Err(err), OnDecodeError::Blank(blank)) => {
+                crate::log!(
+                    Warn,
+                    "decode_error" { frame: i, action: "blank" },
+                    "{}, writing a blank frame",
+                    err
+                );
+                blank.clone()
+            }
+        };
+
+        if let OnDecodeError::DuplicatePrevious = on_error {
+            previous = Some(frame.clone());
+        }
+
+        if sender.send((i, frame)).is_err() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_decodes_by_the_nearer_deadline() {
+        let now = Instant::now();
+        let watchdog = Watchdog {
+            frame: Some(Duration::from_secs(5)),
+            deadline: Some(now + Duration::from_secs(2)),
+        };
+        assert_eq!(watchdog.timeout(now), Some(Duration::from_secs(2)));
+        assert_eq!(
+            watchdog.timeout(now + Duration::from_secs(3)),
+            Some(Duration::ZERO)
+        );
+
+        let watchdog = Watchdog {
+            deadline: None,
+            ..watchdog
+        };
+        assert_eq!(watchdog.timeout(now), Some(Duration::from_secs(5)));
+        assert!(!Watchdog::default().is_set());
+    }
+
+    #[test]
+    fn drops_row_padding() {
+        let plane = [1, 2, 3, 0, 4, 5, 6, 0, 7, 8, 9];
+        assert_eq!(pack_rows(&plane, 4, 3), [1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(pack_rows(&plane[..8], 4, 4), plane[..8]);
+    }
+}
@@ -0,0 +1,220 @@
+use ffms_segmenter::log::json_string;
+use std::fs::File;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+/// `--progress-fd` records, one JSON object per line, for GUIs that draw
+/// a progress bar.
+pub struct ProgressStream {
+    sink: File,
+    input: String,
+    started: Instant,
+    percent: Option<usize>,
+}
+
+/// A duplicate of the already open `fd`, so the descriptor stays open
+/// after the returned file is dropped. `option` names the flag in errors.
+#[cfg(unix)]
+fn duplicate_fd(fd: i32, option: &str) -> io::Result<File> {
+    use std::os::fd::BorrowedFd;
+
+    if fd < 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid {} fd: {}", option, fd),
+        ));
+    }
+    let sink = unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?;
+
+    Ok(File::from(sink))
+}
+
+#[cfg(not(unix))]
+fn duplicate_fd(_fd: i32, option: &str) -> io::Result<File> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("--{}-fd needs a Unix platform", option),
+    ))
+}
+
+impl ProgressStream {
+    /// Writes to a duplicate of `fd`, so the descriptor stays open for the
+    /// next input.
+    pub fn from_fd(fd: i32, input: &str) -> io::Result<Self> {
+        Ok(ProgressStream {
+            sink: duplicate_fd(fd, "progress")?,
+            input: input.to_string(),
+            started: Instant::now(),
+            percent: None,
+        })
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(ProgressStream {
+            sink: self.sink.try_clone()?,
+            input: self.input.clone(),
+            started: self.started,
+            percent: self.percent,
+        })
+    }
+
+    /// Sends a record each time indexing passes another percent.
+    pub fn indexing(&mut self, current: usize, total: usize) -> io::Result<()> {
+        let percent = current * 100 / total.max(1);
+        if self.percent.is_some_and(|last| percent <= last) {
+            return Ok(());
+        }
+        self.percent = Some(percent);
+
+        let line = record(
+            "indexing",
+            &self.input,
+            current,
+            total,
+            self.started.elapsed(),
+        );
+        writeln!(self.sink, "{}", line)
+    }
+}
+
+/// `--events-fd` records, one JSON object per line, following each segment
+/// from `queued` through `started` and `progress` to `finished` or
+/// `failed`, for UIs tracking many segments at once.
+pub struct SegmentEvents {
+    sink: File,
+    /// Input, start and end of the segment being written.
+    current: Option<(usize, usize, usize)>,
+    percent: Option<usize>,
+}
+
+impl SegmentEvents {
+    pub fn from_fd(fd: i32) -> io::Result<Self> {
+        Ok(SegmentEvents {
+            sink: duplicate_fd(fd, "events")?,
+            current: None,
+            percent: None,
+        })
+    }
+
+    fn send(&mut self, line: String) -> io::Result<()> {
+        writeln!(self.sink, "{}", line)
+    }
+
+    pub fn queued(&mut self, input: usize, start: usize, end: usize) -> io::Result<()> {
+        self.send(segment_record("queued", input, start, end, ""))
+    }
+
+    pub fn started(&mut self, input: usize, start: usize, end: usize) -> io::Result<()> {
+        self.current = Some((input, start, end));
+        self.percent = None;
+        self.send(segment_record("started", input, start, end, ""))
+    }
+
+    /// Sends a record each time the segment being written passes another
+    /// percent. Write errors are left for the `finished` record to report.
+    pub fn progress(&mut self, frame: usize) {
+        let Some((input, start, end)) = self.current else {
+            return;
+        };
+        let frames = (frame + 1).clamp(start, end) - start;
+        let percent = frames * 100 / (end - start).max(1);
+        if self.percent.is_some_and(|last| percent <= last) {
+            return;
+        }
+        self.percent = Some(percent);
+
+        let fields = format!(",\"frames\":{},\"percent\":{}", frames, percent);
+        let _ = self.send(segment_record("progress", input, start, end, &fields));
+    }
+
+    /// The segment was written to `path`, or was already there when
+    /// `skipped`.
+    pub fn finished(
+        &mut self,
+        input: usize,
+        start: usize,
+        end: usize,
+        path: &str,
+        skipped: bool,
+    ) -> io::Result<()> {
+        self.current = None;
+        let fields = format!(",\"path\":{},\"skipped\":{}", json_string(path), skipped);
+        self.send(segment_record("finished", input, start, end, &fields))
+    }
+
+    pub fn failed(
+        &mut self,
+        input: usize,
+        start: usize,
+        end: usize,
+        reason: &str,
+    ) -> io::Result<()> {
+        self.current = None;
+        let fields = format!(",\"error\":{}", json_string(reason));
+        self.send(segment_record("failed", input, start, end, &fields))
+    }
+}
+
+/// One `--events-fd` record, with `fields` already formatted as `,"key":value`
+/// pairs.
+fn segment_record(event: &str, input: usize, start: usize, end: usize, fields: &str) -> String {
+    format!(
+        "{{\"event\":\"{}\",\"input\":{},\"start\":{},\"end\":{}{}}}",
+        event, input, start, end, fields
+    )
+}
+
+/// One progress record. The ETA assumes the remaining work goes as fast as
+/// the work so far and is `null` until anything is done.
+pub fn record(stage: &str, input: &str, current: usize, total: usize, elapsed: Duration) -> String {
+    let fraction = match total {
+        0 => 1.0,
+        total => current.min(total) as f64 / total as f64,
+    };
+    let elapsed = elapsed.as_secs_f64();
+    let eta = if fraction > 0.0 {
+        format!("{:.3}", elapsed / fraction - elapsed)
+    } else {
+        "null".to_string()
+    };
+
+    format!(
+        "{{\"stage\":\"{}\",\"input\":{},\"percent\":{:.1},\"elapsed\":{:.3},\"eta\":{}}}",
+        stage,
+        json_string(input),
+        fraction * 100.0,
+        elapsed,
+        eta
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_remaining_time() {
+        assert_eq!(
+            record("indexing", "a.mkv", 25, 100, Duration::from_secs(2)),
+            "{\"stage\":\"indexing\",\"input\":\"a.mkv\",\"percent\":25.0,\
+             \"elapsed\":2.000,\"eta\":6.000}"
+        );
+        assert_eq!(
+            record("indexing", "a.mkv", 0, 100, Duration::ZERO),
+            "{\"stage\":\"indexing\",\"input\":\"a.mkv\",\"percent\":0.0,\
+             \"elapsed\":0.000,\"eta\":null}"
+        );
+    }
+
+    #[test]
+    fn formats_segment_events() {
+        assert_eq!(
+            segment_record("queued", 1, 0, 96, ""),
+            "{\"event\":\"queued\",\"input\":1,\"start\":0,\"end\":96}"
+        );
+        assert_eq!(
+            segment_record("failed", 0, 96, 240, ",\"error\":\"disk full\""),
+            "{\"event\":\"failed\",\"input\":0,\"start\":96,\"end\":240,\"error\":\"disk full\"}"
+        );
+    }
+}
@@ -0,0 +1,181 @@
+use crate::prefetch::DecodedFrame;
+use ffms_segmenter::log;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use y4m::Colorspace;
+
+/// What the quality gate does with a frame that fails the sanity checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Keep the frame and only record it.
+    Flag,
+    /// Replace the frame with the last frame that passed.
+    Replace,
+}
+
+pub fn parse_mode(mode: &str) -> Result<Mode, String> {
+    match mode {
+        "flag" => Ok(Mode::Flag),
+        "replace" => Ok(Mode::Replace),
+        _ => Err(format!("Invalid quality gate mode: {}", mode)),
+    }
+}
+
+/// A frame that failed the quality gate.
+pub struct Rejection {
+    pub frame: usize,
+    pub reason: &'static str,
+    /// Frame written in its place, if it was replaced.
+    pub replaced_by: Option<usize>,
+}
+
+/// Per-segment sanity check for frames produced by error concealment,
+/// which typically come out as all-zero (green) or saturated planes.
+pub struct Gate {
+    mode: Mode,
+    bytes: usize,
+    max_value: u32,
+    last_good: Option<(usize, DecodedFrame)>,
+    rejections: Vec<Rejection>,
+}
+
+fn is_uniform(plane: &[u8], bytes: usize, value: u32) -> bool {
+    if bytes == 2 {
+        plane
+            .chunks_exact(2)
+            .all(|sample| u16::from_le_bytes([sample[0], sample[1]]) as u32 == value)
+    } else {
+        plane.iter().all(|&sample| sample as u32 == value)
+    }
+}
+
+impl Gate {
+    pub fn new(mode: Mode, colorspace: Colorspace) -> Self {
+        Gate {
+            mode,
+            bytes: colorspace.get_bytes_per_sample(),
+            max_value: (1u32 << colorspace.get_bit_depth()) - 1,
+            last_good: None,
+            rejections: Vec::new(),
+        }
+    }
+
+    /// Returns why `frame` looks corrupt, or `None` if it passes.
+    pub fn check(&self, frame: &DecodedFrame) -> Option<&'static str> {
+        let [luma, chroma @ ..] = &frame.planes;
+        let chroma: Vec<&Vec<u8>> = chroma.iter().filter(|plane| !plane.is_empty()).collect();
+
+        let uniform = |plane: &[u8], value| is_uniform(plane, self.bytes, value);
+
+        if uniform(luma, 0) && chroma.iter().all(|plane| uniform(plane, 0)) {
+            Some("zero-frame")
+        } else if chroma
+            .iter()
+            .any(|plane| uniform(plane, 0) || uniform(plane, self.max_value))
+        {
+            Some("extreme-chroma")
+        } else if uniform(luma, self.max_value) {
+            Some("saturated-luma")
+        } else {
+            None
+        }
+    }
+
+    /// Checks `frame` and returns the frame to write in its place.
+    pub fn apply(&mut self, frame_number: usize, frame: DecodedFrame) -> DecodedFrame {
+        let reason = match self.check(&frame) {
+            Some(reason) => reason,
+            None => {
+                if self.mode == Mode::Replace {
+                    self.last_good = Some((frame_number, frame.clone()));
+                }
+                return frame;
+            }
+        };
+
+        let replacement = match (self.mode, &self.last_good) {
+            (Mode::Replace, Some((good_number, good))) => Some((*good_number, good.clone())),
+            _ => None,
+        };
+
+        log!(
+            Warn,
+            "quality_rejected" { frame: frame_number },
+            "Frame {} failed quality gate: {}",
+            frame_number,
+            reason
+        );
+
+        self.rejections.push(Rejection {
+            frame: frame_number,
+            reason,
+            replaced_by: replacement.as_ref().map(|(number, _)| *number),
+        });
+
+        match replacement {
+            Some((_, good)) => good,
+            None => frame,
+        }
+    }
+
+    pub fn rejections(&self) -> &[Rejection] {
+        &self.rejections
+    }
+
+    /// Writes `<frame> <reason> [replaced-by <frame>]` lines for every
+    /// rejected frame.
+    pub fn write_report(&self, path: &Path) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        for rejection in &self.rejections {
+            match rejection.replaced_by {
+                Some(good) => writeln!(
+                    file,
+                    "{} {} replaced-by {}",
+                    rejection.frame, rejection.reason, good
+                )?,
+                None => writeln!(file, "{} {}", rejection.frame, rejection.reason)?,
+            }
+        }
+
+        file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(luma: u8, chroma: u8) -> DecodedFrame {
+        DecodedFrame {
+            planes: [vec![luma; 16], vec![chroma; 4], vec![chroma; 4]],
+            alpha: None,
+        }
+    }
+
+    #[test]
+    fn detects_corrupt_frames() {
+        let gate = Gate::new(Mode::Flag, Colorspace::C420);
+
+        assert_eq!(gate.check(&frame(0, 0)), Some("zero-frame"));
+        assert_eq!(gate.check(&frame(80, 0)), Some("extreme-chroma"));
+        assert_eq!(gate.check(&frame(255, 128)), Some("saturated-luma"));
+        assert_eq!(gate.check(&frame(16, 128)), None);
+        assert_eq!(gate.check(&frame(0, 128)), None);
+    }
+
+    #[test]
+    fn replaces_with_last_good_frame() {
+        let mut gate = Gate::new(Mode::Replace, Colorspace::C420);
+
+        // There is nothing to replace the first frame with yet.
+        assert_eq!(gate.apply(0, frame(0, 0)).planes[0][0], 0);
+        gate.apply(1, frame(40, 128));
+        assert_eq!(gate.apply(2, frame(0, 0)).planes[0][0], 40);
+
+        let replaced: Vec<Option<usize>> =
+            gate.rejections().iter().map(|r| r.replaced_by).collect();
+        assert_eq!(replaced, [None, Some(1)]);
+    }
+}
@@ -0,0 +1,83 @@
+/// Splits a request for several segments into one request per segment,
+/// or returns `None` for anything else. The segments are either separated
+/// by `;`, as in `0 100; 1 240 360`, or given as `start end` pairs on one
+/// line, as in `0 100 240 360`, where options such as `pad-after=2` apply
+/// to every pair. Pairs of another input follow its index, as in
+/// `1 0 100 240 360`. Pairs that would start after they end, as in
+/// `1 0 100 240`, are most likely an input index and three numbers, so
+/// those lines are returned as `None` to be refused as one request.
+pub fn split_ranges(line: &str) -> Option<Vec<String>> {
+    let is_range = |command: &str| {
+        command
+            .split_whitespace()
+            .next()
+            .is_some_and(|token| token.parse::<usize>().is_ok())
+    };
+    if !is_range(line) {
+        return None;
+    }
+
+    if line.contains(';') {
+        let commands: Vec<String> = line
+            .split(';')
+            .map(str::trim)
+            .filter(|command| !command.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        return Some(commands);
+    }
+
+    let (options, numbers): (Vec<&str>, Vec<&str>) = line
+        .split_whitespace()
+        .partition(|token| token.parse::<usize>().is_err());
+    if numbers.len() < 4 {
+        return None;
+    }
+    let (input, pairs) = match numbers.len() % 2 {
+        0 => (None, &numbers[..]),
+        _ => (Some(numbers[0]), &numbers[1..]),
+    };
+    let backwards = |pair: &[&str]| pair[0].parse::<usize>().ok() > pair[1].parse().ok();
+    if pairs.chunks(2).any(backwards) {
+        return None;
+    }
+
+    Some(
+        pairs
+            .chunks(2)
+            .map(|pair| {
+                let mut command: Vec<&str> = input.into_iter().collect();
+                command.extend(pair);
+                command.extend(&options);
+                command.join(" ")
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_multiple_ranges() {
+        assert_eq!(
+            split_ranges("0 100 240 360 pad-after=2 reverse").unwrap(),
+            ["0 100 pad-after=2 reverse", "240 360 pad-after=2 reverse"]
+        );
+        assert_eq!(
+            split_ranges("0 100; 1 240 360;").unwrap(),
+            ["0 100", "1 240 360"]
+        );
+        assert!(split_ranges("0 100").is_none());
+        assert!(split_ranges("1 0 100").is_none());
+        assert_eq!(
+            split_ranges("1 0 100 240 360 reverse").unwrap(),
+            ["1 0 100 reverse", "1 240 360 reverse"]
+        );
+        assert!(split_ranges("1 0 100 240").is_none());
+        assert!(split_ranges("0 100 360 240").is_none());
+        assert!(split_ranges("open a;b.mkv").is_none());
+    }
+}
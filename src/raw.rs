@@ -0,0 +1,131 @@
+use crate::mkv::frame_bytes;
+use crate::SegmentFormat;
+use ffms_segmenter::prefetch::DecodedFrame;
+use ffms_segmenter::scale::plane_sizes;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use y4m::Colorspace;
+
+/// FFmpeg name of the pixel format frames of `colorspace` are stored in.
+pub fn pixel_format(colorspace: Colorspace) -> &'static str {
+    match colorspace {
+        Colorspace::Cmono => "gray",
+        Colorspace::C420 | Colorspace::C420jpeg | Colorspace::C420paldv | Colorspace::C420mpeg2 => {
+            "yuv420p"
+        }
+        Colorspace::C420p10 => "yuv420p10le",
+        Colorspace::C420p12 => "yuv420p12le",
+        Colorspace::C422 => "yuv422p",
+        Colorspace::C422p10 => "yuv422p10le",
+        Colorspace::C422p12 => "yuv422p12le",
+        Colorspace::C444 => "yuv444p",
+        Colorspace::C444p10 => "yuv444p10le",
+        Colorspace::C444p12 => "yuv444p12le",
+    }
+}
+
+/// What a reader needs to map the frames of a raw segment of `frames`
+/// frames of `format`: frame `n` starts at `n * frame_bytes`, and each
+/// plane at its offset within the frame, rows `width * bytes_per_sample`
+/// bytes apart.
+pub fn descriptor(format: &SegmentFormat, frames: usize) -> String {
+    let bytes = format.colorspace.get_bytes_per_sample();
+    let mut offset = 0;
+    let planes: Vec<String> = plane_sizes(format.colorspace, format.width, format.height)
+        .iter()
+        .filter(|plane| plane.width > 0)
+        .map(|plane| {
+            let size = plane.width * plane.height * bytes;
+            let json = format!(
+                "{{\"width\":{},\"height\":{},\"offset\":{},\"bytes\":{}}}",
+                plane.width, plane.height, offset, size
+            );
+            offset += size;
+            json
+        })
+        .collect();
+
+    format!(
+        "{{\"width\":{},\"height\":{},\"pixel_format\":\"{}\",\"bytes_per_sample\":{},\
+         \"fps_num\":{},\"fps_den\":{},\"frames\":{},\"frame_bytes\":{},\"planes\":[{}]}}",
+        format.width,
+        format.height,
+        pixel_format(format.colorspace),
+        bytes,
+        format.framerate.num,
+        format.framerate.den,
+        frames,
+        frame_bytes(format),
+        planes.join(",")
+    )
+}
+
+pub fn write_descriptor(format: &SegmentFormat, frames: usize, path: &Path) -> io::Result<()> {
+    fs::write(path, descriptor(format, frames) + "\n")
+}
+
+/// `--output-format raw` writer: the planes of each frame back to back,
+/// without headers, so every frame has the same size and offset.
+pub struct Writer<W: Write> {
+    sink: W,
+    frame_bytes: usize,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(sink: W, format: &SegmentFormat) -> Self {
+        Writer {
+            sink,
+            frame_bytes: frame_bytes(format),
+        }
+    }
+
+    pub fn write_frame(&mut self, frame: &DecodedFrame) -> io::Result<()> {
+        let len: usize = frame.planes.iter().map(Vec::len).sum();
+        if len != self.frame_bytes {
+            return Err(io::Error::other(format!(
+                "Frame is {} bytes, expected {}",
+                len, self.frame_bytes
+            )));
+        }
+
+        for plane in &frame.planes {
+            self.sink.write_all(plane)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ffms_segmenter::yuv4mpeg::{ColorRange, Interlacing};
+    use y4m::Ratio;
+
+    #[test]
+    fn describes_the_plane_layout() {
+        let format = SegmentFormat {
+            width: 5,
+            height: 3,
+            framerate: Ratio {
+                num: 24000,
+                den: 1001,
+            },
+            colorspace: Colorspace::C420p10,
+            interlacing: Interlacing::Progressive,
+            color_range: ColorRange::Limited,
+            color: None,
+            sar: None,
+        };
+
+        assert_eq!(
+            descriptor(&format, 48),
+            "{\"width\":5,\"height\":3,\"pixel_format\":\"yuv420p10le\",\"bytes_per_sample\":2,\
+             \"fps_num\":24000,\"fps_den\":1001,\"frames\":48,\"frame_bytes\":54,\"planes\":[\
+             {\"width\":5,\"height\":3,\"offset\":0,\"bytes\":30},\
+             {\"width\":3,\"height\":2,\"offset\":30,\"bytes\":12},\
+             {\"width\":3,\"height\":2,\"offset\":42,\"bytes\":12}]}"
+        );
+    }
+}
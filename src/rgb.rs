@@ -0,0 +1,183 @@
+use crate::output::{part_path, Upload};
+use crate::png;
+use crate::thumb;
+use crate::SegmentFormat;
+use ffms_segmenter::prefetch::DecodedFrame;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+/// How `--rgb` segments are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RgbOutput {
+    /// Packed rgb24 frames back to back in one file.
+    Raw,
+    /// One PNG per frame in a folder.
+    Png,
+}
+
+pub fn parse_rgb_output(output: &str) -> Result<RgbOutput, String> {
+    match output {
+        "raw" => Ok(RgbOutput::Raw),
+        "png" => Ok(RgbOutput::Png),
+        _ => Err(format!("Invalid RGB output: {}", output)),
+    }
+}
+
+/// Extension of the file, or folder for PNG sequences, a segment is
+/// written to.
+pub fn extension(output: RgbOutput) -> &'static str {
+    match output {
+        RgbOutput::Raw => "rgb",
+        RgbOutput::Png => "frames",
+    }
+}
+
+/// Interleaves the planes of a gbrp frame into rgb24.
+pub fn interleave_gbr(frame: &DecodedFrame) -> Vec<u8> {
+    let [g, b, r] = &frame.planes;
+
+    g.iter()
+        .zip(b)
+        .zip(r)
+        .flat_map(|((&g, &b), &r)| [r, g, b])
+        .collect()
+}
+
+/// Packs a frame of `format` as rgb24. Frames of RGB sources are only
+/// reordered; YUV frames are converted like thumbnails.
+pub fn to_rgb(frame: &DecodedFrame, format: &SegmentFormat, rgb_source: bool) -> Vec<u8> {
+    if rgb_source {
+        interleave_gbr(frame)
+    } else {
+        thumb::to_rgb(
+            frame,
+            format.colorspace,
+            format.color_range,
+            format.width,
+            format.height,
+        )
+    }
+}
+
+/// Writes frames as packed rgb24 to `sink`.
+pub struct Writer<W: Write> {
+    sink: W,
+    format: SegmentFormat,
+    rgb_source: bool,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(sink: W, format: &SegmentFormat, rgb_source: bool) -> io::Result<Self> {
+        if format.colorspace.get_bytes_per_sample() != 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "RGB output needs 8-bit frames, add --scale-8bit",
+            ));
+        }
+
+        Ok(Writer {
+            sink,
+            format: format.clone(),
+            rgb_source,
+        })
+    }
+
+    pub fn write_frame(&mut self, frame: &DecodedFrame) -> io::Result<()> {
+        self.sink
+            .write_all(&to_rgb(frame, &self.format, self.rgb_source))
+    }
+}
+
+/// Splits a stream of rgb24 frames into numbered PNG files in a folder.
+pub struct PngSequence {
+    folder: PathBuf,
+    /// Where the frames go until the sequence is finished.
+    part: PathBuf,
+    width: usize,
+    height: usize,
+    pending: Vec<u8>,
+    frames: usize,
+    finished: bool,
+}
+
+impl PngSequence {
+    pub fn create(folder: impl Into<PathBuf>, width: usize, height: usize) -> io::Result<Self> {
+        let folder = folder.into();
+        let part = part_path(&folder);
+        fs::create_dir_all(&part)?;
+
+        Ok(PngSequence {
+            folder,
+            part,
+            width,
+            height,
+            pending: Vec::new(),
+            frames: 0,
+            finished: false,
+        })
+    }
+}
+
+impl Write for PngSequence {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+
+        let frame_len = self.width * self.height * 3;
+        while frame_len > 0 && self.pending.len() >= frame_len {
+            let path = self.part.join(format!("{:06}.png", self.frames));
+            png::write_rgb(&path, self.width, self.height, &self.pending[..frame_len])?;
+            self.pending.drain(..frame_len);
+            self.frames += 1;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Upload for PngSequence {
+    fn finish(mut self: Box<Self>) -> io::Result<String> {
+        if !self.pending.is_empty() {
+            return Err(io::Error::other(format!(
+                "{} bytes of a partial frame left over",
+                self.pending.len()
+            )));
+        }
+        // A folder cannot be renamed over one with files in it.
+        if self.folder.exists() {
+            fs::remove_dir_all(&self.folder)?;
+        }
+        fs::rename(&self.part, &self.folder)?;
+        self.finished = true;
+
+        Ok(self.folder.to_string_lossy().into_owned())
+    }
+}
+
+impl Drop for PngSequence {
+    /// An abandoned sequence is removed rather than left incomplete.
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = fs::remove_dir_all(&self.part);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interleaves_gbr_planes() {
+        let frame = DecodedFrame {
+            planes: [vec![1, 4], vec![2, 5], vec![3, 6]],
+            alpha: None,
+        };
+
+        assert_eq!(interleave_gbr(&frame), [3, 1, 2, 6, 4, 5]);
+    }
+}
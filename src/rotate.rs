@@ -0,0 +1,159 @@
+use ffms_segmenter::prefetch::DecodedFrame;
+use ffms_segmenter::scale::{plane_sizes, PlaneSize};
+use std::io;
+use y4m::Colorspace;
+
+/// Clockwise quarter turns applied to frames before they are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rotation(u8);
+
+impl Rotation {
+    pub const NONE: Rotation = Rotation(0);
+
+    /// The turn that displays a stream upright. FFMS2 reports the display
+    /// matrix angle, which is counterclockwise; anything other than a
+    /// multiple of 90 degrees is rounded to the nearest one.
+    pub fn from_ffms(degrees: i32) -> Rotation {
+        let clockwise = (-degrees).rem_euclid(360);
+        Rotation((((clockwise + 45) / 90) % 4) as u8)
+    }
+
+    pub fn degrees(self) -> u32 {
+        self.0 as u32 * 90
+    }
+
+    pub fn swaps_dimensions(self) -> bool {
+        self.0 % 2 == 1
+    }
+
+    /// Checks that frames in `colorspace` can be turned. Quarter turns of
+    /// 4:2:2 would need 4:4:0, which y4m cannot store.
+    pub fn check(self, colorspace: Colorspace) -> io::Result<()> {
+        let half_width_chroma = matches!(
+            colorspace,
+            Colorspace::C422 | Colorspace::C422p10 | Colorspace::C422p12
+        );
+
+        if self.swaps_dimensions() && half_width_chroma {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Cannot rotate 4:2:2 frames by {} degrees, pass --no-autorotate",
+                    self.degrees()
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Turns frames so they come out in a given output size.
+pub struct Rotator {
+    rotation: Rotation,
+    colorspace: Colorspace,
+    /// Size of the frames before turning.
+    width: usize,
+    height: usize,
+}
+
+impl Rotator {
+    /// A rotator whose frames are `width` x `height` once turned, or
+    /// `None` if frames are written as decoded.
+    pub fn new(
+        rotation: Rotation,
+        colorspace: Colorspace,
+        width: usize,
+        height: usize,
+    ) -> Option<Self> {
+        if rotation == Rotation::NONE {
+            return None;
+        }
+
+        let (width, height) = if rotation.swaps_dimensions() {
+            (height, width)
+        } else {
+            (width, height)
+        };
+
+        Some(Rotator {
+            rotation,
+            colorspace,
+            width,
+            height,
+        })
+    }
+
+    pub fn rotate(&self, frame: DecodedFrame) -> DecodedFrame {
+        let sizes = plane_sizes(self.colorspace, self.width, self.height);
+        let bytes = self.colorspace.get_bytes_per_sample();
+        let mut planes: [Vec<u8>; 3] = Default::default();
+        for (plane, size) in sizes.iter().enumerate() {
+            planes[plane] = rotate_plane(&frame.planes[plane], *size, bytes, self.rotation);
+        }
+
+        DecodedFrame {
+            planes,
+            alpha: frame
+                .alpha
+                .map(|alpha| rotate_plane(&alpha, sizes[0], bytes, self.rotation)),
+        }
+    }
+}
+
+/// Turns one plane of `size` with `bytes` per sample.
+pub fn rotate_plane(src: &[u8], size: PlaneSize, bytes: usize, rotation: Rotation) -> Vec<u8> {
+    let PlaneSize { width, height } = size;
+    let mut dst = Vec::with_capacity(src.len());
+
+    let (dst_width, dst_height) = if rotation.swaps_dimensions() {
+        (height, width)
+    } else {
+        (width, height)
+    };
+
+    for y in 0..dst_height {
+        for x in 0..dst_width {
+            let (src_x, src_y) = match rotation.0 {
+                1 => (y, height - 1 - x),
+                2 => (width - 1 - x, height - 1 - y),
+                3 => (width - 1 - y, x),
+                _ => (x, y),
+            };
+            let offset = (src_y * width + src_x) * bytes;
+            dst.extend_from_slice(&src[offset..offset + bytes]);
+        }
+    }
+
+    dst
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn turns_planes_clockwise() {
+        // 1 2 3
+        // 4 5 6
+        let src = [1, 2, 3, 4, 5, 6];
+        let size = PlaneSize {
+            width: 3,
+            height: 2,
+        };
+
+        assert_eq!(
+            rotate_plane(&src, size, 1, Rotation::from_ffms(-90)),
+            [4, 1, 5, 2, 6, 3]
+        );
+        assert_eq!(
+            rotate_plane(&src, size, 1, Rotation::from_ffms(180)),
+            [6, 5, 4, 3, 2, 1]
+        );
+        assert_eq!(
+            rotate_plane(&src, size, 1, Rotation::from_ffms(90)),
+            [3, 6, 2, 5, 1, 4]
+        );
+        assert_eq!(Rotation::from_ffms(0), Rotation::NONE);
+    }
+}
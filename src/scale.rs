@@ -0,0 +1,550 @@
+use crate::prefetch::DecodedFrame;
+use crate::yuv4mpeg::ColorRange;
+use std::io;
+use y4m::Colorspace;
+
+#[cfg(feature = "gpu")]
+mod gpu;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlaneSize {
+    pub width: usize,
+    pub height: usize,
+}
+
+/// How `scale_plane` reduces the bit depth of samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Dither {
+    /// Round to the nearest value, which bands smooth gradients.
+    #[default]
+    None,
+    /// Add an 8x8 Bayer pattern before dropping the low bits.
+    Ordered,
+    /// Floyd-Steinberg: carry the rounding error of each sample over to
+    /// the samples right of and below it. Only done on the CPU.
+    ErrorDiffusion,
+}
+
+/// A linear map of samples, `(value - from) * gain / 4096 + to` clamped
+/// to `0..=max`, that converts them to another range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Levels {
+    pub from: i32,
+    pub to: i32,
+    pub gain: i32,
+    pub max: i32,
+}
+
+impl Levels {
+    /// The maps of the luma and of the chroma samples of `depth` bits from
+    /// the `from` range to the `to` range, if they differ. Limited luma
+    /// spans 16 to 235 and chroma 16 to 240, scaled up for deeper samples.
+    pub fn between(from: ColorRange, to: ColorRange, depth: u32) -> Option<[Levels; 2]> {
+        let low = 16 << (depth - 8);
+        let center = 128 << (depth - 8);
+        let max = (1 << depth) - 1;
+        let gain = |num: i32, den: i32| (4096 * num + den / 2) / den;
+
+        match (from, to) {
+            (ColorRange::Limited, ColorRange::Full) => Some([
+                Levels {
+                    from: low,
+                    to: 0,
+                    gain: gain(255, 219),
+                    max,
+                },
+                Levels {
+                    from: center,
+                    to: center,
+                    gain: gain(255, 224),
+                    max,
+                },
+            ]),
+            (ColorRange::Full, ColorRange::Limited) => Some([
+                Levels {
+                    from: 0,
+                    to: low,
+                    gain: gain(219, 255),
+                    max,
+                },
+                Levels {
+                    from: center,
+                    to: center,
+                    gain: gain(224, 255),
+                    max,
+                },
+            ]),
+            _ => None,
+        }
+    }
+
+    pub fn apply(&self, value: u32) -> u32 {
+        ((((value as i32 - self.from) * self.gain + 2048) >> 12) + self.to).clamp(0, self.max)
+            as u32
+    }
+}
+
+/// Describes how one plane is resampled. Both the CPU and GPU paths use
+/// the same 8-bit fixed point bilinear filter so their output is identical.
+#[derive(Debug, Clone, Copy)]
+pub struct PlaneJob {
+    pub src: PlaneSize,
+    pub dst: PlaneSize,
+    pub src_bytes: usize,
+    pub dst_bytes: usize,
+    /// Right shift applied after filtering to reduce the bit depth.
+    pub shift: u32,
+    pub dither: Dither,
+    /// Range conversion applied after filtering, before the bit depth is
+    /// reduced.
+    pub levels: Option<Levels>,
+}
+
+pub fn plane_sizes(colorspace: Colorspace, width: usize, height: usize) -> [PlaneSize; 3] {
+    let luma = PlaneSize { width, height };
+    let chroma = match colorspace {
+        Colorspace::Cmono => PlaneSize {
+            width: 0,
+            height: 0,
+        },
+        Colorspace::C422 | Colorspace::C422p10 | Colorspace::C422p12 => PlaneSize {
+            width: width.div_ceil(2),
+            height,
+        },
+        Colorspace::C444 | Colorspace::C444p10 | Colorspace::C444p12 => luma,
+        _ => PlaneSize {
+            width: width.div_ceil(2),
+            height: height.div_ceil(2),
+        },
+    };
+
+    [luma, chroma, chroma]
+}
+
+/// The 8-bit colorspace with the same chroma subsampling as `colorspace`.
+pub fn to_8bit(colorspace: Colorspace) -> Colorspace {
+    match colorspace {
+        Colorspace::C420p10 | Colorspace::C420p12 => Colorspace::C420,
+        Colorspace::C422p10 | Colorspace::C422p12 => Colorspace::C422,
+        Colorspace::C444p10 | Colorspace::C444p12 => Colorspace::C444,
+        other => other,
+    }
+}
+
+pub fn parse_dither(dither: &str) -> Result<Dither, String> {
+    match dither {
+        "none" => Ok(Dither::None),
+        "ordered" => Ok(Dither::Ordered),
+        "error-diffusion" => Ok(Dither::ErrorDiffusion),
+        _ => Err(format!("Unknown dither: {}", dither)),
+    }
+}
+
+pub fn parse_size(size: &str) -> Result<PlaneSize, String> {
+    let invalid = || format!("Invalid size, expected WIDTHxHEIGHT: {}", size);
+    let (width, height) = size.split_once('x').ok_or_else(invalid)?;
+    let width = width.parse::<usize>().map_err(|_| invalid())?;
+    let height = height.parse::<usize>().map_err(|_| invalid())?;
+
+    if width == 0 || height == 0 {
+        return Err(invalid());
+    }
+
+    Ok(PlaneSize { width, height })
+}
+
+enum Backend {
+    Cpu,
+    #[cfg(feature = "gpu")]
+    Gpu(gpu::GpuScaler),
+}
+
+/// Resizes (and optionally reduces the bit depth of) decoded frames for
+/// proxy generation.
+pub struct Scaler {
+    jobs: [PlaneJob; 3],
+    src_depth: u32,
+    backend: Backend,
+}
+
+impl Scaler {
+    /// Creates a scaler for frames in `src_colorspace`. When `use_gpu` is
+    /// set the GPU path is tried first and the CPU path is used if no
+    /// adapter is available or the `gpu` feature is disabled.
+    pub fn new(
+        src_colorspace: Colorspace,
+        src: PlaneSize,
+        dst: PlaneSize,
+        dst_colorspace: Colorspace,
+        use_gpu: bool,
+    ) -> io::Result<Self> {
+        let src_planes = plane_sizes(src_colorspace, src.width, src.height);
+        let dst_planes = plane_sizes(dst_colorspace, dst.width, dst.height);
+        let shift = src_colorspace.get_bit_depth() as u32 - dst_colorspace.get_bit_depth() as u32;
+
+        let job = |plane: usize| PlaneJob {
+            src: src_planes[plane],
+            dst: dst_planes[plane],
+            src_bytes: src_colorspace.get_bytes_per_sample(),
+            dst_bytes: dst_colorspace.get_bytes_per_sample(),
+            shift,
+            dither: Dither::None,
+            levels: None,
+        };
+
+        Ok(Scaler {
+            jobs: [job(0), job(1), job(2)],
+            src_depth: src_colorspace.get_bit_depth() as u32,
+            backend: select_backend(use_gpu),
+        })
+    }
+
+    /// Dithers samples when reducing their bit depth. Error diffusion runs
+    /// on the CPU, as each sample depends on the ones before it.
+    pub fn dithered(mut self, dither: Dither) -> Self {
+        for job in &mut self.jobs {
+            job.dither = dither;
+        }
+        if dither == Dither::ErrorDiffusion && !matches!(self.backend, Backend::Cpu) {
+            crate::log!(Info, "Dithering by error diffusion on the CPU");
+            self.backend = Backend::Cpu;
+        }
+        self
+    }
+
+    /// Converts the samples of YUV frames from the `from` range to the
+    /// `to` range. Alpha is left as it is.
+    pub fn converting_range(mut self, from: ColorRange, to: ColorRange) -> Self {
+        if let Some([luma, chroma]) = Levels::between(from, to, self.src_depth) {
+            self.jobs[0].levels = Some(luma);
+            self.jobs[1].levels = Some(chroma);
+            self.jobs[2].levels = Some(chroma);
+        }
+        self
+    }
+
+    pub fn scale(&self, frame: DecodedFrame) -> io::Result<DecodedFrame> {
+        let mut planes: [Vec<u8>; 3] = Default::default();
+
+        for (plane, job) in self.jobs.iter().enumerate() {
+            planes[plane] = self.scale_plane(&frame.planes[plane], job)?;
+        }
+
+        // Alpha is full resolution, so it is scaled like luma.
+        let alpha = match frame.alpha {
+            Some(ref alpha) => Some(self.scale_plane(
+                alpha,
+                &PlaneJob {
+                    levels: None,
+                    ..self.jobs[0]
+                },
+            )?),
+            None => None,
+        };
+
+        Ok(DecodedFrame { planes, alpha })
+    }
+
+    fn scale_plane(&self, src: &[u8], job: &PlaneJob) -> io::Result<Vec<u8>> {
+        Ok(match self.backend {
+            Backend::Cpu => scale_plane(src, job),
+            #[cfg(feature = "gpu")]
+            Backend::Gpu(ref gpu) => gpu.scale_plane(src, job)?,
+        })
+    }
+}
+
+#[cfg(feature = "gpu")]
+fn select_backend(use_gpu: bool) -> Backend {
+    if !use_gpu {
+        return Backend::Cpu;
+    }
+
+    match gpu::GpuScaler::new() {
+        Some(gpu) => {
+            crate::log!(Info, "Scaling frames on the GPU");
+            Backend::Gpu(gpu)
+        }
+        None => {
+            crate::log!(Warn, "No GPU adapter available, scaling frames on the CPU");
+            Backend::Cpu
+        }
+    }
+}
+
+#[cfg(not(feature = "gpu"))]
+fn select_backend(use_gpu: bool) -> Backend {
+    if use_gpu {
+        crate::log!(
+            Warn,
+            "Built without the gpu feature, scaling frames on the CPU"
+        );
+    }
+
+    Backend::Cpu
+}
+
+fn read_sample(plane: &[u8], index: usize, bytes: usize) -> u32 {
+    if bytes == 2 {
+        u16::from_le_bytes([plane[index * 2], plane[index * 2 + 1]]) as u32
+    } else {
+        plane[index] as u32
+    }
+}
+
+/// Source coordinate of destination sample `dst` in 1/256 units.
+fn source_position(dst: usize, src_len: usize, dst_len: usize) -> (usize, u32) {
+    let step = ((src_len << 8) / dst_len) as i64;
+    let position = (dst as i64 * step + step / 2 - 128).max(0) as usize;
+
+    ((position >> 8).min(src_len - 1), (position & 0xff) as u32)
+}
+
+/// Threshold of the 8x8 Bayer matrix at `x`, `y`, from 0 to 63.
+fn bayer(x: usize, y: usize) -> u32 {
+    let (x, y) = (x as u32, y as u32);
+    let z = x ^ y;
+    ((z & 1) << 5)
+        | ((y & 1) << 4)
+        | ((z & 2) << 2)
+        | ((y & 2) << 1)
+        | ((z & 4) >> 1)
+        | ((y & 4) >> 2)
+}
+
+/// Bilinear resample of a single plane. Must stay in sync with the WGSL
+/// shader in `gpu.rs`, apart from error diffusion.
+pub fn scale_plane(src: &[u8], job: &PlaneJob) -> Vec<u8> {
+    let mut dst = Vec::with_capacity(job.dst.width * job.dst.height * job.dst_bytes);
+    if job.src.width == 0 || job.src.height == 0 {
+        return dst;
+    }
+
+    let max_value = (1u32 << (8 * job.dst_bytes as u32)) - 1;
+    // The errors carried into this row and the next, with a sample of
+    // margin either side.
+    let diffuse = job.dither == Dither::ErrorDiffusion && job.shift > 0;
+    let (mut errors, mut next_errors) = match diffuse {
+        true => (vec![0i32; job.dst.width + 2], vec![0i32; job.dst.width + 2]),
+        false => (Vec::new(), Vec::new()),
+    };
+
+    for y in 0..job.dst.height {
+        let (y0, fy) = source_position(y, job.src.height, job.dst.height);
+        let y1 = (y0 + 1).min(job.src.height - 1);
+
+        for x in 0..job.dst.width {
+            let (x0, fx) = source_position(x, job.src.width, job.dst.width);
+            let x1 = (x0 + 1).min(job.src.width - 1);
+
+            let sample = |row: usize, column: usize| {
+                read_sample(src, row * job.src.width + column, job.src_bytes)
+            };
+
+            let top = sample(y0, x0) * (256 - fx) + sample(y0, x1) * fx;
+            let bottom = sample(y1, x0) * (256 - fx) + sample(y1, x1) * fx;
+            let mut value = (top * (256 - fy) + bottom * fy + 32768) >> 16;
+            if let Some(ref levels) = job.levels {
+                value = levels.apply(value);
+            }
+
+            if diffuse {
+                let wanted = value as i32 + errors[x + 1] / 16;
+                let rounded =
+                    ((wanted.max(0) as u32 + (1 << (job.shift - 1))) >> job.shift).min(max_value);
+                let error = wanted - (rounded << job.shift) as i32;
+                errors[x + 2] += error * 7;
+                next_errors[x] += error * 3;
+                next_errors[x + 1] += error * 5;
+                next_errors[x + 2] += error;
+                value = rounded;
+            } else if job.dither == Dither::Ordered && job.shift > 0 {
+                let threshold = ((2 * bayer(x, y) + 1) << job.shift) >> 7;
+                value = (value + threshold) >> job.shift;
+            } else if job.shift > 0 {
+                value = (value + (1 << (job.shift - 1))) >> job.shift;
+            }
+            let value = value.min(max_value);
+
+            if job.dst_bytes == 2 {
+                dst.extend_from_slice(&(value as u16).to_le_bytes());
+            } else {
+                dst.push(value as u8);
+            }
+        }
+
+        if diffuse {
+            errors = std::mem::replace(&mut next_errors, vec![0; job.dst.width + 2]);
+        }
+    }
+
+    dst
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(src: (usize, usize), dst: (usize, usize)) -> PlaneJob {
+        PlaneJob {
+            src: PlaneSize {
+                width: src.0,
+                height: src.1,
+            },
+            dst: PlaneSize {
+                width: dst.0,
+                height: dst.1,
+            },
+            src_bytes: 1,
+            dst_bytes: 1,
+            shift: 0,
+            dither: Dither::None,
+            levels: None,
+        }
+    }
+
+    #[test]
+    fn same_size_is_identity() {
+        let src: Vec<u8> = (0..=255).collect();
+        assert_eq!(scale_plane(&src, &job((16, 16), (16, 16))), src);
+    }
+
+    #[test]
+    fn halving_averages_neighbours() {
+        let src = [0, 100, 200, 255];
+        assert_eq!(scale_plane(&src, &job((4, 1), (2, 1))), [50, 228]);
+    }
+
+    #[test]
+    fn ten_bit_to_eight_bit_rounds() {
+        let src: Vec<u8> = [0u16, 2, 1023, 512]
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect();
+        let job = PlaneJob {
+            src_bytes: 2,
+            shift: 2,
+            ..job((4, 1), (4, 1))
+        };
+
+        assert_eq!(scale_plane(&src, &job), [0, 1, 255, 128]);
+    }
+
+    #[test]
+    fn dithering_keeps_the_average_of_gradients() {
+        // A flat 10-bit value a quarter of the way between two 8-bit ones,
+        // which rounding flattens to the lower one.
+        let src: Vec<u8> = std::iter::repeat_n(401u16.to_le_bytes(), 64)
+            .flatten()
+            .collect();
+        let plain = PlaneJob {
+            src_bytes: 2,
+            shift: 2,
+            ..job((8, 8), (8, 8))
+        };
+        assert!(scale_plane(&src, &plain)
+            .iter()
+            .all(|&sample| sample == 100));
+
+        // About a quarter of the samples go up, fewer with error diffusion,
+        // which drops the errors carried past the edges.
+        for (dither, expected) in [
+            (Dither::Ordered, 16..=16),
+            (Dither::ErrorDiffusion, 12..=16),
+        ] {
+            let dithered = scale_plane(&src, &PlaneJob { dither, ..plain });
+            let higher = dithered.iter().filter(|&&sample| sample == 101).count();
+            assert!(dithered
+                .iter()
+                .all(|&sample| sample == 100 || sample == 101));
+            assert!(expected.contains(&higher), "{:?}: {}", dither, higher);
+        }
+    }
+
+    #[test]
+    fn converts_between_ranges() {
+        let [luma, chroma] = Levels::between(ColorRange::Limited, ColorRange::Full, 8).unwrap();
+        let full = |levels: &Levels, values: [u32; 4]| values.map(|value| levels.apply(value));
+        assert_eq!(full(&luma, [0, 16, 126, 235]), [0, 0, 128, 255]);
+        assert_eq!(full(&chroma, [16, 128, 240, 255]), [0, 128, 255, 255]);
+
+        let [luma, _] = Levels::between(ColorRange::Full, ColorRange::Limited, 10).unwrap();
+        assert_eq!(full(&luma, [0, 512, 1020, 1023]), [64, 504, 940, 943]);
+        assert!(Levels::between(ColorRange::Full, ColorRange::Full, 8).is_none());
+    }
+
+    #[test]
+    fn alpha_is_scaled_like_luma() {
+        let size = |width, height| PlaneSize { width, height };
+        let scaler = Scaler::new(
+            Colorspace::C420,
+            size(4, 2),
+            size(2, 1),
+            Colorspace::C420,
+            false,
+        )
+        .unwrap();
+
+        let scaled = scaler
+            .scale(DecodedFrame {
+                planes: [vec![0; 8], vec![0; 2], vec![0; 2]],
+                alpha: Some(vec![0, 100, 200, 255, 0, 100, 200, 255]),
+            })
+            .unwrap();
+
+        assert_eq!(scaled.alpha, Some(vec![50, 228]));
+        assert!(scaler
+            .scale(DecodedFrame {
+                planes: [vec![0; 8], vec![0; 2], vec![0; 2]],
+                alpha: None,
+            })
+            .unwrap()
+            .alpha
+            .is_none());
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(
+            parse_size("640x360"),
+            Ok(PlaneSize {
+                width: 640,
+                height: 360
+            })
+        );
+        assert!(parse_size("640").is_err());
+        assert!(parse_size("0x360").is_err());
+    }
+
+    #[cfg(feature = "gpu")]
+    #[test]
+    fn gpu_matches_cpu() {
+        let gpu = match gpu::GpuScaler::new() {
+            Some(gpu) => gpu,
+            None => return,
+        };
+
+        let src: Vec<u8> = (0..64 * 48).map(|i| (i * 7 % 251) as u8).collect();
+        for dst in [(32, 24), (17, 9), (64, 48), (100, 70)] {
+            let job = job((64, 48), dst);
+            assert_eq!(
+                gpu.scale_plane(&src, &job).unwrap(),
+                scale_plane(&src, &job)
+            );
+        }
+
+        let deep: Vec<u8> = (0..64 * 48u32)
+            .flat_map(|i| ((i * 7 % 1021) as u16).to_le_bytes())
+            .collect();
+        let job = PlaneJob {
+            src_bytes: 2,
+            shift: 2,
+            dither: Dither::Ordered,
+            ..job((64, 48), (32, 24))
+        };
+        assert_eq!(
+            gpu.scale_plane(&deep, &job).unwrap(),
+            scale_plane(&deep, &job)
+        );
+    }
+}
@@ -0,0 +1,242 @@
+use super::{read_sample, Dither, Levels, PlaneJob};
+use std::io;
+use std::sync::mpsc::channel;
+use wgpu::util::DeviceExt;
+
+/// WGSL port of `scale_plane`. Samples are widened to `u32` on upload
+/// since WGSL has no 8 or 16-bit storage types.
+const SHADER: &str = r#"
+struct Params {
+    src_width: u32,
+    src_height: u32,
+    dst_width: u32,
+    dst_height: u32,
+    shift: u32,
+    max_value: u32,
+    ordered_dither: u32,
+    pad0: u32,
+    levels_from: i32,
+    levels_to: i32,
+    levels_gain: i32,
+    levels_max: i32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> src: array<u32>;
+@group(0) @binding(2) var<storage, read_write> dst: array<u32>;
+
+fn source_position(d: u32, src_len: u32, dst_len: u32) -> vec2<u32> {
+    let step = i32((src_len << 8u) / dst_len);
+    let position = u32(max(i32(d) * step + step / 2 - 128, 0));
+    return vec2<u32>(min(position >> 8u, src_len - 1u), position & 255u);
+}
+
+fn bayer(x: u32, y: u32) -> u32 {
+    let z = x ^ y;
+    return ((z & 1u) << 5u) | ((y & 1u) << 4u) | ((z & 2u) << 2u) | ((y & 2u) << 1u)
+        | ((z & 4u) >> 1u) | ((y & 4u) >> 2u);
+}
+
+@compute @workgroup_size(8, 8)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    if (id.x >= params.dst_width || id.y >= params.dst_height) {
+        return;
+    }
+
+    let py = source_position(id.y, params.src_height, params.dst_height);
+    let px = source_position(id.x, params.src_width, params.dst_width);
+    let x1 = min(px.x + 1u, params.src_width - 1u);
+    let y1 = min(py.x + 1u, params.src_height - 1u);
+    let row0 = py.x * params.src_width;
+    let row1 = y1 * params.src_width;
+
+    let top = src[row0 + px.x] * (256u - px.y) + src[row0 + x1] * px.y;
+    let bottom = src[row1 + px.x] * (256u - px.y) + src[row1 + x1] * px.y;
+    var value = (top * (256u - py.y) + bottom * py.y + 32768u) >> 16u;
+    value = u32(clamp(
+        (((i32(value) - params.levels_from) * params.levels_gain + 2048) >> 12u) + params.levels_to,
+        0,
+        params.levels_max,
+    ));
+
+    if (params.ordered_dither != 0u && params.shift > 0u) {
+        let threshold = ((2u * bayer(id.x, id.y) + 1u) << params.shift) >> 7u;
+        value = (value + threshold) >> params.shift;
+    } else if (params.shift > 0u) {
+        value = (value + (1u << (params.shift - 1u))) >> params.shift;
+    }
+
+    dst[id.y * params.dst_width + id.x] = min(value, params.max_value);
+}
+"#;
+
+pub struct GpuScaler {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    pipeline: wgpu::ComputePipeline,
+}
+
+fn as_bytes(values: &[u32]) -> Vec<u8> {
+    values
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect()
+}
+
+impl GpuScaler {
+    /// Returns `None` when no usable adapter is present.
+    pub fn new() -> Option<Self> {
+        let instance = wgpu::Instance::default();
+        let adapter =
+            pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default()))?;
+        let (device, queue) =
+            pollster::block_on(adapter.request_device(&wgpu::DeviceDescriptor::default(), None))
+                .ok()?;
+
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("scale"),
+            source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+        });
+
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("scale"),
+            layout: None,
+            module: &module,
+            entry_point: "main",
+        });
+
+        Some(GpuScaler {
+            device,
+            queue,
+            pipeline,
+        })
+    }
+
+    pub fn scale_plane(&self, src: &[u8], job: &PlaneJob) -> io::Result<Vec<u8>> {
+        let dst_samples = job.dst.width * job.dst.height;
+        if dst_samples == 0 || job.src.width == 0 || job.src.height == 0 {
+            return Ok(Vec::new());
+        }
+
+        let samples: Vec<u32> = (0..job.src.width * job.src.height)
+            .map(|index| read_sample(src, index, job.src_bytes))
+            .collect();
+        let max_value = (1u32 << (8 * job.dst_bytes as u32)) - 1;
+        // Without a range conversion the levels leave samples as they are.
+        let levels = job.levels.unwrap_or(Levels {
+            from: 0,
+            to: 0,
+            gain: 4096,
+            max: i32::MAX,
+        });
+        let params = [
+            job.src.width as u32,
+            job.src.height as u32,
+            job.dst.width as u32,
+            job.dst.height as u32,
+            job.shift,
+            max_value,
+            (job.dither == Dither::Ordered) as u32,
+            0,
+            levels.from as u32,
+            levels.to as u32,
+            levels.gain as u32,
+            levels.max as u32,
+        ];
+
+        let params_buffer = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("params"),
+                contents: &as_bytes(&params),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+        let src_buffer = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("src"),
+                contents: &as_bytes(&samples),
+                usage: wgpu::BufferUsages::STORAGE,
+            });
+
+        let size = (dst_samples * 4) as u64;
+        let dst_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("dst"),
+            size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let readback = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("readback"),
+            size,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let layout = self.pipeline.get_bind_group_layout(0);
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("scale"),
+            layout: &layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: params_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: src_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: dst_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: None,
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(
+                (job.dst.width as u32).div_ceil(8),
+                (job.dst.height as u32).div_ceil(8),
+                1,
+            );
+        }
+        encoder.copy_buffer_to_buffer(&dst_buffer, 0, &readback, 0, size);
+        self.queue.submit(Some(encoder.finish()));
+
+        let slice = readback.slice(..);
+        let (sender, receiver) = channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        self.device.poll(wgpu::Maintain::Wait);
+        receiver
+            .recv()
+            .map_err(io::Error::other)?
+            .map_err(|err| io::Error::other(format!("GPU readback failed: {:?}", err)))?;
+
+        let mut out = Vec::with_capacity(dst_samples * job.dst_bytes);
+        {
+            let data = slice.get_mapped_range();
+            for chunk in data.chunks_exact(4) {
+                let value = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+                if job.dst_bytes == 2 {
+                    out.extend_from_slice(&(value as u16).to_le_bytes());
+                } else {
+                    out.push(value as u8);
+                }
+            }
+        }
+        readback.unmap();
+
+        Ok(out)
+    }
+}
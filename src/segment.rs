@@ -0,0 +1,606 @@
+use crate::filter::Deinterlacer;
+use crate::rotate::Rotator;
+use crate::{interrupt, mkv, nut, quality, raw, rgb, SegmentFormat};
+use ffms_segmenter::prefetch::{self, DecodedFrame};
+use ffms_segmenter::scale::Scaler;
+use ffms_segmenter::yuv4mpeg;
+use std::io::{self, Write};
+use std::sync::mpsc::sync_channel;
+use std::thread;
+
+/// Filtered frames waiting for the segment writer.
+const WRITE_QUEUE: usize = 4;
+
+/// Processing stage a frame has reached when handed to an inspector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Decoded,
+    Deinterlaced,
+    Scaled,
+    /// The frame exactly as it is about to be written.
+    Output,
+}
+
+/// Destination of the frames of a segment. Frames are written on a thread
+/// of their own.
+pub trait FrameSink: Send {
+    fn write_frame(&mut self, frame: &DecodedFrame) -> io::Result<()>;
+
+    /// Writes anything held back. Called once after the last frame.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<S: FrameSink + ?Sized> FrameSink for &mut S {
+    fn write_frame(&mut self, frame: &DecodedFrame) -> io::Result<()> {
+        (**self).write_frame(frame)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+}
+
+impl<S: FrameSink + ?Sized> FrameSink for Box<S> {
+    fn write_frame(&mut self, frame: &DecodedFrame) -> io::Result<()> {
+        (**self).write_frame(frame)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+}
+
+/// Holds a segment's frames in memory and writes them last to first, for
+/// `reverse` requests.
+pub struct Reversed<S: FrameSink> {
+    sink: S,
+    frames: Vec<DecodedFrame>,
+}
+
+impl<S: FrameSink> Reversed<S> {
+    pub fn new(sink: S) -> Self {
+        Reversed {
+            sink,
+            frames: Vec::new(),
+        }
+    }
+}
+
+impl<S: FrameSink> FrameSink for Reversed<S> {
+    fn write_frame(&mut self, frame: &DecodedFrame) -> io::Result<()> {
+        self.frames.push(frame.clone());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        while let Some(frame) = self.frames.pop() {
+            self.sink.write_frame(&frame)?;
+        }
+        self.sink.finish()
+    }
+}
+
+/// Writes a segment's frames through and then `times - 1` more times from
+/// memory, for `repeat` requests, so the loop is decoded once.
+pub struct Repeated<S: FrameSink> {
+    sink: S,
+    times: usize,
+    frames: Vec<DecodedFrame>,
+}
+
+impl<S: FrameSink> Repeated<S> {
+    pub fn new(sink: S, times: usize) -> Self {
+        Repeated {
+            sink,
+            times,
+            frames: Vec::new(),
+        }
+    }
+}
+
+impl<S: FrameSink> FrameSink for Repeated<S> {
+    fn write_frame(&mut self, frame: &DecodedFrame) -> io::Result<()> {
+        self.frames.push(frame.clone());
+        self.sink.write_frame(frame)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        for _ in 1..self.times {
+            for frame in &self.frames {
+                self.sink.write_frame(frame)?;
+            }
+        }
+        self.frames.clear();
+        self.sink.finish()
+    }
+}
+
+impl<W: Write + Send> FrameSink for yuv4mpeg::Writer<W> {
+    fn write_frame(&mut self, frame: &DecodedFrame) -> io::Result<()> {
+        yuv4mpeg::Writer::write_frame(self, frame)
+    }
+}
+
+impl<W: Write + Send> FrameSink for mkv::Writer<W> {
+    fn write_frame(&mut self, frame: &DecodedFrame) -> io::Result<()> {
+        mkv::Writer::write_frame(self, frame)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        mkv::Writer::finish(self)
+    }
+}
+
+impl<W: Write + Send> FrameSink for nut::Writer<W> {
+    fn write_frame(&mut self, frame: &DecodedFrame) -> io::Result<()> {
+        nut::Writer::write_frame(self, frame)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        nut::Writer::finish(self)
+    }
+}
+
+impl<W: Write + Send> FrameSink for raw::Writer<W> {
+    fn write_frame(&mut self, frame: &DecodedFrame) -> io::Result<()> {
+        raw::Writer::write_frame(self, frame)
+    }
+}
+
+impl<W: Write + Send> FrameSink for rgb::Writer<W> {
+    fn write_frame(&mut self, frame: &DecodedFrame) -> io::Result<()> {
+        rgb::Writer::write_frame(self, frame)
+    }
+}
+
+/// A y4m writer for frames in `format`.
+pub fn y4m_writer<W: Write>(sink: W, format: &SegmentFormat) -> io::Result<yuv4mpeg::Writer<W>> {
+    yuv4mpeg::Writer::new(
+        sink,
+        format.width,
+        format.height,
+        format.framerate,
+        format.colorspace,
+        format.interlacing,
+        format.y4m_parameters(),
+    )
+}
+
+/// Decodes `start..end` and hands each frame to `writer`, calling `inspect`
+/// with each frame after every processing stage. Frames that fail `gate`
+/// may be substituted before they are written.
+///
+/// The deinterlacer also decodes the frames on either side of the range, so
+/// segment boundaries are filtered the same as the middle of a segment.
+///
+/// Decoding, filtering and writing run on three threads. The decoder runs
+/// up to `prefetch` frames ahead, and the writer up to `WRITE_QUEUE` frames
+/// behind.
+#[allow(clippy::too_many_arguments)]
+pub fn encode_segment(
+    writer: &mut dyn FrameSink,
+    video_source: &mut (dyn prefetch::Decoder + Send),
+    mut deinterlacer: Option<&mut Deinterlacer>,
+    scaler: Option<&Scaler>,
+    rotator: Option<&Rotator>,
+    mut gate: Option<&mut quality::Gate>,
+    start: usize,
+    end: usize,
+    step: usize,
+    prefetch: usize,
+    on_error: prefetch::OnDecodeError,
+    inspect: &mut dyn FnMut(Stage, usize, &DecodedFrame),
+) -> io::Result<()> {
+    let (first, last) = match deinterlacer {
+        Some(_) => (
+            start.saturating_sub(1),
+            (end + 1).min(video_source.frames()),
+        ),
+        None => (start, end),
+    };
+
+    let deinterlacing = deinterlacer.is_some();
+    // Frames dropped by `step` are still decoded and deinterlaced, as
+    // their fields are needed by their neighbours.
+    let written = |frame_number: usize| {
+        (start..end).contains(&frame_number) && (frame_number - start).is_multiple_of(step)
+    };
+
+    let mut process = |frame_number: usize,
+                       decoded: DecodedFrame,
+                       inspect: &mut dyn FnMut(Stage, usize, &DecodedFrame)|
+     -> io::Result<DecodedFrame> {
+        if deinterlacing {
+            inspect(Stage::Deinterlaced, frame_number, &decoded);
+        }
+
+        let decoded = match scaler {
+            Some(scaler) => {
+                let scaled = scaler.scale(decoded)?;
+                inspect(Stage::Scaled, frame_number, &scaled);
+                scaled
+            }
+            None => decoded,
+        };
+
+        let decoded = match rotator {
+            Some(rotator) => rotator.rotate(decoded),
+            None => decoded,
+        };
+
+        let decoded = match gate {
+            Some(ref mut gate) => gate.apply(frame_number, decoded),
+            None => decoded,
+        };
+
+        inspect(Stage::Output, frame_number, &decoded);
+
+        Ok(decoded)
+    };
+
+    thread::scope(|scope| -> io::Result<()> {
+        let (frames, decoder) =
+            prefetch::spawn(scope, video_source, first, last, prefetch, on_error);
+
+        let (output, to_write) = sync_channel::<DecodedFrame>(WRITE_QUEUE);
+        let writing = scope.spawn(move || -> io::Result<()> {
+            for frame in to_write {
+                writer.write_frame(&frame)?;
+            }
+            Ok(())
+        });
+        // Fails once the writer stops, whose error is returned instead.
+        let send = |frame: DecodedFrame| {
+            output
+                .send(frame)
+                .map_err(|_| io::Error::other("Frame writer stopped"))
+        };
+
+        let filtered = (|| -> io::Result<()> {
+            for (frame_number, decoded) in frames {
+                interrupt::check()?;
+
+                if (start..end).contains(&frame_number) {
+                    inspect(Stage::Decoded, frame_number, &decoded);
+                }
+
+                let (frame_number, decoded) = match deinterlacer {
+                    Some(ref mut deinterlacer) => match deinterlacer.push(frame_number, decoded) {
+                        Some(filtered) => filtered,
+                        None => continue,
+                    },
+                    None => (frame_number, decoded),
+                };
+
+                if written(frame_number) {
+                    send(process(frame_number, decoded, inspect)?)?;
+                }
+            }
+
+            if let Some((frame_number, decoded)) = deinterlacer.and_then(|d| d.flush()) {
+                if written(frame_number) {
+                    send(process(frame_number, decoded, inspect)?)?;
+                }
+            }
+
+            Ok(())
+        })();
+        drop(output);
+
+        let written = writing.join().unwrap();
+        let decoded = decoder.join().unwrap();
+        written?;
+        filtered?;
+        decoded
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::Deinterlace;
+    use ffms_segmenter::scale::{plane_sizes, PlaneSize};
+    use ffms_segmenter::yuv4mpeg::{ColorRange, Interlacing};
+    use std::path::PathBuf;
+    use y4m::{Colorspace, Ratio};
+
+    /// Frames of gradients that move every frame, each plane packed at the
+    /// size FFMS2 frames are copied out at. With `padding`, rows are first
+    /// laid out with that many bytes of junk after them, as decoders leave
+    /// them, and copied out by `DecodedFrame::from_planes` as FFMS2 frames
+    /// are. Decoding real videos would need FFMS2 and its decoders in the
+    /// test run.
+    struct Pattern {
+        format: SegmentFormat,
+        frames: usize,
+        padding: usize,
+    }
+
+    impl prefetch::Decoder for Pattern {
+        fn decode(&mut self, frame: usize) -> io::Result<DecodedFrame> {
+            let format = &self.format;
+            let sizes = plane_sizes(format.colorspace, format.width, format.height);
+            let high = format.colorspace.get_bytes_per_sample() == 2;
+
+            let mut planes: [Vec<u8>; 3] = Default::default();
+            for (index, &PlaneSize { width, height }) in sizes.iter().enumerate() {
+                for y in 0..height {
+                    for x in 0..width {
+                        let level = x * 3 + y * 5 + frame * 7 + index * 32;
+                        match high {
+                            true => planes[index].extend_from_slice(
+                                &((level * 4 + index) as u16 % 1024).to_le_bytes(),
+                            ),
+                            false => planes[index].push(level as u8),
+                        }
+                    }
+                }
+            }
+
+            if self.padding == 0 {
+                return Ok(DecodedFrame {
+                    planes,
+                    alpha: None,
+                });
+            }
+
+            let bytes = format.colorspace.get_bytes_per_sample();
+            let padded: Vec<(Vec<u8>, usize)> = planes
+                .iter()
+                .zip(sizes)
+                .map(|(plane, size)| {
+                    let row_bytes = size.width * bytes;
+                    let mut data = Vec::new();
+                    for row in plane.chunks(row_bytes.max(1)) {
+                        data.extend_from_slice(row);
+                        data.resize(data.len() + self.padding, 0xa5);
+                    }
+                    (data, row_bytes + self.padding)
+                })
+                .collect();
+            let layout = match format.colorspace {
+                Colorspace::C420 | Colorspace::C420p10 | Colorspace::C420p12 => (bytes, 1, 1),
+                Colorspace::C422 | Colorspace::C422p10 | Colorspace::C422p12 => (bytes, 1, 0),
+                _ => (bytes, 0, 0),
+            };
+            let plane = |i: usize| {
+                let (data, stride) = &padded[i];
+                (!data.is_empty()).then_some((&data[..], *stride))
+            };
+
+            DecodedFrame::from_planes(
+                [plane(0), plane(1), plane(2), None],
+                format.width,
+                format.height,
+                layout,
+            )
+            .ok_or_else(|| io::Error::other("Padded planes are too short"))
+        }
+
+        fn frames(&self) -> usize {
+            self.frames
+        }
+    }
+
+    fn format(colorspace: Colorspace, width: usize, height: usize) -> SegmentFormat {
+        SegmentFormat {
+            width,
+            height,
+            framerate: Ratio {
+                num: 24000,
+                den: 1001,
+            },
+            colorspace,
+            interlacing: Interlacing::Progressive,
+            color_range: ColorRange::Limited,
+            color: None,
+            sar: None,
+        }
+    }
+
+    /// Compares `written` with `tests/golden/<name>.y4m`, or rewrites the
+    /// file when `UPDATE_GOLDEN` is set, after a change to the output that
+    /// is meant.
+    fn check_golden(name: &str, written: &[u8]) {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/golden")
+            .join(format!("{}.y4m", name));
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::write(&path, written).unwrap();
+            return;
+        }
+
+        let golden = std::fs::read(&path)
+            .unwrap_or_else(|err| panic!("{}: {}, run with UPDATE_GOLDEN=1", path.display(), err));
+        assert!(
+            written == golden,
+            "{} differs from {}",
+            name,
+            path.display()
+        );
+    }
+
+    /// Writes `start..end` of five pattern frames as y4m, with every
+    /// `step`th frame and optionally deinterlaced, scaled or reversed.
+    fn write(
+        format: &SegmentFormat,
+        (start, end, step): (usize, usize, usize),
+        deinterlace: Option<Deinterlace>,
+        scaler: Option<(&Scaler, &SegmentFormat)>,
+        reverse: bool,
+    ) -> Vec<u8> {
+        let mut source = Pattern {
+            format: format.clone(),
+            frames: 5,
+            padding: 0,
+        };
+        let mut deinterlacer = deinterlace.map(|mode| Deinterlacer::new(mode, format));
+        let mut output = scaler.map_or(format, |(_, output)| output).stepped(step);
+        if deinterlacer.is_some() {
+            output.interlacing = Interlacing::Progressive;
+        }
+
+        let mut out = Vec::new();
+        let writer = y4m_writer(&mut out, &output).unwrap();
+        let mut writer: Box<dyn FrameSink + '_> = match reverse {
+            true => Box::new(Reversed::new(writer)),
+            false => Box::new(writer),
+        };
+        encode_segment(
+            writer.as_mut(),
+            &mut source,
+            deinterlacer.as_mut(),
+            scaler.map(|(scaler, _)| scaler),
+            None,
+            None,
+            start,
+            end,
+            step,
+            2,
+            prefetch::OnDecodeError::Abort,
+            &mut |_, _, _| {},
+        )
+        .unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+
+        out
+    }
+
+    #[test]
+    fn writes_golden_colorspaces() {
+        let colorspaces = [
+            ("420", Colorspace::C420),
+            ("420p10", Colorspace::C420p10),
+            ("422", Colorspace::C422),
+            ("mono", Colorspace::Cmono),
+        ];
+        for (name, colorspace) in colorspaces {
+            let written = write(&format(colorspace, 10, 6), (1, 4, 1), None, None, false);
+            check_golden(name, &written);
+        }
+
+        // Odd sizes round the chroma planes up.
+        let written = write(
+            &format(Colorspace::C420, 7, 5),
+            (0, 5, 1),
+            None,
+            None,
+            false,
+        );
+        check_golden("420-7x5", &written);
+    }
+
+    #[test]
+    fn writes_golden_padded_rows() {
+        let formats = [
+            ("420", format(Colorspace::C420, 10, 6), (1, 4)),
+            ("420p10", format(Colorspace::C420p10, 10, 6), (1, 4)),
+            ("422", format(Colorspace::C422, 10, 6), (1, 4)),
+            ("mono", format(Colorspace::Cmono, 10, 6), (1, 4)),
+            ("420-7x5", format(Colorspace::C420, 7, 5), (0, 5)),
+        ];
+        for (name, format, (start, end)) in formats {
+            // Odd paddings leave rows unaligned, as some decoders do.
+            for padding in [3, 32] {
+                let mut source = Pattern {
+                    format: format.clone(),
+                    frames: 5,
+                    padding,
+                };
+                let mut out = Vec::new();
+                let mut writer = y4m_writer(&mut out, &format).unwrap();
+                encode_segment(
+                    &mut writer,
+                    &mut source,
+                    None,
+                    None,
+                    None,
+                    None,
+                    start,
+                    end,
+                    1,
+                    2,
+                    prefetch::OnDecodeError::Abort,
+                    &mut |_, _, _| {},
+                )
+                .unwrap();
+                writer.finish().unwrap();
+
+                check_golden(name, &out);
+            }
+        }
+    }
+
+    #[test]
+    fn writes_golden_filtered_ranges() {
+        let source = format(Colorspace::C420, 10, 6);
+
+        let interlaced = SegmentFormat {
+            interlacing: Interlacing::TopFieldFirst,
+            ..source.clone()
+        };
+        let written = write(
+            &interlaced,
+            (1, 4, 1),
+            Some(Deinterlace::Yadif),
+            None,
+            false,
+        );
+        check_golden("420-yadif", &written);
+
+        check_golden(
+            "420-step2-reversed",
+            &write(&source, (0, 5, 2), None, None, true),
+        );
+
+        let deep = format(Colorspace::C420p10, 10, 6);
+        let scaled = format(Colorspace::C420, 6, 4);
+        let scaler = Scaler::new(
+            deep.colorspace,
+            PlaneSize {
+                width: 10,
+                height: 6,
+            },
+            PlaneSize {
+                width: 6,
+                height: 4,
+            },
+            scaled.colorspace,
+            false,
+        )
+        .unwrap();
+        let written = write(&deep, (0, 3, 1), None, Some((&scaler, &scaled)), false);
+        check_golden("420p10-scaled", &written);
+    }
+
+    /// Keeps the first sample of every frame written to it.
+    struct Samples(Vec<u8>);
+
+    impl FrameSink for Samples {
+        fn write_frame(&mut self, frame: &DecodedFrame) -> io::Result<()> {
+            self.0.push(frame.planes[0][0]);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn repeats_then_reverses() {
+        let frame = |sample: u8| DecodedFrame {
+            planes: [vec![sample], Vec::new(), Vec::new()],
+            alpha: None,
+        };
+
+        let mut samples = Samples(Vec::new());
+        let mut sink = Reversed::new(Repeated::new(&mut samples, 3));
+        for sample in 1..=3 {
+            sink.write_frame(&frame(sample)).unwrap();
+        }
+        sink.finish().unwrap();
+        drop(sink);
+
+        assert_eq!(samples.0, [3, 2, 1, 3, 2, 1, 3, 2, 1]);
+    }
+}
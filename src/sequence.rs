@@ -0,0 +1,233 @@
+use ffms_segmenter::failure::{self, Failure};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A printf pattern of frame numbers such as `%06d`: the text around it
+/// and the digit count, if fixed.
+struct Pattern<'a> {
+    prefix: &'a str,
+    suffix: &'a str,
+    width: Option<usize>,
+}
+
+fn printf_pattern(name: &str) -> Option<Pattern<'_>> {
+    let (prefix, rest) = name.split_once('%')?;
+    let (width, rest) = match rest.split_once('d') {
+        Some(("", rest)) => (None, rest),
+        Some((width, rest)) if width.starts_with('0') => (Some(width.parse().ok()?), rest),
+        _ => return None,
+    };
+
+    Some(Pattern {
+        prefix,
+        suffix: rest,
+        width,
+    })
+}
+
+impl Pattern<'_> {
+    /// The frame number `name` stands for.
+    fn number(&self, name: &str) -> Option<u64> {
+        let digits = name.strip_prefix(self.prefix)?.strip_suffix(self.suffix)?;
+        if digits.is_empty()
+            || !digits.bytes().all(|b| b.is_ascii_digit())
+            || self.width.is_some_and(|width| digits.len() != width)
+        {
+            return None;
+        }
+        digits.parse().ok()
+    }
+}
+
+/// Whether `name` matches `glob`, where `*` stands for any run of
+/// characters and `?` for any one.
+fn glob_match(glob: &[char], name: &[char]) -> bool {
+    match glob.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|skip| glob_match(rest, &name[skip..])),
+        Some(('?', rest)) => !name.is_empty() && glob_match(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && glob_match(rest, &name[1..]),
+    }
+}
+
+fn file_name(input: &Path) -> &str {
+    input
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+}
+
+/// Whether `input` names an image sequence rather than a file: a file name
+/// with a frame number pattern like `%06d`, or with `*` or `?` wildcards.
+pub fn is_sequence(input: &Path) -> bool {
+    let name = file_name(input);
+    printf_pattern(name).is_some() || name.contains(['*', '?'])
+}
+
+/// The images of the sequence `input`, in frame order. Numbered frames
+/// must follow on without a gap; wildcard matches are taken in name order.
+pub fn frames(input: &Path) -> io::Result<Vec<PathBuf>> {
+    let name = file_name(input);
+    let dir = match input.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    let names: Vec<String> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .collect();
+
+    let frames: Vec<String> = match printf_pattern(name) {
+        Some(pattern) => {
+            let mut numbered: Vec<(u64, String)> = names
+                .into_iter()
+                .filter_map(|name| Some((pattern.number(&name)?, name)))
+                .collect();
+            numbered.sort();
+            if let Some(pair) = numbered.windows(2).find(|pair| pair[1].0 != pair[0].0 + 1) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Image sequence {} is missing frame {}",
+                        input.display(),
+                        pair[0].0 + 1
+                    ),
+                ));
+            }
+            numbered.into_iter().map(|(_, name)| name).collect()
+        }
+        None => {
+            let glob: Vec<char> = name.chars().collect();
+            let mut matched: Vec<String> = names
+                .into_iter()
+                .filter(|name| glob_match(&glob, &name.chars().collect::<Vec<_>>()))
+                .collect();
+            matched.sort();
+            matched
+        }
+    };
+
+    if frames.is_empty() {
+        return Err(failure::tag(
+            Failure::InputNotFound,
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No images match {}", input.display()),
+            ),
+        ));
+    }
+
+    Ok(frames.into_iter().map(|name| dir.join(name)).collect())
+}
+
+/// Size of `input` in bytes, that of all its images for a sequence.
+pub fn size(input: &Path) -> io::Result<u64> {
+    if !is_sequence(input) {
+        return Ok(fs::metadata(input)?.len());
+    }
+
+    frames(input)?
+        .iter()
+        .map(|frame| Ok(fs::metadata(frame)?.len()))
+        .sum()
+}
+
+/// A directory of links to the images of a sequence, numbered from 0 so
+/// FFMS2 opens them as one input through FFmpeg's image2 demuxer, whatever
+/// the first frame number or the naming. Removed when dropped.
+pub struct Links {
+    dir: PathBuf,
+    /// The `%08d` pattern of the links, to index in place of the sequence.
+    pub input: PathBuf,
+}
+
+impl Links {
+    #[cfg(unix)]
+    pub fn new(sequence: &Path, dir: PathBuf) -> io::Result<Self> {
+        let frames = frames(sequence)?;
+        let extension = frames[0]
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default()
+            .to_string();
+
+        fs::create_dir_all(&dir)?;
+        let links = Links {
+            input: dir.join(format!("%08d.{}", extension)),
+            dir,
+        };
+        for (i, frame) in frames.iter().enumerate() {
+            std::os::unix::fs::symlink(
+                fs::canonicalize(frame)?,
+                links.dir.join(format!("{:08}.{}", i, extension)),
+            )?;
+        }
+
+        Ok(links)
+    }
+
+    #[cfg(not(unix))]
+    pub fn new(_: &Path, _: PathBuf) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Image sequences need symbolic links, which are only made on Unix",
+        ))
+    }
+}
+
+impl Drop for Links {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_sequence_frames() {
+        let dir =
+            std::env::temp_dir().join(format!("ffms-segmenter-sequence-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "plate.1001.exr",
+            "plate.1000.exr",
+            "plate.999.exr",
+            "notes.txt",
+        ] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+
+        assert!(is_sequence(Path::new("plate.%04d.exr")));
+        assert!(is_sequence(Path::new("plate.*.exr")));
+        assert!(!is_sequence(Path::new("plate.mov")));
+
+        let names = |input: &str| -> Vec<String> {
+            frames(&dir.join(input))
+                .unwrap()
+                .iter()
+                .map(|frame| file_name(frame).to_string())
+                .collect()
+        };
+        assert_eq!(
+            names("plate.%04d.exr"),
+            ["plate.1000.exr", "plate.1001.exr"]
+        );
+        assert_eq!(
+            names("plate.%d.exr"),
+            ["plate.999.exr", "plate.1000.exr", "plate.1001.exr"]
+        );
+        assert_eq!(
+            names("plate.*.exr"),
+            ["plate.1000.exr", "plate.1001.exr", "plate.999.exr"]
+        );
+
+        fs::remove_file(dir.join("plate.1000.exr")).unwrap();
+        assert!(frames(&dir.join("plate.%d.exr")).is_err());
+        assert!(frames(&dir.join("*.png")).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+use ffms_segmenter::log;
+use std::fmt;
+use std::io;
+
+/// Lifecycle of an interactive session.
+///
+/// ```text
+/// Opening --Opened--> Ready --RequestReceived--> Extracting
+///                       ^                            |
+///                       +------RequestFinished-------+
+///
+/// Opening/Ready/Extracting --Failed--> Closing
+/// Ready --InputClosed--> Closing
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Indexing the input and creating the video source.
+    Opening,
+    /// Waiting for the next request on stdin.
+    Ready,
+    /// Writing the segments of a request.
+    Extracting,
+    /// Shutting down; no more requests are accepted.
+    Closing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Opened,
+    RequestReceived,
+    RequestFinished,
+    InputClosed,
+    Failed,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl State {
+    /// Returns the state reached by applying `event`, or `None` if the
+    /// event is not valid in this state.
+    pub fn next(self, event: Event) -> Option<State> {
+        match (self, event) {
+            (State::Opening, Event::Opened) => Some(State::Ready),
+            (State::Ready, Event::RequestReceived) => Some(State::Extracting),
+            (State::Ready, Event::InputClosed) => Some(State::Closing),
+            (State::Extracting, Event::RequestFinished) => Some(State::Ready),
+            (State::Opening | State::Ready | State::Extracting, Event::Failed) => {
+                Some(State::Closing)
+            }
+            _ => None,
+        }
+    }
+}
+
+pub struct Session {
+    state: State,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Session {
+            state: State::Opening,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Applies `event`, logging the transition. Invalid transitions leave
+    /// the state untouched and return an error.
+    pub fn handle(&mut self, event: Event) -> io::Result<State> {
+        let next = self.state.next(event).ok_or_else(|| {
+            io::Error::other(format!(
+                "Invalid session event {} in state {}",
+                event, self.state
+            ))
+        })?;
+
+        log!(
+            Info,
+            "session" { from: self.state, to: next },
+            "Session: {} -> {} ({})",
+            self.state,
+            next,
+            event
+        );
+        self.state = next;
+
+        Ok(next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_cycle_returns_to_ready() {
+        let mut session = Session::new();
+
+        assert_eq!(session.handle(Event::Opened).unwrap(), State::Ready);
+        assert_eq!(
+            session.handle(Event::RequestReceived).unwrap(),
+            State::Extracting
+        );
+        assert_eq!(
+            session.handle(Event::RequestFinished).unwrap(),
+            State::Ready
+        );
+        assert_eq!(session.handle(Event::InputClosed).unwrap(), State::Closing);
+    }
+
+    #[test]
+    fn failure_closes_from_any_active_state() {
+        for state in [State::Opening, State::Ready, State::Extracting] {
+            assert_eq!(state.next(Event::Failed), Some(State::Closing));
+        }
+        assert_eq!(State::Closing.next(Event::Failed), None);
+    }
+
+    #[test]
+    fn invalid_event_keeps_state() {
+        let mut session = Session::new();
+
+        assert!(session.handle(Event::RequestReceived).is_err());
+        assert_eq!(session.state(), State::Opening);
+    }
+}
@@ -0,0 +1,86 @@
+use crate::scale::{parse_size, PlaneSize};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Session state persisted with `--session`, so a restarted process can pick
+/// up where the previous one stopped.
+///
+/// The file is plain text with one `<key> <value>` entry per line:
+/// `input`, `track`, `scale`, a `source <path>` line per additional input
+/// and a `pending <command>` line per request that had not finished.
+#[derive(Default)]
+pub struct SavedSession {
+    pub input: Option<PathBuf>,
+    pub track: Option<usize>,
+    pub scale: Option<PlaneSize>,
+    pub sources: Vec<PathBuf>,
+    pub pending: Vec<String>,
+}
+
+impl SavedSession {
+    /// Loads the session at `path`, or returns `None` if it does not exist
+    /// yet.
+    pub fn load(path: &Path) -> io::Result<Option<Self>> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let mut saved = SavedSession::default();
+
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid session line: {}", line),
+                )
+            };
+
+            let (key, value) = line.split_once(' ').ok_or_else(invalid)?;
+            match key {
+                "input" => saved.input = Some(PathBuf::from(value)),
+                "track" => saved.track = Some(value.parse().map_err(|_| invalid())?),
+                "scale" => saved.scale = Some(parse_size(value).map_err(|_| invalid())?),
+                "source" => saved.sources.push(PathBuf::from(value)),
+                "pending" => saved.pending.push(value.to_string()),
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(Some(saved))
+    }
+
+    /// Writes the session to a temporary file and renames it over `path`, so
+    /// a crash while saving never leaves a truncated session behind.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut text = String::from("# ffms-segmenter session\n");
+
+        if let Some(ref input) = self.input {
+            text += &format!("input {}\n", input.display());
+        }
+        if let Some(track) = self.track {
+            text += &format!("track {}\n", track);
+        }
+        if let Some(scale) = self.scale {
+            text += &format!("scale {}x{}\n", scale.width, scale.height);
+        }
+        for source in &self.sources {
+            text += &format!("source {}\n", source.display());
+        }
+        for command in &self.pending {
+            text += &format!("pending {}\n", command);
+        }
+
+        let temp_path = path.with_extension("tmp");
+        let mut file = fs::File::create(&temp_path)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(temp_path, path)
+    }
+}
@@ -0,0 +1,87 @@
+use crate::mkv::Audio;
+use crate::Source;
+use std::io;
+
+/// Parses the `--silence-threshold` level in dBFS below which audio is
+/// silent.
+pub fn parse_level(level: &str) -> Result<f64, String> {
+    match level.trim_end_matches("dB").parse::<f64>() {
+        Ok(level) if level <= 0.0 => Ok(level),
+        _ => Err(format!(
+            "Invalid silence threshold, expected dBFS of 0 or below: {}",
+            level
+        )),
+    }
+}
+
+/// RMS level of `samples` in dBFS, minus infinity for digital silence.
+fn level_db(samples: &[f32]) -> f64 {
+    let sum: f64 = samples.iter().map(|&sample| (sample as f64).powi(2)).sum();
+    let rms = (sum / samples.len().max(1) as f64).sqrt();
+    20.0 * rms.log10()
+}
+
+/// Whether the audio played during each frame of `start..end` of the
+/// first `--audio-track` is quieter than `level` dBFS.
+pub fn silent_frames(
+    source: &Source,
+    start: usize,
+    end: usize,
+    level: f64,
+) -> io::Result<Vec<bool>> {
+    let audio = source.audio.first().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The input has no indexed audio track to detect silence in",
+        )
+    })?;
+
+    (start..end)
+        .map(|frame| {
+            let samples = Audio::read(audio, &source.track, frame, frame + 1, source.total_frames)?;
+            Ok(level_db(&samples.to_f32()) < level)
+        })
+        .collect()
+}
+
+/// The frames both an interval of `black` and one of `silent` cover, as
+/// `(first, last)` intervals. Both must be in order.
+pub fn overlaps(black: &[(usize, usize)], silent: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    let mut overlaps = Vec::new();
+    let (mut b, mut s) = (0, 0);
+
+    while b < black.len() && s < silent.len() {
+        let first = black[b].0.max(silent[s].0);
+        let last = black[b].1.min(silent[s].1);
+        if first <= last {
+            overlaps.push((first, last));
+        }
+        if black[b].1 < silent[s].1 {
+            b += 1;
+        } else {
+            s += 1;
+        }
+    }
+
+    overlaps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_silent_black_frames() {
+        assert_eq!(level_db(&[0.5, -0.5]), 20.0 * 0.5f64.log10());
+        assert_eq!(level_db(&[0.0; 4]), f64::NEG_INFINITY);
+        assert_eq!(parse_level("-50dB"), Ok(-50.0));
+        assert!(parse_level("6").is_err());
+
+        let black = [(10, 20), (40, 45), (60, 60)];
+        let silent = [(0, 12), (18, 42), (50, 70)];
+        assert_eq!(
+            overlaps(&black, &silent),
+            [(10, 12), (18, 20), (40, 42), (60, 60)]
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use crate::{pixel_format_name, source_frame, Source};
+use ffms2::frame::Frame;
+use ffms_segmenter::failure::{self, Failure};
+use std::io::{self, BufWriter, Write};
+
+/// Parses a `<start>-<end>` frame range.
+pub fn parse_range(range: &str) -> Result<(usize, usize), String> {
+    let invalid = || format!("Invalid range, expected START-END: {}", range);
+    let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+    let start = start.parse::<usize>().map_err(|_| invalid())?;
+    let end = end.parse::<usize>().map_err(|_| invalid())?;
+
+    if end <= start {
+        return Err(invalid());
+    }
+
+    Ok((start, end))
+}
+
+/// Prints `<frame> <type> <pts> <keyframe>` for every frame in
+/// `start..end`. The picture type is only known after decoding, so every
+/// frame in the range is decoded. FFMS2 does not expose encoded packet
+/// sizes, so those are not included.
+pub fn print(source: &mut Source, start: usize, end: usize) -> io::Result<()> {
+    let end = end.min(source.total_frames);
+    let mut out = BufWriter::new(io::stdout().lock());
+
+    for frame_number in start..end {
+        let frame = Frame::GetFrame(&mut source.video_source, frame_number).map_err(|err| {
+            failure::tag(
+                Failure::Decode,
+                io::Error::other(format!(
+                    "Failed to decode frame {}: {:?}",
+                    frame_number, err
+                )),
+            )
+        })?;
+        let info = source.track.FrameInfo(frame_number);
+
+        let picture_type = match frame.PictType as u8 {
+            0 => '?',
+            byte => byte as char,
+        };
+
+        writeln!(
+            out,
+            "{} {} {} {}",
+            frame_number,
+            picture_type,
+            info.PTS,
+            info.KeyFrame()
+        )?;
+    }
+
+    out.flush()
+}
+
+/// Answers `frame [<input>] <n>` with
+/// `frame <n> type=<type> pts=<pts> keyframe=<0|1> width=<w> height=<h>
+/// pixel_format=<name>` for frame `n` of the timeline of `source`, and
+/// `source=<frame>` when `--cfr` or `--ivtc` put it on another frame of the
+/// input. The frame is decoded for its picture type, coded size and pixel
+/// format; the rest comes from the index.
+pub fn describe_frame(source: &mut Source, frame_number: usize) -> io::Result<String> {
+    if frame_number >= source.total_frames {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Frame {} is past the end of the input, which has {} frames",
+                frame_number, source.total_frames
+            ),
+        ));
+    }
+
+    let input_frame = source_frame(source, frame_number);
+    let frame = Frame::GetFrame(&mut source.video_source, input_frame).map_err(|err| {
+        failure::tag(
+            Failure::Decode,
+            io::Error::other(format!("Failed to decode frame {}: {:?}", input_frame, err)),
+        )
+    })?;
+    let info = source.track.FrameInfo(input_frame);
+
+    let picture_type = match frame.PictType as u8 {
+        0 => '?',
+        byte => byte as char,
+    };
+    let retimed = match input_frame {
+        frame if frame != frame_number => format!(" source={}", frame),
+        _ => String::new(),
+    };
+
+    Ok(format!(
+        "frame {} type={} pts={} keyframe={} width={} height={} pixel_format={}{}",
+        frame_number,
+        picture_type,
+        info.PTS,
+        info.KeyFrame(),
+        frame.EncodedWidth,
+        frame.EncodedHeight,
+        pixel_format_name(frame.EncodedPixelFormat),
+        retimed
+    ))
+}
@@ -0,0 +1,378 @@
+use crate::{SegmentFormat, Source};
+use ffms_segmenter::log;
+use ffms_segmenter::prefetch::{DecodedFrame, Decoder};
+use ffms_segmenter::scale::plane_sizes;
+use ffms_segmenter::yuv4mpeg::ColorRange;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The image files of the `frames` command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StillFormat {
+    /// 16-bit RGB TIFF, keeping the source's transfer.
+    Tiff,
+    /// Half float RGB OpenEXR, linearized.
+    Exr,
+}
+
+pub fn parse_still_format(format: &str) -> Result<StillFormat, String> {
+    match format {
+        "tiff" => Ok(StillFormat::Tiff),
+        "exr" => Ok(StillFormat::Exr),
+        _ => Err(format!("Unknown image format: {}", format)),
+    }
+}
+
+impl StillFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            StillFormat::Tiff => "tif",
+            StillFormat::Exr => "exr",
+        }
+    }
+}
+
+/// Kr and Kb of the matrix of `format`, by its size when unspecified as for
+/// thumbnails.
+fn coefficients(format: &SegmentFormat) -> (f32, f32) {
+    match format.color.map(|(matrix, _, _)| matrix) {
+        Some(1) => (0.2126, 0.0722),
+        Some(5 | 6) => (0.299, 0.114),
+        Some(9 | 10) => (0.2627, 0.0593),
+        _ if format.height >= 720 => (0.2126, 0.0722),
+        _ => (0.299, 0.114),
+    }
+}
+
+fn sample(plane: &[u8], bytes: usize, index: usize) -> f32 {
+    match bytes {
+        2 => u16::from_le_bytes([plane[2 * index], plane[2 * index + 1]]) as f32,
+        _ => plane[index] as f32,
+    }
+}
+
+/// Converts a frame of `format` to interleaved RGB from 0 to 1, still in
+/// the transfer of the source, at its full bit depth. Frames of RGB
+/// sources are gbrp and only reordered.
+pub fn to_rgb(frame: &DecodedFrame, format: &SegmentFormat, rgb_source: bool) -> Vec<f32> {
+    let (width, height) = (format.width, format.height);
+    let bytes = format.colorspace.get_bytes_per_sample();
+    let depth = format.colorspace.get_bit_depth();
+    let max = ((1u32 << depth) - 1) as f32;
+    let [_, chroma, _] = plane_sizes(format.colorspace, width, height);
+
+    let (kr, kb) = coefficients(format);
+    let kg = 1.0 - kr - kb;
+    let scale = (1u32 << (depth - 8)) as f32;
+    let (black, luma_range, chroma_range) = match format.color_range {
+        ColorRange::Limited => (16.0 * scale, 219.0 * scale, 224.0 * scale),
+        ColorRange::Full => (0.0, max, max),
+    };
+    let neutral = 128.0 * scale;
+
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            let index = y * width + x;
+            if rgb_source {
+                let [g, b, r] = [0, 1, 2].map(|plane| sample(&frame.planes[plane], bytes, index));
+                rgb.extend([r, g, b].map(|value| value / max));
+                continue;
+            }
+
+            let luma = (sample(&frame.planes[0], bytes, index) - black) / luma_range;
+            let (cb, cr) = match chroma.width {
+                0 => (0.0, 0.0),
+                _ => {
+                    let index =
+                        (y * chroma.height / height) * chroma.width + x * chroma.width / width;
+                    let sample = |plane: usize| {
+                        (sample(&frame.planes[plane], bytes, index) - neutral) / chroma_range
+                    };
+                    (sample(1), sample(2))
+                }
+            };
+
+            let r = luma + 2.0 * (1.0 - kr) * cr;
+            let b = luma + 2.0 * (1.0 - kb) * cb;
+            let g = (luma - kr * r - kb * b) / kg;
+            rgb.extend([r, g, b].map(|value| value.clamp(0.0, 1.0)));
+        }
+    }
+
+    rgb
+}
+
+/// The function taking values of `transfer` to linear light. PQ comes out
+/// with 1.0 at 100 cd/m², HLG scene referred from 0 to 1. Unknown
+/// transfers are taken as BT.709.
+fn linearize(transfer: Option<i32>) -> fn(f32) -> f32 {
+    match transfer {
+        Some(8) => |value| value,
+        Some(4) => |value| value.powf(2.2),
+        Some(5) => |value| value.powf(2.8),
+        Some(13) => |value| match value {
+            value if value <= 0.04045 => value / 12.92,
+            value => ((value + 0.055) / 1.055).powf(2.4),
+        },
+        Some(16) => |value| {
+            let (m1, m2) = (2610.0 / 16384.0, 2523.0 / 4096.0 * 128.0);
+            let (c1, c2, c3) = (
+                3424.0 / 4096.0,
+                2413.0 / 4096.0 * 32.0,
+                2392.0 / 4096.0 * 32.0,
+            );
+            let power = value.powf(1.0 / m2);
+            let nits = ((power - c1).max(0.0) / (c2 - c3 * power)).powf(1.0 / m1) * 10000.0;
+            nits / 100.0
+        },
+        Some(18) => |value| {
+            let (a, b, c) = (0.178_832_77, 0.284_668_92, 0.559_910_7);
+            match value {
+                value if value <= 0.5 => value * value / 3.0,
+                value => (((value - c) / a).exp() + b) / 12.0,
+            }
+        },
+        _ => |value| match value {
+            value if value < 0.081 => value / 4.5,
+            value => ((value + 0.099) / 1.099).powf(1.0 / 0.45),
+        },
+    }
+}
+
+/// The nearest IEEE half float to `value`, saturating at the largest
+/// finite one.
+fn to_half(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let magnitude = value.abs();
+
+    if magnitude.is_nan() {
+        return sign | 0x7e00;
+    }
+    if magnitude >= 65520.0 {
+        return sign | 0x7bff;
+    }
+    if magnitude < 6.103_515_6e-5 {
+        // Subnormal halves count in steps of 2^-24.
+        return sign | (magnitude * 16_777_216.0).round_ties_even() as u16;
+    }
+
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+    let mantissa = bits & 0x7f_ffff;
+    let half = ((exponent as u32) << 10) | (mantissa >> 13);
+    // Round to nearest, ties to even, carrying into the exponent.
+    let rest = mantissa & 0x1fff;
+    let half = match rest {
+        rest if rest > 0x1000 || (rest == 0x1000 && half & 1 == 1) => half + 1,
+        _ => half,
+    };
+
+    sign | half as u16
+}
+
+/// An uncompressed 16-bit RGB TIFF of `rgb`, in one strip.
+pub fn tiff(width: usize, height: usize, rgb: &[f32]) -> Vec<u8> {
+    // Header, then the bits per sample, then the pixels, then the IFD.
+    let bits_offset = 8u32;
+    let pixels_offset = bits_offset + 6;
+    let pixels_len = (width * height * 6) as u32;
+    let ifd_offset = pixels_offset + pixels_len;
+
+    let mut out = b"II*\0".to_vec();
+    out.extend(ifd_offset.to_le_bytes());
+    for _ in 0..3 {
+        out.extend(16u16.to_le_bytes());
+    }
+    for &value in rgb {
+        out.extend(((value.clamp(0.0, 1.0) * 65535.0).round() as u16).to_le_bytes());
+    }
+
+    // Tag, type (3 for SHORT, 4 for LONG), count and value, by tag.
+    let entries: [(u16, u16, u32, u32); 10] = [
+        (256, 4, 1, width as u32),
+        (257, 4, 1, height as u32),
+        (258, 3, 3, bits_offset),
+        (259, 3, 1, 1),
+        (262, 3, 1, 2),
+        (273, 4, 1, pixels_offset),
+        (277, 3, 1, 3),
+        (278, 4, 1, height as u32),
+        (279, 4, 1, pixels_len),
+        (284, 3, 1, 1),
+    ];
+    out.extend((entries.len() as u16).to_le_bytes());
+    for (tag, kind, count, value) in entries {
+        out.extend(tag.to_le_bytes());
+        out.extend(kind.to_le_bytes());
+        out.extend(count.to_le_bytes());
+        match (kind, count) {
+            // A single SHORT sits in the first half of the value.
+            (3, 1) => out.extend([(value as u16).to_le_bytes(), [0, 0]].concat()),
+            _ => out.extend(value.to_le_bytes()),
+        }
+    }
+    out.extend(0u32.to_le_bytes());
+
+    out
+}
+
+fn exr_attribute(out: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    out.extend(name.as_bytes());
+    out.push(0);
+    out.extend(kind.as_bytes());
+    out.push(0);
+    out.extend((value.len() as i32).to_le_bytes());
+    out.extend(value);
+}
+
+/// An uncompressed scanline OpenEXR of `rgb` as half floats.
+pub fn exr(width: usize, height: usize, rgb: &[f32]) -> Vec<u8> {
+    let mut out = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+
+    let mut channels = Vec::new();
+    for name in ["B", "G", "R"] {
+        channels.extend(name.as_bytes());
+        channels.push(0);
+        // HALF, not linear, reserved, x and y sampling.
+        channels.extend(1i32.to_le_bytes());
+        channels.extend([0; 4]);
+        channels.extend(1i32.to_le_bytes());
+        channels.extend(1i32.to_le_bytes());
+    }
+    channels.push(0);
+    let window: Vec<u8> = [0, 0, width as i32 - 1, height as i32 - 1]
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect();
+
+    exr_attribute(&mut out, "channels", "chlist", &channels);
+    exr_attribute(&mut out, "compression", "compression", &[0]);
+    exr_attribute(&mut out, "dataWindow", "box2i", &window);
+    exr_attribute(&mut out, "displayWindow", "box2i", &window);
+    exr_attribute(&mut out, "lineOrder", "lineOrder", &[0]);
+    exr_attribute(&mut out, "pixelAspectRatio", "float", &1f32.to_le_bytes());
+    exr_attribute(&mut out, "screenWindowCenter", "v2f", &[0; 8]);
+    exr_attribute(&mut out, "screenWindowWidth", "float", &1f32.to_le_bytes());
+    out.push(0);
+
+    // One line per block: its number, size and the channels in turn.
+    let line_len = 8 + width * 6;
+    let first_line = out.len() + height * 8;
+    for y in 0..height {
+        out.extend(((first_line + y * line_len) as u64).to_le_bytes());
+    }
+    for y in 0..height {
+        out.extend((y as i32).to_le_bytes());
+        out.extend(((width * 6) as i32).to_le_bytes());
+        let row = &rgb[y * width * 3..(y + 1) * width * 3];
+        for channel in [2, 1, 0] {
+            for pixel in row.chunks_exact(3) {
+                out.extend(to_half(pixel[channel]).to_le_bytes());
+            }
+        }
+    }
+
+    out
+}
+
+/// Writes frames `start..end` of `source` to `folder` as numbered images,
+/// named after their frame number, for pulling plates. Returns how many
+/// were written.
+pub fn write(
+    source: &mut Source,
+    start: usize,
+    end: usize,
+    folder: &Path,
+    still_format: StillFormat,
+) -> io::Result<usize> {
+    let end = end.min(source.total_frames);
+    fs::create_dir_all(folder)?;
+
+    let format = source.format.clone();
+    let transfer = format.color.map(|(_, _, transfer)| transfer);
+    if still_format == StillFormat::Exr && !matches!(transfer, Some(1 | 4..=8 | 13..=16 | 18)) {
+        log!(
+            Warn,
+            "Unknown transfer of the input, linearizing it as BT.709"
+        );
+    }
+    let linear = linearize(transfer);
+
+    for frame_number in start..end {
+        let frame = source.video_source.decode(frame_number)?;
+        let rgb = to_rgb(&frame, &format, source.rgb);
+
+        let image = match still_format {
+            StillFormat::Tiff => tiff(format.width, format.height, &rgb),
+            StillFormat::Exr => {
+                let rgb: Vec<f32> = rgb.into_iter().map(linear).collect();
+                exr(format.width, format.height, &rgb)
+            }
+        };
+        let path = folder.join(format!("{:06}.{}", frame_number, still_format.extension()));
+        fs::write(path, image)?;
+    }
+
+    Ok(end.saturating_sub(start))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ffms_segmenter::yuv4mpeg::Interlacing;
+    use y4m::{Colorspace, Ratio};
+
+    #[test]
+    fn converts_to_full_precision_rgb() {
+        let format = SegmentFormat {
+            width: 2,
+            height: 1,
+            framerate: Ratio { num: 24, den: 1 },
+            colorspace: Colorspace::C444p10,
+            interlacing: Interlacing::Progressive,
+            color_range: ColorRange::Limited,
+            color: Some((1, 1, 1)),
+            sar: None,
+        };
+        let plane = |values: [u16; 2]| values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let frame = DecodedFrame {
+            planes: [plane([64, 940]), plane([512, 512]), plane([512, 512])],
+            alpha: None,
+        };
+
+        assert_eq!(
+            to_rgb(&frame, &format, false),
+            [0.0, 0.0, 0.0, 1.0, 1.0, 1.0]
+        );
+        assert_eq!(linearize(Some(1))(1.0), 1.0);
+        assert!((linearize(Some(16))(0.508) - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn encodes_half_floats() {
+        assert_eq!(to_half(0.0), 0);
+        assert_eq!(to_half(1.0), 0x3c00);
+        assert_eq!(to_half(-2.0), 0xc000);
+        assert_eq!(to_half(0.5), 0x3800);
+        assert_eq!(to_half(1e6), 0x7bff);
+        assert_eq!(to_half(5.960_464_5e-8), 1);
+    }
+
+    #[test]
+    fn lays_out_images() {
+        let rgb = [1.0; 6];
+
+        let tiff = tiff(2, 1, &rgb);
+        assert_eq!(&tiff[..4], b"II*\0");
+        assert_eq!(tiff.len(), 8 + 6 + 12 + 2 + 10 * 12 + 4);
+        assert_eq!(&tiff[14..16], [0xff, 0xff]);
+
+        let exr = exr(2, 1, &rgb);
+        assert_eq!(&exr[..4], [0x76, 0x2f, 0x31, 0x01]);
+        let offset = u64::from_le_bytes(exr[exr.len() - 28..exr.len() - 20].try_into().unwrap());
+        assert_eq!(offset as usize, exr.len() - 20);
+        assert_eq!(&exr[exr.len() - 2..], 0x3c00u16.to_le_bytes());
+    }
+}
@@ -0,0 +1,253 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// A cue shown from `start` until `end`, in milliseconds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cue {
+    start: u64,
+    end: u64,
+    /// The text of an SRT cue, or the fields of an ASS `Dialogue` line
+    /// after its times.
+    text: String,
+}
+
+/// A `--subtitles` file, cut into per-segment files with their cues
+/// retimed to the segment.
+#[derive(Debug)]
+pub enum Subtitles {
+    Srt(Vec<Cue>),
+    /// Everything but the `Dialogue` lines is copied to every segment.
+    Ass {
+        header: String,
+        cues: Vec<Cue>,
+    },
+}
+
+fn invalid(path: &Path, line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid subtitle line in {}: {}", path.display(), line),
+    )
+}
+
+/// Parses SRT `HH:MM:SS,mmm` and ASS `H:MM:SS.cc` times.
+fn parse_time(time: &str) -> Option<u64> {
+    let (clock, fraction) = time.trim().split_once([',', '.'])?;
+    let mut parts = clock.split(':').map(|part| part.parse::<u64>().ok());
+    let (hours, minutes, seconds) = (parts.next()??, parts.next()??, parts.next()??);
+    if parts.next().is_some() {
+        return None;
+    }
+
+    // Centiseconds in ASS, milliseconds in SRT.
+    let fraction_ms = match fraction.len() {
+        2 => fraction.parse::<u64>().ok()? * 10,
+        3 => fraction.parse::<u64>().ok()?,
+        _ => return None,
+    };
+
+    Some(((hours * 60 + minutes) * 60 + seconds) * 1000 + fraction_ms)
+}
+
+fn srt_time(ms: u64) -> String {
+    format!(
+        "{:02}:{:02}:{:02},{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+fn ass_time(ms: u64) -> String {
+    format!(
+        "{}:{:02}:{:02}.{:02}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000 / 10
+    )
+}
+
+fn parse_srt(path: &Path, contents: &str) -> io::Result<Vec<Cue>> {
+    let mut cues = Vec::new();
+
+    // Blocks of an index, a `start --> end` line and the text.
+    for block in contents.replace("\r\n", "\n").split("\n\n") {
+        let mut lines = block.trim_matches('\n').lines();
+        let timing = match (lines.next(), lines.next()) {
+            (Some(_), Some(timing)) => timing,
+            (None, _) => continue,
+            (Some(line), None) => return Err(invalid(path, line)),
+        };
+        let (start, end) = timing
+            .split_once("-->")
+            .and_then(|(start, end)| Some((parse_time(start)?, parse_time(end)?)))
+            .ok_or_else(|| invalid(path, timing))?;
+
+        cues.push(Cue {
+            start,
+            end,
+            text: lines.collect::<Vec<&str>>().join("\n"),
+        });
+    }
+
+    Ok(cues)
+}
+
+fn parse_ass(path: &Path, contents: &str) -> io::Result<(String, Vec<Cue>)> {
+    let mut header = String::new();
+    let mut cues = Vec::new();
+
+    for line in contents.lines() {
+        let dialogue = match line.strip_prefix("Dialogue:") {
+            Some(dialogue) => dialogue,
+            None => {
+                header.push_str(line);
+                header.push('\n');
+                continue;
+            }
+        };
+
+        // Layer, Start, End and the rest of the fields.
+        let fields: Vec<&str> = dialogue.splitn(4, ',').collect();
+        let cue = match fields[..] {
+            [layer, start, end, rest] => {
+                parse_time(start)
+                    .zip(parse_time(end))
+                    .map(|(start, end)| Cue {
+                        start,
+                        end,
+                        text: format!("{},{}", layer.trim(), rest),
+                    })
+            }
+            _ => None,
+        };
+        cues.push(cue.ok_or_else(|| invalid(path, line))?);
+    }
+
+    Ok((header, cues))
+}
+
+impl Subtitles {
+    /// Reads an `.srt` or `.ass` file.
+    pub fn load(path: &Path) -> io::Result<Subtitles> {
+        let contents = fs::read_to_string(path)?;
+        // A UTF-8 byte order mark would end up in the first cue.
+        let contents = contents.trim_start_matches('\u{feff}');
+
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("srt") => Ok(Subtitles::Srt(parse_srt(path, contents)?)),
+            Some("ass") | Some("ssa") => {
+                let (header, cues) = parse_ass(path, contents)?;
+                Ok(Subtitles::Ass { header, cues })
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Subtitles must be .srt or .ass: {}", path.display()),
+            )),
+        }
+    }
+
+    /// Extension of the per-segment files.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Subtitles::Srt(_) => "srt",
+            Subtitles::Ass { .. } => "ass",
+        }
+    }
+
+    /// The cues showing between `start` and `end` milliseconds, with times
+    /// relative to `start` and clipped to the segment. `end` is `None` for a
+    /// segment running to the end of the input.
+    pub fn cut(&self, start: u64, end: Option<u64>) -> String {
+        let end = end.unwrap_or(u64::MAX);
+        let retimed = |cues: &[Cue]| -> Vec<Cue> {
+            cues.iter()
+                .filter(|cue| cue.start < end && cue.end > start)
+                .map(|cue| Cue {
+                    start: cue.start.max(start) - start,
+                    end: cue.end.min(end) - start,
+                    text: cue.text.clone(),
+                })
+                .collect()
+        };
+
+        match self {
+            Subtitles::Srt(cues) => retimed(cues)
+                .iter()
+                .enumerate()
+                .map(|(n, cue)| {
+                    format!(
+                        "{}\n{} --> {}\n{}\n\n",
+                        n + 1,
+                        srt_time(cue.start),
+                        srt_time(cue.end),
+                        cue.text
+                    )
+                })
+                .collect(),
+            Subtitles::Ass { header, cues } => {
+                let mut out = header.clone();
+                for cue in retimed(cues) {
+                    // Put the layer back in front of the times.
+                    let (layer, rest) = cue.text.split_once(',').unwrap_or_default();
+                    out.push_str(&format!(
+                        "Dialogue: {},{},{},{}\n",
+                        layer,
+                        ass_time(cue.start),
+                        ass_time(cue.end),
+                        rest
+                    ));
+                }
+                out
+            }
+        }
+    }
+
+    /// Writes the cut for a segment to `path`.
+    pub fn write(&self, path: &Path, start: u64, end: Option<u64>) -> io::Result<()> {
+        fs::write(path, self.cut(start, end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retimes_srt_cues_to_the_segment() {
+        let srt = "1\n00:00:01,000 --> 00:00:03,500\nFirst\n\n\
+                   2\n00:00:04,000 --> 00:00:06,000\nSecond\nline\n\n\
+                   3\n00:01:00,000 --> 00:01:02,000\nLater\n";
+        let subtitles = Subtitles::Srt(parse_srt(Path::new("t.srt"), srt).unwrap());
+
+        assert_eq!(
+            subtitles.cut(3000, Some(5000)),
+            "1\n00:00:00,000 --> 00:00:00,500\nFirst\n\n\
+             2\n00:00:01,000 --> 00:00:02,000\nSecond\nline\n\n"
+        );
+        assert_eq!(
+            subtitles.cut(61_000, None),
+            "1\n00:00:00,000 --> 00:00:01,000\nLater\n\n"
+        );
+        assert!(parse_srt(Path::new("t.srt"), "1\nnot a time\nText\n").is_err());
+    }
+
+    #[test]
+    fn keeps_the_ass_header() {
+        let ass = "[Script Info]\nTitle: t\n\n[Events]\n\
+                   Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n\
+                   Dialogue: 0,0:00:02.50,0:00:04.00,Default,,0,0,0,,Hello, world\n";
+        let (header, cues) = parse_ass(Path::new("t.ass"), ass).unwrap();
+        let subtitles = Subtitles::Ass { header, cues };
+
+        assert_eq!(
+            subtitles.cut(2000, Some(3000)),
+            "[Script Info]\nTitle: t\n\n[Events]\n\
+             Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n\
+             Dialogue: 0,0:00:00.50,0:00:01.00,Default,,0,0,0,,Hello, world\n"
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use crate::png;
+use crate::rgb;
+use crate::Source;
+use ffms2::frame::Frame;
+use ffms_segmenter::failure::{self, Failure};
+use ffms_segmenter::prefetch::DecodedFrame;
+use ffms_segmenter::scale::{self, plane_sizes, PlaneSize, Scaler};
+use ffms_segmenter::yuv4mpeg::ColorRange;
+use std::io;
+use std::path::Path;
+use y4m::Colorspace;
+
+/// Converts an 8-bit frame to RGB, using the BT.709 matrix for HD sizes
+/// and BT.601 otherwise.
+pub fn to_rgb(
+    frame: &DecodedFrame,
+    colorspace: Colorspace,
+    color_range: ColorRange,
+    width: usize,
+    height: usize,
+) -> Vec<u8> {
+    let [_, chroma, _] = plane_sizes(colorspace, width, height);
+    let (kr, kb) = if height >= 720 {
+        (0.2126, 0.0722)
+    } else {
+        (0.299, 0.114)
+    };
+    let kg = 1.0 - kr - kb;
+    let (black, luma_scale, chroma_scale) = match color_range {
+        ColorRange::Limited => (16.0, 255.0 / 219.0, 255.0 / 224.0),
+        ColorRange::Full => (0.0, 1.0, 1.0),
+    };
+
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            let luma = (frame.planes[0][y * width + x] as f32 - black) * luma_scale;
+
+            let (cb, cr) = match chroma.width {
+                0 => (0.0, 0.0),
+                _ => {
+                    let index =
+                        (y * chroma.height / height) * chroma.width + x * chroma.width / width;
+                    let sample =
+                        |plane: usize| (frame.planes[plane][index] as f32 - 128.0) * chroma_scale;
+                    (sample(1), sample(2))
+                }
+            };
+
+            let r = luma + 2.0 * (1.0 - kr) * cr;
+            let b = luma + 2.0 * (1.0 - kb) * cb;
+            let g = (luma - kr * r - kb * b) / kg;
+
+            rgb.extend([r, g, b].map(|value| value.round().clamp(0.0, 255.0) as u8));
+        }
+    }
+
+    rgb
+}
+
+/// Decodes `frame_number` of `source` and writes it to `path` as a PNG,
+/// resized to `size` if given.
+pub fn write(
+    source: &mut Source,
+    frame_number: usize,
+    path: &Path,
+    size: Option<PlaneSize>,
+    use_gpu: bool,
+) -> io::Result<()> {
+    if frame_number >= source.total_frames {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Frame {} is past the {} frames of the input",
+                frame_number, source.total_frames
+            ),
+        ));
+    }
+
+    let mut frame = Frame::GetFrame(&mut source.video_source, frame_number).map_err(|err| {
+        failure::tag(
+            Failure::Decode,
+            io::Error::other(format!(
+                "Failed to decode frame {}: {:?}",
+                frame_number, err
+            )),
+        )
+    })?;
+    let decoded = DecodedFrame::from_frame(&mut frame);
+
+    let format = &source.format;
+    let src = PlaneSize {
+        width: format.width,
+        height: format.height,
+    };
+    let dst = size.unwrap_or(src);
+    let colorspace = scale::to_8bit(format.colorspace);
+    let scaled = Scaler::new(format.colorspace, src, dst, colorspace, use_gpu)?.scale(decoded)?;
+
+    let rgb = if source.rgb {
+        rgb::interleave_gbr(&scaled)
+    } else {
+        to_rgb(
+            &scaled,
+            colorspace,
+            format.color_range,
+            dst.width,
+            dst.height,
+        )
+    };
+
+    png::write_rgb(path, dst.width, dst.height, &rgb)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_limited_range_extremes() {
+        let frame = DecodedFrame {
+            planes: [vec![16, 235, 16, 235], vec![128], vec![128]],
+            alpha: None,
+        };
+
+        assert_eq!(
+            to_rgb(&frame, Colorspace::C420, ColorRange::Limited, 2, 2),
+            [0, 0, 0, 255, 255, 255, 0, 0, 0, 255, 255, 255]
+        );
+        assert_eq!(
+            to_rgb(&frame, Colorspace::C420, ColorRange::Full, 2, 2),
+            [16, 16, 16, 235, 235, 235, 16, 16, 16, 235, 235, 235]
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use ffms2::track::Track;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Presentation time of `frame` in milliseconds.
+pub fn frame_time_ms(track: &Track, frame: usize) -> f64 {
+    let time_base = track.TimeBase();
+    let pts = track.FrameInfo(frame).PTS;
+
+    pts as f64 * time_base.Num as f64 / time_base.Den as f64
+}
+
+/// Milliseconds from the first frame to `frame`. Past the last frame,
+/// frames are taken to last as long as the last one did, so the end of a
+/// segment running to the end of the track has a time too.
+pub fn elapsed_ms(track: &Track, frame: usize) -> f64 {
+    let frames = track.NumFrames();
+    let first = frame_time_ms(track, 0);
+    if frame < frames || frames < 2 {
+        return frame_time_ms(track, frame.min(frames.saturating_sub(1))) - first;
+    }
+
+    let last = frame_time_ms(track, frames - 1);
+    let duration = last - frame_time_ms(track, frames - 2);
+    last - first + duration * (frame - (frames - 1)) as f64
+}
+
+/// `ms` as a timecode that can be part of a file name, as
+/// `00h01m23s456`.
+pub fn file_timecode(ms: u64) -> String {
+    format!(
+        "{:02}h{:02}m{:02}s{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+/// The audio sample at `sample_rate` played with `frame`, counted from the
+/// first frame. It is worked out from the PTS in integers, as milliseconds
+/// in floats land a sample off now and then, and the segments on either
+/// side of a frame must agree on its sample.
+pub fn frame_sample(track: &Track, frame: usize, sample_rate: u32) -> i64 {
+    let time_base = track.TimeBase();
+    let pts = track.FrameInfo(frame).PTS - track.FrameInfo(0).PTS;
+
+    pts_sample(pts, (time_base.Num, time_base.Den), sample_rate)
+}
+
+/// The sample nearest to `pts` of a track whose `time_base` counts
+/// milliseconds, halves rounded up.
+fn pts_sample(pts: i64, (num, den): (i64, i64), sample_rate: u32) -> i64 {
+    let scaled = pts as i128 * num as i128 * sample_rate as i128;
+    let per_sample = den as i128 * 1000;
+
+    (2 * scaled + per_sample).div_euclid(2 * per_sample) as i64
+}
+
+/// Writes an mkvmerge timecodes v2 file for every `step`th frame of
+/// `start..end`, with times relative to the first frame of the range.
+pub fn write_v2(
+    track: &Track,
+    start: usize,
+    end: usize,
+    step: usize,
+    path: &Path,
+) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+
+    writeln!(file, "# timecode format v2")?;
+
+    let first = frame_time_ms(track, start);
+    for frame in (start..end).step_by(step) {
+        writeln!(file, "{:.6}", frame_time_ms(track, frame) - first)?;
+    }
+
+    file.flush()
+}
+
+/// Writes the original PTS and presentation time in milliseconds of every
+/// `step`th frame of `start..end`, one `<frame> <pts> <time>` line each,
+/// after a comment with the time base the PTS count in.
+pub fn write_pts(
+    track: &Track,
+    start: usize,
+    end: usize,
+    step: usize,
+    path: &Path,
+) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+
+    let time_base = track.TimeBase();
+    writeln!(
+        file,
+        "# frame pts time_ms, pts in units of {}/{} ms",
+        time_base.Num, time_base.Den
+    )?;
+
+    for frame in (start..end).step_by(step) {
+        writeln!(
+            file,
+            "{} {} {:.6}",
+            frame,
+            track.FrameInfo(frame).PTS,
+            frame_time_ms(track, frame)
+        )?;
+    }
+
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_pts_to_samples_exactly() {
+        // 1/90000 s ticks at 29.97 fps, 3003 ticks a frame.
+        let time_base = (1, 90);
+        assert_eq!(pts_sample(3003, time_base, 48000), 1602);
+        assert_eq!(pts_sample(3003 * 1000, time_base, 48000), 1_601_600);
+        assert_eq!(pts_sample(3 * 3003, time_base, 48000), 4805);
+        assert_eq!(pts_sample(-3003, time_base, 48000), -1602);
+        assert_eq!(pts_sample(1, (1, 1), 2000), 2);
+    }
+
+    #[test]
+    fn names_files_by_timecode() {
+        assert_eq!(file_timecode(83_456), "00h01m23s456");
+        assert_eq!(file_timecode(120_000), "00h02m00s000");
+        assert_eq!(file_timecode(36_000_001), "10h00m00s001");
+    }
+}
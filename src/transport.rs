@@ -0,0 +1,1042 @@
+use crate::interrupt;
+use ffms_segmenter::log;
+use ffms_segmenter::log::json_string;
+use std::cmp::Reverse;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Messages are capped to keep a corrupt length prefix from allocating
+/// unbounded memory.
+const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+/// Handled commands kept for `status`.
+const STATUS_HISTORY: usize = 100;
+
+/// Returned by [`ChannelTransport::receive`] after the idle time given to
+/// [`ChannelTransport::idle_command`] passes without a command.
+pub const IDLE_COMMAND: &str = "evict";
+
+/// Carries protocol commands in and responses out.
+pub trait Transport {
+    /// Returns the next command, or `None` once the input is closed.
+    fn receive(&mut self) -> io::Result<Option<String>>;
+
+    fn send(&mut self, message: &str) -> io::Result<()>;
+
+    /// Called for each frame written while handling the last received
+    /// command.
+    fn progress(&mut self, _frame: usize) {}
+
+    /// Called once the last received command has been handled.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Newline separated text, one command or response per line. Blank lines
+/// and lines starting with `#` are skipped, so request files can be
+/// commented.
+pub struct LineTransport<R, W> {
+    reader: R,
+    writer: W,
+    format: Format,
+}
+
+impl<R: BufRead, W: Write> LineTransport<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        LineTransport {
+            reader,
+            writer,
+            format: Format::Plain,
+        }
+    }
+
+    /// Writes responses in `format` instead of as they are sent.
+    pub fn formatted(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+}
+
+impl<R: BufRead, W: Write> Transport for LineTransport<R, W> {
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+
+            let command = line.trim();
+            if !command.is_empty() && !command.starts_with('#') {
+                return Ok(Some(command.to_string()));
+            }
+        }
+    }
+
+    fn send(&mut self, message: &str) -> io::Result<()> {
+        writeln!(self.writer, "{}", format_response(self.format, message))?;
+        self.writer.flush()
+    }
+}
+
+/// Each message is a little endian `u32` byte length followed by that many
+/// bytes of UTF-8, so commands and responses never depend on whitespace or
+/// line endings.
+pub struct FramedTransport<R, W> {
+    reader: R,
+    writer: W,
+    format: Format,
+}
+
+impl<R: Read, W: Write> FramedTransport<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        FramedTransport {
+            reader,
+            writer,
+            format: Format::Plain,
+        }
+    }
+
+    /// Writes responses in `format` instead of as they are sent.
+    pub fn formatted(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+}
+
+impl<R: Read, W: Write> Transport for FramedTransport<R, W> {
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        let mut length = [0u8; 4];
+        match self.reader.read_exact(&mut length) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+
+        let length = u32::from_le_bytes(length) as usize;
+        if length > MAX_MESSAGE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Message of {} bytes is too long", length),
+            ));
+        }
+
+        let mut message = vec![0; length];
+        self.reader.read_exact(&mut message)?;
+
+        String::from_utf8(message)
+            .map(Some)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn send(&mut self, message: &str) -> io::Result<()> {
+        let message = format_response(self.format, message);
+        self.writer
+            .write_all(&(message.len() as u32).to_le_bytes())?;
+        self.writer.write_all(message.as_bytes())?;
+        self.writer.flush()
+    }
+}
+
+/// The `<width> <height> <frames> <fps den> <fps num>` response announcing
+/// an opened input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Properties {
+    pub width: u64,
+    pub height: u64,
+    pub frames: u64,
+    pub fps_den: u64,
+    pub fps_num: u64,
+}
+
+impl Properties {
+    pub fn parse(message: &str) -> Option<Self> {
+        let fields = message
+            .split_whitespace()
+            .map(|field| field.parse::<u64>().ok())
+            .collect::<Option<Vec<u64>>>()?;
+
+        match fields[..] {
+            [width, height, frames, fps_den, fps_num] => Some(Properties {
+                width,
+                height,
+                frames,
+                fps_den,
+                fps_num,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Whether `message` is a `<start> <end> <reason>` refusal.
+fn is_refusal(message: &str) -> bool {
+    let mut tokens = message.splitn(3, ' ');
+    tokens
+        .next()
+        .is_some_and(|start| start.parse::<u64>().is_ok())
+        && tokens.next().is_some_and(|end| end.parse::<u64>().is_ok())
+        && tokens.next().is_some()
+}
+
+/// How [`LineTransport`] and [`FramedTransport`] write responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// As sent: space separated fields.
+    Plain,
+    /// One JSON object per response, with its `type`.
+    Json,
+    /// Tab separated fields, with tabs, newlines and backslashes in them
+    /// escaped.
+    Tsv,
+}
+
+pub fn parse_format(format: &str) -> Result<Format, String> {
+    match format {
+        "plain" => Ok(Format::Plain),
+        "json" => Ok(Format::Json),
+        "tsv" => Ok(Format::Tsv),
+        _ => Err(format!("Invalid response format: {}", format)),
+    }
+}
+
+fn tsv_field(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+/// `message` in `format`. Properties, segments and refusals get their own
+/// fields; other responses are split into their first word, as the JSON
+/// `type`, and the rest. Responses that are JSON already are left as is.
+pub fn format_response(format: Format, message: &str) -> String {
+    if format == Format::Plain || message.starts_with('{') {
+        return message.to_string();
+    }
+
+    if let Some(properties) = Properties::parse(message) {
+        return match format {
+            Format::Json => format!(
+                "{{\"type\":\"properties\",\"width\":{},\"height\":{},\"frames\":{},\"fps_num\":{},\"fps_den\":{}}}",
+                properties.width,
+                properties.height,
+                properties.frames,
+                properties.fps_num,
+                properties.fps_den
+            ),
+            _ => message.split_whitespace().collect::<Vec<_>>().join("\t"),
+        };
+    }
+
+    if let Some((start, path)) = parse_segment(message) {
+        return match format {
+            Format::Json => format!(
+                "{{\"type\":\"segment\",\"start\":{},\"path\":{}}}",
+                start,
+                json_string(path)
+            ),
+            _ => format!("{}\t{}", start, tsv_field(path)),
+        };
+    }
+
+    if is_refusal(message) {
+        let mut fields = message.splitn(3, ' ');
+        let (start, end) = (fields.next().unwrap(), fields.next().unwrap());
+        let reason = fields.next().unwrap();
+        return match format {
+            Format::Json => format!(
+                "{{\"type\":\"refused\",\"start\":{},\"end\":{},\"reason\":{}}}",
+                start,
+                end,
+                json_string(reason)
+            ),
+            _ => format!("{}\t{}\t{}", start, end, tsv_field(reason)),
+        };
+    }
+
+    let (kind, rest) = message.split_once(' ').unwrap_or((message, ""));
+    match format {
+        Format::Json => format!(
+            "{{\"type\":{},\"message\":{}}}",
+            json_string(kind),
+            json_string(rest)
+        ),
+        _ => format!("{}\t{}", tsv_field(kind), tsv_field(rest)),
+    }
+}
+
+/// Parses the `<start> <path>` response sent for each segment written.
+/// Refusals, which read `<start> <end> <reason>`, are not segments.
+pub fn parse_segment(message: &str) -> Option<(u64, &str)> {
+    if is_refusal(message) {
+        return None;
+    }
+
+    let (start, path) = message.split_once(' ')?;
+    Some((start.parse().ok()?, path))
+}
+
+/// A response to a command sent through a [`Client`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reply {
+    Message(String),
+    /// A frame has been written.
+    Progress(usize),
+    /// The command has been handled; no more messages follow.
+    Finished,
+}
+
+struct Command {
+    id: u64,
+    priority: usize,
+    line: String,
+    replies: Sender<Reply>,
+}
+
+/// What [`Client::request`] does when the queue of a bounded
+/// [`ChannelTransport`] is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueFull {
+    /// Fail with a `queue full` error.
+    Reject,
+    /// Wait for the session to take a queued command.
+    Block,
+}
+
+pub fn parse_queue_full(mode: &str) -> Result<QueueFull, String> {
+    match mode {
+        "reject" => Ok(QueueFull::Reject),
+        "block" => Ok(QueueFull::Block),
+        _ => Err(format!("Invalid queue full mode: {}", mode)),
+    }
+}
+
+/// The `priority=<n>` of a command, 0 without one.
+fn priority(line: &str) -> usize {
+    line.split_whitespace()
+        .find_map(|token| token.strip_prefix("priority="))
+        .and_then(|priority| priority.parse().ok())
+        .unwrap_or(0)
+}
+
+/// What `status` reports of a command taken by the session.
+struct Record {
+    id: u64,
+    line: String,
+    frame: Option<usize>,
+    segments: Vec<(u64, String)>,
+    errors: Vec<String>,
+    cancelled: bool,
+    finished: bool,
+}
+
+impl Record {
+    fn new(id: u64, line: &str) -> Self {
+        Record {
+            id,
+            line: line.to_string(),
+            frame: None,
+            segments: Vec::new(),
+            errors: Vec::new(),
+            cancelled: false,
+            finished: false,
+        }
+    }
+
+    /// Files written and refusals count; other responses, such as the
+    /// properties `open` answers with, are left to the client.
+    fn message(&mut self, message: &str) {
+        if let Some((start, path)) = parse_segment(message) {
+            self.segments.push((start, path.to_string()));
+        } else if message.starts_with("cancelled ") || message.ends_with(" cancelled") {
+            self.cancelled = true;
+        } else if is_refusal(message) || message.starts_with("error ") {
+            self.errors.push(message.to_string());
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let state = match (self.finished, self.cancelled, self.errors.is_empty()) {
+            (_, true, _) => "cancelled",
+            (false, _, _) => "running",
+            (true, _, true) => "done",
+            (true, _, false) => "failed",
+        };
+        let frame = match self.frame {
+            Some(frame) => frame.to_string(),
+            None => "null".to_string(),
+        };
+        let segments: Vec<String> = self
+            .segments
+            .iter()
+            .map(|(start, path)| format!("{{\"start\":{},\"path\":{}}}", start, json_string(path)))
+            .collect();
+        let errors: Vec<String> = self.errors.iter().map(|error| json_string(error)).collect();
+
+        format!(
+            "{{\"id\":{},\"request\":{},\"state\":\"{}\",\"frame\":{},\"segments\":[{}],\"errors\":[{}]}}",
+            self.id,
+            json_string(&self.line),
+            state,
+            frame,
+            segments.join(","),
+            errors.join(",")
+        )
+    }
+}
+
+struct QueueState {
+    /// Commands not yet taken by the session, in the order they arrived.
+    commands: Vec<Command>,
+    running: Option<Record>,
+    /// The last `STATUS_HISTORY` commands handled, oldest first.
+    completed: VecDeque<Record>,
+    capacity: Option<(usize, QueueFull)>,
+    clients: usize,
+    /// Set once the session is gone.
+    closed: bool,
+    /// Set by [`Drainer::drain`]: no more commands are taken and the
+    /// session ends once the queued ones are handled.
+    draining: bool,
+}
+
+impl QueueState {
+    fn completed(&mut self, record: Record) {
+        if self.completed.len() == STATUS_HISTORY {
+            self.completed.pop_front();
+        }
+        self.completed.push_back(record);
+    }
+
+    /// One line of JSON listing every command remembered: the handled
+    /// ones, the one being handled and the queued ones, in that order.
+    fn status(&self) -> String {
+        let queued = self.commands.iter().map(|command| {
+            format!(
+                "{{\"id\":{},\"request\":{},\"state\":\"queued\",\"frame\":null,\"segments\":[],\"errors\":[]}}",
+                command.id,
+                json_string(&command.line)
+            )
+        });
+        let requests: Vec<String> = self
+            .completed
+            .iter()
+            .chain(&self.running)
+            .map(Record::to_json)
+            .chain(queued)
+            .collect();
+
+        format!("{{\"requests\":[{}]}}", requests.join(","))
+    }
+}
+
+struct Queue {
+    state: Mutex<QueueState>,
+    /// Signalled when a command is queued or the last client goes.
+    added: Condvar,
+    /// Signalled when the session takes a command or goes.
+    taken: Condvar,
+}
+
+impl Queue {
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap()
+    }
+}
+
+/// Receives commands from [`Client`]s on other threads and routes each
+/// response back to the client that sent the command being handled.
+pub struct ChannelTransport {
+    queue: Arc<Queue>,
+    current: Option<Sender<Reply>>,
+    announced: Arc<Mutex<Vec<String>>>,
+    idle: Option<Duration>,
+}
+
+/// Sends commands to a session driven by a [`ChannelTransport`]. Commands
+/// from all clients are handled one at a time, highest `priority=<n>`
+/// first and otherwise in the order they arrive.
+pub struct Client {
+    queue: Arc<Queue>,
+    announced: Arc<Mutex<Vec<String>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+pub fn channel_transport() -> (ChannelTransport, Client) {
+    transport_pair(None)
+}
+
+/// A channel transport queueing at most `capacity` commands besides the
+/// one being handled, so clients cannot grow the queue without bound.
+pub fn bounded_channel_transport(capacity: usize, full: QueueFull) -> (ChannelTransport, Client) {
+    transport_pair(Some((capacity, full)))
+}
+
+fn transport_pair(capacity: Option<(usize, QueueFull)>) -> (ChannelTransport, Client) {
+    let queue = Arc::new(Queue {
+        state: Mutex::new(QueueState {
+            commands: Vec::new(),
+            running: None,
+            completed: VecDeque::new(),
+            capacity,
+            clients: 1,
+            closed: false,
+            draining: false,
+        }),
+        added: Condvar::new(),
+        taken: Condvar::new(),
+    });
+    let announced = Arc::new(Mutex::new(Vec::new()));
+
+    (
+        ChannelTransport {
+            queue: queue.clone(),
+            current: None,
+            announced: announced.clone(),
+            idle: None,
+        },
+        Client {
+            queue,
+            announced,
+            next_id: Arc::new(AtomicU64::new(0)),
+        },
+    )
+}
+
+impl ChannelTransport {
+    /// Makes [`Transport::receive`] return [`IDLE_COMMAND`] whenever `idle`
+    /// passes without a command, so the session can tidy up while waiting.
+    pub fn idle_command(mut self, idle: Duration) -> Self {
+        self.idle = Some(idle);
+        self
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        let mut state = self.queue.lock();
+
+        loop {
+            let next = state
+                .commands
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, command)| (command.priority, Reverse(command.id)))
+                .map(|(i, _)| i);
+
+            match next {
+                Some(i) => {
+                    let command = state.commands.remove(i);
+                    state.running = Some(Record::new(command.id, &command.line));
+                    self.queue.taken.notify_all();
+                    self.current = Some(command.replies);
+                    return Ok(Some(command.line));
+                }
+                None if state.clients == 0 => return Ok(None),
+                None if state.draining => {
+                    log!(
+                        Info,
+                        "drained" { requests: state.completed.len() },
+                        "Drained, final status: {}",
+                        state.status()
+                    );
+                    return Ok(None);
+                }
+                None => match self.idle {
+                    Some(idle) => {
+                        let (guard, wait) = self.queue.added.wait_timeout(state, idle).unwrap();
+                        state = guard;
+                        if wait.timed_out() && state.commands.is_empty() {
+                            return Ok(Some(IDLE_COMMAND.to_string()));
+                        }
+                    }
+                    None => state = self.queue.added.wait(state).unwrap(),
+                },
+            }
+        }
+    }
+
+    fn send(&mut self, message: &str) -> io::Result<()> {
+        match self.current {
+            // A client that stopped listening does not stop the session.
+            Some(ref replies) => {
+                if let Some(ref mut record) = self.queue.lock().running {
+                    record.message(message);
+                }
+                let _ = replies.send(Reply::Message(message.to_string()));
+            }
+            None => self.announced.lock().unwrap().push(message.to_string()),
+        }
+
+        Ok(())
+    }
+
+    fn progress(&mut self, frame: usize) {
+        if let Some(ref replies) = self.current {
+            if let Some(ref mut record) = self.queue.lock().running {
+                record.frame = Some(frame);
+            }
+            let _ = replies.send(Reply::Progress(frame));
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if let Some(replies) = self.current.take() {
+            let _ = replies.send(Reply::Finished);
+        }
+
+        // A cancel arriving as the command finished must not abort the
+        // next one.
+        let mut state = self.queue.lock();
+        if let Some(mut record) = state.running.take() {
+            record.finished = true;
+            state.completed(record);
+        }
+        interrupt::clear_cancel();
+
+        Ok(())
+    }
+}
+
+impl Drop for ChannelTransport {
+    /// Queued commands are dropped, which disconnects their receivers
+    /// without `Finished`.
+    fn drop(&mut self) {
+        let mut state = self.queue.lock();
+        state.closed = true;
+        state.commands.clear();
+        self.queue.taken.notify_all();
+    }
+}
+
+impl Client {
+    /// Queues `line` as a command. The returned receiver yields its
+    /// responses and then [`Reply::Finished`]; it disconnects without
+    /// `Finished` if the session fails first.
+    ///
+    /// The returned id is the one [`Client::cancel`] takes. `cancel <id>`
+    /// lines are handled here rather than queued, answered with
+    /// `cancelling <id>` or `cancel <id> unknown`, and so is `status`,
+    /// answered with the JSON document described in the README.
+    pub fn request(&self, line: &str) -> io::Result<(u64, Receiver<Reply>)> {
+        let (replies, receiver) = channel();
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+
+        if let Some(target) = line.trim().strip_prefix("cancel ") {
+            let message = match target.trim().parse() {
+                Ok(target) if self.cancel(target) => format!("cancelling {}", target),
+                _ => format!("cancel {} unknown", target.trim()),
+            };
+            let _ = replies.send(Reply::Message(message));
+            let _ = replies.send(Reply::Finished);
+            return Ok((id, receiver));
+        }
+
+        if line.trim() == "status" {
+            let _ = replies.send(Reply::Message(self.status()));
+            let _ = replies.send(Reply::Finished);
+            return Ok((id, receiver));
+        }
+
+        let mut state = self.queue.lock();
+        loop {
+            if state.closed {
+                return Err(io::Error::other("Session has closed"));
+            }
+            if state.draining {
+                return Err(io::Error::new(
+                    io::ErrorKind::ResourceBusy,
+                    "Shutting down, no more requests are taken",
+                ));
+            }
+
+            match state.capacity {
+                Some((capacity, full)) if state.commands.len() >= capacity => match full {
+                    QueueFull::Reject => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::ResourceBusy,
+                            "Request queue is full",
+                        ))
+                    }
+                    QueueFull::Block => state = self.queue.taken.wait(state).unwrap(),
+                },
+                _ => break,
+            }
+        }
+
+        state.commands.push(Command {
+            id,
+            priority: priority(line),
+            line: line.to_string(),
+            replies,
+        });
+        self.queue.added.notify_all();
+
+        Ok((id, receiver))
+    }
+
+    /// Drops the queued command `id`, answering it with `cancelled <id>`,
+    /// or aborts it between frames if the session is handling it, removing
+    /// the partial segment. Returns whether `id` was queued or running.
+    pub fn cancel(&self, id: u64) -> bool {
+        let mut state = self.queue.lock();
+
+        if state.running.as_ref().is_some_and(|record| record.id == id) {
+            interrupt::cancel();
+            return true;
+        }
+
+        match state.commands.iter().position(|command| command.id == id) {
+            Some(i) => {
+                let command = state.commands.remove(i);
+                let _ = command
+                    .replies
+                    .send(Reply::Message(format!("cancelled {}", id)));
+                let _ = command.replies.send(Reply::Finished);
+                let mut record = Record::new(id, &command.line);
+                record.cancelled = true;
+                state.completed(record);
+                self.queue.taken.notify_all();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Messages the session sent outside of any command, such as the
+    /// properties of the inputs opened at startup.
+    pub fn announced(&self) -> Vec<String> {
+        self.announced.lock().unwrap().clone()
+    }
+
+    /// One line of JSON describing the commands handled, being handled
+    /// and queued.
+    pub fn status(&self) -> String {
+        self.queue.lock().status()
+    }
+
+    /// Number of commands queued or being handled.
+    pub fn pending(&self) -> usize {
+        let state = self.queue.lock();
+        state.commands.len() + state.running.is_some() as usize
+    }
+
+    /// A handle for ending the session that, unlike a `Client`, does not
+    /// keep its input open.
+    pub fn drainer(&self) -> Drainer {
+        Drainer {
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+/// Ends a session driven by a [`ChannelTransport`] gracefully: see
+/// [`Drainer::drain`].
+pub struct Drainer {
+    queue: Arc<Queue>,
+}
+
+impl Drainer {
+    /// Refuses further commands as busy, so clients are told to go
+    /// elsewhere, while the queued ones and the one being handled go on.
+    /// [`Transport::receive`] then returns `None` once the queue is empty,
+    /// logging the final status.
+    pub fn drain(&self) {
+        let mut state = self.queue.lock();
+        state.draining = true;
+        self.queue.added.notify_all();
+        self.queue.taken.notify_all();
+    }
+
+    /// Number of commands queued or being handled.
+    pub fn pending(&self) -> usize {
+        let state = self.queue.lock();
+        state.commands.len() + state.running.is_some() as usize
+    }
+}
+
+impl Clone for Client {
+    fn clone(&self) -> Self {
+        self.queue.lock().clients += 1;
+
+        Client {
+            queue: self.queue.clone(),
+            announced: self.announced.clone(),
+            next_id: self.next_id.clone(),
+        }
+    }
+}
+
+impl Drop for Client {
+    /// The session's input closes with the last client.
+    fn drop(&mut self) {
+        self.queue.lock().clients -= 1;
+        self.queue.added.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn framed_round_trip() {
+        let mut out = Vec::new();
+        {
+            let mut transport = FramedTransport::new(io::empty(), &mut out);
+            transport.send("0 100").unwrap();
+            transport.send("with\nnewline ").unwrap();
+        }
+
+        let mut transport = FramedTransport::new(&out[..], io::sink());
+        assert_eq!(transport.receive().unwrap().as_deref(), Some("0 100"));
+        assert_eq!(
+            transport.receive().unwrap().as_deref(),
+            Some("with\nnewline ")
+        );
+        assert_eq!(transport.receive().unwrap(), None);
+    }
+
+    #[test]
+    fn lines_skip_blanks_and_comments() {
+        let input = "\n# intro\n  0   24 \r\n\t\n   # credits\n24 48\n";
+        let mut transport = LineTransport::new(input.as_bytes(), io::sink());
+
+        assert_eq!(transport.receive().unwrap().as_deref(), Some("0   24"));
+        assert_eq!(transport.receive().unwrap().as_deref(), Some("24 48"));
+        assert_eq!(transport.receive().unwrap(), None);
+    }
+
+    #[test]
+    fn channel_routes_replies_to_sender() {
+        let (mut transport, client) = channel_transport();
+
+        transport.send("1920 1080 100 1 24").unwrap();
+        let (_, replies) = client.request("0 10").unwrap();
+        assert_eq!(client.pending(), 1);
+
+        assert_eq!(transport.receive().unwrap().as_deref(), Some("0 10"));
+        transport.progress(9);
+        transport.send("0 ./0-10.y4m").unwrap();
+        transport.finish().unwrap();
+
+        assert_eq!(
+            replies.iter().collect::<Vec<_>>(),
+            vec![
+                Reply::Progress(9),
+                Reply::Message("0 ./0-10.y4m".into()),
+                Reply::Finished
+            ]
+        );
+        assert_eq!(client.announced(), vec!["1920 1080 100 1 24"]);
+        assert_eq!(client.pending(), 0);
+
+        drop(client);
+        assert_eq!(transport.receive().unwrap(), None);
+    }
+
+    #[test]
+    fn full_queues_reject_commands() {
+        let (mut transport, client) = bounded_channel_transport(1, QueueFull::Reject);
+
+        let _first = client.request("0 10").unwrap();
+        let err = client.request("10 20").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+        assert_eq!(client.pending(), 1);
+
+        // Taking the command frees its place in the queue.
+        assert_eq!(transport.receive().unwrap().as_deref(), Some("0 10"));
+        let _second = client.request("10 20").unwrap();
+        assert_eq!(client.pending(), 2);
+    }
+
+    #[test]
+    fn cancelled_commands_are_skipped() {
+        let (mut transport, client) = channel_transport();
+
+        let (first, _) = client.request("0 10").unwrap();
+        let (second, replies) = client.request("10 20").unwrap();
+        let _third = client.request("20 30").unwrap();
+
+        let cancel = client.request(&format!("cancel {}", second)).unwrap().1;
+        assert_eq!(
+            cancel.iter().collect::<Vec<_>>(),
+            vec![
+                Reply::Message(format!("cancelling {}", second)),
+                Reply::Finished
+            ]
+        );
+        assert!(!client.cancel(99));
+
+        assert_eq!(transport.receive().unwrap().as_deref(), Some("0 10"));
+        transport.finish().unwrap();
+        assert_eq!(transport.receive().unwrap().as_deref(), Some("20 30"));
+        assert_eq!(
+            replies.iter().collect::<Vec<_>>(),
+            vec![
+                Reply::Message(format!("cancelled {}", second)),
+                Reply::Finished
+            ]
+        );
+        assert!(!client.cancel(first));
+        assert_eq!(client.pending(), 1);
+    }
+
+    #[test]
+    fn higher_priorities_are_received_first() {
+        let (mut transport, client) = channel_transport();
+
+        client.request("0 100").unwrap();
+        client.request("100 200").unwrap();
+        client.request("500 510 priority=2").unwrap();
+        client.request("600 610 priority=1").unwrap();
+
+        let mut received = Vec::new();
+        while client.pending() > 0 {
+            received.push(transport.receive().unwrap().unwrap());
+            transport.finish().unwrap();
+        }
+        assert_eq!(
+            received,
+            vec![
+                "500 510 priority=2",
+                "600 610 priority=1",
+                "0 100",
+                "100 200"
+            ]
+        );
+
+        drop(client);
+        assert_eq!(transport.receive().unwrap(), None);
+    }
+
+    #[test]
+    fn idle_sessions_receive_the_idle_command() {
+        let (transport, client) = channel_transport();
+        let mut transport = transport.idle_command(Duration::from_millis(1));
+
+        assert_eq!(transport.receive().unwrap().as_deref(), Some(IDLE_COMMAND));
+        client.request("0 10").unwrap();
+        assert_eq!(transport.receive().unwrap().as_deref(), Some("0 10"));
+    }
+
+    #[test]
+    fn draining_finishes_queued_requests() {
+        let (mut transport, client) = channel_transport();
+        let drainer = client.drainer();
+
+        client.request("0 10").unwrap();
+        let (_, queued) = client.request("10 20").unwrap();
+        assert_eq!(transport.receive().unwrap().as_deref(), Some("0 10"));
+        drainer.drain();
+
+        let refused = client.request("20 30").unwrap_err();
+        assert_eq!(refused.kind(), io::ErrorKind::ResourceBusy);
+        assert_eq!(drainer.pending(), 2);
+
+        transport.finish().unwrap();
+        assert_eq!(transport.receive().unwrap().as_deref(), Some("10 20"));
+        transport.finish().unwrap();
+        assert_eq!(queued.recv().unwrap(), Reply::Finished);
+        // Ends with the client still connected.
+        assert_eq!(transport.receive().unwrap(), None);
+    }
+
+    #[test]
+    fn status_lists_every_request() {
+        let (mut transport, client) = channel_transport();
+
+        client.request("0 10").unwrap();
+        client.request("10 20").unwrap();
+        assert_eq!(transport.receive().unwrap().as_deref(), Some("0 10"));
+        transport.progress(4);
+        transport.send("0 ./0-10.y4m").unwrap();
+        transport.finish().unwrap();
+        assert_eq!(transport.receive().unwrap().as_deref(), Some("10 20"));
+        transport
+            .send("10 20 not yet available, 15 frames available")
+            .unwrap();
+        let (queued, _) = client.request("20 30").unwrap();
+
+        let (_, status) = client.request("status").unwrap();
+        assert_eq!(
+            status.recv().unwrap(),
+            Reply::Message(format!(
+                concat!(
+                    r#"{{"requests":["#,
+                    r#"{{"id":0,"request":"0 10","state":"done","frame":4,"segments":[{{"start":0,"path":"./0-10.y4m"}}],"errors":[]}},"#,
+                    r#"{{"id":1,"request":"10 20","state":"running","frame":null,"segments":[],"errors":["10 20 not yet available, 15 frames available"]}},"#,
+                    r#"{{"id":{},"request":"20 30","state":"queued","frame":null,"segments":[],"errors":[]}}"#,
+                    "]}}"
+                ),
+                queued
+            ))
+        );
+        assert_eq!(status.recv().unwrap(), Reply::Finished);
+        // Answered without queueing.
+        assert_eq!(client.pending(), 2);
+    }
+
+    #[test]
+    fn formats_responses() {
+        let responses = [
+            "1920 1080 100 1 24",
+            "0 ./out/0 10.y4m",
+            "24 48 not yet available, 30 frames available",
+            "opened 1 b.mkv",
+            r#"{"width":1920}"#,
+        ];
+        let formatted = |format| {
+            responses
+                .iter()
+                .map(|response| format_response(format, response))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(formatted(Format::Plain), responses);
+        assert_eq!(
+            formatted(Format::Json),
+            [
+                r#"{"type":"properties","width":1920,"height":1080,"frames":100,"fps_num":24,"fps_den":1}"#,
+                r#"{"type":"segment","start":0,"path":"./out/0 10.y4m"}"#,
+                r#"{"type":"refused","start":24,"end":48,"reason":"not yet available, 30 frames available"}"#,
+                r#"{"type":"opened","message":"1 b.mkv"}"#,
+                r#"{"width":1920}"#,
+            ]
+        );
+        assert_eq!(
+            formatted(Format::Tsv),
+            [
+                "1920\t1080\t100\t1\t24",
+                "0\t./out/0 10.y4m",
+                "24\t48\tnot yet available, 30 frames available",
+                "opened\t1 b.mkv",
+                r#"{"width":1920}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn refusals_are_not_segments() {
+        assert_eq!(parse_segment("24 ./24-48.y4m"), Some((24, "./24-48.y4m")));
+        assert_eq!(
+            parse_segment("24 48 not yet available, 30 frames available"),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_frames() {
+        let input = u32::MAX.to_le_bytes();
+        let mut transport = FramedTransport::new(&input[..], io::sink());
+
+        assert!(transport.receive().is_err());
+    }
+}
@@ -0,0 +1,178 @@
+use crate::manifest::{Entry, Manifest};
+use ffms_segmenter::checksum::HashWriter;
+use ffms_segmenter::log;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// A reader that hashes everything read through it, so a segment is
+/// checksummed in the same pass that counts its frames.
+struct Hashed<R> {
+    inner: R,
+    hasher: HashWriter,
+}
+
+impl<R: Read> Read for Hashed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.write_all(&buf[..read])?;
+        Ok(read)
+    }
+}
+
+/// The stream of the segment at `path`, decompressed again if it was
+/// written with `--compress`, since the manifest describes the stream.
+fn open(path: &Path) -> io::Result<Box<dyn Read>> {
+    let file = File::open(path)?;
+
+    match path.extension().is_some_and(|extension| extension == "zst") {
+        false => Ok(Box::new(file)),
+        #[cfg(feature = "compress")]
+        true => Ok(Box::new(zstd::stream::read::Decoder::new(file)?)),
+        #[cfg(not(feature = "compress"))]
+        true => Err(io::Error::other(
+            "Built without the compress feature, cannot read it",
+        )),
+    }
+}
+
+/// Counts the frames of the y4m stream in `reader`.
+fn count_frames(reader: impl Read) -> Result<usize, y4m::Error> {
+    let mut decoder = y4m::decode(reader)?;
+
+    let mut frames = 0;
+    loop {
+        match decoder.read_frame() {
+            Ok(_) => frames += 1,
+            Err(y4m::Error::EOF) => return Ok(frames),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Everything wrong with the segment `entry` describes, reading it back.
+/// Frames are only counted in y4m segments, the other containers are
+/// checked by size and checksum alone.
+pub fn check(entry: &Entry) -> Vec<String> {
+    let path = Path::new(&entry.path);
+    let mut discrepancies = Vec::new();
+
+    if entry.start >= entry.end {
+        discrepancies.push(format!("empty range {}..{}", entry.start, entry.end));
+    }
+
+    let mut reader = match open(path) {
+        Ok(inner) => Hashed {
+            inner,
+            hasher: HashWriter::new(),
+        },
+        Err(err) => {
+            discrepancies.push(format!("cannot be read: {}", err));
+            return discrepancies;
+        }
+    };
+
+    let stem = path.file_stem().map(Path::new);
+    let y4m = [Some(path), stem]
+        .iter()
+        .flatten()
+        .any(|path| path.extension().is_some_and(|extension| extension == "y4m"));
+    if y4m {
+        match count_frames(&mut reader) {
+            Ok(frames) if frames != entry.frames => discrepancies.push(format!(
+                "{} frames instead of {} for {}..{}",
+                frames, entry.frames, entry.start, entry.end
+            )),
+            Ok(_) => {}
+            Err(err) => discrepancies.push(format!("invalid y4m: {}", err)),
+        }
+    }
+    if let Err(err) = io::copy(&mut reader, &mut io::sink()) {
+        discrepancies.push(format!("cannot be read: {}", err));
+        return discrepancies;
+    }
+
+    let (bytes, crc) = (reader.hasher.bytes, reader.hasher.crc.finish());
+    if bytes != entry.bytes {
+        discrepancies.push(format!("{} bytes instead of {}", bytes, entry.bytes));
+    }
+    if crc != entry.crc {
+        discrepancies.push(format!("CRC-32 {:08x} instead of {:08x}", crc, entry.crc));
+    }
+
+    discrepancies
+}
+
+/// Checks every segment of the `--manifest` at `path` for the `verify`
+/// subcommand, printing `<path>: <discrepancy>` for each thing that does
+/// not match. Returns whether all of them did.
+pub fn run(path: &Path) -> io::Result<bool> {
+    let manifest = Manifest::read(path)?;
+
+    let mut failed = 0;
+    for entry in manifest.entries() {
+        let discrepancies = check(entry);
+        for discrepancy in &discrepancies {
+            println!("{}: {}", entry.path, discrepancy);
+        }
+        failed += usize::from(!discrepancies.is_empty());
+    }
+
+    log!(
+        Info,
+        "{} of {} segments match {}",
+        manifest.entries().len() - failed,
+        manifest.entries().len(),
+        path.display()
+    );
+
+    Ok(failed == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ffms_segmenter::checksum;
+
+    #[test]
+    fn reports_what_does_not_match() {
+        let dir =
+            std::env::temp_dir().join(format!("ffms-segmenter-verify-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("0-2.y4m");
+        std::fs::write(
+            &path,
+            "YUV4MPEG2 W2 H2 F24:1 Ip A1:1 Cmono\nFRAME\nabcdFRAME\nefgh",
+        )
+        .unwrap();
+        let (bytes, crc) = checksum::file_crc32(&path).unwrap();
+        let entry = Entry {
+            path: path.to_string_lossy().into_owned(),
+            input: 0,
+            start: 0,
+            end: 2,
+            frames: 2,
+            seconds: 2.0 / 24.0,
+            bytes,
+            crc,
+        };
+        assert!(check(&entry).is_empty());
+
+        let wrong = Entry {
+            frames: 3,
+            crc: crc ^ 1,
+            ..entry.clone()
+        };
+        assert_eq!(
+            check(&wrong),
+            [
+                "2 frames instead of 3 for 0..2".to_string(),
+                format!("CRC-32 {:08x} instead of {:08x}", crc, crc ^ 1)
+            ]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(check(&entry)[0].starts_with("cannot be read: "));
+    }
+}
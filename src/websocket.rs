@@ -0,0 +1,354 @@
+use crate::http::read_request;
+use crate::transport::{parse_segment, Client, Reply};
+use ffms_segmenter::log;
+use ffms_segmenter::log::json_string;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Appended to the client key before hashing, from RFC 6455.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+/// Progress events of one request are pushed at most this often.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (state, value) in h.iter_mut().zip([a, b, c, d, e]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (chunk, word) in digest.chunks_exact_mut(4).zip(h) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let triple = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(triple >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, ACCEPT_GUID).as_bytes()))
+}
+
+/// Reads one frame, returning its opcode, FIN bit and unmasked payload.
+fn read_frame(reader: &mut impl Read) -> io::Result<(u8, bool, Vec<u8>)> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header)?;
+
+    let fin = header[0] & 0x80 != 0;
+    let opcode = header[0] & 0x0f;
+    let masked = header[1] & 0x80 != 0;
+
+    let length = match header[1] & 0x7f {
+        126 => {
+            let mut length = [0u8; 2];
+            reader.read_exact(&mut length)?;
+            u16::from_be_bytes(length) as usize
+        }
+        127 => {
+            let mut length = [0u8; 8];
+            reader.read_exact(&mut length)?;
+            u64::from_be_bytes(length) as usize
+        }
+        length => length as usize,
+    };
+
+    if length > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Frame of {} bytes is too long", length),
+        ));
+    }
+
+    let mut mask = [0u8; 4];
+    if masked {
+        reader.read_exact(&mut mask)?;
+    }
+
+    let mut payload = vec![0; length];
+    reader.read_exact(&mut payload)?;
+    if masked {
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+    }
+
+    Ok((opcode, fin, payload))
+}
+
+fn write_frame(writer: &mut impl Write, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut header = vec![0x80 | opcode];
+    match payload.len() {
+        length if length < 126 => header.push(length as u8),
+        length if length <= u16::MAX as usize => {
+            header.push(126);
+            header.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            header.push(127);
+            header.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+
+    writer.write_all(&header)?;
+    writer.write_all(payload)?;
+    writer.flush()
+}
+
+type SharedStream = Arc<Mutex<TcpStream>>;
+
+fn push(stream: &SharedStream, event: &str) -> io::Result<()> {
+    write_frame(&mut *stream.lock().unwrap(), OPCODE_TEXT, event.as_bytes())
+}
+
+/// Pushes the responses to request `id` as JSON events until it finishes.
+fn forward_replies(stream: SharedStream, id: u64, replies: Receiver<Reply>) -> io::Result<()> {
+    let mut last_progress: Option<Instant> = None;
+
+    for reply in replies {
+        let event = match reply {
+            Reply::Progress(frame) => {
+                if last_progress.is_some_and(|last| last.elapsed() < PROGRESS_INTERVAL) {
+                    continue;
+                }
+                last_progress = Some(Instant::now());
+
+                format!(
+                    "{{\"event\":\"progress\",\"request\":{},\"frame\":{}}}",
+                    id, frame
+                )
+            }
+            Reply::Message(message) => match parse_segment(&message) {
+                Some((start, path)) => format!(
+                    "{{\"event\":\"segment\",\"request\":{},\"start\":{},\"path\":{}}}",
+                    id,
+                    start,
+                    json_string(path)
+                ),
+                None => format!(
+                    "{{\"event\":\"message\",\"request\":{},\"message\":{}}}",
+                    id,
+                    json_string(&message)
+                ),
+            },
+            Reply::Finished => {
+                return push(
+                    &stream,
+                    &format!("{{\"event\":\"finished\",\"request\":{}}}", id),
+                );
+            }
+        };
+
+        push(&stream, &event)?;
+    }
+
+    push(
+        &stream,
+        &format!(
+            "{{\"event\":\"error\",\"request\":{},\"message\":\"Session failed\"}}",
+            id
+        ),
+    )
+}
+
+fn handle_connection(client: &Client, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let request = read_request(&mut reader)?;
+    let mut stream = stream;
+
+    let key = match request.header("sec-websocket-key") {
+        Some(key) if request.method == "GET" => key,
+        _ => {
+            let body = "Expected a WebSocket upgrade";
+            return write!(
+                stream,
+                "HTTP/1.1 400 Bad Request\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+        }
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    )?;
+
+    let stream: SharedStream = Arc::new(Mutex::new(stream));
+    let mut message = Vec::new();
+
+    loop {
+        let (opcode, fin, payload) = read_frame(&mut reader)?;
+
+        match opcode {
+            OPCODE_TEXT | OPCODE_CONTINUATION => message.extend_from_slice(&payload),
+            OPCODE_PING => {
+                write_frame(&mut *stream.lock().unwrap(), OPCODE_PONG, &payload)?;
+                continue;
+            }
+            OPCODE_CLOSE => {
+                return write_frame(&mut *stream.lock().unwrap(), OPCODE_CLOSE, &payload);
+            }
+            _ => continue,
+        }
+
+        if !fin {
+            continue;
+        }
+
+        let command = String::from_utf8_lossy(&message).trim().to_string();
+        message.clear();
+
+        let (id, replies) = match client.request(&command) {
+            Err(err) if err.kind() == io::ErrorKind::ResourceBusy => {
+                push(
+                    &stream,
+                    &format!(
+                        "{{\"event\":\"rejected\",\"command\":{},\"message\":{}}}",
+                        json_string(&command),
+                        json_string(&err.to_string())
+                    ),
+                )?;
+                continue;
+            }
+            result => result?,
+        };
+        push(
+            &stream,
+            &format!(
+                "{{\"event\":\"queued\",\"request\":{},\"command\":{}}}",
+                id,
+                json_string(&command)
+            ),
+        )?;
+
+        let stream = stream.clone();
+        thread::spawn(move || forward_replies(stream, id, replies));
+    }
+}
+
+/// Accepts WebSocket connections on `listener`, handling each on its own
+/// thread.
+pub fn serve(listener: TcpListener, client: Client) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                log!(Error, "WebSocket accept failed: {}", err);
+                continue;
+            }
+        };
+
+        let client = client.clone();
+        thread::spawn(move || {
+            if let Err(err) = handle_connection(&client, stream) {
+                if err.kind() != io::ErrorKind::UnexpectedEof {
+                    log!(Error, "WebSocket connection failed: {}", err);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_key_matches_rfc_example() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn reads_masked_frames() {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![0x81, 0x80 | 5];
+        frame.extend_from_slice(&mask);
+        frame.extend(b"Hello".iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+
+        let (opcode, fin, payload) = read_frame(&mut &frame[..]).unwrap();
+        assert_eq!((opcode, fin), (OPCODE_TEXT, true));
+        assert_eq!(payload, b"Hello");
+
+        let mut written = Vec::new();
+        write_frame(&mut written, OPCODE_TEXT, &[0; 300]).unwrap();
+        assert_eq!(written[..4], [0x81, 126, 1, 44]);
+    }
+}
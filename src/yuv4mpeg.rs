@@ -0,0 +1,477 @@
+use crate::prefetch::DecodedFrame;
+use crate::scale::plane_sizes;
+use std::fmt;
+use std::io::{self, IoSlice, Write};
+use y4m::{Colorspace, Ratio};
+
+/// Field order written as the y4m `I` header parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interlacing {
+    Progressive,
+    TopFieldFirst,
+    BottomFieldFirst,
+}
+
+impl Interlacing {
+    /// Field order from the FFMS2 `InterlacedFrame` and `TopFieldFirst`
+    /// frame properties.
+    pub fn from_frame_props(interlaced: i32, top_field_first: i32) -> Self {
+        match (interlaced != 0, top_field_first != 0) {
+            (false, _) => Interlacing::Progressive,
+            (true, true) => Interlacing::TopFieldFirst,
+            (true, false) => Interlacing::BottomFieldFirst,
+        }
+    }
+}
+
+impl fmt::Display for Interlacing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Interlacing::Progressive => "Ip",
+            Interlacing::TopFieldFirst => "It",
+            Interlacing::BottomFieldFirst => "Ib",
+        })
+    }
+}
+
+/// Sample range of the stream, signalled with the `XCOLORRANGE` extension
+/// that FFmpeg reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorRange {
+    Limited,
+    Full,
+}
+
+impl ColorRange {
+    /// Range from the FFMS2 `ColorRange` frame property, treating an
+    /// unspecified range as limited.
+    pub fn from_frame_props(color_range: i32) -> Self {
+        // FFMS_CR_JPEG
+        match color_range {
+            2 => ColorRange::Full,
+            _ => ColorRange::Limited,
+        }
+    }
+}
+
+/// Color signaling of the stream: its range and, when known, the matrix,
+/// primaries and transfer, as the FFmpeg `AVCOL_*` codes FFMS2 reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color {
+    pub range: ColorRange,
+    pub properties: Option<(i32, i32, i32)>,
+}
+
+impl From<ColorRange> for Color {
+    fn from(range: ColorRange) -> Self {
+        Color {
+            range,
+            properties: None,
+        }
+    }
+}
+
+/// The parameters of a y4m header after the frame size and rate: color
+/// signaling and, when known, the sample aspect ratio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Parameters {
+    pub color: Color,
+    /// Written as the `A` parameter, so players scale anamorphic frames.
+    pub sar: Option<(usize, usize)>,
+}
+
+impl From<Color> for Parameters {
+    fn from(color: Color) -> Self {
+        Parameters { color, sar: None }
+    }
+}
+
+impl From<ColorRange> for Parameters {
+    fn from(range: ColorRange) -> Self {
+        Color::from(range).into()
+    }
+}
+
+/// FFmpeg's name of a matrix, in capitals like the other y4m parameters.
+fn matrix_name(matrix: i32) -> Option<&'static str> {
+    Some(match matrix {
+        0 => "GBR",
+        1 => "BT709",
+        4 => "FCC",
+        5 => "BT470BG",
+        6 => "SMPTE170M",
+        7 => "SMPTE240M",
+        8 => "YCGCO",
+        9 => "BT2020NC",
+        10 => "BT2020C",
+        11 => "SMPTE2085",
+        12 => "CHROMA-DERIVED-NC",
+        13 => "CHROMA-DERIVED-C",
+        14 => "ICTCP",
+        _ => return None,
+    })
+}
+
+fn primaries_name(primaries: i32) -> Option<&'static str> {
+    Some(match primaries {
+        1 => "BT709",
+        4 => "BT470M",
+        5 => "BT470BG",
+        6 => "SMPTE170M",
+        7 => "SMPTE240M",
+        8 => "FILM",
+        9 => "BT2020",
+        10 => "SMPTE428",
+        11 => "SMPTE431",
+        12 => "SMPTE432",
+        22 => "EBU3213",
+        _ => return None,
+    })
+}
+
+fn transfer_name(transfer: i32) -> Option<&'static str> {
+    Some(match transfer {
+        1 => "BT709",
+        4 => "GAMMA22",
+        5 => "GAMMA28",
+        6 => "SMPTE170M",
+        7 => "SMPTE240M",
+        8 => "LINEAR",
+        9 => "LOG100",
+        10 => "LOG316",
+        11 => "IEC61966-2-4",
+        12 => "BT1361E",
+        13 => "IEC61966-2-1",
+        14 => "BT2020-10",
+        15 => "BT2020-12",
+        16 => "SMPTE2084",
+        17 => "SMPTE428",
+        18 => "ARIB-STD-B67",
+        _ => return None,
+    })
+}
+
+/// The X-prefixed parameters carrying `color`. Only `XCOLORRANGE` is read
+/// by FFmpeg; `XCOLORMATRIX`, `XCOLORPRIMARIES` and `XCOLORTRC` are left
+/// out when unspecified.
+fn color_parameters(color: Color) -> String {
+    let range = match color.range {
+        ColorRange::Limited => "LIMITED",
+        ColorRange::Full => "FULL",
+    };
+    let mut parameters = format!(" XCOLORRANGE={}", range);
+
+    if let Some((matrix, primaries, transfer)) = color.properties {
+        let tags = [
+            ("XCOLORMATRIX", matrix_name(matrix)),
+            ("XCOLORPRIMARIES", primaries_name(primaries)),
+            ("XCOLORTRC", transfer_name(transfer)),
+        ];
+        for (key, name) in tags {
+            if let Some(name) = name {
+                parameters.push_str(&format!(" {}={}", key, name));
+            }
+        }
+    }
+
+    parameters
+}
+
+fn header(
+    width: usize,
+    height: usize,
+    framerate: Ratio,
+    colorspace: Colorspace,
+    interlacing: Interlacing,
+    parameters: Parameters,
+) -> String {
+    let aspect = match parameters.sar {
+        Some((num, den)) => format!(" A{}:{}", num, den),
+        None => String::new(),
+    };
+
+    format!(
+        "YUV4MPEG2 W{} H{} F{}:{} {}{} {:?}{}\n",
+        width,
+        height,
+        framerate.num,
+        framerate.den,
+        interlacing,
+        aspect,
+        colorspace,
+        color_parameters(parameters.color)
+    )
+}
+
+fn plane_lengths(colorspace: Colorspace, width: usize, height: usize) -> [usize; 3] {
+    let bytes = colorspace.get_bytes_per_sample();
+    plane_sizes(colorspace, width, height).map(|plane| plane.width * plane.height * bytes)
+}
+
+/// Size in bytes of a stream of `frames` frames.
+pub fn stream_size(
+    width: usize,
+    height: usize,
+    framerate: Ratio,
+    colorspace: Colorspace,
+    interlacing: Interlacing,
+    parameters: impl Into<Parameters>,
+    frames: usize,
+) -> u64 {
+    let frame_len: usize = plane_lengths(colorspace, width, height).iter().sum();
+    let header = header(
+        width,
+        height,
+        framerate,
+        colorspace,
+        interlacing,
+        parameters.into(),
+    );
+
+    header.len() as u64 + frames as u64 * (b"FRAME\n".len() + frame_len) as u64
+}
+
+/// Writes every slice, in as few calls as `sink` allows.
+fn write_all_vectored(sink: &mut impl Write, mut slices: &mut [IoSlice]) -> io::Result<()> {
+    while !slices.is_empty() {
+        match sink.write_vectored(slices) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => IoSlice::advance_slices(&mut slices, written),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(())
+}
+
+/// YUV4MPEG2 stream writer. Unlike the `y4m` crate's encoder it can write
+/// the interlacing parameter.
+pub struct Writer<W: Write> {
+    sink: W,
+    plane_lengths: [usize; 3],
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(
+        mut sink: W,
+        width: usize,
+        height: usize,
+        framerate: Ratio,
+        colorspace: Colorspace,
+        interlacing: Interlacing,
+        parameters: impl Into<Parameters>,
+    ) -> io::Result<Self> {
+        let header = header(
+            width,
+            height,
+            framerate,
+            colorspace,
+            interlacing,
+            parameters.into(),
+        );
+        sink.write_all(header.as_bytes())?;
+
+        Ok(Writer {
+            sink,
+            plane_lengths: plane_lengths(colorspace, width, height),
+        })
+    }
+
+    pub fn write_frame(&mut self, frame: &DecodedFrame) -> io::Result<()> {
+        for (plane, &length) in frame.planes.iter().zip(&self.plane_lengths) {
+            if plane.len() != length {
+                return Err(io::Error::other(format!(
+                    "Frame plane is {} bytes, expected {}",
+                    plane.len(),
+                    length
+                )));
+            }
+        }
+
+        // The planes go to the sink straight from the frame, along with the
+        // header, rather than through a buffer.
+        let [y, u, v] = &frame.planes;
+        write_all_vectored(
+            &mut self.sink,
+            &mut [
+                IoSlice::new(b"FRAME\n"),
+                IoSlice::new(y),
+                IoSlice::new(u),
+                IoSlice::new(v),
+            ],
+        )
+    }
+
+    pub fn into_inner(self) -> W {
+        self.sink
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_interlacing_in_header() {
+        let mut out = Vec::new();
+        let framerate = Ratio {
+            num: 30000,
+            den: 1001,
+        };
+        let mut writer = Writer::new(
+            &mut out,
+            2,
+            2,
+            framerate,
+            Colorspace::C420,
+            Interlacing::TopFieldFirst,
+            ColorRange::Limited,
+        )
+        .unwrap();
+
+        writer
+            .write_frame(&DecodedFrame {
+                planes: [vec![1; 4], vec![2], vec![3]],
+                alpha: None,
+            })
+            .unwrap();
+        assert!(writer
+            .write_frame(&DecodedFrame {
+                planes: [vec![1; 3], vec![2], vec![3]],
+                alpha: None,
+            })
+            .is_err());
+
+        assert_eq!(
+            out,
+            b"YUV4MPEG2 W2 H2 F30000:1001 It C420 XCOLORRANGE=LIMITED\nFRAME\n\x01\x01\x01\x01\x02\x03"
+        );
+        assert_eq!(
+            stream_size(
+                2,
+                2,
+                framerate,
+                Colorspace::C420,
+                Interlacing::TopFieldFirst,
+                ColorRange::Limited,
+                1
+            ),
+            out.len() as u64
+        );
+    }
+
+    #[test]
+    fn signals_full_range() {
+        let framerate = Ratio { num: 25, den: 1 };
+
+        assert_eq!(
+            header(
+                4,
+                2,
+                framerate,
+                Colorspace::C422,
+                Interlacing::Progressive,
+                ColorRange::Full.into()
+            ),
+            "YUV4MPEG2 W4 H2 F25:1 Ip C422 XCOLORRANGE=FULL\n"
+        );
+    }
+
+    #[test]
+    fn signals_sample_aspect_ratio() {
+        let parameters = Parameters {
+            color: ColorRange::Limited.into(),
+            sar: Some((10, 11)),
+        };
+
+        assert_eq!(
+            header(
+                720,
+                480,
+                Ratio {
+                    num: 30000,
+                    den: 1001
+                },
+                Colorspace::C420,
+                Interlacing::BottomFieldFirst,
+                parameters
+            ),
+            "YUV4MPEG2 W720 H480 F30000:1001 Ib A10:11 C420 XCOLORRANGE=LIMITED\n"
+        );
+    }
+
+    #[test]
+    fn signals_known_color_properties() {
+        let color = Color {
+            range: ColorRange::Limited,
+            // BT.2020 non-constant luminance with PQ, and unspecified
+            // primaries.
+            properties: Some((9, 2, 16)),
+        };
+
+        assert_eq!(
+            color_parameters(color),
+            " XCOLORRANGE=LIMITED XCOLORMATRIX=BT2020NC XCOLORTRC=SMPTE2084"
+        );
+    }
+
+    /// Takes at most 3 bytes per call.
+    struct Trickle(Vec<u8>);
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let len = buf.len().min(3);
+            self.0.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn resumes_partial_frame_writes() {
+        let mut trickle = Trickle(Vec::new());
+        write_all_vectored(
+            &mut trickle,
+            &mut [
+                IoSlice::new(b"FRAME\n"),
+                IoSlice::new(&[1, 2, 3, 4]),
+                IoSlice::new(&[]),
+                IoSlice::new(&[5]),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(trickle.0, b"FRAME\n\x01\x02\x03\x04\x05");
+    }
+
+    #[test]
+    fn writes_monochrome_frames() {
+        let mut out = Vec::new();
+        let mut writer = Writer::new(
+            &mut out,
+            2,
+            1,
+            Ratio { num: 24, den: 1 },
+            Colorspace::Cmono,
+            Interlacing::Progressive,
+            ColorRange::Limited,
+        )
+        .unwrap();
+
+        writer
+            .write_frame(&DecodedFrame {
+                planes: [vec![7, 9], Vec::new(), Vec::new()],
+                alpha: None,
+            })
+            .unwrap();
+
+        assert_eq!(
+            out,
+            b"YUV4MPEG2 W2 H1 F24:1 Ip Cmono XCOLORRANGE=LIMITED\nFRAME\n\x07\x09"
+        );
+    }
+}
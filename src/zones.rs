@@ -0,0 +1,28 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Reads `<start> <end>` lines, ignoring blank lines and `#` comments.
+pub fn read(path: &Path) -> io::Result<Vec<(usize, usize)>> {
+    let text = fs::read_to_string(path)?;
+    let mut zones = Vec::new();
+
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let bounds: Vec<usize> = line
+            .split_whitespace()
+            .map(|token| token.parse::<usize>())
+            .collect::<Result<_, _>>()
+            .map_err(|_| io::Error::other(format!("Invalid zone line: {}", line)))?;
+
+        match bounds[..] {
+            [start, end] if start < end => zones.push((start, end)),
+            _ => return Err(io::Error::other(format!("Invalid zone line: {}", line))),
+        }
+    }
+
+    Ok(zones)
+}
@@ -0,0 +1,143 @@
+//! Guards the public API against accidental breaking changes. These tests
+//! only need to compile and agree with the documented protocol; changing
+//! them means a semver-major release.
+
+use ffms_segmenter::{
+    Chroma, Event, Handshake, Interlacing, OutputFormat, Protocol, RangeConvention, SegmentRequest,
+    Segmenter,
+};
+use std::io;
+use std::path::Path;
+
+type WriteSegment =
+    fn(&mut Segmenter, SegmentRequest, Vec<u8>, &mut dyn FnMut(Event)) -> io::Result<()>;
+
+#[test]
+fn segmenter_signatures() {
+    let _: fn(&Path, Option<usize>, usize) -> io::Result<Segmenter> = Segmenter::open;
+    let _: fn(&Segmenter) -> OutputFormat = Segmenter::format;
+    let _: fn(&Segmenter) -> usize = Segmenter::frame_count;
+    let _: WriteSegment = Segmenter::write_segment::<Vec<u8>>;
+}
+
+#[test]
+fn public_fields() {
+    let request = SegmentRequest::new(10, 20);
+    assert_eq!((request.input, request.start, request.end), (0, 10, 20));
+
+    let _ = |format: OutputFormat| -> (usize, usize, usize, usize, Chroma, usize, Interlacing) {
+        (
+            format.width,
+            format.height,
+            format.framerate_num,
+            format.framerate_den,
+            format.chroma,
+            format.bit_depth,
+            format.interlacing,
+        )
+    };
+
+    let _ = |event: Event| match event {
+        Event::FrameWritten { frame } => frame,
+        Event::SegmentFinished { start, end } => end - start,
+        _ => 0,
+    };
+}
+
+#[test]
+fn protocol_v1() {
+    assert_eq!(Protocol::V1.version(), 1);
+    assert_eq!(Protocol::from_version(1), Some(Protocol::V1));
+    assert_eq!(Protocol::from_version(0), None);
+
+    assert_eq!(
+        Protocol::V1.parse_request("100 200\n"),
+        Some(SegmentRequest::new(100, 200))
+    );
+    assert_eq!(Protocol::V1.parse_request("100"), None);
+    assert_eq!(Protocol::V1.parse_request("100 200 300"), None);
+    assert_eq!(
+        Protocol::V1.format_segment(100, "./100-200.y4m"),
+        "100 ./100-200.y4m"
+    );
+}
+
+#[test]
+fn protocol_v2() {
+    assert_eq!(Protocol::LATEST, Protocol::V2);
+    assert_eq!(Protocol::V2.version(), 2);
+    assert_eq!(Protocol::from_version(2), Some(Protocol::V2));
+
+    assert_eq!(
+        Protocol::V2.parse_request("100 200"),
+        Some(SegmentRequest::new(100, 200))
+    );
+    let request = Protocol::V2.parse_request("1 100 200").unwrap();
+    assert_eq!((request.input, request.start, request.end), (1, 100, 200));
+    assert_eq!(Protocol::V1.parse_request("1 100 200"), None);
+}
+
+#[test]
+fn protocol_multiple_ranges() {
+    // A line with several ranges is one request to the binary, but not to
+    // `parse_request`.
+    assert_eq!(Protocol::V2.parse_request("0 100 240 360"), None);
+    assert_eq!(Protocol::V2.parse_request("0 100; 240 360"), None);
+
+    let _: fn(Protocol, &str) -> Option<Vec<SegmentRequest>> = Protocol::parse_requests;
+    let split = [SegmentRequest::new(0, 100), SegmentRequest::new(240, 360)];
+    assert_eq!(Protocol::V2.parse_requests("0 100 240 360").unwrap(), split);
+    assert_eq!(
+        Protocol::V1.parse_requests("0 100; 240 360").unwrap(),
+        split
+    );
+    assert_eq!(
+        Protocol::V2.parse_requests("100 200").unwrap(),
+        [SegmentRequest::new(100, 200)]
+    );
+
+    let request = &Protocol::V2.parse_requests("0 100; 1 240 360").unwrap()[1];
+    assert_eq!((request.input, request.start, request.end), (1, 240, 360));
+    assert_eq!(Protocol::V1.parse_requests("0 100; 1 240 360"), None);
+    let requests = Protocol::V2.parse_requests("1 0 100 240 360").unwrap();
+    assert!(requests.iter().all(|request| request.input == 1));
+    assert_eq!(Protocol::V2.parse_requests("1 0 100 240"), None);
+    assert_eq!(Protocol::V1.parse_requests("0 100 240"), None);
+}
+
+#[test]
+fn handshake() {
+    let line = "hello protocol=2 version=0.1.0 commands=range,open colorspaces=420 formats=y4m";
+    let handshake = Handshake::parse(line).unwrap();
+
+    assert_eq!(handshake.protocol(), Some(Protocol::V2));
+    assert_eq!(handshake.version, "0.1.0");
+    assert!(handshake.supports("open"));
+    assert!(!handshake.supports("thumb"));
+    assert_eq!(handshake.colorspaces, ["420"]);
+    assert_eq!(handshake.formats, ["y4m"]);
+    assert_eq!(handshake.format(), line);
+    assert_eq!(handshake.ranges, None);
+
+    let future = Handshake::parse("hello protocol=9 compression=zstd").unwrap();
+    assert_eq!((future.protocol, future.protocol()), (9, None));
+    assert_eq!(Handshake::parse("1920 1080 48 1001 24000"), None);
+}
+
+#[test]
+fn range_conventions() {
+    assert_eq!(RangeConvention::default(), RangeConvention::Exclusive);
+    assert_eq!(RangeConvention::Exclusive.exclusive_end(24), 24);
+    assert_eq!(RangeConvention::Inclusive.exclusive_end(23), 24);
+    assert_eq!(RangeConvention::Inclusive.format_range(0, 24), "0 23");
+    assert_eq!(
+        RangeConvention::from_name("inclusive"),
+        Some(RangeConvention::Inclusive)
+    );
+
+    let line = "hello protocol=2 version=0.1.0 commands=range colorspaces=420 formats=y4m \
+                ranges=inclusive";
+    let handshake = Handshake::parse(line).unwrap();
+    assert_eq!(handshake.ranges, Some(RangeConvention::Inclusive));
+    assert_eq!(handshake.format(), line);
+}